@@ -7,9 +7,13 @@ pub type FitsHandle = *mut c_void;
 pub const READONLY: c_int = 0;
 pub const FILE_NOT_OPENED: c_int = 104; // "could not open the named file"
 pub const READ_ERROR: c_int = 108; // "error reading from FITS file"
+pub const TBYTE: c_int = 11;
 pub const TSTRING: c_int = 16;
 pub const TSHORT: c_int = 21;
+pub const TFLOAT: c_int = 42;
+pub const TLONGLONG: c_int = 81;
 pub const TDOUBLE: c_int = 82;
+pub const RICE_1: c_int = 11;
 
 extern "C" {
     /// Register a new I/O driver with the library.
@@ -80,6 +84,24 @@ extern "C" {
         status: *mut c_int,
     ) -> c_int;
 
+    /// Append a new image HDU, longlong mode
+    pub fn ffcrimll(
+        handle: FitsHandle,
+        bitpix: c_int,
+        naxis: c_int,
+        naxes: *const c_longlong,
+        status: *mut c_int,
+    ) -> c_int;
+
+    /// Copy the current HDU of one file to a new HDU at the end of another. A
+    /// primary array becomes an image extension.
+    pub fn ffcopy(
+        infptr: FitsHandle,
+        outfptr: FitsHandle,
+        morekeys: c_int,
+        status: *mut c_int,
+    ) -> c_int;
+
     /// Update a HDU header
     pub fn ffuky(
         handle: FitsHandle,
@@ -90,6 +112,15 @@ extern "C" {
         status: *mut c_int,
     ) -> c_int;
 
+    /// Append a `HISTORY` record to a HDU header
+    pub fn ffphis(handle: FitsHandle, history: *const c_char, status: *mut c_int) -> c_int;
+
+    /// Append an 80-character header record to the current HDU.
+    pub fn ffprec(handle: FitsHandle, card: *const c_char, status: *mut c_int) -> c_int;
+
+    /// Append a COMMENT record to the current HDU.
+    pub fn ffpcom(handle: FitsHandle, comment: *const c_char, status: *mut c_int) -> c_int;
+
     /// Extract HDU headers as string(s), converting as needed if the
     /// HDU is for a compressed image.
     pub fn ffcnvthdr2str(
@@ -114,6 +145,26 @@ extern "C" {
         status: *mut c_int,
     ) -> c_int;
 
+    /// Read a rectangular subset of an image, short-int format. The corner
+    /// and increment arrays are 1-based and inclusive.
+    pub fn ffgsvi(
+        handle: FitsHandle,
+        colnum: c_int,
+        naxis: c_int,
+        naxes: *const c_long,
+        blc: *const c_long,
+        trc: *const c_long,
+        inc: *const c_long,
+        nulval: c_short,
+        array: *mut c_short,
+        anynul: *mut c_int,
+        status: *mut c_int,
+    ) -> c_int;
+
+    /// Get the optimal number of pixels of an image (or rows of a table) to
+    /// access at once, given the size of CFITSIO's internal buffers.
+    pub fn ffgrsz(handle: FitsHandle, ndata: *mut c_long, status: *mut c_int) -> c_int;
+
     /// Write pixel values, longlong indexing.
     pub fn ffppxll(
         handle: FitsHandle,
@@ -124,6 +175,28 @@ extern "C" {
         status: *mut c_int,
     ) -> c_int;
 
+    /// Flush buffers and rewrite the current HDU's structural keywords.
+    pub fn ffflus(handle: FitsHandle, status: *mut c_int) -> c_int;
+
+    /// Get the byte offsets of the start of the current HDU's header and data,
+    /// and of the end of its data, including padding.
+    pub fn ffghadll(
+        handle: FitsHandle,
+        headstart: *mut c_longlong,
+        datastart: *mut c_longlong,
+        dataend: *mut c_longlong,
+        status: *mut c_int,
+    ) -> c_int;
+
+    /// Get the total number of HDUs in the file.
+    pub fn ffthdu(handle: FitsHandle, nhdu: *mut c_int, status: *mut c_int) -> c_int;
+
+    /// Set the tile-compression algorithm for images created from now on.
+    /// Compressed images are stored in binary table extensions, so if the
+    /// file is empty, a null primary array is created in front of the first.
+    pub fn fits_set_compression_type(handle: FitsHandle, ctype: c_int, status: *mut c_int)
+        -> c_int;
+
     /// Close a handle, freeing the structure if this is the
     /// last one referencing the given file.
     pub fn ffclos(handle: FitsHandle, status: *mut c_int) -> c_int;
@@ -4,8 +4,58 @@ use libc::{c_char, c_double, c_int, c_void};
 
 pub type WcsPrm = *mut c_void;
 
+/// `wcspih` relax flag: accept no nonstandard WCS keywords.
+pub const WCSHDR_NONE: c_int = 0x0;
+
+/// `wcspih` relax flag: accept all of the nonstandard WCS keywords that
+/// wcslib knows about.
 pub const WCSHDR_ALL: c_int = 0xFFFFF;
 
+/// `wcspih` relax flag: reject nonstandard keywords, rather than ignoring
+/// them, so that they're counted and reported.
+pub const WCSHDR_REJECT: c_int = 0x10000000;
+
+/// `wcspih` relax flag: also reject keywords that are technically
+/// nonstandard but usually tolerated.
+pub const WCSHDR_STRICT: c_int = 0x20000000;
+
+/// `wcspih` ctrl value: report each rejected keyword and why.
+pub const WCSHDR_CTRL_REPORT: c_int = 2;
+
+/// `wcshdo` ctrl value: only write the informal extensions that are widely
+/// recognized.
+pub const WCSHDO_SAFE: c_int = 0x0000F;
+
+/// `wcshdo` ctrl flag: write floating-point values with 14 significant
+/// digits.
+pub const WCSHDO_P14: c_int = 0x04000;
+
+/// The number of fixes applied by `wcsfixi`, which sizes its status arrays.
+pub const NWCSFIX: usize = 7;
+
+/// The names of the fixes applied by `wcsfixi`, in the order of its status
+/// arrays.
+pub const WCSFIX_NAMES: [&str; NWCSFIX] = [
+    "cdfix", "datfix", "obsfix", "unitfix", "spcfix", "celfix", "cylfix",
+];
+
+/// `wcsfixi` status value: the fix didn't change anything.
+pub const FIXERR_NO_CHANGE: c_int = -1;
+
+/// `wcsfixi` status value: the fix wasn't attempted.
+pub const FIXERR_NOT_INVOKED: c_int = -2;
+
+/// wcslib's error and message structure.
+#[repr(C)]
+#[derive(Debug)]
+pub struct WcsErr {
+    pub status: c_int,
+    pub line_no: c_int,
+    pub function: *const c_char,
+    pub file: *const c_char,
+    pub msg: *mut c_char,
+}
+
 extern "C" {
     /// Parse FITS headers for WCS.
     pub fn wcspih(
@@ -44,9 +94,39 @@ extern "C" {
         stat: *mut c_int,
     ) -> c_int;
 
+    /// Write out a WCS structure as FITS header records. The `header` must be
+    /// freed with `wcsdealloc`.
+    pub fn wcshdo(ctrl: c_int, wcs: WcsPrm, nkeyrec: *mut c_int, header: *mut *mut c_char)
+        -> c_int;
+
     /// Get size of WCS structure; sizes must be able to fit 2 ints
     pub fn wcssize(wcs: WcsPrm, sizes: *mut c_int) -> c_int;
 
     /// Free a list of WCS structures.
     pub fn wcsvfree(nwcs: *mut c_int, wcs: *mut WcsPrm) -> c_int;
+
+    /// Repair nonstandard values in a WCS structure. If `naxis` is null, the
+    /// fix for malformed cylindrical projections is skipped. Messages about
+    /// the fixes are only filled into `info` if enabled with
+    /// `wcserr_enable`; their `msg` strings must be freed with `wcsdealloc`.
+    pub fn wcsfixi(
+        ctrl: c_int,
+        naxis: *const c_int,
+        wcs: WcsPrm,
+        stat: *mut c_int,
+        info: *mut WcsErr,
+    ) -> c_int;
+
+    /// Enable or disable wcslib's error messages. Not thread-safe.
+    pub fn wcserr_enable(enable: c_int) -> c_int;
+
+    /// Free memory allocated by wcslib.
+    pub fn wcsdealloc(ptr: *mut c_void);
+
+    /// Set where wcslib's diagnostic output goes. If `wcsout` is null, it's
+    /// captured in an internal buffer, which this call empties.
+    pub fn wcsprintf_set(wcsout: *mut c_void) -> c_int;
+
+    /// Get the buffer of captured diagnostic output.
+    pub fn wcsprintf_buf() -> *const c_char;
 }
@@ -0,0 +1,139 @@
+//! Admission control for heavy operations.
+//!
+//! A warm Lambda instance can be asked to handle several requests at once, and
+//! API Gateway will retry requests that time out, so that work can pile up on
+//! one instance. Cutouts need tens of megabytes of scratch space apiece, so
+//! enough of them running at once will exhaust the instance's memory and
+//! crash everything in flight.
+//!
+//! To prevent this, each heavy operation must reserve its estimated memory
+//! usage from a per-instance budget before it starts. If there isn't room, it
+//! waits a little while for other operations to finish. If there still isn't
+//! room, the request is rejected with a [`BusyError`], which clients should
+//! treat as a signal to retry later.
+//!
+//! Each service declares its memory cost in the registry, along with the
+//! maximum number of its invocations that may run at once in one instance, if
+//! that's limited. Every invocation is admitted here, subject to both, before
+//! it's dispatched, so that heavyweight services can share an instance with
+//! cheap metadata services without starving them. Services with a cost of
+//! zero and no concurrency limit are admitted immediately.
+
+use std::{collections::HashMap, fmt, time::Duration};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::{config::Config, registry::HandlerInfo};
+
+/// The per-instance budget for heavy operations.
+#[derive(Debug)]
+pub struct Admission {
+    /// One permit per MiB of the budget.
+    budget: Semaphore,
+    budget_mib: u32,
+    wait: Duration,
+
+    /// One semaphore per service with a concurrency limit, holding one permit
+    /// per allowed invocation.
+    slots: HashMap<&'static str, Semaphore>,
+}
+
+/// The reservations held by an admitted invocation, released when it's
+/// dropped.
+pub struct Permit<'a> {
+    _slot: Option<SemaphorePermit<'a>>,
+    _memory: Option<SemaphorePermit<'a>>,
+}
+
+/// The error returned when an operation can't be admitted.
+#[derive(Debug)]
+pub struct BusyError {
+    pub operation: &'static str,
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for BusyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "busy: too many concurrent `{}` operations on this server; retry after {} ms",
+            self.operation,
+            self.retry_after.as_millis()
+        )
+    }
+}
+
+impl std::error::Error for BusyError {}
+
+impl Admission {
+    pub fn new(config: &Config, handlers: &[HandlerInfo]) -> Self {
+        // Always allow at least one operation, even if the budget is silly.
+        let budget_mib = config.memory_budget_mib.max(1);
+
+        let slots = handlers
+            .iter()
+            .filter_map(|h| Some((h.name, Semaphore::new(h.max_concurrency?.max(1)))))
+            .collect();
+
+        Admission {
+            budget: Semaphore::new(budget_mib as usize),
+            budget_mib,
+            wait: config.admission_wait,
+            slots,
+        }
+    }
+
+    /// Admit an invocation of a service, taking one of its concurrency slots
+    /// and reserving its memory cost from the budget. The reservations are
+    /// held until the returned permit is dropped.
+    ///
+    /// An operation that costs more than the entire budget is charged the
+    /// entire budget, so that it can run, but only by itself.
+    pub async fn admit(&self, handler: &HandlerInfo) -> Result<Permit<'_>, BusyError> {
+        let operation = handler.name;
+        let slots = self.slots.get(operation);
+
+        let acquire = async {
+            let slot = match slots {
+                Some(s) => Some(s.acquire().await?),
+                None => None,
+            };
+
+            let memory = match handler.memory_cost_mib {
+                0 => None,
+                cost => Some(
+                    self.budget
+                        .acquire_many(cost.clamp(1, self.budget_mib))
+                        .await?,
+                ),
+            };
+
+            Ok::<_, tokio::sync::AcquireError>(Permit {
+                _slot: slot,
+                _memory: memory,
+            })
+        };
+
+        match tokio::time::timeout(self.wait, acquire).await {
+            Ok(Ok(permit)) => Ok(permit),
+
+            // Timed out. (The semaphores are never closed, so the inner error
+            // can't happen.)
+            _ => {
+                eprintln!(
+                    "rejecting `{}` operation: {} of {} MiB in use, {} invocations free",
+                    operation,
+                    self.budget_mib as usize - self.budget.available_permits(),
+                    self.budget_mib,
+                    slots.map_or("unlimited".to_owned(), |s| s
+                        .available_permits()
+                        .to_string()),
+                );
+
+                Err(BusyError {
+                    operation,
+                    retry_after: self.wait,
+                })
+            }
+        }
+    }
+}
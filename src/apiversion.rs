@@ -0,0 +1,114 @@
+//! Versioning of the service response formats.
+//!
+//! Deployed versions of daschlab can't be updated in lockstep with the
+//! services, so requests may set an `api_version` field to ask for the output
+//! schema that they were written against. Each version freezes the shape of
+//! the responses, including the set and order of the CSV columns. Requests
+//! that don't specify a version get the current one.
+//!
+//! - Version 1 is the original format. Results are returned bare, without
+//!   the envelope described in `envelope.rs`. `queryexps` and `querycat`
+//!   return arrays of CSV rows with the original columns, and `cutout`
+//!   returns the encoded FITS file as a string. Features that can't be
+//!   expressed in this format, like estimates and partial results, aren't
+//!   available.
+//! - Version 2 adds the envelope, and the richer results that come with it.
+//!
+//! The services always compute their results in the current format, and
+//! convert them to older formats at the end. When making a breaking change to
+//! a response, bump [`CURRENT`] and teach the affected services to convert.
+
+use lambda_http::Error;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{envelope, validation};
+
+/// The oldest supported API version.
+pub const OLDEST: u32 = 1;
+
+/// The current API version.
+pub const CURRENT: u32 = 2;
+
+/// The error for version-1 requests whose results are truncated, because they
+/// ran short of time or hit the row cap. That version has no way to return
+/// partial results.
+pub const TRUNCATED_V1: &str =
+    "the query results were truncated; use api_version 2 or later to get partial results";
+
+/// The default value of the `api_version` request field, for serde.
+pub fn default_api_version() -> u32 {
+    CURRENT
+}
+
+/// Whether an API version is the current one. Used to leave the field out of
+/// request echoes, so that request hashes don't change when it's given
+/// explicitly.
+pub fn is_current(version: &u32) -> bool {
+    *version == CURRENT
+}
+
+/// Validate a requested API version.
+pub fn validate(name: &str, version: u32) -> Result<u32, Error> {
+    Ok(validation::range(name, version, OLDEST..=CURRENT)?)
+}
+
+/// Reject a request that uses a feature not available in its API version.
+pub fn require(version: u32, needed: u32, feature: &str) -> Result<(), Error> {
+    if version < needed {
+        return Err(format!("{feature} requires api_version {needed} or later").into());
+    }
+
+    Ok(())
+}
+
+/// Wrap a result in the response format of the given API version: the
+/// envelope for current requests, or the result itself for version 1.
+pub fn respond<R: Serialize, T: Serialize>(
+    version: u32,
+    service: &'static str,
+    request: &R,
+    result: T,
+) -> Result<Value, Error> {
+    if version < 2 {
+        Ok(serde_json::to_value(result)?)
+    } else {
+        envelope::wrap(service, request, result)
+    }
+}
+
+/// Select a subset of the columns of some CSV rows, by name, to match an
+/// older schema. The first row is the header. The columns that we produce
+/// never contain commas, so we don't need to worry about quoting. Fails if
+/// the rows lack one of the columns, which normalization should have
+/// prevented.
+pub fn project_rows(rows: Vec<String>, columns: &[&str]) -> Result<Vec<String>, Error> {
+    let Some(header) = rows.first() else {
+        return Ok(rows);
+    };
+
+    let indices: Vec<usize> = {
+        let current: Vec<&str> = header.split(',').collect();
+        columns
+            .iter()
+            .map(|c| {
+                current
+                    .iter()
+                    .position(|h| h == c)
+                    .ok_or_else(|| -> Error { format!("results lack the `{c}` column").into() })
+            })
+            .collect::<Result<_, _>>()?
+    };
+
+    Ok(rows
+        .iter()
+        .map(|row| {
+            let cells: Vec<&str> = row.split(',').collect();
+            indices
+                .iter()
+                .map(|&i| cells.get(i).copied().unwrap_or_default())
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+        .collect())
+}
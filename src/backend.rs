@@ -0,0 +1,776 @@
+//! Abstractions over the AWS data services that we use.
+//!
+//! The handlers don't talk to the DynamoDB and S3 clients directly; instead they
+//! go through the [`TableStore`] and [`ObjectStore`] traits defined here. In
+//! production these are implemented by [`AwsStore`], which just forwards to the
+//! AWS SDK. But the indirection means that we can also run the handlers against
+//! on-disk fixtures (see `crate::fixtures`), which is the only way to test them
+//! end-to-end without live AWS credentials.
+//!
+//! The traits use boxed futures so that they can be used as trait objects.
+//!
+//! Table reads report the capacity that they consume to `crate::capacity`.
+
+use aws_config::{Region, SdkConfig};
+use aws_sdk_dynamodb::primitives::Blob;
+use aws_sdk_dynamodb::types::{AttributeValue, ConsumedCapacity, ReturnConsumedCapacity};
+use aws_sdk_s3::{
+    error::ProvideErrorMetadata,
+    presigning::PresigningConfig,
+    types::{CompletedMultipartUpload, CompletedPart},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::future::BoxFuture;
+use lambda_http::Error;
+use lambda_runtime::tracing::{info_span, Instrument};
+use once_cell::sync::OnceCell;
+use serde_json::{Map, Value};
+use std::{collections::HashMap, time::Duration};
+
+use crate::{capacity, config::Config};
+
+/// A raw DynamoDB item.
+pub type Item = HashMap<String, AttributeValue>;
+
+/// The results of a batch lookup.
+#[derive(Debug, Default)]
+pub struct BatchGetOutput {
+    /// The items that were retrieved.
+    pub items: Vec<Item>,
+
+    /// The keys that the service didn't get around to processing. These should
+    /// be resubmitted.
+    pub unprocessed: Vec<AttributeValue>,
+}
+
+/// Access to DynamoDB-style tables.
+pub trait TableStore: Send + Sync {
+    /// Get a single item by its partition key, returning only the attributes
+    /// named in the projection expression.
+    fn get_item<'a>(
+        &'a self,
+        table: &'a str,
+        key_attr: &'a str,
+        key: AttributeValue,
+        projection: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Item>, Error>>;
+
+    /// Get a batch of items by their partition keys. The results may be
+    /// incomplete, in which case the missing keys are returned as
+    /// "unprocessed".
+    fn batch_get_items<'a>(
+        &'a self,
+        table: &'a str,
+        key_attr: &'a str,
+        keys: Vec<AttributeValue>,
+        projection: &'a str,
+    ) -> BoxFuture<'a, Result<BatchGetOutput, Error>>;
+
+    /// Get all of the items sharing a partition key.
+    fn query_items<'a>(
+        &'a self,
+        table: &'a str,
+        partition_attr: &'a str,
+        partition_value: AttributeValue,
+    ) -> BoxFuture<'a, Result<Vec<Item>, Error>>;
+
+    /// Get all of the items sharing a partition key of a global secondary
+    /// index of a table, returning only the attributes named in the
+    /// projection expression.
+    fn query_index<'a>(
+        &'a self,
+        table: &'a str,
+        index: &'a str,
+        partition_attr: &'a str,
+        partition_value: AttributeValue,
+        projection: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<Item>, Error>>;
+
+    /// Write an item, replacing any existing item with the same key. The
+    /// partition key attribute is named so that backends without a table
+    /// schema know how to file it.
+    fn put_item<'a>(
+        &'a self,
+        table: &'a str,
+        partition_attr: &'a str,
+        item: Item,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+/// Access to S3-style object storage.
+pub trait ObjectStore: Send + Sync {
+    /// Get the full contents of an object, or None if it does not exist.
+    fn get_object<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Vec<u8>>, Error>>;
+
+    /// Get at most the first `max_bytes` bytes of an object, or None if it
+    /// does not exist.
+    fn get_object_start<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        max_bytes: usize,
+    ) -> BoxFuture<'a, Result<Option<Vec<u8>>, Error>>;
+
+    /// Get a URL that CFITSIO can use to open the specified object as a FITS
+    /// file.
+    fn fits_url(&self, bucket: &str, key: &str) -> String;
+
+    /// Start a multipart upload of a new object, returning the upload ID.
+    fn create_upload<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        content_type: &'a str,
+    ) -> BoxFuture<'a, Result<String, Error>>;
+
+    /// Upload one part of a multipart upload, returning its ETag. Part numbers
+    /// start at 1. All parts but the last must be at least 5 MiB.
+    fn upload_part<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        upload_id: &'a str,
+        part_number: i32,
+        data: Vec<u8>,
+    ) -> BoxFuture<'a, Result<String, Error>>;
+
+    /// Finish a multipart upload, given the part numbers and ETags of its
+    /// parts, in order.
+    fn complete_upload<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        upload_id: &'a str,
+        parts: Vec<(i32, String)>,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// Get a URL from which a client can download an object without
+    /// credentials, valid for the given length of time.
+    fn download_url<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        expires_in: Duration,
+    ) -> BoxFuture<'a, Result<String, Error>>;
+
+    /// Get a URL to which a client can upload an object with an HTTP PUT,
+    /// without credentials, valid for the given length of time. The upload
+    /// must have the given content type.
+    fn upload_url<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        content_type: &'a str,
+        expires_in: Duration,
+    ) -> BoxFuture<'a, Result<String, Error>>;
+}
+
+/// The production implementation of the storage traits, backed by the AWS SDK.
+///
+/// The clients are constructed lazily, since each Lambda instance only needs
+/// some of them.
+pub struct AwsStore {
+    dynamodb_config: aws_sdk_dynamodb::Config,
+    s3_config: aws_sdk_s3::Config,
+    dc: OnceCell<aws_sdk_dynamodb::Client>,
+    s3c: OnceCell<aws_sdk_s3::Client>,
+}
+
+impl AwsStore {
+    pub fn new(sdk: &SdkConfig, config: &Config) -> Self {
+        AwsStore {
+            dynamodb_config: dynamodb_client_config(sdk, config),
+            s3_config: s3_client_config(sdk, config),
+            dc: OnceCell::new(),
+            s3c: OnceCell::new(),
+        }
+    }
+
+    fn dynamodb(&self) -> &aws_sdk_dynamodb::Client {
+        self.dc
+            .get_or_init(|| aws_sdk_dynamodb::Client::from_conf(self.dynamodb_config.clone()))
+    }
+
+    fn s3(&self) -> &aws_sdk_s3::Client {
+        self.s3c
+            .get_or_init(|| aws_sdk_s3::Client::from_conf(self.s3_config.clone()))
+    }
+}
+
+/// The DynamoDB client configuration, including any deployment overrides.
+pub fn dynamodb_client_config(sdk: &SdkConfig, config: &Config) -> aws_sdk_dynamodb::Config {
+    let mut builder = aws_sdk_dynamodb::config::Builder::from(sdk);
+    let ep = &config.dynamodb_endpoint;
+
+    if let Some(r) = &ep.region {
+        builder = builder.region(Region::new(r.clone()));
+    }
+
+    if let Some(u) = &ep.url {
+        builder = builder.endpoint_url(u);
+    }
+
+    builder.build()
+}
+
+/// The S3 client configuration, including any deployment overrides.
+pub fn s3_client_config(sdk: &SdkConfig, config: &Config) -> aws_sdk_s3::Config {
+    let mut builder = aws_sdk_s3::config::Builder::from(sdk);
+    let ep = &config.s3_endpoint;
+
+    if let Some(r) = &ep.region {
+        builder = builder.region(Region::new(r.clone()));
+    }
+
+    if let Some(u) = &ep.url {
+        builder = builder.endpoint_url(u);
+    }
+
+    builder.force_path_style(config.s3_force_path_style).build()
+}
+
+impl TableStore for AwsStore {
+    fn get_item<'a>(
+        &'a self,
+        table: &'a str,
+        key_attr: &'a str,
+        key: AttributeValue,
+        projection: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Item>, Error>> {
+        let span = info_span!(
+            "aws",
+            aws.service = "DynamoDB",
+            aws.operation = "GetItem",
+            table = table
+        );
+
+        Box::pin(
+            async move {
+                let result = self
+                    .dynamodb()
+                    .get_item()
+                    .table_name(table)
+                    .key(key_attr, key)
+                    .projection_expression(projection)
+                    .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                    .send()
+                    .await?;
+
+                record_capacity(result.consumed_capacity.as_ref());
+                Ok(result.item)
+            }
+            .instrument(span),
+        )
+    }
+
+    fn batch_get_items<'a>(
+        &'a self,
+        table: &'a str,
+        key_attr: &'a str,
+        keys: Vec<AttributeValue>,
+        projection: &'a str,
+    ) -> BoxFuture<'a, Result<BatchGetOutput, Error>> {
+        let span = info_span!(
+            "aws",
+            aws.service = "DynamoDB",
+            aws.operation = "BatchGetItem",
+            table = table,
+            n_keys = keys.len()
+        );
+
+        Box::pin(
+            async move {
+                let keys = keys
+                    .into_iter()
+                    .map(|k| {
+                        // I see no better way to do this ...
+                        let mut m = HashMap::with_capacity(1);
+                        m.insert(key_attr.to_owned(), k);
+                        m
+                    })
+                    .collect();
+
+                let kaa = aws_sdk_dynamodb::types::KeysAndAttributes::builder()
+                    .projection_expression(projection)
+                    .set_keys(Some(keys))
+                    .build()?;
+
+                let resp = self
+                    .dynamodb()
+                    .batch_get_item()
+                    .request_items(table, kaa)
+                    .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                    .send()
+                    .await?;
+
+                for cc in resp.consumed_capacity() {
+                    record_capacity(Some(cc));
+                }
+
+                let items = resp
+                    .responses
+                    .and_then(|mut r| r.remove(table))
+                    .unwrap_or_default();
+
+                // The type structure of this API is pretty gnarly.
+                let unprocessed = resp
+                    .unprocessed_keys
+                    .and_then(|mut t| t.remove(table))
+                    .map(|kv| kv.keys)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|mut k| k.remove(key_attr))
+                    .collect();
+
+                Ok(BatchGetOutput { items, unprocessed })
+            }
+            .instrument(span),
+        )
+    }
+
+    fn query_items<'a>(
+        &'a self,
+        table: &'a str,
+        partition_attr: &'a str,
+        partition_value: AttributeValue,
+    ) -> BoxFuture<'a, Result<Vec<Item>, Error>> {
+        let span = info_span!(
+            "aws",
+            aws.service = "DynamoDB",
+            aws.operation = "Query",
+            table = table
+        );
+
+        Box::pin(
+            async move {
+                let mut stream = self
+                    .dynamodb()
+                    .query()
+                    .table_name(table)
+                    .expression_attribute_names("#p", partition_attr)
+                    .expression_attribute_values(":val", partition_value)
+                    .key_condition_expression("#p = :val")
+                    .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                    .into_paginator()
+                    .send();
+
+                let mut items = Vec::new();
+
+                while let Some(page) = stream.next().await {
+                    let page = page?;
+                    record_capacity(page.consumed_capacity.as_ref());
+                    items.extend(page.items.unwrap_or_default());
+                }
+
+                Ok(items)
+            }
+            .instrument(span),
+        )
+    }
+
+    fn query_index<'a>(
+        &'a self,
+        table: &'a str,
+        index: &'a str,
+        partition_attr: &'a str,
+        partition_value: AttributeValue,
+        projection: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<Item>, Error>> {
+        let span = info_span!(
+            "aws",
+            aws.service = "DynamoDB",
+            aws.operation = "Query",
+            table = table,
+            index = index
+        );
+
+        Box::pin(
+            async move {
+                let mut stream = self
+                    .dynamodb()
+                    .query()
+                    .table_name(table)
+                    .index_name(index)
+                    .expression_attribute_names("#p", partition_attr)
+                    .expression_attribute_values(":val", partition_value)
+                    .key_condition_expression("#p = :val")
+                    .projection_expression(projection)
+                    .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                    .into_paginator()
+                    .send();
+
+                let mut items = Vec::new();
+
+                while let Some(page) = stream.next().await {
+                    let page = page?;
+                    record_capacity(page.consumed_capacity.as_ref());
+                    items.extend(page.items.unwrap_or_default());
+                }
+
+                Ok(items)
+            }
+            .instrument(span),
+        )
+    }
+
+    fn put_item<'a>(
+        &'a self,
+        table: &'a str,
+        _partition_attr: &'a str,
+        item: Item,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let span = info_span!(
+            "aws",
+            aws.service = "DynamoDB",
+            aws.operation = "PutItem",
+            table = table
+        );
+
+        Box::pin(
+            async move {
+                self.dynamodb()
+                    .put_item()
+                    .table_name(table)
+                    .set_item(Some(item))
+                    .send()
+                    .await?;
+                Ok(())
+            }
+            .instrument(span),
+        )
+    }
+}
+
+/// Add the capacity reported by a DynamoDB call to the current request's total.
+fn record_capacity(cc: Option<&ConsumedCapacity>) {
+    if let Some(units) = cc.and_then(|c| c.capacity_units) {
+        capacity::record(units);
+    }
+}
+
+impl ObjectStore for AwsStore {
+    fn get_object<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Vec<u8>>, Error>> {
+        let span = info_span!(
+            "aws",
+            aws.service = "S3",
+            aws.operation = "GetObject",
+            bucket = bucket,
+            key = key
+        );
+
+        Box::pin(
+            async move {
+                let resp = match self.s3().get_object().bucket(bucket).key(key).send().await {
+                    Ok(r) => r,
+
+                    Err(e) => {
+                        let e = e.into_service_error();
+
+                        if e.is_no_such_key() {
+                            return Ok(None);
+                        }
+
+                        return Err(e.into());
+                    }
+                };
+
+                Ok(Some(resp.body.collect().await?.to_vec()))
+            }
+            .instrument(span),
+        )
+    }
+
+    fn get_object_start<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        max_bytes: usize,
+    ) -> BoxFuture<'a, Result<Option<Vec<u8>>, Error>> {
+        let range = format!("bytes=0-{}", max_bytes.saturating_sub(1));
+        let span = info_span!(
+            "aws",
+            aws.service = "S3",
+            aws.operation = "GetObject",
+            bucket = bucket,
+            key = key,
+            range = range.as_str()
+        );
+
+        Box::pin(
+            async move {
+                let get = self.s3().get_object().bucket(bucket).key(key);
+
+                let resp = match get.range(&range).send().await {
+                    Ok(r) => r,
+
+                    Err(e) => {
+                        let e = e.into_service_error();
+
+                        if e.is_no_such_key() {
+                            return Ok(None);
+                        }
+
+                        // S3 refuses any range of an empty object.
+                        if e.code() == Some("InvalidRange") {
+                            return Ok(Some(Vec::new()));
+                        }
+
+                        return Err(e.into());
+                    }
+                };
+
+                Ok(Some(resp.body.collect().await?.to_vec()))
+            }
+            .instrument(span),
+        )
+    }
+
+    fn fits_url(&self, bucket: &str, key: &str) -> String {
+        format!("s3://{bucket}/{key}")
+    }
+
+    fn create_upload<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        content_type: &'a str,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        let span = info_span!(
+            "aws",
+            aws.service = "S3",
+            aws.operation = "CreateMultipartUpload",
+            bucket = bucket,
+            key = key
+        );
+
+        Box::pin(
+            async move {
+                let resp = self
+                    .s3()
+                    .create_multipart_upload()
+                    .bucket(bucket)
+                    .key(key)
+                    .content_type(content_type)
+                    .send()
+                    .await?;
+
+                resp.upload_id
+                    .ok_or_else(|| "S3 did not return a multipart upload ID".into())
+            }
+            .instrument(span),
+        )
+    }
+
+    fn upload_part<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        upload_id: &'a str,
+        part_number: i32,
+        data: Vec<u8>,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        let span = info_span!(
+            "aws",
+            aws.service = "S3",
+            aws.operation = "UploadPart",
+            bucket = bucket,
+            key = key
+        );
+
+        Box::pin(
+            async move {
+                let resp = self
+                    .s3()
+                    .upload_part()
+                    .bucket(bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .part_number(part_number)
+                    .body(data.into())
+                    .send()
+                    .await?;
+
+                resp.e_tag
+                    .ok_or_else(|| "S3 did not return an ETag for an uploaded part".into())
+            }
+            .instrument(span),
+        )
+    }
+
+    fn complete_upload<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        upload_id: &'a str,
+        parts: Vec<(i32, String)>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let span = info_span!(
+            "aws",
+            aws.service = "S3",
+            aws.operation = "CompleteMultipartUpload",
+            bucket = bucket,
+            key = key
+        );
+
+        Box::pin(
+            async move {
+                let parts = parts
+                    .into_iter()
+                    .map(|(n, etag)| CompletedPart::builder().part_number(n).e_tag(etag).build())
+                    .collect();
+
+                self.s3()
+                    .complete_multipart_upload()
+                    .bucket(bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .multipart_upload(
+                        CompletedMultipartUpload::builder()
+                            .set_parts(Some(parts))
+                            .build(),
+                    )
+                    .send()
+                    .await?;
+                Ok(())
+            }
+            .instrument(span),
+        )
+    }
+
+    fn download_url<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        expires_in: Duration,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        Box::pin(async move {
+            let req = self
+                .s3()
+                .get_object()
+                .bucket(bucket)
+                .key(key)
+                .presigned(PresigningConfig::expires_in(expires_in)?)
+                .await?;
+            Ok(req.uri().to_string())
+        })
+    }
+
+    fn upload_url<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        content_type: &'a str,
+        expires_in: Duration,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        Box::pin(async move {
+            let req = self
+                .s3()
+                .put_object()
+                .bucket(bucket)
+                .key(key)
+                .content_type(content_type)
+                .presigned(PresigningConfig::expires_in(expires_in)?)
+                .await?;
+            Ok(req.uri().to_string())
+        })
+    }
+}
+
+/// Get the textual form of a key value, for naming things.
+pub fn key_text(key: &AttributeValue) -> Result<&str, Error> {
+    match key {
+        AttributeValue::S(s) => Ok(s),
+        AttributeValue::N(n) => Ok(n),
+        _ => Err("only string and number keys are supported".into()),
+    }
+}
+
+/// Convert a DynamoDB item into the "DynamoDB JSON" format used by the AWS CLI.
+pub fn item_to_json(item: &Item) -> Value {
+    Value::Object(
+        item.iter()
+            .map(|(k, v)| (k.clone(), attr_to_json(v)))
+            .collect(),
+    )
+}
+
+fn attr_to_json(av: &AttributeValue) -> Value {
+    let (tag, value) = match av {
+        AttributeValue::S(s) => ("S", Value::from(s.clone())),
+        AttributeValue::N(n) => ("N", Value::from(n.clone())),
+        AttributeValue::B(b) => ("B", Value::from(STANDARD.encode(b.as_ref()))),
+        AttributeValue::Bool(b) => ("BOOL", Value::from(*b)),
+        AttributeValue::Null(b) => ("NULL", Value::from(*b)),
+        AttributeValue::Ss(ss) => ("SS", Value::from(ss.clone())),
+        AttributeValue::Ns(ns) => ("NS", Value::from(ns.clone())),
+        AttributeValue::L(l) => ("L", Value::Array(l.iter().map(attr_to_json).collect())),
+        AttributeValue::M(m) => ("M", item_to_json(m)),
+        _ => ("NULL", Value::from(true)),
+    };
+
+    let mut obj = Map::with_capacity(1);
+    obj.insert(tag.to_owned(), value);
+    Value::Object(obj)
+}
+
+/// Convert an item in "DynamoDB JSON" format back into its native form.
+pub fn item_from_json(value: &Value) -> Result<Item, Error> {
+    let obj = value
+        .as_object()
+        .ok_or_else(|| -> Error { "DynamoDB JSON item must be an object".into() })?;
+
+    obj.iter()
+        .map(|(k, v)| Ok((k.clone(), attr_from_json(v)?)))
+        .collect()
+}
+
+fn attr_from_json(value: &Value) -> Result<AttributeValue, Error> {
+    let (tag, value) = value
+        .as_object()
+        .and_then(|o| o.iter().next())
+        .ok_or_else(|| -> Error { "DynamoDB JSON attribute must be a tagged object".into() })?;
+
+    let as_str = |v: &Value| -> Result<String, Error> {
+        v.as_str()
+            .map(|s| s.to_owned())
+            .ok_or_else(|| format!("DynamoDB JSON `{tag}` value must be a string").into())
+    };
+
+    let as_strs = |v: &Value| -> Result<Vec<String>, Error> {
+        v.as_array()
+            .ok_or_else(|| -> Error {
+                format!("DynamoDB JSON `{tag}` value must be a list").into()
+            })?
+            .iter()
+            .map(as_str)
+            .collect()
+    };
+
+    Ok(match tag.as_ref() {
+        "S" => AttributeValue::S(as_str(value)?),
+        "N" => AttributeValue::N(as_str(value)?),
+        "B" => AttributeValue::B(Blob::new(STANDARD.decode(as_str(value)?)?)),
+        "BOOL" => AttributeValue::Bool(value.as_bool().unwrap_or_default()),
+        "NULL" => AttributeValue::Null(true),
+        "SS" => AttributeValue::Ss(as_strs(value)?),
+        "NS" => AttributeValue::Ns(as_strs(value)?),
+        "L" => AttributeValue::L(
+            value
+                .as_array()
+                .ok_or_else(|| -> Error { "DynamoDB JSON `L` value must be a list".into() })?
+                .iter()
+                .map(attr_from_json)
+                .collect::<Result<_, _>>()?,
+        ),
+        "M" => AttributeValue::M(item_from_json(value)?),
+        _ => return Err(format!("unsupported DynamoDB JSON attribute type `{tag}`").into()),
+    })
+}
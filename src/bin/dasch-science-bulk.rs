@@ -0,0 +1,541 @@
+//! Bulk generation of DASCH data products, outside of Lambda.
+//!
+//! This executable runs a manifest of cutout and query requests through the
+//! service implementations directly, using the library API of the `client`
+//! feature (see [`dasch_science_lambda::client`]), and saves the results:
+//!
+//! ```text
+//! dasch-science-bulk [--jobs N] --output DEST MANIFEST
+//! ```
+//!
+//! `MANIFEST` is a file path, or `-` for standard input, containing one JSON
+//! object per line, like:
+//!
+//! ```json
+//! {"service": "cutout", "output": "m31-a10000", "request": {"plate_id": "a10000", ...}}
+//! ```
+//!
+//! The `service` is one of `cutout`, `querycat`, `queryexps`, or `queryepoch`,
+//! and the `request` is as for the JSON service. The optional `output` names
+//! the result file, defaulting to the line number, `NNNNNN`. Blank lines are
+//! skipped.
+//!
+//! `DEST` is a local directory, or an S3 location of the form
+//! `s3://BUCKET/PREFIX`. Cutouts are saved as FITS files, with the extension
+//! `.fits.gz` if they're gzipped, or as `.png` or `.jpg` previews if the
+//! request asks for them; each stamp of a series is saved separately,
+//! as `NAME-SOLNUM.fits[.gz]`, and each stamp of a batch as
+//! `NAME-INDEX.fits[.gz]`, or `NAME-INDEX.err` if it failed. Query results are saved as CSV files, named
+//! `NAME.csv`, following continuation tokens until the query is done.
+//! Estimates, and results staged to S3, are saved as their JSON responses, in
+//! `NAME.json`. If a request fails, its error message is saved in `NAME.err`
+//! and processing continues; the program exits with an error at the end if any
+//! requests failed.
+//!
+//! Up to `N` requests, by default 4, run at once. Unlike the Lambda services,
+//! these requests have no time limit and aren't subject to admission control,
+//! but the configuration is otherwise taken from the environment as usual.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::stream::{self, StreamExt};
+use lambda_runtime::Error;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    env, fs,
+    io::{self, BufRead},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use dasch_science_lambda::client::{
+    self, cutout, querycat, queryepoch, queryexps, AwsStore, BufferPool, Config, Deadline,
+    DenyList, FitsPool, GscBinning, ObjectStore, PlateCache, SeriesDefaults,
+};
+
+const USAGE: &str = "usage: dasch-science-bulk [--jobs N] --output DEST MANIFEST";
+
+/// The default number of requests to run at once.
+const DEFAULT_JOBS: usize = 4;
+
+/// One line of the manifest.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    service: String,
+    request: Value,
+    #[serde(default)]
+    output: Option<String>,
+}
+
+/// Where the results go.
+enum Destination {
+    Local(PathBuf),
+    S3 { bucket: String, prefix: String },
+}
+
+/// A result file to save.
+struct Product {
+    suffix: String,
+    content_type: &'static str,
+    data: Vec<u8>,
+}
+
+impl Product {
+    fn json<T: serde::Serialize>(suffix: &str, value: &T) -> Result<Self, Error> {
+        Ok(Product {
+            suffix: suffix.to_owned(),
+            content_type: "application/json",
+            data: serde_json::to_vec(value)?,
+        })
+    }
+}
+
+/// The state shared by the requests.
+struct Context {
+    config: Config,
+    store: AwsStore,
+    bin1: GscBinning,
+    bin64: GscBinning,
+    plates: PlateCache,
+    buffers: BufferPool,
+    fits_pool: Arc<FitsPool>,
+    deny_list: DenyList,
+    series_defaults: SeriesDefaults,
+    destination: Destination,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let mut args = env::args().skip(1);
+    let mut jobs = DEFAULT_JOBS;
+    let mut dest = None;
+    let mut positional = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--jobs" => {
+                jobs = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| -> Error { "--jobs requires a positive integer".into() })?;
+            }
+
+            "--output" => {
+                dest = Some(args.next().ok_or_else(|| -> Error {
+                    "--output requires a destination argument".into()
+                })?);
+            }
+
+            _ => positional.push(arg),
+        }
+    }
+
+    let (Some(dest), Ok([source])) = (dest, <[String; 1]>::try_from(positional)) else {
+        return Err(USAGE.into());
+    };
+
+    let destination = match dest.strip_prefix("s3://") {
+        Some(rest) => {
+            let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+
+            if bucket.is_empty() {
+                return Err(format!("illegal S3 destination `{dest}`").into());
+            }
+
+            let mut prefix = prefix.to_owned();
+
+            if !prefix.is_empty() && !prefix.ends_with('/') {
+                prefix.push('/');
+            }
+
+            Destination::S3 {
+                bucket: bucket.to_owned(),
+                prefix,
+            }
+        }
+
+        None => {
+            fs::create_dir_all(&dest)?;
+            Destination::Local(dest.into())
+        }
+    };
+
+    let input: Box<dyn BufRead> = match source.as_ref() {
+        "-" => Box::new(io::stdin().lock()),
+        _ => Box::new(io::BufReader::new(fs::File::open(&source)?)),
+    };
+
+    let mut entries = Vec::new();
+
+    for (i_line, line) in input.lines().enumerate() {
+        let line = line?;
+
+        if !line.trim().is_empty() {
+            entries.push((i_line + 1, line));
+        }
+    }
+
+    let sdk = aws_config::load_from_env().await;
+    let config = Config::from_env();
+    let store = AwsStore::new(&sdk, &config);
+    client::register_s3_driver(&sdk, &config);
+    let deny_list = DenyList::load(&config, &store).await?;
+    let series_defaults = SeriesDefaults::load(&config, &store).await?;
+
+    let ctx = Context {
+        fits_pool: Arc::new(FitsPool::new(config.fits_pool_size)),
+        bin1: GscBinning::new1(),
+        bin64: GscBinning::new64(),
+        plates: Default::default(),
+        buffers: Default::default(),
+        config,
+        store,
+        deny_list,
+        series_defaults,
+        destination,
+    };
+
+    let n_done = entries.len();
+    let ctx = &ctx;
+
+    let n_failed = stream::iter(entries)
+        .map(|(line_num, line)| async move {
+            let mut name = format!("{line_num:06}");
+
+            let result = match serde_json::from_str::<Entry>(&line) {
+                Ok(entry) => {
+                    if let Some(output) = entry.output {
+                        name = output;
+                    }
+
+                    match run(ctx, &entry.service, entry.request).await {
+                        Ok(products) => save(ctx, &name, products).await,
+                        Err(e) => Err(e),
+                    }
+                }
+
+                Err(e) => Err(e.into()),
+            };
+
+            match result {
+                Ok(()) => false,
+
+                Err(e) => {
+                    eprintln!("line {line_num}: request failed: {e}");
+                    let error = Product {
+                        suffix: ".err".to_owned(),
+                        content_type: "text/plain",
+                        data: format!("{e}\n").into_bytes(),
+                    };
+
+                    if let Err(e) = save(ctx, &name, vec![error]).await {
+                        eprintln!("line {line_num}: failed to save the error: {e}");
+                    }
+
+                    true
+                }
+            }
+        })
+        .buffer_unordered(jobs)
+        .filter(|failed| futures::future::ready(*failed))
+        .count()
+        .await;
+
+    eprintln!("processed {n_done} requests, {n_failed} failed");
+
+    if n_failed > 0 {
+        return Err(format!("{n_failed} of {n_done} requests failed").into());
+    }
+
+    Ok(())
+}
+
+/// Run one request, returning the files to save.
+async fn run(ctx: &Context, service: &str, request: Value) -> Result<Vec<Product>, Error> {
+    let config = &ctx.config;
+    let deadline = Deadline::default();
+
+    match service {
+        "cutout" => {
+            let mut request: cutout::Request = serde_json::from_value(request)?;
+
+            if request.is_batch() {
+                request.load_target_list(config, &ctx.store).await?;
+                let stamps = cutout::implementation_batch(
+                    request.into_batch(config, &ctx.series_defaults)?,
+                    config,
+                    &ctx.store,
+                    &ctx.store,
+                    &ctx.plates,
+                    &ctx.buffers,
+                    &ctx.fits_pool,
+                    &ctx.deny_list,
+                    None,
+                    deadline,
+                )
+                .await;
+
+                return cutout_products(cutout::Response::Batch(stamps), "");
+            }
+
+            let response = cutout::implementation(
+                request
+                    .with_series_defaults(&ctx.series_defaults)
+                    .normalize(config)?,
+                config,
+                &ctx.store,
+                &ctx.store,
+                &ctx.plates,
+                &ctx.buffers,
+                &ctx.fits_pool,
+                &ctx.deny_list,
+            )
+            .await?;
+
+            cutout_products(response, "")
+        }
+
+        "querycat" => {
+            query_pages(request, |request| async move {
+                let request: querycat::Request = serde_json::from_value(request)?;
+                let response = querycat::implementation(
+                    request.normalize(config)?,
+                    config,
+                    &ctx.store,
+                    &ctx.bin64,
+                    deadline,
+                )
+                .await?;
+                Ok(Page::Rows {
+                    rows: response.rows,
+                    truncated: response.truncated,
+                    continuation: response.continuation,
+                })
+            })
+            .await
+        }
+
+        "queryexps" => {
+            query_pages(request, |request| async move {
+                let request: queryexps::Request = serde_json::from_value(request)?;
+                let request = request.normalize(config)?;
+
+                if request.estimate {
+                    let estimate = queryexps::estimate(
+                        request,
+                        config,
+                        &ctx.store,
+                        &ctx.bin1,
+                        &ctx.deny_list,
+                        &ctx.series_defaults,
+                    )
+                    .await?;
+                    return Ok(Page::Done(Product::json(".json", &estimate)?));
+                }
+
+                let response = queryexps::implementation(
+                    request,
+                    config,
+                    &ctx.store,
+                    &ctx.store,
+                    &ctx.bin1,
+                    &ctx.bin64,
+                    &ctx.plates,
+                    &ctx.deny_list,
+                    &ctx.series_defaults,
+                    deadline,
+                )
+                .await?;
+
+                if response.staged.is_some() {
+                    return Ok(Page::Done(Product::json(".json", &response)?));
+                }
+
+                Ok(Page::Rows {
+                    rows: response.rows,
+                    truncated: response.truncated,
+                    continuation: response.continuation,
+                })
+            })
+            .await
+        }
+
+        "queryepoch" => {
+            query_pages(request, |request| async move {
+                let request: queryepoch::Request = serde_json::from_value(request)?;
+                let response = queryepoch::implementation(
+                    request.normalize(config)?,
+                    config,
+                    &ctx.store,
+                    &ctx.deny_list,
+                    &ctx.series_defaults,
+                    deadline,
+                )
+                .await?;
+                Ok(Page::Rows {
+                    rows: response.rows,
+                    truncated: response.truncated,
+                    continuation: response.continuation,
+                })
+            })
+            .await
+        }
+
+        _ => Err(format!("unsupported service `{service}`").into()),
+    }
+}
+
+/// The files to save for a cutout response. `tag` is appended to the name,
+/// for the stamps of a series or batch.
+fn cutout_products(response: cutout::Response, tag: &str) -> Result<Vec<Product>, Error> {
+    let (b64, encoding) = match response {
+        cutout::Response::Image(image) => (image, "fits+gzip+base64"),
+        cutout::Response::Encoded(e) => (e.image, e.encoding),
+        cutout::Response::Estimate(e) => {
+            return Ok(vec![Product::json(&format!("{tag}.json"), &e)?])
+        }
+
+        cutout::Response::Series(stamps) => {
+            let mut products = Vec::new();
+
+            for stamp in stamps {
+                let tag = format!("{tag}-{}", stamp.solution_number);
+                products.extend(cutout_products(stamp.stamp, &tag)?);
+            }
+
+            return Ok(products);
+        }
+
+        cutout::Response::Batch(stamps) => {
+            let mut products = Vec::new();
+
+            for (i, stamp) in stamps.into_iter().enumerate() {
+                let tag = format!("{tag}-{i}");
+
+                match (stamp.stamp, stamp.error) {
+                    (Some(stamp), _) => products.extend(cutout_products(stamp, &tag)?),
+                    (None, error) => products.push(Product {
+                        suffix: format!("{tag}.err"),
+                        content_type: "text/plain",
+                        data: format!("{}\n", error.unwrap_or_default()).into_bytes(),
+                    }),
+                }
+            }
+
+            return Ok(products);
+        }
+
+        cutout::Response::Staged(staged) => {
+            return Ok(vec![Product::json(&format!("{tag}.json"), &staged)?])
+        }
+    };
+
+    let (suffix, content_type) = match encoding {
+        "fits+gzip+base64" => (".fits.gz", "application/fits"),
+        "fits+rice+base64" => (".fits", "application/fits"),
+        "png+base64" => (".png", "image/png"),
+        "jpeg+base64" => (".jpg", "image/jpeg"),
+        other => return Err(format!("unrecognized cutout encoding {other:?}").into()),
+    };
+
+    Ok(vec![Product {
+        suffix: format!("{tag}{suffix}"),
+        content_type,
+        data: STANDARD.decode(b64)?,
+    }])
+}
+
+/// One response of a query: a page of CSV rows, starting with the header, or
+/// something to save as-is.
+enum Page {
+    Rows {
+        rows: Vec<String>,
+        truncated: bool,
+        continuation: Option<String>,
+    },
+    Done(Product),
+}
+
+/// Run a query, following continuation tokens, and gather its rows into a
+/// CSV file.
+async fn query_pages<F, Fut>(mut request: Value, mut query: F) -> Result<Vec<Product>, Error>
+where
+    F: FnMut(Value) -> Fut,
+    Fut: std::future::Future<Output = Result<Page, Error>>,
+{
+    let mut csv = String::new();
+    let mut first = true;
+
+    loop {
+        let (rows, truncated, continuation) = match query(request.clone()).await? {
+            Page::Rows {
+                rows,
+                truncated,
+                continuation,
+            } => (rows, truncated, continuation),
+            Page::Done(product) => return Ok(vec![product]),
+        };
+
+        // Every page starts with the header row.
+        for row in rows.iter().skip(if first { 0 } else { 1 }) {
+            csv.push_str(row);
+            csv.push('\n');
+        }
+
+        first = false;
+
+        let Some(token) = continuation else {
+            if truncated {
+                // Rows lost to throttling can't be continued.
+                return Err("the query results are incomplete; try again later".into());
+            }
+
+            break;
+        };
+
+        match &mut request {
+            Value::Object(fields) => {
+                fields.insert("continuation".to_owned(), token.into());
+            }
+
+            _ => return Err("request must be a JSON object".into()),
+        }
+    }
+
+    Ok(vec![Product {
+        suffix: ".csv".to_owned(),
+        content_type: "text/csv",
+        data: csv.into_bytes(),
+    }])
+}
+
+/// Save the files of one request.
+async fn save(ctx: &Context, name: &str, products: Vec<Product>) -> Result<(), Error> {
+    for product in products {
+        let file_name = format!("{name}{}", product.suffix);
+
+        match &ctx.destination {
+            Destination::Local(dir) => {
+                fs::write(dir.join(file_name), product.data)?;
+            }
+
+            Destination::S3 { bucket, prefix } => {
+                let key = format!("{prefix}{file_name}");
+                let store = &ctx.store;
+                let upload_id = store
+                    .create_upload(bucket, &key, product.content_type)
+                    .await?;
+                let etag = store
+                    .upload_part(bucket, &key, &upload_id, 1, product.data)
+                    .await?;
+                store
+                    .complete_upload(bucket, &key, &upload_id, vec![(1, etag)])
+                    .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -4,23 +4,155 @@
 //! For the cloud deployment, we need to use the "proxy event" version, which
 //! has additional infrastructure to interact with AWS API Gateway's "proxy
 //! event" framework.
+//!
+//! By default, the server speaks the Lambda runtime protocol, so it needs to
+//! be run inside the Lambda runtime emulator (see `go.sh`). With `--http ADDR`,
+//! it instead runs a plain HTTP server listening on `ADDR`, mapping `POST
+//! /cutout`, `/querycat`, and so on to the corresponding services. (`POST
+//! /describe` lists them all.) This is convenient for pointing clients like
+//! daschlab at a local development server.
+//!
+//! In Lambda, invocations may come from AWS Step Functions tasks, including
+//! ones that wait for a callback with a task token; see
+//! [`Services::dispatch_task`].
 
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    body::{Bytes, Incoming},
+    server::conn::http1,
+    Method, StatusCode,
+};
+use hyper_util::rt::TokioIo;
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
-use serde_json::Value;
+use serde_json::{json, Value};
+use std::{convert::Infallible, env, sync::Arc};
+use tokio::net::TcpListener;
 
-use dasch_science_lambda::Services;
+use dasch_science_lambda::{
+    error_body, lambda_deadline, service_names, BusyError, ForbiddenError, Services,
+};
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
+    let mut args = env::args().skip(1);
+    let mut http_addr = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--http" => {
+                http_addr =
+                    Some(args.next().ok_or_else(|| -> Error {
+                        "--http requires an address argument".into()
+                    })?);
+            }
+
+            _ => return Err(format!("unexpected argument `{arg}`").into()),
+        }
+    }
+
     let svcs = Services::init().await?;
+
+    if let Some(addr) = http_addr {
+        return serve_http(Arc::new(svcs), &addr).await;
+    }
+
     let ref_svcs = &svcs;
 
     run(service_fn(|event: LambdaEvent<Value>| async move {
         let (payload, context) = event.into_parts();
         ref_svcs
-            .dispatch(context.invoked_function_arn, Some(payload))
+            .dispatch_task(
+                context.invoked_function_arn,
+                Some(payload),
+                lambda_deadline(context.deadline),
+            )
             .await
     }))
     .await?;
     Ok(())
 }
+
+/// Run a plain HTTP/1 server on the specified address, forever.
+async fn serve_http(svcs: Arc<Services>, addr: &str) -> Result<(), Error> {
+    let listener = TcpListener::bind(addr).await?;
+    eprintln!("listening on http://{}", listener.local_addr()?);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let svcs = svcs.clone();
+
+        tokio::spawn(async move {
+            let service = hyper::service::service_fn(move |req| handle_http(svcs.clone(), req));
+
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                eprintln!("HTTP connection error: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_http(
+    svcs: Arc<Services>,
+    req: hyper::Request<Incoming>,
+) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
+    let function = req.uri().path().trim_matches('/').to_owned();
+
+    if !service_names().any(|n| n == function) {
+        return Ok(http_error(
+            StatusCode::NOT_FOUND,
+            format!("no such function `{function}`"),
+        ));
+    }
+
+    if req.method() != Method::POST {
+        return Ok(http_error(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "requests must use POST".to_owned(),
+        ));
+    }
+
+    let body = match req.into_body().collect().await {
+        Ok(b) => b.to_bytes(),
+        Err(e) => return Ok(http_error(StatusCode::BAD_REQUEST, e.to_string())),
+    };
+
+    let payload: Value = match serde_json::from_slice(&body) {
+        Ok(v) => v,
+        Err(e) => {
+            return Ok(http_error(
+                StatusCode::BAD_REQUEST,
+                format!("invalid JSON payload: {e}"),
+            ))
+        }
+    };
+
+    eprintln!("POST /{function}");
+
+    match svcs.dispatch(function, Some(payload)).await {
+        Ok(v) => Ok(http_json(StatusCode::OK, &v)),
+        Err(e) if e.is::<BusyError>() => {
+            Ok(http_json(StatusCode::SERVICE_UNAVAILABLE, &error_body(&e)))
+        }
+        Err(e) if e.is::<ForbiddenError>() => Ok(http_json(StatusCode::FORBIDDEN, &error_body(&e))),
+        Err(e) => Ok(http_json(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &error_body(&e),
+        )),
+    }
+}
+
+fn http_json(status: StatusCode, value: &Value) -> hyper::Response<Full<Bytes>> {
+    hyper::Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Full::new(Bytes::from(value.to_string())))
+        .unwrap()
+}
+
+/// Errors are reported in the same shape as the Lambda runtime uses.
+fn http_error(status: StatusCode, message: String) -> hyper::Response<Full<Bytes>> {
+    http_json(status, &json!({ "errorMessage": message }))
+}
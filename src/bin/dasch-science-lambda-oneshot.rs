@@ -1,31 +1,215 @@
 //! "Oneshot" version of the DASCH science Lambda implementations.
 //!
-//! This executable runs one API function, based on arguments given on the
-//! command line.
+//! This executable runs API functions based on arguments given on the command
+//! line:
+//!
+//! ```text
+//! dasch-science-lambda-oneshot [--record DIR] [--decode-fits PATH] ARN PAYLOAD
+//! dasch-science-lambda-oneshot [--record DIR] --batch OUTDIR ARN INPUT
+//! ```
+//!
+//! In the first form, `PAYLOAD` is the JSON payload text, `@PATH` to read the
+//! payload from a file, or `-` to read it from standard input. The result is
+//! printed to standard output. For cutout requests, `--decode-fits` unwraps the
+//! base64-encoded response and writes it to `PATH` as a FITS file instead,
+//! decompressing it if it was gzipped.
+//!
+//! In the second form, `INPUT` is a file path, or `-` for standard input,
+//! containing one JSON payload per line. The payloads are executed one after
+//! another, and the result of the payload on line N is written to
+//! `OUTDIR/NNNNNN.json`. If the request fails, the error message is written to
+//! `OUTDIR/NNNNNN.err` instead and processing continues; the program exits
+//! with an error at the end if any requests failed. Blank lines are skipped.
+//!
+//! Requests run as [`dasch_science_lambda::OPERATOR`], since whoever runs
+//! this program has AWS credentials for the data already, so admin-only
+//! services are available.
+//!
+//! With `--record`, all of the data fetched from AWS are saved into the fixture
+//! directory `DIR`, for later use in testing. See
+//! [`dasch_science_lambda::fixtures`].
 
+use base64::{engine::general_purpose::STANDARD, Engine};
+use flate2::read::GzDecoder;
 use lambda_runtime::Error;
 use serde_json::Value;
-use std::env;
+use std::{
+    env, fs,
+    io::{self, BufRead, Read},
+    path::{Path, PathBuf},
+};
 
-use dasch_science_lambda::Services;
+use dasch_science_lambda::{Services, OPERATOR};
+
+const USAGE: &str =
+    "usage: dasch-science-lambda-oneshot [--record DIR] [--batch OUTDIR | --decode-fits PATH] ARN PAYLOAD";
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    let mut args = env::args();
-    args.next(); // skip argv[0]
+    let mut args = env::args().skip(1);
+    let mut record_dir = None;
+    let mut batch_dir = None;
+    let mut fits_path = None;
+    let mut positional = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--record" => {
+                record_dir =
+                    Some(args.next().ok_or_else(|| -> Error {
+                        "--record requires a directory argument".into()
+                    })?);
+            }
+
+            "--batch" => {
+                batch_dir = Some(PathBuf::from(args.next().ok_or_else(|| -> Error {
+                    "--batch requires a directory argument".into()
+                })?));
+            }
+
+            "--decode-fits" => {
+                fits_path = Some(PathBuf::from(args.next().ok_or_else(|| -> Error {
+                    "--decode-fits requires a path argument".into()
+                })?));
+            }
+
+            _ => positional.push(arg),
+        }
+    }
+
+    let (arn, source) = match <[String; 2]>::try_from(positional) {
+        Ok([arn, source]) => (arn, source),
+        Err(_) => return Err(USAGE.into()),
+    };
+
+    if batch_dir.is_some() && fits_path.is_some() {
+        return Err("--batch and --decode-fits cannot be used together".into());
+    }
+
+    let mut svcs = Services::init().await?;
+
+    if let Some(dir) = record_dir {
+        svcs = svcs.record_to(dir);
+    }
+
+    match batch_dir {
+        None => {
+            let json_text = match source.as_ref() {
+                "-" => {
+                    let mut text = String::new();
+                    io::stdin().read_to_string(&mut text)?;
+                    text
+                }
+
+                _ => match source.strip_prefix('@') {
+                    Some(path) => fs::read_to_string(path)?,
+                    None => source,
+                },
+            };
+
+            let payload: Value = serde_json::from_str(&json_text)?;
+            let result = svcs.dispatch_as(arn, Some(payload), None, OPERATOR).await?;
+
+            match fits_path {
+                Some(path) => write_fits(&result, &path)?,
+                None => serde_json::to_writer(io::stdout().lock(), &result)?,
+            }
+        }
+
+        Some(outdir) => {
+            let input: Box<dyn BufRead> = match source.as_ref() {
+                "-" => Box::new(io::stdin().lock()),
+                _ => Box::new(io::BufReader::new(fs::File::open(&source)?)),
+            };
+
+            fs::create_dir_all(&outdir)?;
+            run_batch(&svcs, &arn, input, &outdir).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Decode a cutout response into a FITS file. The result is a JSON string
+/// containing the base64 encoding of the gzipped FITS data, or, if the request
+/// chose the compression, an object with the encoded `image` and its
+/// `encoding`. A RICE-compressed file is written as-is.
+fn write_fits(response: &Value, path: &Path) -> Result<(), Error> {
+    let result = &response["result"];
+    let (b64, encoding) = match result.as_str() {
+        Some(b64) => (Some(b64), Some("fits+gzip+base64")),
+        None => (result["image"].as_str(), result["encoding"].as_str()),
+    };
+
+    let b64 =
+        b64.ok_or_else(|| -> Error { "response is not a cutout (expected an image)".into() })?;
+    let data = STANDARD.decode(b64)?;
+
+    let fits = match encoding {
+        Some("fits+gzip+base64") => {
+            let mut fits = Vec::new();
+            GzDecoder::new(&data[..]).read_to_end(&mut fits)?;
+            fits
+        }
+
+        Some("fits+rice+base64") => data,
+        other => return Err(format!("unrecognized cutout encoding {other:?}").into()),
+    };
+
+    fs::write(path, fits)?;
+    Ok(())
+}
+
+/// Execute a newline-delimited stream of payloads, writing each result into
+/// `outdir`.
+async fn run_batch(
+    svcs: &Services,
+    arn: &str,
+    input: Box<dyn BufRead>,
+    outdir: &Path,
+) -> Result<(), Error> {
+    let mut n_done = 0;
+    let mut n_failed = 0;
+
+    for (i_line, line) in input.lines().enumerate() {
+        let line = line?;
+        let line_num = i_line + 1;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let result = match serde_json::from_str::<Value>(&line) {
+            Ok(payload) => {
+                svcs.dispatch_as(arn.to_owned(), Some(payload), None, OPERATOR)
+                    .await
+            }
+            Err(e) => Err(e.into()),
+        };
+
+        match result {
+            Ok(v) => {
+                fs::write(
+                    outdir.join(format!("{line_num:06}.json")),
+                    serde_json::to_vec(&v)?,
+                )?;
+            }
+
+            Err(e) => {
+                eprintln!("line {line_num}: request failed: {e}");
+                fs::write(outdir.join(format!("{line_num:06}.err")), format!("{e}\n"))?;
+                n_failed += 1;
+            }
+        }
 
-    let arn = args.next().ok_or_else(|| -> Error {
-        "first argument should be ARN to use (cutout, querycat, queryexps)".into()
-    })?;
+        n_done += 1;
+    }
 
-    let json_text = args
-        .next()
-        .ok_or_else(|| -> Error { "second argument should be JSON payload text".into() })?;
-    let payload: Value = serde_json::from_str(&json_text)?;
+    eprintln!("processed {n_done} requests, {n_failed} failed");
 
-    let svcs = Services::init().await?;
-    let result = svcs.dispatch(arn, Some(payload)).await?;
+    if n_failed > 0 {
+        return Err(format!("{n_failed} of {n_done} requests failed").into());
+    }
 
-    serde_json::to_writer(std::io::stdout().lock(), &result)?;
     Ok(())
 }
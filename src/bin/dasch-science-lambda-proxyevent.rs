@@ -4,11 +4,37 @@
 //! according to AWS API Gateway's "proxy event" protocol. This adds an
 //! additional layer of complexity beyond simple JSON-in, JSON-out. The "bare"
 //! version of the server is simpler and is more useful for local testing.
+//!
+//! Because API Gateway tells us who is calling, this is also where we apply
+//! per-client rate limits; see [`dasch_science_lambda::ratelimit`]. Rejected
+//! requests get an HTTP 429 response with a JSON body.
+//!
+//! For the same reason, this is the one server through which admin-only
+//! services can be reached: clients whose API keys are listed in the
+//! configuration may call them, and everyone else gets an HTTP 403 response.
+//!
+//! It's also where we implement CORS, including answering `OPTIONS` preflight
+//! requests, so that browser-based tools can call the APIs directly; see
+//! [`dasch_science_lambda::cors`].
+//!
+//! Besides JSON `POST` bodies, it accepts `GET` requests whose query parameters
+//! give the request fields, so that the services can be used from a browser
+//! address bar or linked from documentation; see
+//! [`dasch_science_lambda::query_request`]. Malformed query strings get an HTTP
+//! 400 response.
 
-use lambda_http::{run, service_fn, Error, Request, RequestExt, RequestPayloadExt};
-use serde_json::Value;
+use lambda_http::{
+    http::{Method, StatusCode},
+    request::RequestContext,
+    run, service_fn, Body, Error, Request, RequestExt, RequestPayloadExt, Response,
+};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
 
-use dasch_science_lambda::Services;
+use dasch_science_lambda::{
+    error_body, lambda_deadline, query_request, ratelimit::RateLimitError, BusyError,
+    ForbiddenError, Services,
+};
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
@@ -16,12 +42,154 @@ async fn main() -> Result<(), Error> {
     let ref_svcs = &svcs;
 
     run(service_fn(|req: Request| async move {
-        let context = req.lambda_context();
-        let payload: Option<Value> = req.payload()?;
-        ref_svcs
-            .dispatch(context.invoked_function_arn, payload)
-            .await
+        let cors = ref_svcs.cors();
+        let request_headers = req.headers().clone();
+
+        if req.method() == Method::OPTIONS {
+            // A CORS preflight request.
+            let mut r = Response::new(Body::Empty);
+            *r.status_mut() = StatusCode::NO_CONTENT;
+            cors.apply_preflight(&request_headers, r.headers_mut());
+            return Ok(r);
+        }
+
+        let mut response = handle(ref_svcs, req).await?;
+        cors.apply(&request_headers, response.headers_mut());
+        Ok::<_, Error>(response)
     }))
     .await?;
     Ok(())
 }
+
+async fn handle(svcs: &Services, req: Request) -> Result<Response<Body>, Error> {
+    let identity = client_identity(&req);
+    let context = req.lambda_context();
+
+    let limiter = svcs.rate_limiter();
+
+    let guard = if limiter.is_enabled() {
+        match limiter.begin(&identity) {
+            Ok(g) => Some(g),
+            Err(e) => return Ok(too_many_requests(e)),
+        }
+    } else {
+        None
+    };
+
+    let payload: Option<Value> = if req.method() == Method::GET {
+        match query_request(
+            &context.invoked_function_arn,
+            req.query_string_parameters_ref()
+                .into_iter()
+                .flat_map(|q| q.iter()),
+        ) {
+            Ok(v) => Some(v),
+            Err(e) => return json_response(StatusCode::BAD_REQUEST, &error_body(&e)),
+        }
+    } else {
+        req.payload()?
+    };
+
+    // Errors are reported as JSON responses, rather than by failing the
+    // invocation, so that they carry CORS headers and browser scripts can
+    // read them.
+    let response = match svcs
+        .dispatch_as(
+            context.invoked_function_arn,
+            payload,
+            lambda_deadline(context.deadline),
+            &identity,
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(e) if e.is::<BusyError>() => {
+            return json_response(StatusCode::SERVICE_UNAVAILABLE, &error_body(&e))
+        }
+        Err(e) if e.is::<ForbiddenError>() => {
+            eprintln!("refusing admin-only request from client `{identity}`");
+            let mut body = error_body(&e);
+            body["errorType"] = "Forbidden".into();
+            return json_response(StatusCode::FORBIDDEN, &body);
+        }
+        Err(e) => return json_response(StatusCode::INTERNAL_SERVER_ERROR, &error_body(&e)),
+    };
+
+    if let Some(g) = guard {
+        g.charge(&response);
+    }
+
+    json_response(StatusCode::OK, &response)
+}
+
+/// Identify the client making a request, for rate limiting, admin checks, and
+/// provenance records. We prefer the API key, if there is one, and fall back
+/// to the source IP.
+fn client_identity(req: &Request) -> String {
+    let (key, ip) = match req.request_context_ref() {
+        Some(RequestContext::ApiGatewayV1(ctx)) => (
+            key_identity(&ctx.identity.api_key_id, &ctx.identity.api_key),
+            ctx.identity.source_ip.clone(),
+        ),
+
+        Some(RequestContext::ApiGatewayV2(ctx)) => (None, ctx.http.source_ip.clone()),
+
+        Some(RequestContext::WebSocket(ctx)) => (
+            key_identity(&ctx.identity.api_key_id, &ctx.identity.api_key),
+            ctx.identity.source_ip.clone(),
+        ),
+
+        _ => (None, None),
+    };
+
+    match (key, ip) {
+        (Some(k), _) => k,
+        (_, Some(ip)) if !ip.is_empty() => format!("ip:{ip}"),
+        _ => "anonymous".to_owned(),
+    }
+}
+
+/// Identify a client by its API key: by the key's ID if we have it, and
+/// otherwise by a truncated hash of the key, since identities end up in logs
+/// and provenance records, where the key itself mustn't.
+fn key_identity(id: &Option<String>, key: &Option<String>) -> Option<String> {
+    match (id.as_deref(), key.as_deref()) {
+        (Some(id), _) if !id.is_empty() => Some(format!("key:{id}")),
+        (_, Some(key)) if !key.is_empty() => Some(format!(
+            "keyhash:{}",
+            hex::encode(&Sha256::digest(key)[..8])
+        )),
+        _ => None,
+    }
+}
+
+fn json_response(status: StatusCode, body: &Value) -> Result<Response<Body>, Error> {
+    Ok(Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(body.to_string().into())?)
+}
+
+fn too_many_requests(e: RateLimitError) -> Response<Body> {
+    eprintln!(
+        "rate limiting client `{}` ({} budget)",
+        e.identity, e.budget
+    );
+
+    let body = json!({
+        "errorType": "TooManyRequests",
+        "errorMessage": e.to_string(),
+        "budget": e.budget,
+        "retryAfterMs": e.retry_after.as_millis() as u64,
+    });
+
+    // Retry-After is in whole seconds; round up.
+    let retry_secs = e.retry_after.as_millis().div_ceil(1000).max(1);
+
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header("content-type", "application/json")
+        .header("retry-after", retry_secs.to_string())
+        .body(body.to_string().into())
+        .unwrap()
+}
@@ -0,0 +1,27 @@
+//! "SQS" version of the DASCH science Lambda implementations.
+//!
+//! This executable is a batch worker: it expects to be triggered by an SQS
+//! event source mapping, and runs each message of each batch as a request,
+//! saving the results to S3 and announcing them on SNS rather than returning
+//! them. See [`Services::dispatch_sqs`] for the details. It has no API Gateway
+//! in the loop, so it's the cheapest way to generate products in bulk.
+
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use serde_json::Value;
+
+use dasch_science_lambda::{lambda_deadline, Services};
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let svcs = Services::init().await?;
+    let ref_svcs = &svcs;
+
+    run(service_fn(|event: LambdaEvent<Value>| async move {
+        let (payload, context) = event.into_parts();
+        ref_svcs
+            .dispatch_sqs(payload, lambda_deadline(context.deadline))
+            .await
+    }))
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,69 @@
+//! Pooling of large scratch buffers.
+//!
+//! The cutout service allocates several image-sized arrays for every request.
+//! On a warm Lambda instance we can hang on to these between invocations rather
+//! than going back to the allocator each time. Buffers are grouped into size
+//! classes (powers of two) so that requests with slightly different sizes can
+//! still share storage.
+
+use std::{collections::HashMap, sync::Mutex};
+
+/// The maximum number of idle buffers to retain in each size class.
+const MAX_PER_CLASS: usize = 4;
+
+#[derive(Debug)]
+pub struct VecPool<T> {
+    classes: Mutex<HashMap<usize, Vec<Vec<T>>>>,
+}
+
+impl<T> Default for VecPool<T> {
+    fn default() -> Self {
+        VecPool {
+            classes: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: Clone + Default> VecPool<T> {
+    /// Get a buffer of the specified length, filled with default values.
+    pub fn take(&self, len: usize) -> Vec<T> {
+        let class = len.next_power_of_two();
+
+        let mut buf = self
+            .classes
+            .lock()
+            .unwrap()
+            .get_mut(&class)
+            .and_then(|bufs| bufs.pop())
+            .unwrap_or_else(|| Vec::with_capacity(class));
+
+        buf.clear();
+        buf.resize(len, T::default());
+        buf
+    }
+
+    /// Return a buffer to the pool so that it can be reused.
+    pub fn give(&self, buf: Vec<T>) {
+        let class = buf.capacity();
+
+        // Only keep buffers that are exactly the size of a class; anything else
+        // must have come from somewhere besides `take()`.
+        if class == 0 || !class.is_power_of_two() {
+            return;
+        }
+
+        let mut classes = self.classes.lock().unwrap();
+        let bufs = classes.entry(class).or_default();
+
+        if bufs.len() < MAX_PER_CLASS {
+            bufs.push(buf);
+        }
+    }
+}
+
+/// The set of buffer pools used by the services.
+#[derive(Debug, Default)]
+pub struct BufferPool {
+    pub f64s: VecPool<f64>,
+    pub usizes: VecPool<usize>,
+}
@@ -0,0 +1,75 @@
+//! Accounting for the DynamoDB read capacity that requests consume.
+//!
+//! Our tables are provisioned for a certain read throughput, and a handful of
+//! heavy users account for most of it. To plan capacity from data, we ask
+//! DynamoDB to report the capacity consumed by every call, and add it up for
+//! each request. The total is reported in the response envelope, as
+//! `read_capacity_units`, and, if `DASCH_METRICS_NAMESPACE` is set, published
+//! as a CloudWatch metric using the [embedded metric format][emf]: a specially
+//! structured JSON line in the Lambda logs, which CloudWatch extracts without
+//! any API calls on our part.
+//!
+//! [emf]: https://docs.aws.amazon.com/AmazonCloudWatch/latest/monitoring/CloudWatch_Embedded_Metric_Format_Specification.html
+//!
+//! The running total lives in a Tokio task-local, set up by
+//! [`Services::dispatch_until`](crate::Services::dispatch_until) around each
+//! invocation, so that the storage backends can add to it without threading
+//! an accumulator through every handler. Reads that are served from the query
+//! cache don't touch DynamoDB, and so don't count.
+
+use serde_json::json;
+use std::{
+    cell::Cell,
+    future::Future,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+tokio::task_local! {
+    static CONSUMED: Cell<f64>;
+}
+
+/// Run a future, returning its output along with the read capacity units that
+/// it consumed.
+pub async fn measure<F: Future>(fut: F) -> (F::Output, f64) {
+    CONSUMED
+        .scope(Cell::new(0.), async move {
+            let output = fut.await;
+            (output, consumed())
+        })
+        .await
+}
+
+/// Add to the read capacity consumed by the current request. This does nothing
+/// outside of [`measure`].
+pub fn record(units: f64) {
+    let _ = CONSUMED.try_with(|c| c.set(c.get() + units));
+}
+
+/// The read capacity consumed so far by the current request.
+pub fn consumed() -> f64 {
+    CONSUMED.try_with(|c| c.get()).unwrap_or(0.)
+}
+
+/// Publish the read capacity consumed by a request to CloudWatch, by printing
+/// an embedded-metric-format record to standard output.
+pub fn emit_metric(namespace: &str, service: &str, units: f64) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default();
+
+    let record = json!({
+        "_aws": {
+            "Timestamp": timestamp,
+            "CloudWatchMetrics": [{
+                "Namespace": namespace,
+                "Dimensions": [["Service"]],
+                "Metrics": [{"Name": "ConsumedReadCapacityUnits", "Unit": "Count"}],
+            }],
+        },
+        "Service": service,
+        "ConsumedReadCapacityUnits": units,
+    });
+
+    println!("{record}");
+}
@@ -0,0 +1,171 @@
+//! The refcat binning consistency checker.
+//!
+//! This is an admin-only service. The refcat tables are partitioned by
+//! `gscBinIndex`, the 1/64-degree GSC bin of each source, which is computed at
+//! ingestion time. `querycat` only looks in the bins that [`GscBinning`] says
+//! cover its search box, so if the ingestion code ever disagrees with it, the
+//! affected sources silently go missing from query results. Given a refcat
+//! and a total bin number, this service reads every source in the bin,
+//! recomputes its bin from its RA and declination, and reports the sources
+//! that don't belong.
+//!
+//! Sources with placeholder positions (see [`BUILTIN_PLACEHOLDERS`]) or without
+//! positions can't be checked, and are only counted.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use lambda_http::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    backend::TableStore,
+    config::{default_data_release, Config},
+    envelope,
+    gscbin::GscBinning,
+    mosaics::BUILTIN_PLACEHOLDERS,
+    querycat::item_position,
+    refcats,
+    validation::{self, validate_fields},
+};
+
+/// Sync with `json-schemas/checkbin_request.json`.
+#[derive(Deserialize, Serialize)]
+pub struct Request {
+    refcat: String,
+    total_bin: usize,
+    /// The data release to check.
+    #[serde(default = "default_data_release")]
+    data_release: String,
+}
+
+impl Request {
+    /// Validate the request.
+    fn normalize(mut self, config: &Config, binning: &GscBinning) -> Result<Self, Error> {
+        if refcats::lookup(&self.refcat).is_none() {
+            return Err("illegal refcat parameter".into());
+        }
+
+        config.check_release(&self.data_release)?;
+
+        validate_fields!(self {
+            total_bin: |n, v| validation::range(n, v, 0..binning.n_bins()),
+        });
+
+        Ok(self)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Response {
+    /// The number of sources stored in the bin.
+    pub n_sources: usize,
+
+    /// The number of sources whose bins we could check.
+    pub n_checked: usize,
+
+    /// The number of sources with placeholder positions or no positions,
+    /// which we couldn't check.
+    pub n_unpositioned: usize,
+
+    /// The sources whose positions put them in a different bin.
+    pub mismatches: Vec<Mismatch>,
+}
+
+/// A source that's stored in the wrong bin.
+#[derive(Serialize)]
+pub struct Mismatch {
+    /// The source's reference text, or its ID in catalogs without reference
+    /// numbers.
+    pub ref_text: String,
+    pub ra_deg: f64,
+    pub dec_deg: f64,
+
+    /// The `gscBinIndex` stored with the source.
+    pub stored_bin: usize,
+
+    /// The bin that the source's position lands in, or None if its
+    /// declination is out of range.
+    pub computed_bin: Option<usize>,
+}
+
+pub async fn handler(
+    req: Option<Value>,
+    config: &Config,
+    tables: &dyn TableStore,
+    binning: &GscBinning,
+) -> Result<Value, Error> {
+    let request: Request =
+        serde_json::from_value(req.ok_or_else(|| -> Error { "no request payload".into() })?)?;
+    let request = request.normalize(config, binning)?;
+    let echo = serde_json::to_value(&request)?;
+    let result = implementation(request, config, tables, binning).await?;
+    envelope::wrap("checkbin", &echo, result)
+}
+
+/// Check one bin of a reference catalog. The request must have been
+/// normalized.
+pub async fn implementation(
+    request: Request,
+    config: &Config,
+    tables: &dyn TableStore,
+    binning: &GscBinning,
+) -> Result<Response, Error> {
+    let cat_table = config.refcat_table(&request.data_release, &request.refcat);
+
+    // We checked the name during normalization.
+    let catalog = refcats::lookup(&request.refcat).unwrap();
+
+    let items = tables
+        .query_items(
+            &cat_table,
+            "gscBinIndex",
+            AttributeValue::N(request.total_bin.to_string()),
+        )
+        .await?;
+
+    let mut response = Response {
+        n_sources: items.len(),
+        n_checked: 0,
+        n_unpositioned: 0,
+        mismatches: Vec::new(),
+    };
+
+    for item in items {
+        let (ra_deg, dec_deg) = match item_position(&item) {
+            Some((r, d)) if !BUILTIN_PLACEHOLDERS.is_placeholder(r, d) => (r, d),
+            _ => {
+                response.n_unpositioned += 1;
+                continue;
+            }
+        };
+
+        // Out-of-range declinations would make the binning panic; they're
+        // certainly in the wrong place.
+        let computed_bin = (-90. ..=90.)
+            .contains(&dec_deg)
+            .then(|| binning.get_total_bin(binning.get_dec_bin(dec_deg), ra_deg));
+
+        // The partition key is the bin that we queried, but don't assume it.
+        let stored_bin = item
+            .get("gscBinIndex")
+            .and_then(|av| av.as_n().ok())
+            .and_then(|text| text.parse::<usize>().ok())
+            .unwrap_or(request.total_bin);
+
+        response.n_checked += 1;
+
+        if computed_bin != Some(stored_bin) {
+            let ref_text = catalog.source_text(&item);
+
+            response.mismatches.push(Mismatch {
+                ref_text,
+                ra_deg,
+                dec_deg,
+                stored_bin,
+                computed_bin,
+            });
+        }
+    }
+
+    Ok(response)
+}
@@ -0,0 +1,128 @@
+//! The services as a Rust library, for embedding.
+//!
+//! Other DASCH tools written in Rust, like bulk reprocessing jobs and CLIs,
+//! can call the service implementations directly, rather than going through
+//! the Lambda JSON protocol. This module, available with the `client` feature,
+//! collects what they need: a submodule per service, with its typed request
+//! and response structs and its `implementation` function, and the shared
+//! state that the implementations take.
+//!
+//! Requests are built by deserializing them, typically from
+//! [`serde_json::json!`] values with the same fields as the JSON requests, and
+//! must then be validated with their `normalize` method, which fills in
+//! defaults. Unlike the Lambda handlers, the implementations don't resolve
+//! `target_name`s, wrap their results in the response envelope, or record
+//! provenance. They return their results as the typed responses, which
+//! serialize to the `result` of the corresponding JSON responses.
+//!
+//! The state that the [`Services`](crate::Services) object keeps is passed to
+//! the implementations explicitly, so that callers can share it as they see
+//! fit. [`AwsStore`] accesses the real data; on-disk fixtures can be used
+//! instead (see [`crate::fixtures`]). Cutouts read the mosaics through CFITSIO,
+//! which must be set up to read from S3 with [`register_s3_driver`] first.
+//!
+//! ```ignore
+//! use dasch_science_lambda::client::{self, cutout, AwsStore, Config};
+//!
+//! let sdk = aws_config::load_from_env().await;
+//! let config = Config::from_env();
+//! let store = AwsStore::new(&sdk, &config);
+//! client::register_s3_driver(&sdk, &config);
+//!
+//! let request: cutout::Request = serde_json::from_value(json!({
+//!     "plate_id": "a10000",
+//!     "solution_number": 0,
+//!     "center_ra_deg": 83.8,
+//!     "center_dec_deg": -5.4,
+//! }))?;
+//! let response = cutout::implementation(
+//!     request.normalize(&config)?,
+//!     &config,
+//!     &store,
+//!     &store,
+//!     &client::PlateCache::default(),
+//!     &client::BufferPool::default(),
+//!     &Arc::new(client::FitsPool::new(config.fits_pool_size)),
+//!     &client::DenyList::load(&config, &store).await?,
+//! )
+//! .await?;
+//! ```
+
+use aws_config::SdkConfig;
+use std::sync::Once;
+
+pub use crate::{
+    backend::{AwsStore, BatchGetOutput, Item, ObjectStore, TableStore},
+    bufpool::BufferPool,
+    config::Config,
+    deadline::Deadline,
+    denylist::{DenyList, DenyMode},
+    fitsfile::{FitsFile, Pixel},
+    fitspool::FitsPool,
+    gscbin::GscBinning,
+    mosaics::{load_b01_header, UnreadableHeaderError},
+    observing::ObservingSite,
+    platecache::PlateCache,
+    seriesdefaults::{CutoutDefaults, SeriesDefaults},
+    wcs::{HeaderOptions, Wcs, WcsCollection, WcsRelax},
+};
+
+/// Set up CFITSIO to open the `s3://` URLs of [`AwsStore::fits_url`]. This
+/// must be done before making any cutouts from AWS data, and only the first
+/// call has any effect.
+pub fn register_s3_driver(sdk: &SdkConfig, config: &Config) {
+    static REGISTERED: Once = Once::new();
+
+    REGISTERED.call_once(|| {
+        crate::s3fits::register(
+            crate::backend::s3_client_config(sdk, config),
+            crate::diskcache::DiskCache::from_config(config),
+        );
+    });
+}
+
+/// Cutouts of plate mosaics; see [`crate::Services`] for the JSON service.
+pub mod cutout {
+    pub use crate::cutout::{
+        implementation, implementation_batch, BatchStamp, EncodedImage, Estimate, NullPixels,
+        OutputCompression, PixelGrid, Request, Response, SeriesStamp, StagedBatch, StagedStamp,
+    };
+    pub use crate::stampstats::{AsinhStretch, ImageStatistics};
+}
+
+/// Reference catalog searches.
+pub mod querycat {
+    pub use crate::querycat::{implementation, Request, Response};
+}
+
+/// Searches for the exposures covering a position.
+pub mod queryexps {
+    pub use crate::queryexps::{
+        estimate, implementation, Estimate, Request, Response, SolutionExposure,
+    };
+}
+
+/// Searches for the exposures taken in a window of time.
+pub mod queryepoch {
+    pub use crate::queryepoch::{implementation, Request, Response};
+}
+
+/// The astrometric residuals of plate solutions.
+pub mod residuals {
+    pub use crate::residuals::{implementation, Request, ResidualsFormat, Response};
+}
+
+/// Photometric calibrations of plates.
+pub mod photcal {
+    pub use crate::photcal::{implementation, Calibration, Request, Response};
+}
+
+/// Detection-level photometry of catalog sources.
+pub mod lightcurve {
+    pub use crate::lightcurve::{implementation, Request, Response};
+}
+
+/// Bulk exports of plate headers.
+pub mod exportheaders {
+    pub use crate::exportheaders::{implementation, Request, Response};
+}
@@ -0,0 +1,647 @@
+//! Deployment configuration.
+//!
+//! The names of the S3 buckets and DynamoDB tables that the services use are
+//! set up here, so that mirrors, test buckets, and new data releases can be
+//! switched at deploy time. Each setting can be overridden with an environment
+//! variable; the defaults correspond to our production deployment.
+//!
+//! The table names and coverage-bin prefix are templates, in which `{env}` is
+//! replaced by the environment name, `{release}` by the data release, and, for
+//! the refcat tables, `{refcat}` by the name of the reference catalog.
+//!
+//! Every request may specify the data release that it targets (for instance,
+//! `dr7`), so that multiple releases can be served side by side from one
+//! deployment. The mosaic key templates are stored in the plates table, so
+//! they follow the release automatically.
+
+use std::{env, time::Duration};
+
+use crate::{features::Features, snapshot::PlatesSource};
+
+/// The default value of the environment name.
+pub const ENVIRONMENT: &str = "dev";
+
+/// The default value of the data bucket name.
+pub const BUCKET: &str = "dasch-prod-user";
+
+/// The data release used if a request doesn't specify one.
+pub const DEFAULT_DATA_RELEASE: &str = "dr7";
+
+/// The default value of the `data_release` request field, for serde.
+pub fn default_data_release() -> String {
+    DEFAULT_DATA_RELEASE.to_owned()
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The deployment environment name. Environment variable:
+    /// `DASCH_ENVIRONMENT`.
+    pub environment: String,
+
+    /// The S3 bucket holding the mosaics and coverage-bin files. Environment
+    /// variable: `DASCH_BUCKET`.
+    pub bucket: String,
+
+    /// The template for the name of the plates table. Environment variable:
+    /// `DASCH_PLATES_TABLE`.
+    pub plates_table: String,
+
+    /// The name of the plates table's global secondary index keyed by
+    /// observing day; see `crate::queryepoch`. Environment variable:
+    /// `DASCH_PLATES_DATE_INDEX`.
+    pub plates_date_index: String,
+
+    /// Where plate metadata is read from; see `crate::snapshot`. Environment
+    /// variable: `DASCH_PLATES_SOURCE`, one of `dynamodb`, `fallback`, or
+    /// `snapshot`.
+    pub plates_source: PlatesSource,
+
+    /// The template for the key prefix of the S3 snapshot of the plates
+    /// table. Environment variable: `DASCH_PLATES_SNAPSHOT_PREFIX`.
+    pub plates_snapshot_prefix: String,
+
+    /// The template for the names of the refcat tables. Environment variable:
+    /// `DASCH_REFCAT_TABLE`.
+    pub refcat_table: String,
+
+    /// The template for the name of the photometric calibration table; see
+    /// `crate::photcal`. Environment variable: `DASCH_CALIBRATION_TABLE`.
+    pub calibration_table: String,
+
+    /// The key prefix of the coverage-bin files in the bucket. Environment
+    /// variable: `DASCH_COVERAGE_BINS_PREFIX`.
+    pub coverage_bins_prefix: String,
+
+    /// The template for the key prefix of the raw photometry files in the
+    /// bucket; see `crate::lightcurve`. Environment variable:
+    /// `DASCH_PHOTOMETRY_PREFIX`.
+    pub photometry_prefix: String,
+
+    /// The key of the plate deny-list in the bucket; see `crate::denylist`.
+    /// Environment variable: `DASCH_DENY_LIST_KEY`.
+    pub deny_list_key: String,
+
+    /// The key of the per-series default cutout parameters in the bucket; see
+    /// `crate::seriesdefaults`. Environment variable:
+    /// `DASCH_SERIES_DEFAULTS_KEY`.
+    pub series_defaults_key: String,
+
+    /// Overrides for how we reach DynamoDB. Environment variables:
+    /// `DASCH_DYNAMODB_REGION` and `DASCH_DYNAMODB_ENDPOINT_URL`.
+    pub dynamodb_endpoint: ServiceEndpoint,
+
+    /// Overrides for how we reach S3. Environment variables: `DASCH_S3_REGION`
+    /// and `DASCH_S3_ENDPOINT_URL`.
+    pub s3_endpoint: ServiceEndpoint,
+
+    /// Whether to use path-style S3 URLs, as needed by some S3-compatible
+    /// services like LocalStack. Environment variable:
+    /// `DASCH_S3_FORCE_PATH_STYLE`, which is true if set to `1` or `true`.
+    pub s3_force_path_style: bool,
+
+    /// The data releases that this deployment serves. Environment variable:
+    /// `DASCH_DATA_RELEASES`, a comma-separated list.
+    pub data_releases: Vec<String>,
+
+    /// The memory budget for heavy operations on one instance, in MiB; see
+    /// `crate::admission`. Environment variable: `DASCH_MEMORY_BUDGET_MIB`.
+    /// If unset, we use half of the Lambda function's memory allocation.
+    pub memory_budget_mib: u32,
+
+    /// The maximum number of idle mosaic handles to keep open for reuse; see
+    /// `crate::fitspool`. Environment variable: `DASCH_FITS_POOL_SIZE`.
+    pub fits_pool_size: usize,
+
+    /// The size limit of the local disk cache of mosaic data, in MiB, or zero
+    /// to disable it; see `crate::diskcache`. Environment variable:
+    /// `DASCH_MOSAIC_CACHE_MIB`.
+    pub mosaic_cache_mib: u32,
+
+    /// The directory of the mosaic disk cache. The cache keeps its blocks in
+    /// a `blocks` subdirectory, which is emptied at startup. Environment
+    /// variable: `DASCH_MOSAIC_CACHE_DIR`.
+    pub mosaic_cache_dir: String,
+
+    /// How long a heavy operation may wait for room in the memory budget
+    /// before being rejected. Environment variable: `DASCH_ADMISSION_WAIT_MS`.
+    pub admission_wait: Duration,
+
+    /// The maximum number of requests that one client may have in flight on
+    /// one instance, or zero for no limit; see `crate::ratelimit`.
+    /// Environment variable: `DASCH_RATE_MAX_CONCURRENT`.
+    pub rate_max_concurrent: u32,
+
+    /// The maximum number of result rows that one client may receive from
+    /// one instance per rate window, or zero for no limit. Environment
+    /// variable: `DASCH_RATE_MAX_ROWS`.
+    pub rate_max_rows: u64,
+
+    /// The length of the window over which the row budget is tracked.
+    /// Environment variable: `DASCH_RATE_WINDOW_SECS`.
+    pub rate_window: Duration,
+
+    /// The browser origins allowed to make cross-origin requests, or `*` for
+    /// any; see `crate::cors`. If empty, CORS is not supported. Environment
+    /// variable: `DASCH_CORS_ALLOWED_ORIGINS`, a comma-separated list.
+    pub cors_allowed_origins: Vec<String>,
+
+    /// The request headers allowed in cross-origin requests. Environment
+    /// variable: `DASCH_CORS_ALLOWED_HEADERS`.
+    pub cors_allowed_headers: String,
+
+    /// How long browsers may cache CORS preflight results. Environment
+    /// variable: `DASCH_CORS_MAX_AGE_SECS`.
+    pub cors_max_age: Duration,
+
+    /// How long before the Lambda time limit the long-running handlers should
+    /// stop and return partial results; see `crate::deadline`. Environment
+    /// variable: `DASCH_DEADLINE_MARGIN_MS`.
+    pub deadline_margin: Duration,
+
+    /// The maximum number of rows in a tabular result returned inline; see
+    /// `crate::rowcap`. Environment variable: `DASCH_MAX_ROWS`.
+    pub max_rows: usize,
+
+    /// The API key IDs of the clients allowed to call admin-only services
+    /// through the proxy-event server. Environment variable:
+    /// `DASCH_ADMIN_API_KEYS`, a comma-separated list.
+    pub admin_api_keys: Vec<String>,
+
+    /// The secrets that continuation tokens are signed with; see
+    /// `crate::continuation`. New tokens are signed with the first one, and
+    /// tokens signed with any of them are accepted, so that the secret can be
+    /// rotated without breaking the tokens in flight. There's no default:
+    /// without a secret, requests that need a token fail. Environment
+    /// variable: `DASCH_TOKEN_SECRETS`, a comma-separated list.
+    pub token_secrets: Vec<String>,
+
+    /// How long continuation tokens are valid. Environment variable:
+    /// `DASCH_CONTINUATION_TTL_SECS`.
+    pub continuation_ttl: Duration,
+
+    /// The Redis/ElastiCache URL of the catalog query cache, if any; see
+    /// `crate::querycache`. Only used if the `elasticache` feature is
+    /// enabled. Environment variable: `DASCH_QUERY_CACHE_URL`.
+    pub query_cache_url: Option<String>,
+
+    /// How long cached catalog queries are kept. Environment variable:
+    /// `DASCH_QUERY_CACHE_TTL_SECS`.
+    pub query_cache_ttl: Duration,
+
+    /// How long lookups of nonexistent plates and S3 objects are remembered,
+    /// or zero not to; see `crate::negcache`. Environment variable:
+    /// `DASCH_NEGATIVE_CACHE_TTL_SECS`.
+    pub negative_cache_ttl: Duration,
+
+    /// The S3 bucket that large query results are staged to, and that target
+    /// lists are uploaded to, if not the data bucket. Environment variable:
+    /// `DASCH_RESULTS_BUCKET`.
+    pub results_bucket: Option<String>,
+
+    /// The key prefix of staged query results. The bucket should have a
+    /// lifecycle rule expiring objects under it. Environment variable:
+    /// `DASCH_RESULTS_PREFIX`.
+    pub results_prefix: String,
+
+    /// How long the download URLs of staged query results are valid. A
+    /// presigned URL also stops working when the credentials that signed it
+    /// expire, which for a Lambda role session can be well before a long TTL
+    /// is up, so the default is one hour. Environment variable:
+    /// `DASCH_RESULTS_URL_TTL_SECS`.
+    pub results_url_ttl: Duration,
+
+    /// The key prefix of uploaded target lists, which are stored in the
+    /// results bucket; see `crate::targetlists`. Environment variable:
+    /// `DASCH_TARGET_LISTS_PREFIX`.
+    pub target_lists_prefix: String,
+
+    /// How long the upload URLs of target lists are valid. Environment
+    /// variable: `DASCH_UPLOAD_URL_TTL_SECS`.
+    pub upload_url_ttl: Duration,
+
+    /// The key prefix of the results of requests from the batch queue, which
+    /// are stored in the results bucket; see `crate::sqsworker`. Environment
+    /// variable: `DASCH_BATCH_RESULTS_PREFIX`.
+    pub batch_results_prefix: String,
+
+    /// The SNS topic that completions of batch-queue requests are announced
+    /// on, if any. Environment variable: `DASCH_BATCH_TOPIC_ARN`.
+    pub batch_topic_arn: Option<String>,
+
+    /// The EventBridge bus that completions of batch-queue requests are
+    /// announced on, if any. Environment variable: `DASCH_BATCH_EVENT_BUS`.
+    pub batch_event_bus: Option<String>,
+
+    /// The CloudWatch namespace of the metrics that we publish, if any; see
+    /// `crate::capacity`. Environment variable: `DASCH_METRICS_NAMESPACE`.
+    pub metrics_namespace: Option<String>,
+
+    /// The DynamoDB table that cutout provenance records are written to, if
+    /// any; see `crate::provenance`. `{env}` is replaced with the deployment
+    /// environment. Environment variable: `DASCH_PROVENANCE_TABLE`.
+    pub provenance_table: Option<String>,
+
+    /// The DynamoDB table that `queryexps` caches WCS overlap tests in, if
+    /// any; see `crate::overlapcache`. `{env}` and `{release}` are replaced as
+    /// for the plates table. Environment variable:
+    /// `DASCH_OVERLAP_CACHE_TABLE`.
+    pub overlap_cache_table: Option<String>,
+
+    /// The URL of CDS's Sesame name resolver, for the `sesame_lookup`
+    /// feature; see `crate::targets`. Environment variable:
+    /// `DASCH_SESAME_URL`.
+    pub sesame_url: String,
+
+    /// The settings of the feature flags; see `crate::features`. Environment
+    /// variable: `DASCH_FEATURES`.
+    pub features: Features,
+}
+
+/// Overrides of the AWS region and endpoint used for one service. By default,
+/// these come from the standard AWS configuration. Setting them separately for
+/// each service lets disaster-recovery deployments read from a replica region,
+/// and lets local integration tests point at something like LocalStack.
+#[derive(Clone, Debug, Default)]
+pub struct ServiceEndpoint {
+    pub region: Option<String>,
+    pub url: Option<String>,
+}
+
+impl ServiceEndpoint {
+    fn from_env(prefix: &str) -> Self {
+        let var = |suffix: &str| {
+            env::var(format!("{prefix}_{suffix}"))
+                .ok()
+                .filter(|v| !v.is_empty())
+        };
+
+        ServiceEndpoint {
+            region: var("REGION"),
+            url: var("ENDPOINT_URL"),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            environment: ENVIRONMENT.to_owned(),
+            bucket: BUCKET.to_owned(),
+            plates_table: "dasch-{env}-{release}-plates".to_owned(),
+            plates_date_index: "expDay-index".to_owned(),
+            plates_source: PlatesSource::default(),
+            plates_snapshot_prefix: "dasch-{release}-plates-snapshot/".to_owned(),
+            refcat_table: "dasch-{env}-{release}-refcat-{refcat}".to_owned(),
+            calibration_table: "dasch-{env}-{release}-calibration".to_owned(),
+            coverage_bins_prefix: "dasch-{release}-coverage-bins/".to_owned(),
+            photometry_prefix: "dasch-{release}-photometry/{refcat}/".to_owned(),
+            deny_list_key: "dasch-plate-deny-list.json".to_owned(),
+            series_defaults_key: "dasch-series-cutout-defaults.json".to_owned(),
+            dynamodb_endpoint: ServiceEndpoint::default(),
+            s3_endpoint: ServiceEndpoint::default(),
+            s3_force_path_style: false,
+            data_releases: vec![DEFAULT_DATA_RELEASE.to_owned()],
+            memory_budget_mib: 512,
+            fits_pool_size: 4,
+            mosaic_cache_mib: 0,
+            mosaic_cache_dir: "/tmp/dasch-mosaic-cache".to_owned(),
+            admission_wait: Duration::from_secs(2),
+            rate_max_concurrent: 0,
+            rate_max_rows: 0,
+            rate_window: Duration::from_secs(60),
+            cors_allowed_origins: Vec::new(),
+            cors_allowed_headers: "content-type, x-api-key".to_owned(),
+            cors_max_age: Duration::from_secs(600),
+            deadline_margin: Duration::from_secs(3),
+            max_rows: 20_000,
+            admin_api_keys: Vec::new(),
+            token_secrets: Vec::new(),
+            continuation_ttl: Duration::from_secs(7 * 86400),
+            query_cache_url: None,
+            query_cache_ttl: Duration::from_secs(86400),
+            negative_cache_ttl: Duration::from_secs(30),
+            results_bucket: None,
+            results_prefix: "dasch-query-results/".to_owned(),
+            results_url_ttl: Duration::from_secs(3600),
+            target_lists_prefix: "dasch-target-lists/".to_owned(),
+            upload_url_ttl: Duration::from_secs(3600),
+            batch_results_prefix: "dasch-batch-results/".to_owned(),
+            batch_topic_arn: None,
+            batch_event_bus: None,
+            metrics_namespace: None,
+            provenance_table: None,
+            overlap_cache_table: None,
+            sesame_url: "http://cdsweb.u-strasbg.fr/cgi-bin/nph-sesame/-oI/SNV".to_owned(),
+            features: Features::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Load the configuration from the environment, using defaults for
+    /// anything that isn't specified.
+    pub fn from_env() -> Self {
+        let mut config = Config::default();
+
+        for (var, field) in [
+            ("DASCH_ENVIRONMENT", &mut config.environment),
+            ("DASCH_BUCKET", &mut config.bucket),
+            ("DASCH_PLATES_TABLE", &mut config.plates_table),
+            ("DASCH_PLATES_DATE_INDEX", &mut config.plates_date_index),
+            (
+                "DASCH_PLATES_SNAPSHOT_PREFIX",
+                &mut config.plates_snapshot_prefix,
+            ),
+            ("DASCH_REFCAT_TABLE", &mut config.refcat_table),
+            ("DASCH_CALIBRATION_TABLE", &mut config.calibration_table),
+            (
+                "DASCH_COVERAGE_BINS_PREFIX",
+                &mut config.coverage_bins_prefix,
+            ),
+            ("DASCH_PHOTOMETRY_PREFIX", &mut config.photometry_prefix),
+            ("DASCH_DENY_LIST_KEY", &mut config.deny_list_key),
+            ("DASCH_SERIES_DEFAULTS_KEY", &mut config.series_defaults_key),
+            ("DASCH_MOSAIC_CACHE_DIR", &mut config.mosaic_cache_dir),
+            (
+                "DASCH_CORS_ALLOWED_HEADERS",
+                &mut config.cors_allowed_headers,
+            ),
+            ("DASCH_RESULTS_PREFIX", &mut config.results_prefix),
+            ("DASCH_TARGET_LISTS_PREFIX", &mut config.target_lists_prefix),
+            (
+                "DASCH_BATCH_RESULTS_PREFIX",
+                &mut config.batch_results_prefix,
+            ),
+            ("DASCH_SESAME_URL", &mut config.sesame_url),
+        ] {
+            if let Ok(value) = env::var(var) {
+                *field = value;
+            }
+        }
+
+        config.dynamodb_endpoint = ServiceEndpoint::from_env("DASCH_DYNAMODB");
+        config.s3_endpoint = ServiceEndpoint::from_env("DASCH_S3");
+
+        if let Ok(value) = env::var("DASCH_S3_FORCE_PATH_STYLE") {
+            config.s3_force_path_style = matches!(value.to_lowercase().as_str(), "1" | "true");
+        }
+
+        let list = |value: String| -> Vec<String> {
+            value
+                .split(',')
+                .map(|s| s.trim().to_owned())
+                .filter(|s| !s.is_empty())
+                .collect()
+        };
+
+        if let Ok(value) = env::var("DASCH_DATA_RELEASES") {
+            config.data_releases = list(value);
+        }
+
+        if let Ok(value) = env::var("DASCH_CORS_ALLOWED_ORIGINS") {
+            config.cors_allowed_origins = list(value);
+        }
+
+        if let Ok(value) = env::var("DASCH_ADMIN_API_KEYS") {
+            config.admin_api_keys = list(value);
+        }
+
+        if let Ok(value) = env::var("DASCH_TOKEN_SECRETS") {
+            config.token_secrets = list(value);
+        }
+
+        if let Some(secs) = env::var("DASCH_CONTINUATION_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            config.continuation_ttl = Duration::from_secs(secs);
+        }
+
+        let mib = |var: &str| env::var(var).ok().and_then(|v| v.parse::<u32>().ok());
+
+        if let Some(n) = mib("DASCH_MEMORY_BUDGET_MIB") {
+            config.memory_budget_mib = n;
+        } else if let Some(n) = mib("AWS_LAMBDA_FUNCTION_MEMORY_SIZE") {
+            config.memory_budget_mib = n / 2;
+        }
+
+        if let Some(n) = env::var("DASCH_FITS_POOL_SIZE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            config.fits_pool_size = n;
+        }
+
+        if let Some(n) = mib("DASCH_MOSAIC_CACHE_MIB") {
+            config.mosaic_cache_mib = n;
+        }
+
+        if let Some(ms) = env::var("DASCH_ADMISSION_WAIT_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            config.admission_wait = Duration::from_millis(ms);
+        }
+
+        if let Some(n) = env::var("DASCH_RATE_MAX_CONCURRENT")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            config.rate_max_concurrent = n;
+        }
+
+        if let Some(n) = env::var("DASCH_RATE_MAX_ROWS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            config.rate_max_rows = n;
+        }
+
+        if let Some(secs) = env::var("DASCH_RATE_WINDOW_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            config.rate_window = Duration::from_secs(secs);
+        }
+
+        if let Some(secs) = env::var("DASCH_CORS_MAX_AGE_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            config.cors_max_age = Duration::from_secs(secs);
+        }
+
+        if let Some(ms) = env::var("DASCH_DEADLINE_MARGIN_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            config.deadline_margin = Duration::from_millis(ms);
+        }
+
+        if let Some(n) = env::var("DASCH_MAX_ROWS").ok().and_then(|v| v.parse().ok()) {
+            config.max_rows = n;
+        }
+
+        config.query_cache_url = env::var("DASCH_QUERY_CACHE_URL")
+            .ok()
+            .filter(|v| !v.is_empty());
+
+        if let Some(secs) = env::var("DASCH_QUERY_CACHE_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            config.query_cache_ttl = Duration::from_secs(secs);
+        }
+
+        if let Some(secs) = env::var("DASCH_NEGATIVE_CACHE_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            config.negative_cache_ttl = Duration::from_secs(secs);
+        }
+
+        config.results_bucket = env::var("DASCH_RESULTS_BUCKET")
+            .ok()
+            .filter(|v| !v.is_empty());
+
+        if let Some(secs) = env::var("DASCH_RESULTS_URL_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            config.results_url_ttl = Duration::from_secs(secs);
+        }
+
+        if let Some(secs) = env::var("DASCH_UPLOAD_URL_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            config.upload_url_ttl = Duration::from_secs(secs);
+        }
+
+        config.batch_topic_arn = env::var("DASCH_BATCH_TOPIC_ARN")
+            .ok()
+            .filter(|v| !v.is_empty());
+
+        config.batch_event_bus = env::var("DASCH_BATCH_EVENT_BUS")
+            .ok()
+            .filter(|v| !v.is_empty());
+
+        config.metrics_namespace = env::var("DASCH_METRICS_NAMESPACE")
+            .ok()
+            .filter(|v| !v.is_empty());
+
+        config.provenance_table = env::var("DASCH_PROVENANCE_TABLE")
+            .ok()
+            .filter(|v| !v.is_empty());
+
+        config.overlap_cache_table = env::var("DASCH_OVERLAP_CACHE_TABLE")
+            .ok()
+            .filter(|v| !v.is_empty());
+
+        if let Ok(value) = env::var("DASCH_PLATES_SOURCE") {
+            match PlatesSource::parse(&value) {
+                Ok(source) => config.plates_source = source,
+                Err(e) => eprintln!("ignoring DASCH_PLATES_SOURCE: {e}"),
+            }
+        }
+
+        if let Ok(value) = env::var("DASCH_FEATURES") {
+            config.features = Features::parse(&value);
+        }
+
+        config
+    }
+
+    /// Check that a requested data release is one that we serve.
+    pub fn check_release(&self, release: &str) -> Result<(), String> {
+        if self.data_releases.iter().any(|r| r == release) {
+            Ok(())
+        } else {
+            Err(format!(
+                "unsupported data_release `{}` (this deployment serves: {})",
+                release,
+                self.data_releases.join(", ")
+            ))
+        }
+    }
+
+    fn expand(&self, template: &str, release: &str) -> String {
+        template
+            .replace("{env}", &self.environment)
+            .replace("{release}", release)
+    }
+
+    /// The name of the plates table for the specified release.
+    pub fn plates_table(&self, release: &str) -> String {
+        self.expand(&self.plates_table, release)
+    }
+
+    /// The S3 key prefix of the snapshot of the plates table for the
+    /// specified release.
+    pub fn plates_snapshot_prefix(&self, release: &str) -> String {
+        self.expand(&self.plates_snapshot_prefix, release)
+    }
+
+    /// The name of the table for the specified release and reference catalog.
+    pub fn refcat_table(&self, release: &str, refcat: &str) -> String {
+        self.expand(&self.refcat_table, release)
+            .replace("{refcat}", refcat)
+    }
+
+    /// The name of the photometric calibration table for the specified
+    /// release.
+    pub fn calibration_table(&self, release: &str) -> String {
+        self.expand(&self.calibration_table, release)
+    }
+
+    /// The name of the cutout provenance table, if provenance is recorded.
+    pub fn provenance_table(&self) -> Option<String> {
+        self.provenance_table
+            .as_deref()
+            .map(|t| t.replace("{env}", &self.environment))
+    }
+
+    /// The name of the overlap cache table for the specified release, if
+    /// overlap tests are cached.
+    pub fn overlap_cache_table(&self, release: &str) -> Option<String> {
+        self.overlap_cache_table
+            .as_deref()
+            .map(|t| self.expand(t, release))
+    }
+
+    /// The S3 bucket that large query results are staged to, and that
+    /// target lists are uploaded to.
+    pub fn results_bucket(&self) -> &str {
+        self.results_bucket.as_deref().unwrap_or(&self.bucket)
+    }
+
+    /// The S3 key of the coverage-bin file for the specified release and bin.
+    pub fn coverage_bin_key(&self, release: &str, total_bin: usize) -> String {
+        format!(
+            "{}{}.csv",
+            self.expand(&self.coverage_bins_prefix, release),
+            total_bin
+        )
+    }
+
+    /// The S3 key of the v2 coverage-bin file for the specified release and
+    /// bin. These live alongside the CSV files, which they supersede.
+    pub fn coverage_bin_v2_key(&self, release: &str, total_bin: usize) -> String {
+        format!(
+            "{}{}.bin",
+            self.expand(&self.coverage_bins_prefix, release),
+            total_bin
+        )
+    }
+
+    /// The S3 key of the raw photometry file for the specified release,
+    /// reference catalog, and bin.
+    pub fn photometry_key(&self, release: &str, refcat: &str, total_bin: usize) -> String {
+        format!(
+            "{}{}.csv.gz",
+            self.expand(&self.photometry_prefix, release)
+                .replace("{refcat}", refcat),
+            total_bin
+        )
+    }
+}
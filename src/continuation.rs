@@ -0,0 +1,170 @@
+//! Continuation tokens.
+//!
+//! Queries that stop early, because time ran short (see `deadline.rs`) or
+//! they hit a row cap (see `rowcap.rs`), hand back a token that can be passed
+//! back in the `continuation` field of an otherwise identical request to pick
+//! up where they left off. Tokens are opaque to users. Internally, they record
+//! an offset into the service's (deterministically ordered) units of work,
+//! such as the list of sky bins to search, and how many of that unit's rows
+//! have already been returned, since responses cut short by a row cap can end
+//! partway through a unit.
+//!
+//! Tokens pass through clients that we don't control, so they're signed: the
+//! URL-safe Base64 encoding of
+//!
+//! - a format version byte, currently 2;
+//! - the expiry time, as big-endian 32-bit Unix seconds;
+//! - the work offset and the number of rows to skip, as big-endian 32-bit
+//!   integers; and
+//! - the first 12 bytes of the HMAC-SHA256 of the service name, a zero byte,
+//!   the hash of the request that the token continues, and all of the above,
+//!   keyed with a deployment secret.
+//!
+//! The request hash is computed as for the response envelope (see
+//! `envelope.rs`), from the normalized request without its `continuation`
+//! field; see [`Binding`]. A token is therefore only good for the service and
+//! the request that it was issued for, can't be tampered with, and expires
+//! after `Config::continuation_ttl`. `Config::token_secrets` can list old
+//! secrets alongside the current one, so that tokens survive the rotation of
+//! the secret.
+//!
+//! The version byte lets handlers recognize the tokens of older formats.
+//! Version 1 tokens weren't bound to a request, so there's no way to check
+//! that they're used with the request that they were issued for, and they're
+//! rejected: clients holding one must start their query again.
+//!
+//! There's no default secret. A deployment that doesn't configure one can't
+//! issue or accept tokens at all, so requests that would need one fail.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use lambda_http::Error;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{config::Config, envelope};
+
+/// The current token format version.
+const VERSION: u8 = 2;
+
+/// The format version of tokens that weren't bound to a request.
+const UNBOUND_VERSION: u8 = 1;
+
+/// The length of the signed part of a token.
+const BODY_LEN: usize = 13;
+
+/// The length of the truncated signature.
+const MAC_LEN: usize = 12;
+
+/// What a continuation token is good for: a service, and the request that it
+/// continues.
+pub struct Binding {
+    service: &'static str,
+    request_hash: String,
+}
+
+impl Binding {
+    /// Bind tokens to a normalized request of the given service. Its
+    /// `continuation` field is ignored, so that the tokens of each page of a
+    /// query bind to the same thing.
+    pub fn new<T: Serialize>(service: &'static str, request: &T) -> Result<Self, Error> {
+        let mut request = serde_json::to_value(request)?;
+
+        if let Value::Object(fields) = &mut request {
+            fields.remove("continuation");
+        }
+
+        Ok(Binding {
+            service,
+            request_hash: envelope::request_hash(service, &request),
+        })
+    }
+}
+
+/// Encode a continuation token for the given request, work offset, and
+/// number of rows of that unit of work to skip.
+pub fn encode_token(
+    config: &Config,
+    binding: &Binding,
+    offset: usize,
+    skip: usize,
+) -> Result<String, Error> {
+    let secret = config.token_secrets.first().ok_or_else(no_secret)?;
+    let expiry = now_secs().saturating_add(config.continuation_ttl.as_secs());
+
+    let mut token = Vec::with_capacity(BODY_LEN + MAC_LEN);
+    token.push(VERSION);
+    token.extend_from_slice(&(expiry.min(u32::MAX as u64) as u32).to_be_bytes());
+    token.extend_from_slice(&(offset as u32).to_be_bytes());
+    token.extend_from_slice(&(skip as u32).to_be_bytes());
+
+    let mac = mac(secret, binding, &token).finalize();
+    token.extend_from_slice(&mac.into_bytes()[..MAC_LEN]);
+    Ok(URL_SAFE_NO_PAD.encode(token))
+}
+
+/// Decode a continuation token produced by [`encode_token`] for the given
+/// request, returning the work offset and the number of rows to skip.
+pub fn decode_token(
+    config: &Config,
+    binding: &Binding,
+    token: &str,
+) -> Result<(usize, usize), Error> {
+    let bad = || -> Error { "invalid continuation token".into() };
+
+    if config.token_secrets.is_empty() {
+        return Err(no_secret());
+    }
+
+    let token = URL_SAFE_NO_PAD.decode(token).map_err(|_| bad())?;
+
+    if token.first() == Some(&UNBOUND_VERSION) {
+        return Err("outdated continuation token: start the query again".into());
+    }
+
+    if token.first() != Some(&VERSION) || token.len() != BODY_LEN + MAC_LEN {
+        return Err(bad());
+    }
+
+    let (body, tag) = token.split_at(BODY_LEN);
+
+    if !config.token_secrets.iter().any(|secret| {
+        mac(secret, binding, body)
+            .verify_truncated_left(tag)
+            .is_ok()
+    }) {
+        return Err(bad());
+    }
+
+    let field = |i: usize| u32::from_be_bytes(body[i..i + 4].try_into().unwrap());
+
+    if u64::from(field(1)) < now_secs() {
+        return Err("expired continuation token: start the query again".into());
+    }
+
+    Ok((field(5) as usize, field(9) as usize))
+}
+
+/// The MAC of a token body for a request, keyed with the given secret.
+fn mac(secret: &str, binding: &Binding, body: &[u8]) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(binding.service.as_bytes());
+    mac.update(&[0]);
+    mac.update(binding.request_hash.as_bytes());
+    mac.update(body);
+    mac
+}
+
+fn no_secret() -> Error {
+    "continuation tokens are disabled, since this deployment has no token secret".into()
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,157 @@
+//! Shared handling of sky coordinates.
+//!
+//! RA wraparound is easy to get subtly wrong, and our services used to each
+//! have their own slightly different logic for it. The conventions here are:
+//!
+//! - Requests may specify RAs in the closed range [0, 360], and declinations in
+//!   the closed range [-90, 90]. Anything else, including NaN, is rejected.
+//! - Internally, RAs are normalized into the half-open range [0, 360), so that
+//!   RA = 360 is always treated identically to RA = 0.
+//! - RA differences are expressed in the half-open range [-180, 180).
+//! - A search region whose RA extent would cover the whole circle -- e.g.,
+//!   because it touches a pole -- is treated as covering all RAs.
+
+use std::ops::RangeInclusive;
+
+use crate::gscbin::D2R;
+
+/// Normalize an RA value, in degrees, into the range [0, 360).
+pub fn normalize_ra(ra_deg: f64) -> f64 {
+    let ra = ra_deg.rem_euclid(360.);
+
+    // `rem_euclid` can return exactly 360 for tiny negative inputs due to
+    // rounding.
+    if ra >= 360. {
+        0.
+    } else {
+        ra
+    }
+}
+
+/// Compute `ra1 - ra2`, in degrees, wrapped into the range [-180, 180).
+pub fn delta_ra(ra1_deg: f64, ra2_deg: f64) -> f64 {
+    (ra1_deg - ra2_deg + 180.).rem_euclid(360.) - 180.
+}
+
+/// Compute the angular separation between two positions, in degrees.
+///
+/// This uses the haversine formula, which is well-conditioned at small
+/// separations.
+pub fn angular_separation(ra1_deg: f64, dec1_deg: f64, ra2_deg: f64, dec2_deg: f64) -> f64 {
+    let half_ddec = 0.5 * D2R * (dec1_deg - dec2_deg);
+    let half_dra = 0.5 * D2R * delta_ra(ra1_deg, ra2_deg);
+
+    let h = half_ddec.sin().powi(2)
+        + (D2R * dec1_deg).cos() * (D2R * dec2_deg).cos() * half_dra.sin().powi(2);
+
+    2. * h.sqrt().min(1.).asin() / D2R
+}
+
+/// Compute the position angle of the second position as seen from the first,
+/// in degrees east of north, in the range [0, 360).
+pub fn position_angle(ra1_deg: f64, dec1_deg: f64, ra2_deg: f64, dec2_deg: f64) -> f64 {
+    let dra = D2R * delta_ra(ra2_deg, ra1_deg);
+    let (dec1, dec2) = (D2R * dec1_deg, D2R * dec2_deg);
+
+    let pa = f64::atan2(
+        dra.sin() * dec2.cos(),
+        dec1.cos() * dec2.sin() - dec1.sin() * dec2.cos() * dra.cos(),
+    );
+
+    normalize_ra(pa / D2R)
+}
+
+/// Precess a J2000 position to the mean equinox of a Julian Date, returning
+/// the RA and Dec in degrees, with the IAU 1976 precession model. The RA is
+/// not normalized.
+pub fn precess_from_j2000(jd: f64, ra_deg: f64, dec_deg: f64) -> (f64, f64) {
+    let t = (jd - 2451545.) / 36525.;
+    let asec = D2R / 3600.;
+    let zeta = asec * t * (2306.2181 + t * (0.30188 + t * 0.017998));
+    let z = asec * t * (2306.2181 + t * (1.09468 + t * 0.018203));
+    let theta = asec * t * (2004.3109 - t * (0.42665 + t * 0.041833));
+
+    let ra = D2R * ra_deg + zeta;
+    let dec = D2R * dec_deg;
+    let a = dec.cos() * ra.sin();
+    let b = theta.cos() * dec.cos() * ra.cos() - theta.sin() * dec.sin();
+    let c = theta.sin() * dec.cos() * ra.cos() + theta.cos() * dec.sin();
+
+    ((f64::atan2(a, b) + z) / D2R, c.clamp(-1., 1.).asin() / D2R)
+}
+
+/// The RA intervals covered by a search box.
+///
+/// If the box crosses RA = 0, it is split into two intervals. Each interval
+/// satisfies `0 <= start <= end <= 360`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RaIntervals {
+    pub first: RangeInclusive<f64>,
+    pub second: Option<RangeInclusive<f64>>,
+}
+
+impl RaIntervals {
+    /// An interval covering the whole circle.
+    pub fn all() -> Self {
+        RaIntervals {
+            first: 0. ..=360.,
+            second: None,
+        }
+    }
+
+    /// Compute the RA intervals covered by a box centered on `center_ra_deg`
+    /// (which should be normalized) with a half-width in RA of
+    /// `half_width_deg`, measured in RA degrees (i.e., *not* corrected for
+    /// declination).
+    pub fn around(center_ra_deg: f64, half_width_deg: f64) -> Self {
+        let min_ra = center_ra_deg - half_width_deg;
+        let max_ra = center_ra_deg + half_width_deg;
+
+        if half_width_deg >= 180. {
+            RaIntervals::all()
+        } else if min_ra < 0. {
+            // We need to break our search into two RA chunks:
+            // (0, naive-max) and (wrapped-naive-min, 360)
+            RaIntervals {
+                first: 0. ..=max_ra,
+                second: Some(min_ra + 360. ..=360.),
+            }
+        } else if max_ra > 360. {
+            // Analogous to the previous case
+            RaIntervals {
+                first: min_ra..=360.,
+                second: Some(0. ..=max_ra - 360.),
+            }
+        } else {
+            RaIntervals {
+                first: min_ra..=max_ra,
+                second: None,
+            }
+        }
+    }
+
+    /// Compute the RA intervals covered by a "box" search of the given radius
+    /// around the specified position. If the box reaches a pole, all RAs are
+    /// covered.
+    pub fn for_box(ra_deg: f64, dec_deg: f64, radius_deg: f64) -> Self {
+        let min_dec = dec_deg - radius_deg;
+        let max_dec = dec_deg + radius_deg;
+
+        if min_dec <= -90. || max_dec >= 90. {
+            return RaIntervals::all();
+        }
+
+        let cos_dec = f64::min(f64::cos(min_dec * D2R), f64::cos(max_dec * D2R));
+
+        if cos_dec <= 0. {
+            RaIntervals::all()
+        } else {
+            RaIntervals::around(ra_deg, radius_deg / cos_dec)
+        }
+    }
+
+    /// Iterate over the intervals.
+    pub fn iter(&self) -> impl Iterator<Item = &RangeInclusive<f64>> {
+        std::iter::once(&self.first).chain(self.second.iter())
+    }
+}
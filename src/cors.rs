@@ -0,0 +1,180 @@
+//! Cross-origin resource sharing (CORS).
+//!
+//! Browser-based tools, like the Starglass web UI or a JS9 viewer, can only
+//! call our APIs directly if the responses carry the appropriate CORS headers,
+//! and if the browser's `OPTIONS` "preflight" requests are answered. The
+//! proxy-event server does both, according to the policy here.
+//!
+//! The allowed origins are configured at deploy time. A request whose `Origin`
+//! isn't allowed still gets processed -- CORS is enforced by browsers, not
+//! servers -- but its response won't carry the headers that would let a
+//! browser script read it.
+
+use lambda_http::http::{header, HeaderMap, HeaderValue};
+
+use crate::config::Config;
+
+/// The methods that our APIs accept.
+const ALLOWED_METHODS: &str = "POST, OPTIONS";
+
+#[derive(Debug)]
+pub struct Cors {
+    origins: Vec<String>,
+    allowed_headers: String,
+    max_age_secs: u64,
+}
+
+impl Cors {
+    pub fn new(config: &Config) -> Self {
+        Cors {
+            origins: config.cors_allowed_origins.clone(),
+            allowed_headers: config.cors_allowed_headers.clone(),
+            max_age_secs: config.cors_max_age.as_secs(),
+        }
+    }
+
+    /// Whether any cross-origin requests are allowed.
+    pub fn is_enabled(&self) -> bool {
+        !self.origins.is_empty()
+    }
+
+    /// The value of the `Access-Control-Allow-Origin` header for a request
+    /// with the given request headers, if the request's origin is allowed.
+    fn allow_origin(&self, request_headers: &HeaderMap) -> Option<HeaderValue> {
+        if self.origins.iter().any(|o| o == "*") {
+            return Some(HeaderValue::from_static("*"));
+        }
+
+        let origin = request_headers.get(header::ORIGIN)?;
+        let text = origin.to_str().ok()?;
+
+        if self.origins.iter().any(|o| o.eq_ignore_ascii_case(text)) {
+            Some(origin.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Add the CORS headers for an ordinary response to `response_headers`.
+    pub fn apply(&self, request_headers: &HeaderMap, response_headers: &mut HeaderMap) {
+        // If the allowed origin depends on the request, caches need to know.
+        if self.is_enabled() && !self.origins.iter().any(|o| o == "*") {
+            response_headers.append(header::VARY, HeaderValue::from_static("origin"));
+        }
+
+        if let Some(origin) = self.allow_origin(request_headers) {
+            response_headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+
+            // Let scripts see when rate-limited requests may be retried.
+            response_headers.insert(
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                HeaderValue::from_static("retry-after"),
+            );
+        }
+    }
+
+    /// Add the CORS headers for a response to an `OPTIONS` preflight request
+    /// to `response_headers`.
+    pub fn apply_preflight(&self, request_headers: &HeaderMap, response_headers: &mut HeaderMap) {
+        self.apply(request_headers, response_headers);
+
+        if !response_headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN) {
+            return;
+        }
+
+        response_headers.insert(
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_static(ALLOWED_METHODS),
+        );
+
+        if let Ok(v) = HeaderValue::from_str(&self.allowed_headers) {
+            response_headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, v);
+        }
+
+        response_headers.insert(
+            header::ACCESS_CONTROL_MAX_AGE,
+            HeaderValue::from(self.max_age_secs),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors(origins: &[&str]) -> Cors {
+        Cors::new(&Config {
+            cors_allowed_origins: origins.iter().map(|o| o.to_string()).collect(),
+            ..Default::default()
+        })
+    }
+
+    fn request_from(origin: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ORIGIN, HeaderValue::from_str(origin).unwrap());
+        headers
+    }
+
+    #[test]
+    fn origin_matching() {
+        let cors = cors(&["https://starglass.cfa.harvard.edu"]);
+        let mut response = HeaderMap::new();
+        cors.apply(
+            &request_from("https://Starglass.cfa.harvard.edu"),
+            &mut response,
+        );
+        assert_eq!(
+            response[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://Starglass.cfa.harvard.edu"
+        );
+        assert_eq!(response[header::VARY], "origin");
+
+        let mut response = HeaderMap::new();
+        cors.apply(&request_from("https://evil.example.com"), &mut response);
+        assert!(!response.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert_eq!(response[header::VARY], "origin");
+
+        let mut response = HeaderMap::new();
+        cors.apply(&HeaderMap::new(), &mut response);
+        assert!(!response.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[test]
+    fn wildcard_and_disabled() {
+        let mut response = HeaderMap::new();
+        cors(&["*"]).apply(&request_from("https://any.example.com"), &mut response);
+        assert_eq!(response[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(!response.contains_key(header::VARY));
+
+        let disabled = cors(&[]);
+        assert!(!disabled.is_enabled());
+        let mut response = HeaderMap::new();
+        disabled.apply_preflight(&request_from("https://any.example.com"), &mut response);
+        assert!(response.is_empty());
+    }
+
+    #[test]
+    fn preflight_headers() {
+        let cors = cors(&["https://starglass.cfa.harvard.edu"]);
+        let mut response = HeaderMap::new();
+        cors.apply_preflight(
+            &request_from("https://starglass.cfa.harvard.edu"),
+            &mut response,
+        );
+        assert_eq!(
+            response[header::ACCESS_CONTROL_ALLOW_METHODS],
+            "POST, OPTIONS"
+        );
+        assert_eq!(
+            response[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "content-type, x-api-key"
+        );
+        assert_eq!(response[header::ACCESS_CONTROL_MAX_AGE], "600");
+
+        // A preflight from a disallowed origin gets none of them.
+        let mut response = HeaderMap::new();
+        cors.apply_preflight(&request_from("https://evil.example.com"), &mut response);
+        assert!(!response.contains_key(header::ACCESS_CONTROL_ALLOW_METHODS));
+        assert!(!response.contains_key(header::ACCESS_CONTROL_MAX_AGE));
+    }
+}
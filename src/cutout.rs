@@ -10,30 +10,1002 @@
 //! buffered Lambdas, which means we can operate in the cheaper buffered mode.
 //! The result of a buffered Lambda can only be JSON, so we return a complete
 //! gzipped FITS file as a Base64-encoded string.
+//!
+//! The output image is 835×835 pixels by default, but requests can choose a
+//! different width and height -- for instance, a small thumbnail, a wider
+//! field, or a long thin strip along the trail of an asteroid -- as long as
+//! the total number of pixels stays within our memory budget and the image is
+//! sure to fit in the response. API version 2 requests can also choose the output
+//! pixel scale, and get per-series defaults for all three if they don't; see
+//! `seriesdefaults.rs`.
+//!
+//! Resampling smooths the pixel noise, and blurs the point-spread function a
+//! bit, which matters for some analyses. So API version 2 requests can ask for
+//! `grid: native`, in which case we skip the resampling and return the
+//! rectangle of mosaic pixels around the target, with the plate's own WCS for
+//! the solution, shifted to match the crop. The width and height are then
+//! counted in mosaic pixels. See [`PixelGrid`].
+//!
+//! API version 2 requests can also describe the cutout with an IVOA SODA
+//! `circle` or `polygon`, for VO tools; we make the smallest stamp that
+//! contains the shape. See `soda.rs`.
+//!
+//! Instead of an explicit center, a request can give a short `ephemeris` of a
+//! moving object, in which case the cutout is centered on the object's
+//! position at the midpoint of the exposure matching the requested solution.
+//! See `ephemeris.rs`.
+//!
+//! Output pixels that don't land on the plate are "null". Different analysis
+//! stacks expect these to be represented differently, so the request's
+//! `null_pixels` field chooses between a 16-bit image with a `BLANK` value of
+//! zero (the default), a 32-bit floating-point image with NaNs, or a 16-bit
+//! image plus an 8-bit `MASK` image extension; see [`NullPixels`].
+//!
+//! If the request's `estimate` field is true, we stop once we know what part
+//! of the plate we'd need to read, and return an [`Estimate`] of the work
+//! and the response size instead of the image. This skips the expensive S3
+//! reads and the resampling.
+//!
+//! If the request's `statistics` field is true, the result includes
+//! statistics of the stamp's pixel values and a suggested display stretch,
+//! so that viewers can show it without a pass over the pixels of their own;
+//! see `stampstats.rs`. Like choosing the compression, this makes the result
+//! an object rather than a bare string.
+//!
+//! Web front-ends can set `output_format` to `png` or `jpeg` to get a
+//! stretched 8-bit preview image instead of a FITS file; see `preview.rs`.
+//!
+//! Gzipping the whole file is the traditional encoding, but for the integer
+//! images, a RICE tile-compressed FITS file is usually smaller, and can be
+//! read directly by FITS libraries. The request's `compression` field can ask
+//! for either, or for `auto`, in which case we make both and return whichever
+//! is smaller; see [`OutputCompression`].
+//!
+//! A request can give a list of `solution_numbers` instead of a single
+//! `solution_number`, to get a series of cutouts from the different exposures
+//! of a multi-exposure plate. This is much cheaper than separate requests:
+//! the plate is only looked up once, and the stamps are all read through one
+//! handle to the mosaic, so that they share the data that it has fetched. With
+//! an `ephemeris`, each stamp is centered on the object at the time of its own
+//! exposure.
+//!
+//! Or, with `all_solutions`, a request can get a single FITS file holding
+//! all of the exposures of the plate that cover the target: a null primary
+//! HDU, then an image extension for each such solution, named `SOL<n>`, with
+//! its own header. The stamps are made as for a series, then their HDUs are
+//! copied into the combined file, so the usual encodings all work; see
+//! [`OutputFiles::combine`].
+//!
+//! Similarly, API version 2 requests can list several `centers`, to get a
+//! batch of stamps of one plate and solution at different positions, or several
+//! `plates`, each with its solution number, to get a batch of stamps of one
+//! position from different plates, like the frames of a lightcurve. The
+//! stamps are made one after another, sharing the cached plate records and
+//! mosaic handles. Unlike a series, a stamp of a batch that fails -- say,
+//! because its plate doesn't cover the position -- reports its error in place,
+//! without failing the others. See [`Request::into_batch`]. The `centers` can
+//! also be given as a `target_list`, the `upload_id` of a list uploaded with
+//! the `targetlist` service, for batches too big to list inline.
+//!
+//! If the request's `stage_results` field is true, each stamp is uploaded to
+//! its own object in the results bucket as soon as it's made, rather than
+//! returned, so that a batch isn't limited by the size of a Lambda response,
+//! and the stamps that were finished survive a batch that runs out of time.
+//! When the request is done, a manifest listing each stamp, with its download
+//! URL or its error, is uploaded next to the stamps and returned. See
+//! `staging.rs`.
+//!
+//! The output header records the pixel units (`BUNIT`), the plate, solution,
+//! and mosaic that the cutout came from, and the mosaic statistics from the
+//! plates table, along with a `COMMENT` explaining what the pixel values mean.
+//!
+//! Successful cutouts can be logged to a provenance table, so that they can be
+//! attributed and re-created later; see `provenance.rs`.
 
 use aws_sdk_dynamodb::types::AttributeValue;
-use base64::{engine::general_purpose::STANDARD, write::EncoderWriter};
+use base64::{engine::general_purpose::STANDARD, write::EncoderWriter, Engine as _};
 use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use lambda_http::Error;
-use ndarray::{s, Array, Axis, Ix2};
+use lambda_runtime::tracing;
+use ndarray::{s, Array, Axis, Ix1, Ix2};
 use ndarray_interp::interp2d;
-use serde::Deserialize;
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{collections::HashSet, fmt, os::raw::c_int, pin::Pin, sync::Arc, time::Instant};
 
 use crate::{
+    apiversion::{self, default_api_version},
+    backend::{ObjectStore, TableStore},
+    bufpool::BufferPool,
+    config::{default_data_release, Config},
+    deadline::Deadline,
+    denylist::{DenyList, DenyMode},
+    envelope::DetailedError,
+    ephemeris::{interpolate, validate_ephemeris, EphemerisPoint},
+    features::{Feature, FeatureOverrides, Features},
     fitsfile::FitsFile,
-    mosaics::{load_b01_header, wcslib_solnum},
-    BUCKET,
+    fitspool::FitsPool,
+    mosaics::{load_b01_header, wcslib_solnum, PlaceholderPolicy, PlateId},
+    platecache::PlateCache,
+    preview::{self, OutputFormat},
+    provenance::{self, CutoutSummary},
+    seriesdefaults::SeriesDefaults,
+    soda::Shape,
+    staging::{self, Stager},
+    stampstats::{self, ImageStatistics},
+    targetlists, targets,
+    timeutil::UtcTime,
+    validation::{self, validate_fields},
+    warnings,
+    wcs::{HeaderOptions, WcsCollection, WcsRelax},
 };
 
 /// Sync with `json-schemas/cutout_request.json`, which then needs to be
 /// synced into S3.
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct Request {
-    plate_id: String,
+    /// The plate to make the cutout from. Required, unless the request lists
+    /// `plates`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    plate_id: Option<PlateId>,
+    /// The solution to make the cutout from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    solution_number: Option<usize>,
+    /// Instead of `solution_number`, several solutions to make a series of
+    /// cutouts from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    solution_numbers: Option<Vec<usize>>,
+    /// Instead of choosing solutions, make one FITS file with an image
+    /// extension for each solution that overlaps the target.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    all_solutions: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    center_ra_deg: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    center_dec_deg: Option<f64>,
+    /// The name of the target to center on, resolved into `center_ra_deg` and
+    /// `center_dec_deg` before the request is parsed; see `targets.rs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target_name: Option<String>,
+    /// Instead of a center and size, the text of a SODA `CIRCLE` to make the
+    /// cutout of, which normalization converts into them; see `soda.rs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    circle: Option<String>,
+    /// Likewise, the text of a SODA `POLYGON`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    polygon: Option<String>,
+    /// The ephemeris of a moving object to center on, instead of a fixed
+    /// position.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ephemeris: Option<Vec<EphemerisPoint>>,
+    /// Instead of a single center, several centers to make a batch of cutouts
+    /// of, all from the same plate and solution.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    centers: Option<Vec<BatchCenter>>,
+    /// Instead of `centers`, the `upload_id` of a target list giving them;
+    /// see `targetlists.rs`. It's read before the batch is split up.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target_list: Option<String>,
+    /// Instead of `plate_id` and `solution_number`, several plates and
+    /// solutions to make a batch of cutouts from, all at the same position.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    plates: Option<Vec<BatchPlate>>,
+    /// The data release to query.
+    #[serde(default = "default_data_release")]
+    data_release: String,
+    /// How to handle deny-listed plates.
+    #[serde(default)]
+    deny_list: DenyMode,
+    /// How to represent pixels that don't land on the plate.
+    #[serde(default)]
+    null_pixels: NullPixels,
+    /// The width of the output image, in pixels. Filled in by normalization.
+    #[serde(default)]
+    width_pixels: Option<usize>,
+    /// The height of the output image, in pixels. Filled in by
+    /// normalization.
+    #[serde(default)]
+    height_pixels: Option<usize>,
+    /// The output pixel scale, in arcseconds per pixel, if not the standard
+    /// one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pixel_scale_arcsec: Option<f64>,
+    /// Whether to resample the plate, or copy its native pixels.
+    #[serde(default, skip_serializing_if = "PixelGrid::is_default")]
+    grid: PixelGrid,
+    /// If true, estimate the cost of the cutout instead of making it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    estimate: bool,
+    /// If true, upload the stamp to S3 as soon as it's made, instead of
+    /// returning it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    stage_results: bool,
+    /// How strictly to parse the plate's WCS header.
+    #[serde(default, skip_serializing_if = "WcsRelax::is_default")]
+    wcs_relax: WcsRelax,
+    /// If true, record the WCS header keywords that wcslib rejected, and the
+    /// fixes that it applied, in the output's `HISTORY`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    wcs_report: bool,
+    /// Overrides of the deployment's feature flags; see `features.rs`.
+    #[serde(default, skip_serializing_if = "FeatureOverrides::is_empty")]
+    features: FeatureOverrides,
+    /// How to compress the output file.
+    #[serde(default, skip_serializing_if = "OutputCompression::is_default")]
+    compression: OutputCompression,
+    /// Whether to return a FITS file, or a preview image; see `preview.rs`.
+    #[serde(default, skip_serializing_if = "OutputFormat::is_default")]
+    output_format: OutputFormat,
+    /// If true, return statistics of the pixel values and display hints along
+    /// with the image; see `stampstats.rs`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    statistics: bool,
+    /// The version of the response format to use; see `apiversion.rs`.
+    #[serde(
+        default = "default_api_version",
+        skip_serializing_if = "apiversion::is_current"
+    )]
+    api_version: u32,
+    /// The placeholder positions of the plate's series, set by
+    /// [`Request::with_series_defaults`].
+    #[serde(skip)]
+    placeholders: PlaceholderPolicy,
+}
+
+/// How a cutout represents pixels that don't land on the plate.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NullPixels {
+    /// 16-bit integer pixels, with null pixels set to zero, which is declared
+    /// as the `BLANK` value. Real pixels that happen to be zero can't be
+    /// distinguished from null ones.
+    #[default]
+    Blank,
+
+    /// 32-bit floating-point pixels, with null pixels set to NaN.
+    Nan,
+
+    /// 16-bit integer pixels, with null pixels set to zero, plus an 8-bit
+    /// image extension named `MASK` that is 1 for null pixels and 0 otherwise.
+    Mask,
+}
+
+/// How a cutout's FITS file is compressed.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputCompression {
+    /// Gzip the whole file. This is the traditional format, and the only one
+    /// available in API version 1.
+    #[default]
+    Gzip,
+
+    /// Write the images as RICE tile-compressed image extensions, which FITS
+    /// libraries decompress transparently, after a null primary array. Only
+    /// available for integer pixels.
+    Rice,
+
+    /// Make both of the above and return the smaller one. Floating-point
+    /// images are always gzipped.
+    Auto,
+}
+
+impl OutputCompression {
+    fn is_default(&self) -> bool {
+        *self == OutputCompression::Gzip
+    }
+}
+
+/// The pixel grid of a cutout.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PixelGrid {
+    /// Resample the plate onto a TAN grid centered on the target, with north
+    /// up.
+    #[default]
+    Tan,
+
+    /// Copy the mosaic pixels around the target without resampling, with the
+    /// WCS of the astrometric solution. The output width and height are in
+    /// mosaic pixels.
+    Native,
+}
+
+impl PixelGrid {
+    fn is_default(&self) -> bool {
+        *self == PixelGrid::Tan
+    }
+}
+
+/// One center of a batch request.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct BatchCenter {
+    ra_deg: f64,
+    dec_deg: f64,
+}
+
+/// One plate and solution of a batch request.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct BatchPlate {
+    plate_id: PlateId,
     solution_number: usize,
-    center_ra_deg: f64,
-    center_dec_deg: f64,
+}
+
+/// The result of a cutout request.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum Response {
+    /// The gzipped FITS image, as a Base64-encoded string.
+    Image(String),
+
+    /// The FITS image and how it was encoded, for requests that choose the
+    /// compression.
+    Encoded(EncodedImage),
+
+    /// An estimate of what making the image would involve.
+    Estimate(Estimate),
+
+    /// The stamps of a series, in the order requested.
+    Series(Vec<SeriesStamp>),
+
+    /// The stamps of a batch, in the order requested.
+    Batch(Vec<BatchStamp>),
+
+    /// The manifest of stamps that were uploaded to S3.
+    Staged(StagedBatch),
+}
+
+impl Response {
+    /// Convert a bare image into an [`EncodedImage`], which says how it's
+    /// encoded.
+    fn into_encoded(self) -> Response {
+        match self {
+            Response::Image(image) => Response::Encoded(EncodedImage {
+                image,
+                encoding: "fits+gzip+base64",
+                statistics: None,
+            }),
+            other => other,
+        }
+    }
+}
+
+/// One stamp of a cutout series.
+#[derive(Serialize)]
+pub struct SeriesStamp {
+    /// The solution that the stamp was made from.
+    pub solution_number: usize,
+
+    /// The encoded image, or the estimate of what making it would involve.
+    #[serde(flatten)]
+    pub stamp: Response,
+}
+
+/// One stamp of a cutout batch. Items that fail have an `error` instead of
+/// an image, without failing the rest of the batch.
+#[derive(Serialize)]
+pub struct BatchStamp {
+    /// The plate that the stamp was made from.
+    pub plate_id: String,
+
+    /// The solution that the stamp was made from.
+    pub solution_number: usize,
+
+    /// The requested center of the stamp, unless it followed an ephemeris.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub center_ra_deg: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub center_dec_deg: Option<f64>,
+
+    /// The encoded image, or the estimate of what making it would involve.
+    #[serde(flatten)]
+    pub stamp: Option<Response>,
+
+    /// Why the stamp couldn't be made.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+
+    /// In a staged request, where the stamp was uploaded.
+    #[serde(flatten)]
+    pub staged: Option<StagedStamp>,
+}
+
+/// An encoded FITS image.
+#[derive(Serialize)]
+pub struct EncodedImage {
+    /// The encoded FITS file.
+    pub image: String,
+
+    /// The encoding: `fits+gzip+base64` for a gzipped file, or
+    /// `fits+rice+base64` for a tile-compressed one.
+    pub encoding: &'static str,
+
+    /// Statistics of the pixel values, if requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub statistics: Option<ImageStatistics>,
+}
+
+/// A stamp of a staged request, uploaded to S3.
+#[derive(Serialize)]
+pub struct StagedStamp {
+    /// A URL from which the stamp can be downloaded, without credentials,
+    /// for a limited time.
+    pub url: String,
+
+    /// The encoding of the stamp, as for an inline one, but without the
+    /// Base64 layer: `fits+gzip`, `fits+rice`, `png`, or `jpeg`.
+    pub encoding: &'static str,
+
+    /// Statistics of the pixel values, if requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub statistics: Option<ImageStatistics>,
+}
+
+/// The result of a staged request.
+#[derive(Serialize)]
+pub struct StagedBatch {
+    /// A URL from which this manifest, without itself, can be downloaded.
+    pub manifest_url: String,
+
+    /// The stamps of the request, in the order requested, with the URLs of
+    /// the ones that were made.
+    pub stamps: Vec<BatchStamp>,
+}
+
+/// The result of an estimate-mode request.
+#[derive(Serialize)]
+pub struct Estimate {
+    /// The width of the region of the plate mosaic that would be read, in
+    /// pixels.
+    pub source_width: usize,
+
+    /// The height of the region of the plate mosaic that would be read, in
+    /// pixels.
+    pub source_height: usize,
+
+    /// The uncompressed size of the source region, in bytes.
+    pub source_bytes: usize,
+
+    /// The fraction of the output image that lands on the plate.
+    pub coverage: f64,
+
+    /// The width of the output image, in pixels.
+    pub output_width: usize,
+
+    /// The height of the output image, in pixels.
+    pub output_height: usize,
+
+    /// An upper bound on the size of the encoded output image, in bytes.
+    pub max_response_bytes: usize,
+
+    /// The memory reserved for the cutout on the server, in MiB.
+    pub memory_cost_mib: u32,
+}
+
+impl Request {
+    /// Fill in the output parameters that the request leaves out with the
+    /// defaults for its plate's series, if it has any; see
+    /// `seriesdefaults.rs`. This should be done before normalization. Only API
+    /// version 2 requests get the series defaults, and native-pixel cutouts
+    /// don't, since they're chosen for the resampled grid. All requests get
+    /// the series' placeholder positions.
+    pub fn with_series_defaults(mut self, defaults: &SeriesDefaults) -> Self {
+        let series = self.plate_id.as_ref().map_or("", PlateId::series);
+        self.placeholders = defaults.placeholders().get(series).clone();
+
+        if self.api_version < 2 || self.grid == PixelGrid::Native {
+            return self;
+        }
+
+        if let Some(d) = defaults.cutout(series) {
+            self.pixel_scale_arcsec = self.pixel_scale_arcsec.or(d.pixel_scale_arcsec);
+
+            // The size of a stamp of a SODA shape is set by the shape.
+            if self.circle.is_none() && self.polygon.is_none() {
+                self.width_pixels = self.width_pixels.or(d.width_pixels);
+                self.height_pixels = self.height_pixels.or(d.height_pixels);
+            }
+        }
+
+        self
+    }
+
+    /// Validate the request and normalize its coordinates.
+    pub fn normalize(mut self, config: &Config) -> Result<Self, Error> {
+        config.check_release(&self.data_release)?;
+
+        if self.is_batch() {
+            return Err(
+                "batch requests must be split up with `into_batch` before normalization".into(),
+            );
+        }
+
+        if self.plate_id.is_none() {
+            return Err("must specify `plate_id`".into());
+        }
+
+        validate_fields!(self {
+            api_version: apiversion::validate,
+            width_pixels: validation::optional(|n, v| validation::range(
+                n,
+                v,
+                1..=MAX_OUTPUT_DIMENSION
+            )),
+            height_pixels: validation::optional(|n, v| validation::range(
+                n,
+                v,
+                1..=MAX_OUTPUT_DIMENSION
+            )),
+            pixel_scale_arcsec: validation::optional(|n, v| validation::range(
+                n,
+                v,
+                MIN_PIXEL_SCALE_ARCSEC..=MAX_PIXEL_SCALE_ARCSEC
+            )),
+            center_ra_deg: validation::optional(validation::ra),
+            center_dec_deg: validation::optional(validation::dec),
+        });
+
+        self.apply_shape()?;
+        self.width_pixels.get_or_insert(OUTPUT_IMAGE_FULLSIZE);
+        self.height_pixels.get_or_insert(OUTPUT_IMAGE_FULLSIZE);
+
+        if self.pixel_scale_arcsec.is_some() {
+            apiversion::require(self.api_version, 2, "`pixel_scale_arcsec`")?;
+        }
+
+        if self.estimate {
+            apiversion::require(self.api_version, 2, "`estimate`")?;
+        }
+
+        if self.target_name.is_some() {
+            apiversion::require(self.api_version, 2, "`target_name`")?;
+        }
+
+        if self.stage_results {
+            apiversion::require(self.api_version, 2, "`stage_results`")?;
+
+            if self.estimate {
+                return Err("`stage_results` can't be combined with `estimate`".into());
+            }
+        }
+
+        if !self.compression.is_default() {
+            apiversion::require(self.api_version, 2, "`compression`")?;
+        }
+
+        if self.statistics {
+            apiversion::require(self.api_version, 2, "`statistics`")?;
+        }
+
+        if !self.output_format.is_default() {
+            apiversion::require(self.api_version, 2, "`output_format`")?;
+
+            if !self.compression.is_default() || self.all_solutions {
+                return Err(
+                    "`compression` and `all_solutions` only apply to `output_format: fits`".into(),
+                );
+            }
+        }
+
+        if self.all_solutions {
+            apiversion::require(self.api_version, 2, "`all_solutions`")?;
+
+            if self.solution_number.is_some() || self.solution_numbers.is_some() {
+                return Err(
+                    "`all_solutions` can't be combined with `solution_number` or `solution_numbers`"
+                        .into(),
+                );
+            }
+
+            if self.statistics {
+                return Err(
+                    "`statistics` can't be used with `all_solutions`; ask for the solutions separately"
+                        .into(),
+                );
+            }
+
+            if self.stage_results {
+                return Err("`stage_results` can't be combined with `all_solutions`".into());
+            }
+        }
+
+        if self.grid == PixelGrid::Native {
+            apiversion::require(self.api_version, 2, "`grid`")?;
+
+            if self.pixel_scale_arcsec.is_some() {
+                return Err(
+                    "`pixel_scale_arcsec` can't be used with `grid: native`, whose pixels are those of the mosaic"
+                        .into(),
+                );
+            }
+        }
+
+        if self.compression == OutputCompression::Rice && self.null_pixels == NullPixels::Nan {
+            return Err(
+                "`compression: rice` requires integer pixels, so it can't be used with `null_pixels: nan`"
+                    .into(),
+            );
+        }
+
+        let (width, height) = self.size();
+        let npix = width * height;
+
+        if npix > MAX_OUTPUT_NPIX {
+            return Err(format!(
+                "requested output image is {}×{} = {} pixels, but at most {} are allowed",
+                width, height, npix, MAX_OUTPUT_NPIX
+            )
+            .into());
+        }
+
+        let n_stamps = self.solution_numbers.as_ref().map_or(1, Vec::len);
+        let response_bytes = n_stamps * self.max_response_bytes(npix);
+
+        if response_bytes > MAX_RESPONSE_BYTES {
+            return Err(format!(
+                "requested output could take up to {response_bytes} bytes, but responses are limited to {MAX_RESPONSE_BYTES}; ask for fewer pixels, or for `null_pixels: blank`"
+            )
+            .into());
+        }
+
+        match (self.solution_number, &self.solution_numbers) {
+            (Some(_), None) => {}
+            (None, None) if self.all_solutions => {}
+
+            (None, Some(nums)) => {
+                apiversion::require(self.api_version, 2, "`solution_numbers`")?;
+
+                if self.stage_results {
+                    return Err("`stage_results` can't be combined with `solution_numbers`".into());
+                }
+
+                if nums.is_empty() || nums.len() > MAX_SERIES_LENGTH {
+                    return Err(format!(
+                        "illegal `solution_numbers` parameter: must list between 1 and {MAX_SERIES_LENGTH} solutions; got {}",
+                        nums.len()
+                    )
+                    .into());
+                }
+
+                let mut seen = HashSet::new();
+
+                if let Some(dup) = nums.iter().find(|n| !seen.insert(**n)) {
+                    return Err(format!(
+                        "illegal `solution_numbers` parameter: solution {dup} is listed more than once"
+                    )
+                    .into());
+                }
+
+                if npix * nums.len() > MAX_SERIES_NPIX {
+                    return Err(format!(
+                        "requested series is {} images of {} pixels, but at most {} pixels are allowed in total",
+                        nums.len(),
+                        npix,
+                        MAX_SERIES_NPIX
+                    )
+                    .into());
+                }
+            }
+
+            _ => {
+                return Err(
+                    "must specify exactly one of `solution_number`, `solution_numbers`, and `all_solutions`".into(),
+                )
+            }
+        }
+
+        match (self.center_ra_deg, self.center_dec_deg, self.ephemeris) {
+            (Some(ra), Some(dec), None) => Ok(Request {
+                center_ra_deg: Some(ra),
+                center_dec_deg: Some(dec),
+                ephemeris: None,
+                ..self
+            }),
+
+            (None, None, Some(eph)) => Ok(Request {
+                ephemeris: Some(validate_ephemeris("ephemeris", eph)?),
+                ..self
+            }),
+
+            _ => Err(
+                "must specify either both `center_ra_deg` and `center_dec_deg`, or `ephemeris`"
+                    .into(),
+            ),
+        }
+    }
+}
+
+impl Request {
+    /// Whether this is a batch request, listing several `centers` or
+    /// `plates`, or giving a `target_list`.
+    pub fn is_batch(&self) -> bool {
+        self.centers.is_some() || self.plates.is_some() || self.target_list.is_some()
+    }
+
+    /// If the request gives a `target_list`, read it, and fill in `centers`
+    /// from its targets.
+    pub async fn load_target_list(
+        &mut self,
+        config: &Config,
+        objects: &dyn ObjectStore,
+    ) -> Result<(), Error> {
+        let Some(upload_id) = &self.target_list else {
+            return Ok(());
+        };
+
+        if self.centers.is_some() {
+            return Err("`target_list` can't be combined with `centers`".into());
+        }
+
+        let targets = targetlists::load(objects, config, upload_id).await?;
+        self.centers = Some(
+            targets
+                .into_iter()
+                .map(|t| BatchCenter {
+                    ra_deg: t.ra_deg,
+                    dec_deg: t.dec_deg,
+                })
+                .collect(),
+        );
+        Ok(())
+    }
+
+    /// Split a batch request into the requests for its stamps, each with its
+    /// series defaults applied and normalized. Problems with the parameters
+    /// of the batch or any of its stamps are errors, but problems with the
+    /// plates themselves are only found when the stamps are made; see
+    /// [`implementation_batch`].
+    pub fn into_batch(
+        mut self,
+        config: &Config,
+        defaults: &SeriesDefaults,
+    ) -> Result<Vec<Request>, Error> {
+        let centers = self.centers.take();
+        let plates = self.plates.take();
+        let staged = std::mem::take(&mut self.stage_results);
+        let centers_field = match self.target_list.take() {
+            Some(_) => "target_list",
+            None => "centers",
+        };
+
+        let (field, n_items) = match (&centers, &plates) {
+            (Some(c), None) => (centers_field, c.len()),
+            (None, Some(p)) => ("plates", p.len()),
+            _ => return Err("must specify at most one of `centers` and `plates`".into()),
+        };
+
+        apiversion::require(self.api_version, 2, &format!("`{field}`"))?;
+
+        if n_items == 0 || n_items > MAX_BATCH_LENGTH {
+            return Err(format!(
+                "illegal `{field}` parameter: must list between 1 and {MAX_BATCH_LENGTH} items; got {n_items}"
+            )
+            .into());
+        }
+
+        if self.solution_numbers.is_some() {
+            return Err("`solution_numbers` can't be used in a batch request".into());
+        }
+
+        if staged && self.estimate {
+            return Err("`stage_results` can't be combined with `estimate`".into());
+        }
+
+        if self.all_solutions {
+            return Err("`all_solutions` can't be used in a batch request".into());
+        }
+
+        let items: Vec<Request> = match (centers, plates) {
+            (Some(centers), _) => {
+                if self.center_ra_deg.is_some()
+                    || self.center_dec_deg.is_some()
+                    || self.ephemeris.is_some()
+                    || self.circle.is_some()
+                    || self.polygon.is_some()
+                {
+                    return Err(format!(
+                        "`{field}` can't be combined with `center_ra_deg`, `center_dec_deg`, `target_name`, `ephemeris`, `circle`, or `polygon`"
+                    )
+                    .into());
+                }
+
+                centers
+                    .into_iter()
+                    .map(|c| Request {
+                        center_ra_deg: Some(c.ra_deg),
+                        center_dec_deg: Some(c.dec_deg),
+                        ..self.clone()
+                    })
+                    .collect()
+            }
+
+            (_, Some(plates)) => {
+                if self.plate_id.is_some() || self.solution_number.is_some() {
+                    return Err(
+                        "`plates` can't be combined with `plate_id` or `solution_number`".into(),
+                    );
+                }
+
+                plates
+                    .into_iter()
+                    .map(|p| Request {
+                        plate_id: Some(p.plate_id),
+                        solution_number: Some(p.solution_number),
+                        ..self.clone()
+                    })
+                    .collect()
+            }
+
+            _ => unreachable!(),
+        };
+
+        let items = items
+            .into_iter()
+            .enumerate()
+            .map(|(i, item)| {
+                item.with_series_defaults(defaults)
+                    .normalize(config)
+                    .map_err(|e| -> Error { format!("`{field}` item {i}: {e}").into() })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if staged {
+            return Ok(items);
+        }
+
+        let response_bytes: usize = items
+            .iter()
+            .map(|item| {
+                let (width, height) = item.size();
+                item.max_response_bytes(width * height)
+            })
+            .sum();
+
+        if response_bytes > MAX_RESPONSE_BYTES {
+            return Err(format!(
+                "requested batch could take up to {response_bytes} bytes, but responses are limited to {MAX_RESPONSE_BYTES}; ask for fewer or smaller stamps"
+            )
+            .into());
+        }
+
+        Ok(items)
+    }
+}
+
+impl Request {
+    /// If the request gives a SODA shape, replace it with the center and size
+    /// of the stamp that contains it; see `soda.rs`. This must be done after
+    /// the pixel scale is validated.
+    fn apply_shape(&mut self) -> Result<(), Error> {
+        let (field, shape) = match (self.circle.take(), self.polygon.take()) {
+            (None, None) => return Ok(()),
+            (Some(text), None) => ("circle", Shape::circle("circle", &text)?),
+            (None, Some(text)) => ("polygon", Shape::polygon("polygon", &text)?),
+            (Some(_), Some(_)) => {
+                return Err("must specify at most one of `circle` and `polygon`".into())
+            }
+        };
+
+        apiversion::require(self.api_version, 2, &format!("`{field}`"))?;
+
+        if self.center_ra_deg.is_some()
+            || self.center_dec_deg.is_some()
+            || self.ephemeris.is_some()
+            || self.width_pixels.is_some()
+            || self.height_pixels.is_some()
+        {
+            return Err(format!(
+                "`{field}` can't be combined with `center_ra_deg`, `center_dec_deg`, `target_name`, `ephemeris`, `width_pixels`, or `height_pixels`"
+            )
+            .into());
+        }
+
+        if self.grid == PixelGrid::Native {
+            return Err(format!(
+                "`{field}` can't be used with `grid: native`, whose pixel scale depends on the plate"
+            )
+            .into());
+        }
+
+        let region = shape.region(field)?;
+        let scale = self.pixel_scale_deg();
+        let pixels = |half_size: f64| usize::max((2. * half_size / scale).ceil() as usize, 1);
+        let width = pixels(region.half_width_deg);
+        let height = pixels(region.half_height_deg);
+
+        if usize::max(width, height) > MAX_OUTPUT_DIMENSION {
+            return Err(format!(
+                "the `{field}` region is {width}×{height} pixels at {:.2}″ per pixel, but at most {MAX_OUTPUT_DIMENSION} are allowed on a side; ask for a smaller region or a coarser `pixel_scale_arcsec`",
+                3600. * scale
+            )
+            .into());
+        }
+
+        self.center_ra_deg = Some(region.center_ra_deg);
+        self.center_dec_deg = Some(region.center_dec_deg);
+        self.width_pixels = Some(width);
+        self.height_pixels = Some(height);
+        Ok(())
+    }
+
+    /// The plate to make the cutout from. Only valid after normalization.
+    fn plate_id(&self) -> &PlateId {
+        self.plate_id
+            .as_ref()
+            .expect("normalized cutout requests have a plate_id")
+    }
+
+    /// The width and height of the output image, in pixels. Only valid after
+    /// normalization.
+    fn size(&self) -> (usize, usize) {
+        (
+            self.width_pixels.unwrap_or(OUTPUT_IMAGE_FULLSIZE),
+            self.height_pixels.unwrap_or(OUTPUT_IMAGE_FULLSIZE),
+        )
+    }
+
+    /// The most bytes that the encoded image of a stamp with `npix` pixels can
+    /// take up in the response. The FITS output is one header block and the
+    /// pixel data, padded to whole blocks, possibly followed by the mask HDU.
+    /// In the worst case gzip doesn't help, and then Base64 expands it by a
+    /// third. Preview images are bounded in `preview.rs`.
+    fn max_response_bytes(&self, npix: usize) -> usize {
+        if !self.output_format.is_default() {
+            return 4 * preview::max_bytes(npix).div_ceil(3);
+        }
+
+        let hdu_bytes =
+            |bytes_per_pixel: usize| 2880 + (bytes_per_pixel * npix).div_ceil(2880) * 2880;
+
+        let mut fits_bytes = match self.null_pixels {
+            NullPixels::Blank => hdu_bytes(2),
+            NullPixels::Nan => hdu_bytes(4),
+            NullPixels::Mask => hdu_bytes(2) + hdu_bytes(1),
+        };
+
+        // A RICE-compressed file adds a null primary HDU. Incompressible tiles
+        // are stored raw, so the pixel data can't grow.
+        if self.compression == OutputCompression::Rice {
+            fits_bytes += 2880;
+        }
+
+        4 * fits_bytes.div_ceil(3)
+    }
+
+    /// The output pixel scale, in degrees per pixel.
+    fn pixel_scale_deg(&self) -> f64 {
+        self.pixel_scale_arcsec
+            .map_or(OUTPUT_IMAGE_PIXSCALE, |a| a / 3600.)
+    }
+
+    /// The solutions to make cutouts from, in order, for a plate with
+    /// `n_solutions` of them.
+    fn solutions(&self, n_solutions: usize) -> Vec<usize> {
+        match &self.solution_numbers {
+            _ if self.all_solutions => (0..n_solutions).collect(),
+            Some(nums) => nums.clone(),
+            None => self.solution_number.into_iter().collect(),
+        }
+    }
+
+    /// Combine the per-solution results into the response: the only one for
+    /// a plain request, or a series. The estimates of an `all_solutions`
+    /// request are a series, too.
+    fn collect(&self, mut results: Vec<(usize, Response)>) -> Response {
+        if self.solution_numbers.is_none() && !self.all_solutions {
+            return results.pop().unwrap().1;
+        }
+
+        Response::Series(
+            results
+                .into_iter()
+                .map(|(solution_number, stamp)| SeriesStamp {
+                    solution_number,
+                    stamp: stamp.into_encoded(),
+                })
+                .collect(),
+        )
+    }
 }
 
 #[derive(Deserialize)]
@@ -50,6 +1022,17 @@ struct PlatesAstrometryResult {
     b01_header_gz: Vec<u8>,
     n_solutions: usize,
     rotation_delta: isize,
+    #[serde(default)]
+    exposures: Vec<Option<PlatesExposureResult>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlatesExposureResult {
+    number: Option<i8>,
+    midpoint_date: Option<String>,
+    ra_deg: Option<f64>,
+    dec_deg: Option<f64>,
 }
 
 #[derive(Deserialize)]
@@ -58,21 +1041,348 @@ struct PlatesMosaicResult {
     b01_height: usize,
     b01_width: usize,
     s3_key_template: String,
+    creation_date: Option<String>,
+    mos_num: Option<i8>,
+    scan_num: Option<i8>,
+    background_level: Option<f64>,
+    saturation_fraction: Option<f64>,
 }
 
+/// The attributes that we need from the plates table.
+const PLATES_PROJECTION: &str = "astrometry.b01HeaderGz,\
+    astrometry.nSolutions,\
+    astrometry.rotationDelta,\
+    astrometry.exposures,\
+    mosaic.b01Height,\
+    mosaic.b01Width,\
+    mosaic.s3KeyTemplate,\
+    mosaic.creationDate,\
+    mosaic.mosNum,\
+    mosaic.scanNum,\
+    mosaic.backgroundLevel,\
+    mosaic.saturationFraction";
+
 const OUTPUT_IMAGE_HALFSIZE: usize = 417;
-const OUTPUT_IMAGE_FULLSIZE: usize = 2 * OUTPUT_IMAGE_HALFSIZE + 1;
-const OUTPUT_IMAGE_NPIX: usize = OUTPUT_IMAGE_FULLSIZE * OUTPUT_IMAGE_FULLSIZE;
-const OUTPUT_IMAGE_PIXSCALE: f64 = 0.0004; // deg/pix
-
-pub async fn handler(req: Option<Value>, dc: &aws_sdk_dynamodb::Client) -> Result<Value, Error> {
-    Ok(serde_json::to_value(
-        implementation(
-            serde_json::from_value(req.ok_or_else(|| -> Error { "no request payload".into() })?)?,
-            dc,
+pub const OUTPUT_IMAGE_FULLSIZE: usize = 2 * OUTPUT_IMAGE_HALFSIZE + 1;
+pub const OUTPUT_IMAGE_PIXSCALE: f64 = 0.0004; // deg/pix
+
+/// The largest allowed number of output pixels, twice that of the default
+/// square image, which bounds our memory usage. Depending on the pixel format,
+/// the response size limit may be reached first; see
+/// [`Request::max_response_bytes`].
+pub const MAX_OUTPUT_NPIX: usize = 2 * OUTPUT_IMAGE_FULLSIZE * OUTPUT_IMAGE_FULLSIZE;
+
+/// The most bytes of image data that a response may hold, leaving room for the
+/// envelope within the 6 MiB limit on buffered Lambda responses.
+pub const MAX_RESPONSE_BYTES: usize = 6 * 1024 * 1024 - 64 * 1024;
+
+/// The largest allowed output width or height.
+pub const MAX_OUTPUT_DIMENSION: usize = 4 * OUTPUT_IMAGE_FULLSIZE;
+
+/// The range of output pixel scales that requests can choose, in arcseconds
+/// per pixel. The standard scale is 1.44.
+pub const MIN_PIXEL_SCALE_ARCSEC: f64 = 0.1;
+pub const MAX_PIXEL_SCALE_ARCSEC: f64 = 10.;
+
+/// The most mosaic pixels that a stamp can read. Coarse output pixel scales
+/// can otherwise cover huge areas of fine-grained plates.
+pub const MAX_SOURCE_NPIX: usize = 4096 * 4096;
+
+/// The most solutions that a series can request.
+pub const MAX_SERIES_LENGTH: usize = 16;
+
+/// The most stamps that a batch can request. The response size limit usually
+/// bites first.
+pub const MAX_BATCH_LENGTH: usize = 256;
+
+/// The most output pixels that a series can make, across all of its stamps.
+/// The stamps are all held in memory at once, and returned in one response,
+/// so this is the budget of a single cutout.
+pub const MAX_SERIES_NPIX: usize = MAX_OUTPUT_NPIX;
+
+/// A rough upper bound on the memory needed to make one cutout, in MiB, for
+/// admission control. The main consumers are the world and pixel coordinate
+/// arrays (16 bytes per output pixel apiece) and the interpolation buffers.
+pub const MEMORY_COST_MIB: u32 = 96;
+
+#[allow(clippy::too_many_arguments)]
+pub async fn handler(
+    req: Option<Value>,
+    config: &Config,
+    tables: &dyn TableStore,
+    objects: &dyn ObjectStore,
+    plates: &PlateCache,
+    buffers: &BufferPool,
+    fits_pool: &Arc<FitsPool>,
+    deny_list: &DenyList,
+    series_defaults: &SeriesDefaults,
+    identity: &str,
+    deadline: Deadline,
+) -> Result<Value, Error> {
+    let started = Instant::now();
+    let mut req = req.ok_or_else(|| -> Error { "no request payload".into() })?;
+    targets::resolve_position(&mut req, config, "center_ra_deg", "center_dec_deg").await?;
+    let mut request: Request = serde_json::from_value(req)?;
+
+    if request.is_batch() {
+        let echo = serde_json::to_value(&request)?;
+        request.load_target_list(config, objects).await?;
+        let estimate = request.estimate;
+        let data_release = request.data_release.clone();
+        let staging = request
+            .stage_results
+            .then(|| format!("{}cutout/{}/", config.results_prefix, staging::new_name()));
+        let items = request.into_batch(config, series_defaults)?;
+        let stamps = implementation_batch(
+            items,
+            config,
+            tables,
+            objects,
+            plates,
+            buffers,
+            fits_pool,
+            deny_list,
+            staging.as_deref(),
+            deadline,
+        )
+        .await;
+
+        if !estimate {
+            for stamp in stamps.iter().filter(|s| s.error.is_none()) {
+                let summary = CutoutSummary {
+                    plate_id: &stamp.plate_id,
+                    solution_number: stamp.solution_number,
+                    center_deg: stamp.center_ra_deg.zip(stamp.center_dec_deg),
+                    data_release: &data_release,
+                };
+                provenance::record_cutout(
+                    config,
+                    tables,
+                    identity,
+                    summary,
+                    &echo,
+                    started.elapsed(),
+                )
+                .await;
+            }
+        }
+
+        let response = match staging {
+            Some(prefix) => {
+                Response::Staged(stage_manifest(stamps, &prefix, config, objects).await?)
+            }
+            None => Response::Batch(stamps),
+        };
+
+        return apiversion::respond(2, "cutout", &echo, response);
+    }
+
+    let request = request
+        .with_series_defaults(series_defaults)
+        .normalize(config)?;
+    let echo = serde_json::to_value(&request)?;
+    let version = request.api_version;
+    let estimate = request.estimate;
+    let plate_id = request.plate_id().to_string();
+    let center_deg = request.center_ra_deg.zip(request.center_dec_deg);
+    let solution_number = request.solution_number.unwrap_or_default();
+    let data_release = request.data_release.clone();
+    let staging = request
+        .stage_results
+        .then(|| format!("{}cutout/{}/", config.results_prefix, staging::new_name()));
+    let (mut result, solutions) = make_cutouts(
+        request, config, tables, objects, plates, buffers, fits_pool, deny_list,
+    )
+    .await?;
+
+    // A staged single stamp is reported like a batch of one.
+    if let Some(prefix) = staging {
+        let key = format!("{prefix}00000");
+        let stamp = BatchStamp {
+            plate_id: plate_id.clone(),
+            solution_number,
+            center_ra_deg: center_deg.map(|c| c.0),
+            center_dec_deg: center_deg.map(|c| c.1),
+            stamp: None,
+            error: None,
+            staged: Some(stage_stamp(result.into_encoded(), &key, config, objects).await?),
+        };
+        result = Response::Staged(stage_manifest(vec![stamp], &prefix, config, objects).await?);
+    }
+
+    // Each stamp of a series, or extension of an `all_solutions` file, is
+    // recorded separately, so that usage is attributed to each solution.
+    if !estimate {
+        for solution_number in solutions {
+            let summary = CutoutSummary {
+                plate_id: &plate_id,
+                solution_number,
+                center_deg,
+                data_release: &data_release,
+            };
+            provenance::record_cutout(config, tables, identity, summary, &echo, started.elapsed())
+                .await;
+        }
+    }
+
+    apiversion::respond(version, "cutout", &echo, result)
+}
+
+/// Make the stamps of a batch, given the requests returned by
+/// [`Request::into_batch`]. A stamp that fails doesn't fail the batch: the
+/// error is reported in its place. If the deadline passes, the remaining
+/// stamps are skipped, and reported as such, so that they can be requested
+/// again.
+#[allow(clippy::too_many_arguments)]
+pub async fn implementation_batch(
+    items: Vec<Request>,
+    config: &Config,
+    tables: &dyn TableStore,
+    objects: &dyn ObjectStore,
+    plates: &PlateCache,
+    buffers: &BufferPool,
+    fits_pool: &Arc<FitsPool>,
+    deny_list: &DenyList,
+    staging: Option<&str>,
+    deadline: Deadline,
+) -> Vec<BatchStamp> {
+    let mut stamps = Vec::with_capacity(items.len());
+
+    for item in items {
+        let mut stamp = BatchStamp {
+            plate_id: item.plate_id().to_string(),
+            solution_number: item.solution_number.unwrap_or_default(),
+            center_ra_deg: item.center_ra_deg,
+            center_dec_deg: item.center_dec_deg,
+            stamp: None,
+            error: None,
+            staged: None,
+        };
+
+        if deadline.is_near() {
+            stamp.error = Some("skipped because the request ran out of time".to_owned());
+        } else {
+            match implementation(
+                item, config, tables, objects, plates, buffers, fits_pool, deny_list,
+            )
+            .await
+            {
+                Ok(response) => match staging {
+                    Some(prefix) => {
+                        let key = format!("{prefix}{:05}", stamps.len());
+
+                        match stage_stamp(response.into_encoded(), &key, config, objects).await {
+                            Ok(staged) => stamp.staged = Some(staged),
+                            Err(e) => stamp.error = Some(format!("failed to stage the stamp: {e}")),
+                        }
+                    }
+
+                    None => stamp.stamp = Some(response.into_encoded()),
+                },
+                Err(e) => stamp.error = Some(e.to_string()),
+            }
+        }
+
+        stamps.push(stamp);
+    }
+
+    stamps
+}
+
+/// Upload a finished stamp of a staged request to the results bucket, under
+/// the given key stem. The filename extension is added to suit its encoding.
+async fn stage_stamp(
+    response: Response,
+    key: &str,
+    config: &Config,
+    objects: &dyn ObjectStore,
+) -> Result<StagedStamp, Error> {
+    let Response::Encoded(encoded) = response else {
+        return Err("only images can be staged".into());
+    };
+
+    let (encoding, extension, content_type) = match encoded.encoding {
+        "fits+gzip+base64" => ("fits+gzip", "fits.gz", "application/fits"),
+        "fits+rice+base64" => ("fits+rice", "fits", "application/fits"),
+        "png+base64" => ("png", "png", "image/png"),
+        _ => ("jpeg", "jpg", "image/jpeg"),
+    };
+
+    let data = STANDARD.decode(&encoded.image)?;
+    let mut stager =
+        Stager::start_at(objects, config, format!("{key}.{extension}"), content_type).await?;
+    stager.push_bytes(&data).await?;
+
+    Ok(StagedStamp {
+        url: stager.finish().await?.url,
+        encoding,
+        statistics: encoded.statistics,
+    })
+}
+
+/// Upload the manifest of a staged request, listing its stamps, next to them.
+async fn stage_manifest(
+    stamps: Vec<BatchStamp>,
+    prefix: &str,
+    config: &Config,
+    objects: &dyn ObjectStore,
+) -> Result<StagedBatch, Error> {
+    let key = format!("{prefix}manifest.json");
+    let mut stager = Stager::start_at(objects, config, key, "application/json").await?;
+    stager.push_bytes(&serde_json::to_vec(&stamps)?).await?;
+
+    Ok(StagedBatch {
+        manifest_url: stager.finish().await?.url,
+        stamps,
+    })
+}
+
+/// Build the error for an out-of-range solution number. It lists the valid
+/// solutions, along with their exposure numbers and approximate centers, so
+/// that clients can correct themselves. The exposure list is sorted to match
+/// the solutions.
+fn solution_range_error(
+    request: &Request,
+    solution_number: usize,
+    astrom: &PlatesAstrometryResult,
+) -> Error {
+    let solutions: Vec<Value> = (0..astrom.n_solutions)
+        .map(|i| {
+            let exp = astrom.exposures.get(i).and_then(|e| e.as_ref());
+            let center = exp
+                .and_then(|e| e.ra_deg.zip(e.dec_deg))
+                .filter(|(r, d)| !request.placeholders.is_placeholder(*r, *d));
+
+            json!({
+                "solution_number": i,
+                "exposure_number": exp.and_then(|e| e.number),
+                "ra_deg": center.map(|c| c.0),
+                "dec_deg": center.map(|c| c.1),
+            })
+        })
+        .collect();
+
+    let message = if astrom.n_solutions == 0 {
+        format!(
+            "requested astrometric solution #{} (0-based) for plate `{}` but it has no solutions",
+            solution_number,
+            request.plate_id()
+        )
+    } else {
+        format!(
+            "requested astrometric solution #{} (0-based) for plate `{}` but it only has {} solutions (valid: 0-{})",
+            solution_number,
+            request.plate_id(),
+            astrom.n_solutions,
+            astrom.n_solutions - 1
         )
-        .await?,
-    )?)
+    };
+
+    DetailedError {
+        message,
+        details: json!({ "valid_solutions": solutions }),
+    }
+    .into()
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -98,108 +1408,505 @@ impl TryFrom<isize> for DeltaRotation {
     }
 }
 
-pub async fn implementation(
-    request: Request,
-    dc: &aws_sdk_dynamodb::Client,
-) -> Result<String, Error> {
-    // Early validation, with NaN-sensitive logic
-
-    if !(request.center_ra_deg >= 0. && request.center_ra_deg <= 360.) {
-        return Err("illegal center_ra_deg parameter".into());
+impl DeltaRotation {
+    /// Convert 0-based pixel indices on the grid of the astrometric solution
+    /// into ones on the mosaic, which has the given dimensions. This matches
+    /// the floating-point transformation in [`plan_stamp`].
+    fn to_mosaic(self, x: isize, y: isize, width: isize, height: isize) -> (isize, isize) {
+        let (w, h) = (width - 1, height - 1);
+
+        match self {
+            DeltaRotation::None => (x, y),
+            DeltaRotation::Plus180 => (w - x, h - y),
+            DeltaRotation::Minus90 => (w - y, x),
+            DeltaRotation::Plus90 => (y, h - x),
+        }
     }
+}
 
-    if !(request.center_dec_deg >= -90. && request.center_dec_deg <= 90.) {
-        return Err("illegal center_dec_deg parameter".into());
-    }
+/// Make a cutout, or a series of them. The request must have been normalized.
+#[allow(clippy::too_many_arguments)]
+pub async fn implementation(
+    request: Request,
+    config: &Config,
+    tables: &dyn TableStore,
+    objects: &dyn ObjectStore,
+    plates: &PlateCache,
+    buffers: &BufferPool,
+    fits_pool: &Arc<FitsPool>,
+    deny_list: &DenyList,
+) -> Result<Response, Error> {
+    let (response, _) = make_cutouts(
+        request, config, tables, objects, plates, buffers, fits_pool, deny_list,
+    )
+    .await?;
+    Ok(response)
+}
 
+/// Make a cutout, or a series of them, also returning the solutions that the
+/// stamps were made from, which only an `all_solutions` request doesn't know
+/// in advance.
+#[allow(clippy::too_many_arguments)]
+async fn make_cutouts(
+    request: Request,
+    config: &Config,
+    tables: &dyn TableStore,
+    objects: &dyn ObjectStore,
+    plates: &PlateCache,
+    buffers: &BufferPool,
+    fits_pool: &Arc<FitsPool>,
+    deny_list: &DenyList,
+) -> Result<(Response, Vec<usize>), Error> {
     // Get the information we need about this plate and validate the basic request.
 
-    let plates_table = format!("dasch-{}-dr7-plates", super::ENVIRONMENT);
-
-    let result = dc
-        .get_item()
-        .table_name(plates_table)
-        .key("plateId", AttributeValue::S(request.plate_id.clone()))
-        .projection_expression(
-            "astrometry.b01HeaderGz,\
-            astrometry.nSolutions,\
-            astrometry.rotationDelta,\
-            mosaic.b01Height,\
-            mosaic.b01Width,\
-            mosaic.s3KeyTemplate",
-        )
-        .send()
-        .await?;
+    let features = config.features.with_overrides(&request.features);
+    let deny_reason = deny_list.reason(request.plate_id().as_str());
+
+    if let (Some(reason), DenyMode::Omit) = (deny_reason, request.deny_list) {
+        return Err(format!("plate `{}` is deny-listed: {}", request.plate_id(), reason).into());
+    }
 
-    let item = result
-        .item
-        .ok_or_else(|| -> Error { format!("no such plate_id `{}`", request.plate_id).into() })?;
+    let plates_table = config.plates_table(&request.data_release);
+
+    let item = match plates.get(
+        PLATES_PROJECTION,
+        &plates_table,
+        request.plate_id().as_str(),
+    ) {
+        Some(item) => item,
+
+        None => {
+            let result = tables
+                .get_item(
+                    &plates_table,
+                    "plateId",
+                    AttributeValue::S(request.plate_id().to_string()),
+                    PLATES_PROJECTION,
+                )
+                .await?;
+
+            let item = result.ok_or_else(|| -> Error {
+                format!("no such plate_id `{}`", request.plate_id()).into()
+            })?;
+
+            plates.insert(
+                PLATES_PROJECTION,
+                &plates_table,
+                request.plate_id().to_string(),
+                item.clone(),
+            );
+            item
+        }
+    };
 
     let item: PlatesResult = serde_dynamo::from_item(item)?;
     let mos_data = item.mosaic.ok_or_else(|| -> Error {
         format!(
             "plate `{}` has no registered FITS mosaic information (never scanned?)",
-            request.plate_id
+            request.plate_id()
         )
         .into()
     })?;
     let astrom_data = item.astrometry.ok_or_else(|| -> Error {
         format!(
             "plate `{}` has no registered astrometric solutions",
-            request.plate_id
+            request.plate_id()
         )
         .into()
     })?;
 
-    if request.solution_number >= astrom_data.n_solutions {
+    let solutions = request.solutions(astrom_data.n_solutions);
+
+    for &solnum in &solutions {
+        if solnum >= astrom_data.n_solutions {
+            return Err(solution_range_error(&request, solnum, &astrom_data));
+        }
+    }
+
+    let plate = Plate {
+        drot: DeltaRotation::try_from(astrom_data.rotation_delta)?,
+        mos_data,
+        astrom_data,
+        deny_reason,
+    };
+
+    // Work out what each stamp needs from the mosaic. An `all_solutions`
+    // request skips the solutions that don't overlap the target.
+
+    let mut stamps = Vec::with_capacity(solutions.len());
+    let mut omitted = Vec::new();
+
+    for &solnum in &solutions {
+        match plan_stamp(&request, solnum, &plate, &features, buffers) {
+            Ok(stamp) => stamps.push(stamp),
+            Err(e) if request.all_solutions && e.is::<NoOverlapError>() => {
+                omitted.push(solnum.to_string())
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    if request.all_solutions {
+        check_all_solutions(&request, stamps.len())?;
+
+        if !omitted.is_empty() {
+            warnings::add(
+                "solutions_omitted",
+                format!(
+                    "solutions {} of plate `{}` don't cover the target, and were left out",
+                    omitted.join(", "),
+                    request.plate_id()
+                ),
+            );
+        }
+    }
+
+    let solutions: Vec<usize> = stamps.iter().map(|s| s.solution_number).collect();
+
+    if request.estimate {
+        let estimates = stamps
+            .into_iter()
+            .map(|stamp| {
+                let solution_number = stamp.solution_number;
+                let estimate = stamp.estimate(&request, buffers);
+                (solution_number, Response::Estimate(estimate))
+            })
+            .collect();
+        return Ok((request.collect(estimates), solutions));
+    }
+
+    // Actually get the source pixels. The stamps of a series are all read
+    // through the same handle to the mosaic, so that they can share what it
+    // has fetched and buffered.
+    //
+    // Gross: as far as I can see, since we're bridging across C code, the
+    // CFITSIO S3 I/O callbacks can't leverage the main async runtime even
+    // though they in turn call async code. I believe that we need to create
+    // this "blocking" wrapper thread, which in turn creates its own runtime and
+    // does the S3 work.
+
+    let rects: Vec<_> = stamps.iter().map(|s| s.source).collect();
+
+    for r in &rects {
+        if r.nx * r.ny > MAX_SOURCE_NPIX {
+            return Err(format!(
+                "the cutout would read {} pixels of the plate mosaic, but at most {} are allowed; ask for a smaller image or a finer `pixel_scale_arcsec`",
+                r.nx * r.ny,
+                MAX_SOURCE_NPIX
+            )
+            .into());
+        }
+    }
+
+    for r in &rects {
+        eprintln!(
+            "to fetch: {} rows, {} cols, {} total pixels",
+            r.ny,
+            r.nx,
+            r.nx * r.ny
+        );
+    }
+
+    let s3path = plate
+        .mos_data
+        .s3_key_template
+        .replace("{bin}", "01")
+        .replace("{tnx}", "_tnx");
+    let s3url = objects.fits_url(&config.bucket, &s3path);
+
+    // Keep the S3 reads made by CFITSIO in this request's trace.
+    let span = tracing::Span::current();
+
+    // Reuse an open handle to the mosaic if we have one; see `fitspool.rs`.
+    let fits_pool = fits_pool.clone();
+
+    let src_data = tokio::task::spawn_blocking(move || -> Result<Vec<Array<i16, Ix2>>, Error> {
+        let _entered = span.enter();
+        let mut fits = fits_pool.checkout(&s3url, || FitsFile::open(&s3url))?;
+        fits.move_to_hdu(1)?;
+        let data = rects
+            .iter()
+            .map(|r| fits.read_rectangle(r.xmin, r.ymin, r.nx, r.ny))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        fits.finish();
+        Ok(data)
+    })
+    .await??;
+
+    if request.all_solutions {
+        let mut parts = Vec::with_capacity(stamps.len());
+
+        for (stamp, data) in stamps.into_iter().zip(src_data) {
+            let solution_number = stamp.solution_number;
+            let (files, _, _) = stamp.fill(data, &request, buffers)?;
+            parts.push((solution_number, files));
+        }
+
+        let files = OutputFiles::combine(parts, request.plate_id().as_str())?;
+        return Ok((files.encode(request.compression)?, solutions));
+    }
+
+    let mut images = Vec::with_capacity(stamps.len());
+
+    for (stamp, data) in stamps.into_iter().zip(src_data) {
+        let solution_number = stamp.solution_number;
+        images.push((solution_number, stamp.finish(data, &request, buffers)?));
+    }
+
+    Ok((request.collect(images), solutions))
+}
+
+/// Check that the stamps of the solutions that overlap the target of an
+/// `all_solutions` request are within the limits of a series, now that we
+/// know how many there are.
+fn check_all_solutions(request: &Request, n_stamps: usize) -> Result<(), Error> {
+    if n_stamps == 0 {
+        return Err(format!(
+            "none of the solutions of plate `{}` overlap the target region",
+            request.plate_id()
+        )
+        .into());
+    }
+
+    let (width, height) = request.size();
+    let npix = width * height;
+
+    if n_stamps > MAX_SERIES_LENGTH || npix * n_stamps > MAX_SERIES_NPIX {
         return Err(format!(
-            "requested astrometric solution #{} (0-based) for plate `{}` but it only has {} solutions",
-            request.solution_number,
-            request.plate_id,
-            astrom_data.n_solutions
+            "plate `{}` has {} solutions overlapping the target, but only {} images of {} pixels fit in one file; ask for a smaller image, or for the solutions separately",
+            request.plate_id(),
+            n_stamps,
+            usize::min(MAX_SERIES_LENGTH, MAX_SERIES_NPIX / npix),
+            npix
         )
         .into());
     }
 
-    let drot = DeltaRotation::try_from(astrom_data.rotation_delta)?;
+    // The file adds a null primary HDU.
+    let response_bytes = n_stamps * request.max_response_bytes(npix) + 4 * 2880 / 3;
+
+    if response_bytes > MAX_RESPONSE_BYTES {
+        return Err(format!(
+            "plate `{}` has {} solutions overlapping the target, and their images could take up to {} bytes, but responses are limited to {}; ask for fewer pixels, or for `null_pixels: blank`",
+            request.plate_id(),
+            n_stamps,
+            response_bytes,
+            MAX_RESPONSE_BYTES
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// The error of a stamp that doesn't overlap its plate at all.
+#[derive(Debug)]
+struct NoOverlapError {
+    plate_id: String,
+    solution_number: usize,
+}
+
+impl fmt::Display for NoOverlapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "plate `{}` solnum {} does not overlap the target region",
+            self.plate_id, self.solution_number
+        )
+    }
+}
+
+impl std::error::Error for NoOverlapError {}
+
+fn no_overlap_error(request: &Request, solution_number: usize) -> Error {
+    NoOverlapError {
+        plate_id: request.plate_id().to_string(),
+        solution_number,
+    }
+    .into()
+}
+
+/// What we know about a plate, for making cutouts of it.
+struct Plate<'a> {
+    mos_data: PlatesMosaicResult,
+    astrom_data: PlatesAstrometryResult,
+    drot: DeltaRotation,
+    deny_reason: Option<&'a str>,
+}
+
+/// The region of the plate mosaic that a stamp needs.
+#[derive(Clone, Copy, Debug)]
+struct SourceRect {
+    xmin: usize,
+    ymin: usize,
+    nx: usize,
+    ny: usize,
+}
+
+/// A cutout from one solution of a plate, ready to be filled in once its
+/// source pixels have been read.
+struct StampPlan {
+    solution_number: usize,
+    dest_files: OutputFiles,
+
+    /// The number of output pixels that land on the plate.
+    n_filtered: usize,
+    source: SourceRect,
+    sampling: Sampling,
+}
+
+/// How the output pixels of a stamp are computed from the source pixels.
+enum Sampling {
+    /// Interpolate onto the output grid.
+    Resample {
+        /// The mosaic pixel coordinates of the output pixels that land on the
+        /// plate, packed into the first `n_filtered` rows.
+        dp_flat: Array<f64, Ix2>,
+
+        /// The null flags of the output pixels, in full-array order.
+        df_flat: Array<c_int, Ix1>,
+
+        /// The full-array indices of the packed pixels.
+        decompress_indices: Vec<usize>,
+    },
+
+    /// Copy the pixels of the solution's grid, starting at `(x0, y0)`.
+    Native {
+        x0: isize,
+        y0: isize,
+        grid_width: isize,
+        grid_height: isize,
+        drot: DeltaRotation,
+    },
+}
+
+/// Start a cutout from one solution of a plate: write its header and figure
+/// out what part of the mosaic it needs.
+fn plan_stamp(
+    request: &Request,
+    solution_number: usize,
+    plate: &Plate,
+    features: &Features,
+    buffers: &BufferPool,
+) -> Result<StampPlan, Error> {
+    let mos_data = &plate.mos_data;
+    let astrom_data = &plate.astrom_data;
+
+    // When was the exposure taken? The exposure list is sorted to match the
+    // solutions.
+
+    let exposure_time = astrom_data
+        .exposures
+        .get(solution_number)
+        .and_then(|e| e.as_ref())
+        .and_then(|e| e.midpoint_date.as_deref())
+        .and_then(UtcTime::parse);
+
+    // Figure out where to center. If we have an ephemeris, that depends on
+    // the exposure time.
+
+    let (center_ra_deg, center_dec_deg) = match &request.ephemeris {
+        None => (
+            request.center_ra_deg.unwrap(),
+            request.center_dec_deg.unwrap(),
+        ),
+
+        Some(eph) => {
+            let mjd = exposure_time
+                .map(|t| t.mjd())
+                .ok_or_else(|| -> Error {
+                    format!(
+                        "plate `{}` solution #{} has no known exposure midpoint, so the ephemeris cannot be used",
+                        request.plate_id(), solution_number
+                    )
+                    .into()
+                })?;
+
+            let (ra, dec) = interpolate(eph, mjd).ok_or_else(|| -> Error {
+                format!(
+                    "the ephemeris does not cover the exposure midpoint (MJD {:.5}) of plate `{}` solution #{}",
+                    mjd, request.plate_id(), solution_number
+                )
+                .into()
+            })?;
+
+            (ra, dec)
+        }
+    };
 
     // We can compute the target WCS and start building the output FITS.
     //
     // TODO: add lots more headers, including approximate WCS for the other
     // exposures on this plate.
 
-    let mut dest_fits = FitsFile::create_mem()?;
-    dest_fits.write_square_image_header(OUTPUT_IMAGE_FULLSIZE as u64)?;
-    dest_fits.set_u16_header("BLANK", 0)?;
-    dest_fits.set_string_header("CTYPE1", "RA---TAN")?;
-    dest_fits.set_string_header("CTYPE2", "DEC--TAN")?;
-    dest_fits.set_string_header("CUNIT1", "deg")?;
-    dest_fits.set_string_header("CUNIT2", "deg")?;
-    dest_fits.set_f64_header("CRVAL1", request.center_ra_deg)?;
-    dest_fits.set_f64_header("CRVAL2", request.center_dec_deg)?;
-    dest_fits.set_f64_header("CD1_1", -OUTPUT_IMAGE_PIXSCALE)?;
-    dest_fits.set_f64_header("CD2_2", OUTPUT_IMAGE_PIXSCALE)?;
-    dest_fits.set_f64_header("CRPIX1", OUTPUT_IMAGE_HALFSIZE as f64 + 1.)?; // 1-based pixel coords
-    dest_fits.set_f64_header("CRPIX2", OUTPUT_IMAGE_HALFSIZE as f64 + 1.)?;
+    let (width, height) = request.size();
+    let npix = width * height;
+    let mut dest_files = OutputFiles::new(request.compression, request.null_pixels)?;
+
+    dest_files.each(|f| {
+        match request.null_pixels {
+            NullPixels::Blank => {
+                f.write_image_header::<i16>(width as u64, height as u64)?;
+                f.set_u16_header("BLANK", 0)?;
+            }
+
+            NullPixels::Nan => f.write_image_header::<f32>(width as u64, height as u64)?,
+            NullPixels::Mask => f.write_image_header::<i16>(width as u64, height as u64)?,
+        }
+
+        Ok(())
+    })?;
+
+    if request.grid == PixelGrid::Native {
+        return plan_native_stamp(
+            request,
+            solution_number,
+            plate,
+            features,
+            dest_files,
+            (center_ra_deg, center_dec_deg),
+            exposure_time,
+        );
+    }
+
+    let pixel_scale = request.pixel_scale_deg();
+
+    dest_files.each(|f| {
+        f.set_string_header("CTYPE1", "RA---TAN")?;
+        f.set_string_header("CTYPE2", "DEC--TAN")?;
+        f.set_string_header("CUNIT1", "deg")?;
+        f.set_string_header("CUNIT2", "deg")?;
+        f.set_f64_header("CRVAL1", center_ra_deg)?;
+        f.set_f64_header("CRVAL2", center_dec_deg)?;
+        f.set_f64_header("CD1_1", -pixel_scale)?;
+        f.set_f64_header("CD2_2", pixel_scale)?;
+        f.set_f64_header("CRPIX1", (width as f64 + 1.) / 2.)?; // 1-based pixel coords
+        f.set_f64_header("CRPIX2", (height as f64 + 1.) / 2.)?;
+        write_provenance(
+            f,
+            request,
+            solution_number,
+            plate,
+            (center_ra_deg, center_dec_deg),
+            exposure_time,
+        )
+    })?;
 
     let dest_world = {
-        let mut dest_wcs = dest_fits.get_wcs()?;
-        dest_wcs
-            .get(0)
-            .unwrap()
-            .sample_world_square(OUTPUT_IMAGE_FULLSIZE)?
+        let mut dest_wcs = dest_files.first().get_wcs()?;
+        dest_wcs.get(0).unwrap().sample_world(width, height)?
     };
 
     // Figure out where we land on the source image.
 
     let (destpix, destflags) = {
-        let mut src_wcs = load_b01_header(GzDecoder::new(&astrom_data.b01_header_gz[..]))?;
-        let wsn = wcslib_solnum(request.solution_number, astrom_data.n_solutions)?;
+        let mut src_wcs = load_source_wcs(request, plate, features, &mut dest_files)?;
+        let wsn = wcslib_solnum(solution_number, astrom_data.n_solutions)?;
         src_wcs.get(wsn)?.world_to_pixel(dest_world)?
     };
 
-    let mut dp_flat = destpix.into_shape((OUTPUT_IMAGE_NPIX, 2)).unwrap();
-    let mut df_flat = destflags.into_shape(OUTPUT_IMAGE_NPIX).unwrap();
+    let mut dp_flat = destpix.into_shape((npix, 2)).unwrap();
+    let mut df_flat = destflags.into_shape(npix).unwrap();
 
     // If there's a "delta rotation" between how the WCS was solved
     // and the mosaic on disk, we need to transform the WCS pixel coordinates into
@@ -208,7 +1915,7 @@ pub async fn implementation(
     let w = mos_data.b01_width as f64 - 1.;
     let h = mos_data.b01_height as f64 - 1.;
 
-    match drot {
+    match plate.drot {
         DeltaRotation::None => {}
 
         DeltaRotation::Plus180 => {
@@ -253,12 +1960,12 @@ pub async fn implementation(
     // ndarray doesn't have fancy-indexing or boolean mask indexing, so to
     // accomplish the filtering, we need to compress the array manually.
 
-    let mut decompress_indices = Array::uninit(OUTPUT_IMAGE_NPIX);
+    let mut decompress_indices = buffers.usizes.take(npix);
     let mut next_index = 0;
 
-    for full_index in 0..OUTPUT_IMAGE_NPIX {
+    for full_index in 0..npix {
         if df_flat[full_index] == 0 {
-            decompress_indices[next_index].write(full_index);
+            decompress_indices[next_index] = full_index;
 
             if next_index != full_index {
                 dp_flat[(next_index, 0)] = dp_flat[(full_index, 0)];
@@ -270,17 +1977,12 @@ pub async fn implementation(
     }
 
     if next_index == 0 {
-        return Err(format!(
-            "plate `{}` solnum {} does not overlap the target region",
-            request.plate_id, request.solution_number,
-        )
-        .into());
+        buffers.usizes.give(decompress_indices);
+        return Err(no_overlap_error(request, solution_number));
     }
 
     let n_filtered = next_index;
     let dp_filtered = dp_flat.slice(s![0..n_filtered, ..]);
-    let dci_filtered = decompress_indices.slice(s![0..n_filtered]);
-    let dci_filtered = unsafe { dci_filtered.assume_init() }; // We've initialized this subset
 
     let mins = dp_filtered.map_axis(Axis(0), |view| {
         view.into_iter().copied().reduce(f64::min).unwrap()
@@ -299,117 +2001,677 @@ pub async fn implementation(
 
     if src_nx < 1 || src_ny < 1 {
         // With our filtering this shouldn't be possible, but just in case ...
-        return Err(format!(
-            "plate `{}` solnum {} does not overlap the target region",
-            request.plate_id, request.solution_number,
-        )
-        .into());
+        buffers.usizes.give(decompress_indices);
+        return Err(no_overlap_error(request, solution_number));
     }
 
-    // Actually get the source pixels.
-    //
-    // Gross: as far as I can see, since we're bridging across C code, the
-    // CFITSIO S3 I/O callbacks can't leverage the main async runtime even
-    // though they in turn call async code. I believe that we need to create
-    // this "blocking" wrapper thread, which in turn creates its own runtime and
-    // does the S3 work.
+    Ok(StampPlan {
+        solution_number,
+        dest_files,
+        n_filtered,
+        source: SourceRect {
+            xmin,
+            ymin,
+            nx: src_nx,
+            ny: src_ny,
+        },
+        sampling: Sampling::Resample {
+            dp_flat,
+            df_flat,
+            decompress_indices,
+        },
+    })
+}
 
-    eprintln!(
-        "to fetch: {} rows, {} cols, {} total pixels",
-        src_ny,
-        src_nx,
-        src_nx * src_ny
-    );
+/// Start a native-pixel cutout from one solution of a plate. The output image
+/// is the rectangle of the solution's pixel grid centered on the pixel nearest
+/// the target, with the solution's own WCS, shifted to match. If the mosaic is
+/// rotated relative to the solution, its pixels are rotated back when they're
+/// copied, which loses nothing. Pixels beyond the edge of the mosaic are null.
+fn plan_native_stamp(
+    request: &Request,
+    solution_number: usize,
+    plate: &Plate,
+    features: &Features,
+    mut dest_files: OutputFiles,
+    center_deg: (f64, f64),
+    exposure_time: Option<UtcTime>,
+) -> Result<StampPlan, Error> {
+    let mos_data = &plate.mos_data;
+    let (width, height) = request.size();
+
+    let no_overlap = || no_overlap_error(request, solution_number);
+
+    let mut src_wcs = load_source_wcs(request, plate, features, &mut dest_files)?;
+    let wsn = wcslib_solnum(solution_number, plate.astrom_data.n_solutions)?;
+    let mut wcs = src_wcs.get(wsn)?;
+    let (x, y) = wcs
+        .world_to_pixel_scalar(center_deg.0, center_deg.1)?
+        .ok_or_else(no_overlap)?;
+
+    // The dimensions of the solution's pixel grid, and the part of the output
+    // that lands on it.
+
+    let (grid_width, grid_height) = match plate.drot {
+        DeltaRotation::Plus90 | DeltaRotation::Minus90 => {
+            (mos_data.b01_height as isize, mos_data.b01_width as isize)
+        }
+        _ => (mos_data.b01_width as isize, mos_data.b01_height as isize),
+    };
 
-    let s3path = mos_data
-        .s3_key_template
-        .replace("{bin}", "01")
-        .replace("{tnx}", "_tnx");
-    let s3url = format!("s3://{BUCKET}/{s3path}");
+    let x0 = (x - 0.5 * (width as f64 - 1.)).round() as isize;
+    let y0 = (y - 0.5 * (height as f64 - 1.)).round() as isize;
+    let gx0 = isize::max(x0, 0);
+    let gy0 = isize::max(y0, 0);
+    let gx1 = isize::min(x0 + width as isize, grid_width) - 1;
+    let gy1 = isize::min(y0 + height as isize, grid_height) - 1;
 
-    let src_data = tokio::task::spawn_blocking(move || -> Result<Array<i16, Ix2>, Error> {
-        let mut fits = FitsFile::open(s3url)?;
-        fits.move_to_hdu(1)?;
-        Ok(fits.read_rectangle(xmin, ymin, src_nx, src_ny)?)
+    if gx1 < gx0 || gy1 < gy0 {
+        return Err(no_overlap());
+    }
+
+    let cards = wcs.primary_header((x0 as f64, y0 as f64))?;
+
+    dest_files.each(|f| {
+        for card in &cards {
+            f.add_record(card)?;
+        }
+
+        write_provenance(
+            f,
+            request,
+            solution_number,
+            plate,
+            center_deg,
+            exposure_time,
+        )
+    })?;
+
+    // Opposite corners of the region on the solution's grid are opposite
+    // corners on the mosaic, too.
+
+    let (w, h) = (mos_data.b01_width as isize, mos_data.b01_height as isize);
+    let (ax, ay) = plate.drot.to_mosaic(gx0, gy0, w, h);
+    let (bx, by) = plate.drot.to_mosaic(gx1, gy1, w, h);
+
+    Ok(StampPlan {
+        solution_number,
+        dest_files,
+        n_filtered: ((gx1 + 1 - gx0) * (gy1 + 1 - gy0)) as usize,
+        source: SourceRect {
+            xmin: isize::min(ax, bx) as usize,
+            ymin: isize::min(ay, by) as usize,
+            nx: (ax - bx).unsigned_abs() + 1,
+            ny: (ay - by).unsigned_abs() + 1,
+        },
+        sampling: Sampling::Native {
+            x0,
+            y0,
+            grid_width,
+            grid_height,
+            drot: plate.drot,
+        },
     })
-    .await??;
+}
 
-    // Perform the interpolation
-    //
-    // ndarray_interp requires that the x, y, and data types must all be the
-    // same. So we have to translate our image data to f64.
-    //
-    // Also note that its "x" and "y" terminology is such that 2D arrays are
-    // indexed `arr[x,y]`, which is the opposite of our convention.
+/// Load the plate's astrometric header, recording what wcslib made of it in
+/// the output if the request asks.
+fn load_source_wcs(
+    request: &Request,
+    plate: &Plate,
+    features: &Features,
+    dest_files: &mut OutputFiles,
+) -> Result<WcsCollection, Error> {
+    let options = HeaderOptions {
+        relax: request.wcs_relax,
+        report: request.wcs_report,
+        skip_fix: !features.enabled(Feature::WcsFix),
+    };
+    let src_wcs = load_b01_header(
+        GzDecoder::new(&plate.astrom_data.b01_header_gz[..]),
+        options,
+    )
+    .map_err(|e| -> Error { format!("plate `{}`: {}", request.plate_id(), e).into() })?;
+
+    if !src_wcs.fixes().is_empty() {
+        warnings::add(
+            "wcs_fixed",
+            format!(
+                "wcslib patched nonstandard values in the WCS header of plate `{}`; set `wcs_report` for details",
+                request.plate_id()
+            ),
+        );
+    }
 
-    let xs = dp_filtered
-        .view()
-        .slice(s![.., 0])
-        .to_owned()
-        .into_shape(n_filtered)
-        .unwrap()
-        - xmin as f64;
-    let ys = dp_filtered
-        .view()
-        .slice(s![.., 1])
-        .to_owned()
-        .into_shape(n_filtered)
-        .unwrap()
-        - ymin as f64;
+    if request.wcs_report {
+        dest_files.each(|f| {
+            f.add_history(format!(
+                "wcslib rejected {} WCS keywords of the plate header",
+                src_wcs.n_rejected()
+            ))?;
 
-    let src_data = src_data.mapv(|e| e as f64);
-    let interp = interp2d::Interp2DBuilder::new(src_data).build()?;
+            for line in src_wcs.report() {
+                f.add_history(line)?;
+            }
 
-    // Full-size destination bitmap, interpreted as 1D:
-    let mut dest_data: Array<f64, _> = Array::zeros(OUTPUT_IMAGE_NPIX);
+            for fix in src_wcs.fixes() {
+                f.add_history(format!("wcsfix: {fix}"))?;
+            }
 
-    // We'll interpolate into the first n_filtered cells of the array:
-    interp.interp_array_into(&ys, &xs, dest_data.slice_mut(s![..n_filtered]))?;
+            Ok(())
+        })?;
+    }
 
-    let mut dest_data = dest_data.mapv(|e| e as i16);
+    Ok(src_wcs)
+}
 
-    // Now decompress from the filtered portion out into the full array. We have
-    // to do this backwards since the first pixels might overwrite ones that are
-    // at indices less than n_filtered.
+/// Write the header keywords that say what a stamp's pixels are, and where
+/// they came from, so that photometry tools don't have to guess, and so that
+/// the stamp can be traced back to its plate once it's been archived
+/// somewhere else. `center_deg` is the requested center, or for an ephemeris,
+/// its position at the exposure.
+///
+/// `DATE` gives just the day of processing, so that re-creating a stamp from
+/// its provenance record (see `provenance.rs`) on the same day gives the
+/// same bytes.
+fn write_provenance(
+    f: &mut FitsFile,
+    request: &Request,
+    solution_number: usize,
+    plate: &Plate,
+    center_deg: (f64, f64),
+    exposure_time: Option<UtcTime>,
+) -> anyhow::Result<()> {
+    let mos_data = &plate.mos_data;
+    let plate_id = request.plate_id();
+
+    f.set_string_header("BUNIT", "adu")?;
+    f.set_string_header("PLATEID", plate_id.as_str())?;
+    f.set_string_header("SERIES", plate_id.series())?;
+    f.set_i64_header("PLATENUM", plate_id.number().into())?;
+    f.set_i64_header("SOLNUM", solution_number as i64)?;
+    f.set_f64_header("REQRA", center_deg.0)?;
+    f.set_f64_header("REQDEC", center_deg.1)?;
+    f.set_string_header(
+        "CREATOR",
+        concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")),
+    )?;
+    f.set_string_header("DATE", UtcTime::now().date())?;
+
+    if let Some(n) = mos_data.mos_num {
+        f.set_i64_header("MOSNUM", n.into())?;
+    }
 
-    for filtered_index in (0..n_filtered).rev() {
-        let full_index = dci_filtered[filtered_index];
+    if let Some(n) = mos_data.scan_num {
+        f.set_i64_header("SCANNUM", n.into())?;
+    }
+
+    if let Some(d) = &mos_data.creation_date {
+        f.set_string_header("MOSDATE", d)?;
+    }
+
+    if let Some(v) = mos_data.background_level {
+        f.set_f64_header("BKGLEVEL", v)?;
+    }
+
+    if let Some(v) = mos_data.saturation_fraction {
+        f.set_f64_header("SATFRAC", v)?;
+    }
+
+    let origin = match request.grid {
+        PixelGrid::Tan => "resampled from",
+        PixelGrid::Native => "copied unresampled from",
+    };
+
+    f.add_comment(format!(
+        "Pixel values are scanner ADU, {origin} the plate mosaic. They \
+         measure the photographic density of the emulsion, which is not linear \
+         in the sky intensity: photometry needs the plate's calibration (see \
+         the DASCH photcal service). BKGLEVEL is the median ADU of the whole \
+         mosaic and SATFRAC the fraction of its pixels that are saturated."
+    ))?;
+
+    if let Some(t) = exposure_time {
+        f.set_string_header("DATE-OBS", t.iso())?;
+        f.set_f64_header("MJD-OBS", t.mjd())?;
+        f.set_f64_header("JD-OBS", t.jd())?;
+    }
+
+    if let Some(reason) = plate.deny_reason {
+        // Keep to the length of a single-card FITS string.
+        let reason: String = reason.chars().take(68).collect();
+        f.set_string_header("DASCHBAD", reason)?;
+    }
+
+    Ok(())
+}
 
-        if full_index != filtered_index {
-            dest_data[full_index] = dest_data[filtered_index];
+/// A stamp's FITS files, pixels, and pixel null flags, once it's been filled
+/// in.
+type FilledStamp = (OutputFiles, Array<i16, Ix2>, Array<c_int, Ix2>);
+
+impl StampPlan {
+    /// Estimate what making the stamp would involve, instead of making it.
+    fn estimate(self, request: &Request, buffers: &BufferPool) -> Estimate {
+        if let Sampling::Resample {
+            decompress_indices, ..
+        } = self.sampling
+        {
+            buffers.usizes.give(decompress_indices);
         }
 
-        // If this actual cell ought to be flagged, make sure to zero it out.
-        // Otherwise, the "actual" value for this cell will be written by some
-        // other cell at a smaller filtered_index.
-        if df_flat[filtered_index] != 0 {
-            dest_data[filtered_index] = 0;
+        let (width, height) = request.size();
+        let npix = width * height;
+
+        Estimate {
+            source_width: self.source.nx,
+            source_height: self.source.ny,
+            source_bytes: 2 * self.source.nx * self.source.ny,
+            coverage: self.n_filtered as f64 / npix as f64,
+            output_width: width,
+            output_height: height,
+            max_response_bytes: request.max_response_bytes(npix),
+            memory_cost_mib: MEMORY_COST_MIB,
         }
     }
 
-    // After all that, we're ready to reinterpret this as a 2D array.
+    /// Fill in the stamp's pixels from the source pixels and encode it.
+    fn finish(
+        self,
+        src_data: Array<i16, Ix2>,
+        request: &Request,
+        buffers: &BufferPool,
+    ) -> Result<Response, Error> {
+        let (dest_files, dest_data, null_mask) = self.fill(src_data, request, buffers)?;
+
+        // Previews need the statistics for their stretch.
+        let statistics = if request.statistics || !request.output_format.is_default() {
+            stampstats::compute(
+                dest_data
+                    .iter()
+                    .zip(null_mask.iter())
+                    .filter(|(_, flag)| **flag == 0)
+                    .map(|(v, _)| *v),
+            )
+        } else {
+            None
+        };
+
+        let mut image = match request.output_format {
+            OutputFormat::Fits => {
+                let response = dest_files.encode(request.compression)?;
+
+                if !request.statistics {
+                    return Ok(response);
+                }
+
+                let Response::Encoded(image) = response.into_encoded() else {
+                    unreachable!("images always encode to images");
+                };
+
+                image
+            }
 
-    let dest_data = dest_data
-        .into_shape((OUTPUT_IMAGE_FULLSIZE, OUTPUT_IMAGE_FULLSIZE))
-        .unwrap();
+            format => {
+                let stretch = statistics.as_ref().map(|s| &s.stretch);
+                let bytes = preview::encode(format, &dest_data, &null_mask, stretch)?;
+
+                EncodedImage {
+                    image: STANDARD.encode(bytes),
+                    encoding: match format {
+                        OutputFormat::Png => "png+base64",
+                        _ => "jpeg+base64",
+                    },
+                    statistics: None,
+                }
+            }
+        };
 
-    // Write out the pixels, and we're done.
-    //
-    // Buffered lambdas can only emit JSON values. We emit the result as a
-    // single string, which is a base64-encoded form of the output file. That
-    // file is itself gzipped. So to get uncompressed FITS from the output of
-    // this API, you have to decode JSON -> un-base64 -> un-gzip.
+        if request.statistics {
+            image.statistics = statistics;
+        }
+
+        Ok(Response::Encoded(image))
+    }
+
+    /// Fill in the stamp's pixels from the source pixels, and write them into
+    /// its FITS files, unless the request wants a preview image. Returns the
+    /// files, along with the pixels and their null flags.
+    fn fill(
+        self,
+        src_data: Array<i16, Ix2>,
+        request: &Request,
+        buffers: &BufferPool,
+    ) -> Result<FilledStamp, Error> {
+        let StampPlan {
+            mut dest_files,
+            n_filtered,
+            source,
+            sampling,
+            ..
+        } = self;
+
+        let (width, height) = request.size();
+
+        let (dest_data, null_mask) = match sampling {
+            Sampling::Resample {
+                dp_flat,
+                df_flat,
+                decompress_indices,
+            } => {
+                let npix = width * height;
+                let dp_filtered = dp_flat.slice(s![0..n_filtered, ..]);
+                let dci_filtered = &decompress_indices[..n_filtered];
+
+                // Perform the interpolation
+                //
+                // ndarray_interp requires that the x, y, and data types must all be the
+                // same. So we have to translate our image data to f64.
+                //
+                // Also note that its "x" and "y" terminology is such that 2D arrays are
+                // indexed `arr[x,y]`, which is the opposite of our convention.
+
+                let mut xs = Array::from_vec(buffers.f64s.take(n_filtered));
+                xs.zip_mut_with(&dp_filtered.slice(s![.., 0]), |x, v| {
+                    *x = v - source.xmin as f64
+                });
+                let mut ys = Array::from_vec(buffers.f64s.take(n_filtered));
+                ys.zip_mut_with(&dp_filtered.slice(s![.., 1]), |y, v| {
+                    *y = v - source.ymin as f64
+                });
+
+                let src_data = src_data.mapv(|e| e as f64);
+                let interp = interp2d::Interp2DBuilder::new(src_data).build()?;
+
+                // Full-size destination bitmap, interpreted as 1D:
+                let mut dest_data = Array::from_vec(buffers.f64s.take(npix));
+
+                // We'll interpolate into the first n_filtered cells of the array:
+                interp.interp_array_into(&ys, &xs, dest_data.slice_mut(s![..n_filtered]))?;
+
+                let dest_i16 = dest_data.mapv(|e| e as i16);
+
+                // Our scratch space can go back into the pool for the next request.
+                buffers.f64s.give(xs.into_raw_vec());
+                buffers.f64s.give(ys.into_raw_vec());
+                buffers.f64s.give(dest_data.into_raw_vec());
+                let mut dest_data = dest_i16;
+
+                // Now decompress from the filtered portion out into the full array. We have
+                // to do this backwards since the first pixels might overwrite ones that are
+                // at indices less than n_filtered.
+
+                for filtered_index in (0..n_filtered).rev() {
+                    let full_index = dci_filtered[filtered_index];
+
+                    if full_index != filtered_index {
+                        dest_data[full_index] = dest_data[filtered_index];
+                    }
+
+                    // If this actual cell ought to be flagged, make sure to zero it out.
+                    // Otherwise, the "actual" value for this cell will be written by some
+                    // other cell at a smaller filtered_index.
+                    if df_flat[filtered_index] != 0 {
+                        dest_data[filtered_index] = 0;
+                    }
+                }
+
+                buffers.usizes.give(decompress_indices);
+
+                // After all that, we're ready to reinterpret this as a 2D array.
+
+                let dest_data = dest_data.into_shape((height, width)).unwrap();
+
+                // The flags in `df_flat` are still in full-array order, so they tell
+                // us which pixels are null.
+
+                let null_mask = df_flat.into_shape((height, width)).unwrap();
+
+                (dest_data, null_mask)
+            }
+
+            Sampling::Native {
+                x0,
+                y0,
+                grid_width,
+                grid_height,
+                drot,
+            } => copy_native(
+                &src_data,
+                source,
+                (x0, y0),
+                (grid_width, grid_height),
+                drot,
+                (width, height),
+            ),
+        };
+
+        // Write out the pixels, and we're done.
+
+        if !request.output_format.is_default() {
+            return Ok((dest_files, dest_data, null_mask));
+        }
+
+        let dest_f32 = (request.null_pixels == NullPixels::Nan).then(|| {
+            let mut dest_f32 = dest_data.mapv(|e| e as f32);
+            dest_f32.zip_mut_with(&null_mask, |v, flag| {
+                if *flag != 0 {
+                    *v = f32::NAN;
+                }
+            });
+            dest_f32
+        });
+
+        dest_files.each(|f| {
+            match request.null_pixels {
+                NullPixels::Blank => f.write_pixels(&dest_data)?,
+                NullPixels::Nan => f.write_pixels(dest_f32.as_ref().unwrap())?,
+
+                NullPixels::Mask => {
+                    f.write_pixels(&dest_data)?;
+                    f.append_image::<u8>(width as u64, height as u64)?;
+                    f.set_string_header("EXTNAME", "MASK")?;
+                    f.write_pixels(&null_mask.mapv(|flag| (flag != 0) as u8))?;
+                }
+            }
+
+            Ok(())
+        })?;
+
+        Ok((dest_files, dest_data, null_mask))
+    }
+}
+
+/// Copy the pixels of a native-pixel stamp out of the source region,
+/// returning them with their null flags. The stamp starts at `origin` on the
+/// solution's pixel grid, which has the dimensions `grid`.
+fn copy_native(
+    src_data: &Array<i16, Ix2>,
+    source: SourceRect,
+    origin: (isize, isize),
+    grid: (isize, isize),
+    drot: DeltaRotation,
+    (width, height): (usize, usize),
+) -> (Array<i16, Ix2>, Array<c_int, Ix2>) {
+    let mut dest_data = Array::zeros((height, width));
+    let mut null_mask = Array::ones((height, width));
+
+    // The mosaic dimensions:
+    let (w, h) = match drot {
+        DeltaRotation::Plus90 | DeltaRotation::Minus90 => (grid.1, grid.0),
+        _ => grid,
+    };
+
+    for ((j, i), value) in dest_data.indexed_iter_mut() {
+        let x = origin.0 + i as isize;
+        let y = origin.1 + j as isize;
+
+        if x < 0 || x >= grid.0 || y < 0 || y >= grid.1 {
+            continue;
+        }
+
+        let (mx, my) = drot.to_mosaic(x, y, w, h);
+        *value = src_data[(my as usize - source.ymin, mx as usize - source.xmin)];
+        null_mask[(j, i)] = 0;
+    }
+
+    (dest_data, null_mask)
+}
+
+/// The output FITS files of a cutout: one for each encoding that we might
+/// return. Everything written to the files is written to each of them, so
+/// that we can compare their encoded sizes at the end.
+struct OutputFiles {
+    gzip: Option<Pin<Box<FitsFile>>>,
+    rice: Option<Pin<Box<FitsFile>>>,
+}
+
+impl OutputFiles {
+    fn new(compression: OutputCompression, null_pixels: NullPixels) -> Result<Self, Error> {
+        let rice = match compression {
+            OutputCompression::Gzip => None,
+            OutputCompression::Auto if null_pixels == NullPixels::Nan => None,
+
+            OutputCompression::Rice | OutputCompression::Auto => {
+                let mut fits = FitsFile::create_mem()?;
+                fits.set_rice_compression()?;
+                Some(fits)
+            }
+        };
 
-    dest_fits.write_pixels(&dest_data)?;
+        let gzip = if compression == OutputCompression::Rice {
+            None
+        } else {
+            Some(FitsFile::create_mem()?)
+        };
 
-    let mut dest_gz_b64 = Vec::new();
+        Ok(OutputFiles { gzip, rice })
+    }
 
+    /// Apply some writes to each of the files.
+    fn each<F>(&mut self, mut write: F) -> anyhow::Result<()>
+    where
+        F: FnMut(&mut FitsFile) -> anyhow::Result<()>,
     {
-        let dest_gz = EncoderWriter::new(&mut dest_gz_b64, &STANDARD);
-        let mut dest = GzEncoder::new(dest_gz, Compression::default());
-        dest_fits.into_stream(&mut dest)?;
+        for fits in [&mut self.gzip, &mut self.rice].into_iter().flatten() {
+            write(fits)?;
+        }
+
+        Ok(())
     }
 
-    let dest_gz_b64 = String::from_utf8(dest_gz_b64)?;
-    Ok(dest_gz_b64)
+    /// Gather the files of the stamps of an `all_solutions` request into one
+    /// set of files. Each starts with a null primary HDU, followed by the HDUs
+    /// of each stamp, renamed after its solution: `SOL<n>` for the image and
+    /// `MASK<n>` for its mask. The stamps' HDUs are copied as they are, so
+    /// RICE-compressed ones stay compressed.
+    fn combine(parts: Vec<(usize, OutputFiles)>, plate_id: &str) -> Result<Self, Error> {
+        let mut combined = OutputFiles {
+            gzip: None,
+            rice: None,
+        };
+
+        if let Some((_, files)) = parts.first() {
+            if files.gzip.is_some() {
+                combined.gzip = Some(FitsFile::create_mem()?);
+            }
+
+            if files.rice.is_some() {
+                combined.rice = Some(FitsFile::create_mem()?);
+            }
+        }
+
+        combined.each(|f| {
+            f.write_null_header()?;
+            f.set_string_header("PLATEID", plate_id)
+        })?;
+
+        for (solution_number, mut files) in parts {
+            // The stamp's RICE file has a null primary HDU of its own.
+            for (dest, src, first_hdu) in [
+                (&mut combined.gzip, &mut files.gzip, 0),
+                (&mut combined.rice, &mut files.rice, 1),
+            ] {
+                let (Some(dest), Some(src)) = (dest, src) else {
+                    continue;
+                };
+
+                for hdu in first_hdu..src.num_hdus()? {
+                    let name = if hdu == first_hdu { "SOL" } else { "MASK" };
+                    src.move_to_hdu(hdu)?;
+                    src.copy_hdu_to(dest)?;
+                    dest.set_string_header("EXTNAME", format!("{name}{solution_number}"))?;
+                }
+            }
+        }
+
+        Ok(combined)
+    }
+
+    /// One of the files, for reading back what's been written.
+    fn first(&mut self) -> &mut FitsFile {
+        self.gzip.as_mut().or(self.rice.as_mut()).unwrap()
+    }
+
+    /// Encode the files, and choose which to return.
+    ///
+    /// Buffered lambdas can only emit JSON values. We emit the result as a
+    /// single string, which is a base64-encoded form of the output file. The
+    /// traditional output file is itself gzipped, so to get uncompressed FITS
+    /// from it, you have to decode JSON -> un-base64 -> un-gzip.
+    fn encode(self, compression: OutputCompression) -> Result<Response, Error> {
+        let gzip_b64 = match self.gzip {
+            Some(fits) => {
+                let mut dest_gz_b64 = Vec::new();
+
+                {
+                    let dest_gz = EncoderWriter::new(&mut dest_gz_b64, &STANDARD);
+                    let mut dest = GzEncoder::new(dest_gz, Compression::default());
+                    fits.into_stream(&mut dest)?;
+                }
+
+                Some(String::from_utf8(dest_gz_b64)?)
+            }
+
+            None => None,
+        };
+
+        if compression.is_default() {
+            return Ok(Response::Image(gzip_b64.unwrap()));
+        }
+
+        let rice_b64 = match self.rice {
+            Some(fits) => {
+                let mut dest_b64 = Vec::new();
+                fits.into_stream(EncoderWriter::new(&mut dest_b64, &STANDARD))?;
+                Some(String::from_utf8(dest_b64)?)
+            }
+
+            None => None,
+        };
+
+        let image = match (gzip_b64, rice_b64) {
+            (Some(gzip), Some(rice)) if rice.len() < gzip.len() => EncodedImage {
+                image: rice,
+                encoding: "fits+rice+base64",
+                statistics: None,
+            },
+
+            (Some(gzip), _) => EncodedImage {
+                image: gzip,
+                encoding: "fits+gzip+base64",
+                statistics: None,
+            },
+
+            (None, Some(rice)) => EncodedImage {
+                image: rice,
+                encoding: "fits+rice+base64",
+                statistics: None,
+            },
+
+            (None, None) => unreachable!(),
+        };
+
+        Ok(Response::Encoded(image))
+    }
 }
@@ -0,0 +1,28 @@
+//! Returning partial results when time runs short.
+//!
+//! Lambda invocations have a hard time limit. A big query that hits it dies
+//! with nothing to show for the work that it has done, and the user can only
+//! retry it and hit the limit again. So, the long-running handlers check a
+//! [`Deadline`] as they go. When it is near, they stop early and return what
+//! they have, marked as truncated, along with a continuation token. Passing
+//! the token back in the `continuation` field of an otherwise identical
+//! request picks up where the previous one left off; see `continuation.rs`.
+
+use std::time::{Duration, Instant};
+
+/// The point in time by which a handler should wrap up.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+    /// A deadline `margin` before the hard time limit `limit`, leaving that
+    /// much time to package up and return the results.
+    pub fn before(limit: Option<Instant>, margin: Duration) -> Self {
+        Deadline(limit.map(|t| t.checked_sub(margin).unwrap_or(t)))
+    }
+
+    /// Whether the deadline has passed.
+    pub fn is_near(&self) -> bool {
+        self.0.is_some_and(|t| Instant::now() >= t)
+    }
+}
@@ -0,0 +1,64 @@
+//! The deny-list of known-bad plates.
+//!
+//! Some scans are catastrophically bad -- for instance, the wrong plate was
+//! scanned, or the astrometry latched onto the wrong field -- but fixing the
+//! underlying data takes time. The deny-list lets us filter such plates
+//! centrally, rather than in every client. It's a JSON object in the data
+//! bucket mapping plate IDs to the reasons that they're listed:
+//!
+//! ```json
+//! { "b12345": "wrong plate scanned" }
+//! ```
+//!
+//! Requests choose what happens to listed plates with their `deny_list` field:
+//! `flag` (the default) reports them but marks them as listed, while `omit`
+//! drops them from `queryexps` results and makes `cutout` fail.
+//!
+//! The list is loaded once per warm instance, so changes take effect as
+//! instances are recycled. If it doesn't exist, nothing is denied.
+
+use lambda_http::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::{backend::ObjectStore, config::Config};
+
+/// How a request wants deny-listed plates to be handled.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DenyMode {
+    /// Report deny-listed plates, but mark them.
+    #[default]
+    Flag,
+
+    /// Leave out deny-listed plates.
+    Omit,
+}
+
+#[derive(Debug, Default)]
+pub struct DenyList {
+    reasons: HashMap<String, String>,
+}
+
+impl DenyList {
+    /// Load the deny-list from the data bucket.
+    pub async fn load(config: &Config, objects: &dyn ObjectStore) -> Result<Self, Error> {
+        let data = match objects
+            .get_object(&config.bucket, &config.deny_list_key)
+            .await?
+        {
+            Some(d) => d,
+            None => return Ok(DenyList::default()),
+        };
+
+        let reasons = serde_json::from_slice(&data)
+            .map_err(|e| -> Error { format!("malformed plate deny-list: {e}").into() })?;
+
+        Ok(DenyList { reasons })
+    }
+
+    /// If the plate is deny-listed, get the reason why.
+    pub fn reason(&self, plate_id: &str) -> Option<&str> {
+        self.reasons.get(plate_id).map(|s| s.as_ref())
+    }
+}
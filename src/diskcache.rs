@@ -0,0 +1,324 @@
+//! A local disk cache of mosaic data.
+//!
+//! Interactive sessions tend to hammer the same few plates, and every cutout
+//! re-reads its mosaic's headers and tile index, and often the same tiles,
+//! from S3. Warm Lambda instances keep their `/tmp` directories between
+//! invocations, so if `DASCH_MOSAIC_CACHE_MIB` is set, the S3 buffers (see
+//! `s3buffer.rs`) save the mosaic data that they fetch there, and check for it
+//! before issuing GETs.
+//!
+//! The cache stores aligned blocks of each mosaic, one per file, so that reads
+//! at different offsets can share them. Reads memory-map the block files, so
+//! hot blocks come straight from the page cache. Once the cache grows past its
+//! size limit, the least recently used blocks are deleted. Mosaics aren't
+//! modified in place, so the blocks never need to be invalidated.
+//!
+//! The index of the cache is kept in memory, so the directory of blocks is
+//! emptied when the cache is set up, rather than trusting whatever an earlier
+//! process may have left there. The blocks go in a `blocks` subdirectory of
+//! the configured directory, which is all that we delete, so that a
+//! misconfigured directory can't cost anything but our own files.
+
+use anyhow::{bail, Result};
+use aws_sdk_s3::operation::get_object::builders::GetObjectFluentBuilder;
+use lambda_runtime::tracing::warn;
+use memmap2::Mmap;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{config::Config, s3buffer};
+
+/// The size of a cached block. This is a fraction of the biggest S3 buffer,
+/// so that a refill of it reuses whichever of its blocks are already cached.
+const BLOCK_SIZE: u64 = 1 << 20;
+
+#[derive(Debug, Default)]
+struct CacheState {
+    /// The size and last use of each cached block, keyed by file name.
+    blocks: HashMap<String, (u64, u64)>,
+
+    /// The total size of the cached blocks.
+    total_bytes: u64,
+
+    /// A counter that orders the uses of the blocks.
+    clock: u64,
+}
+
+#[derive(Debug)]
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    state: Mutex<CacheState>,
+
+    /// A counter to give each block being written a unique temporary name.
+    n_writes: AtomicU64,
+}
+
+impl DiskCache {
+    /// Set up the cache described by the configuration, emptying its
+    /// directory of blocks. Returns `None` if the cache is disabled, or if its
+    /// directory can't be set up, in which case reads go straight to S3.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        if config.mosaic_cache_mib == 0 {
+            return None;
+        }
+
+        let dir = PathBuf::from(&config.mosaic_cache_dir).join("blocks");
+        let _ = fs::remove_dir_all(&dir);
+
+        if let Err(e) = fs::create_dir_all(&dir) {
+            warn!(
+                "mosaic cache disabled: can't create `{}`: {e}",
+                dir.display()
+            );
+            return None;
+        }
+
+        Some(DiskCache {
+            dir,
+            max_bytes: config.mosaic_cache_mib as u64 * 1024 * 1024,
+            state: Mutex::new(CacheState::default()),
+            n_writes: AtomicU64::new(0),
+        })
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.state.lock().unwrap().blocks.contains_key(name)
+    }
+
+    /// Map a cached block into memory, marking it as recently used. If it's
+    /// evicted in the meantime, this just misses.
+    fn get(&self, name: &str) -> Option<Mmap> {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.clock += 1;
+            let clock = state.clock;
+            state.blocks.get_mut(name)?.1 = clock;
+        }
+
+        let file = fs::File::open(self.dir.join(name)).ok()?;
+
+        // Safety: blocks are written in full before being renamed into place,
+        // and are never modified afterwards. Evicting a block unlinks its
+        // file, which leaves existing maps of it intact.
+        unsafe { Mmap::map(&file) }.ok()
+    }
+
+    /// Save a block, evicting the least recently used blocks to make room.
+    /// Failures are ignored, since the block can always be fetched again.
+    fn insert(&self, name: String, data: &[u8]) {
+        let size = data.len() as u64;
+
+        if size > self.max_bytes {
+            return;
+        }
+
+        let path = self.dir.join(&name);
+        let n = self.n_writes.fetch_add(1, Ordering::Relaxed);
+        let temp = self.dir.join(format!("{name}.{n}.tmp"));
+
+        if fs::write(&temp, data).is_err() {
+            let _ = fs::remove_file(&temp);
+            return;
+        }
+
+        // Rename and evict under the lock, so that the index always matches
+        // the files on disk.
+        let mut state = self.state.lock().unwrap();
+
+        if fs::rename(&temp, &path).is_err() {
+            let _ = fs::remove_file(&temp);
+            return;
+        }
+
+        state.clock += 1;
+        let clock = state.clock;
+
+        if let Some((old_size, _)) = state.blocks.insert(name, (size, clock)) {
+            state.total_bytes -= old_size;
+        }
+
+        state.total_bytes += size;
+
+        // There are at most a few thousand blocks, so a linear search for the
+        // oldest is fine.
+        while state.total_bytes > self.max_bytes {
+            let Some(oldest) = state
+                .blocks
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(name, _)| name.clone())
+            else {
+                break;
+            };
+
+            let (old_size, _) = state.blocks.remove(&oldest).unwrap();
+            state.total_bytes -= old_size;
+            let _ = fs::remove_file(self.dir.join(&oldest));
+        }
+    }
+}
+
+/// An S3 object whose reads go through a [`DiskCache`].
+#[derive(Debug)]
+pub struct CachedObject {
+    cache: &'static DiskCache,
+
+    /// A digest of the object's bucket and key, which prefixes the names of
+    /// its blocks' files.
+    prefix: String,
+
+    /// The size of the object, so that we never ask for data past its end.
+    size: u64,
+}
+
+impl CachedObject {
+    pub fn new(cache: &'static DiskCache, bucket: &str, key: &str, size: u64) -> Self {
+        let digest = Sha256::digest(format!("{bucket}/{key}"));
+
+        CachedObject {
+            cache,
+            prefix: hex::encode(&digest[..16]),
+            size,
+        }
+    }
+
+    fn block_name(&self, block: u64) -> String {
+        format!("{}-{block}", self.prefix)
+    }
+
+    /// Read the bytes from `start` up to, but not including, `end`, or the end
+    /// of the object, appending them to `dest`. Blocks that aren't cached are
+    /// fetched with `get`, each run of missing blocks in one request.
+    pub async fn read_range(
+        &self,
+        get: GetObjectFluentBuilder,
+        start: u64,
+        end: u64,
+        dest: &mut Vec<u8>,
+    ) -> Result<()> {
+        let end = u64::min(end, self.size);
+
+        if start >= end {
+            return Ok(());
+        }
+
+        // Append the part of `data`, which starts at `offset` in the object,
+        // that falls within the requested range.
+        let mut append = |data: &[u8], offset: u64| {
+            let i_start = (u64::max(start, offset) - offset) as usize;
+            let i_end = (u64::min(end, offset + data.len() as u64) - offset) as usize;
+            dest.extend_from_slice(&data[i_start..i_end]);
+        };
+
+        let last = (end - 1) / BLOCK_SIZE;
+        let mut block = start / BLOCK_SIZE;
+
+        while block <= last {
+            if let Some(map) = self.cache.get(&self.block_name(block)) {
+                append(&map, block * BLOCK_SIZE);
+                block += 1;
+                continue;
+            }
+
+            let mut run_end = block + 1;
+
+            while run_end <= last && !self.cache.contains(&self.block_name(run_end)) {
+                run_end += 1;
+            }
+
+            let fetch_start = block * BLOCK_SIZE;
+            let fetch_end = u64::min(run_end * BLOCK_SIZE, self.size);
+            let mut fetched = Vec::with_capacity((fetch_end - fetch_start) as usize);
+            s3buffer::get_range(get.clone(), fetch_start, fetch_end, &mut fetched).await?;
+
+            // Don't cache a truncated block that would poison later reads.
+            if fetched.len() as u64 != fetch_end - fetch_start {
+                bail!("S3 returned a short read of a cached mosaic block");
+            }
+
+            for (i, chunk) in fetched.chunks(BLOCK_SIZE as usize).enumerate() {
+                self.cache.insert(self.block_name(block + i as u64), chunk);
+            }
+
+            append(&fetched, fetch_start);
+            block = run_end;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh, empty directory for a test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("dasch-diskcache-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn hits_and_evictions() {
+        let dir = test_dir("lru");
+        let cache = DiskCache {
+            dir: dir.clone(),
+            max_bytes: 10,
+            state: Default::default(),
+            n_writes: AtomicU64::new(0),
+        };
+
+        cache.insert("a".to_owned(), b"aaaa");
+        cache.insert("b".to_owned(), b"bbbb");
+        assert_eq!(&cache.get("a").unwrap()[..], b"aaaa");
+
+        // This goes over the limit, and `b` is now the least recently used.
+        cache.insert("c".to_owned(), b"cccc");
+        assert!(cache.get("b").is_none());
+        assert!(!dir.join("b").exists());
+        assert_eq!(&cache.get("a").unwrap()[..], b"aaaa");
+        assert_eq!(&cache.get("c").unwrap()[..], b"cccc");
+        assert_eq!(cache.state.lock().unwrap().total_bytes, 8);
+
+        // Blocks bigger than the whole cache aren't kept.
+        cache.insert("d".to_owned(), &[0; 11]);
+        assert!(cache.get("d").is_none());
+        assert!(cache.contains("a"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn setup_only_empties_blocks() {
+        let dir = test_dir("setup");
+        fs::write(dir.join("unrelated"), b"keep").unwrap();
+        fs::create_dir_all(dir.join("blocks")).unwrap();
+        fs::write(dir.join("blocks").join("stale"), b"old").unwrap();
+
+        let config = Config {
+            mosaic_cache_mib: 1,
+            mosaic_cache_dir: dir.display().to_string(),
+            ..Default::default()
+        };
+        let cache = DiskCache::from_config(&config).unwrap();
+        assert!(dir.join("unrelated").exists());
+        assert!(!dir.join("blocks").join("stale").exists());
+
+        cache.insert("a".to_owned(), b"aaaa");
+        assert!(dir.join("blocks").join("a").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
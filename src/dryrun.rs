@@ -0,0 +1,352 @@
+//! Dry runs: reporting the AWS operations that a request performs.
+//!
+//! When a request has `"dry_run": true`, the service runs it as usual, but
+//! instead of its result, returns the list of DynamoDB and S3 operations that
+//! it made, in the order that they started, with the tables and keys or buckets
+//! and object keys involved. This helps to debug access-denied errors, since
+//! they show exactly what a deployment's IAM policy has to allow, and to see
+//! what large batch jobs will touch before running them; the response envelope
+//! still reports the read capacity consumed.
+//!
+//! Reads are really performed, since later operations usually depend on what
+//! they return. That makes a dry run as expensive as the request itself -- a
+//! dry-run cutout reads the mosaic in full -- so it's not a cost estimate; the
+//! `estimate` mode of the services that have one is. Writes -- uploads of
+//! staged results and table items such as provenance records -- are reported
+//! but not performed, so a dry run leaves no trace in S3 or DynamoDB. Reads
+//! answered from in-process caches, such as the plate cache, the deny-list,
+//! and the cache of misses (see `negcache.rs`), aren't reported. Mosaic FITS
+//! files are read in pieces by CFITSIO, through its own S3 driver, so they're
+//! reported once, as an `OpenFits` operation, without the byte ranges read.
+//!
+//! Like `capacity.rs`, the log lives in a Tokio task-local, set up by
+//! [`Services::dispatch_as`](crate::Services::dispatch_as) around dry-run
+//! invocations. The [`DryRunStore`] wraps the storage backends and consults it
+//! on every call, acting as a plain pass-through outside of dry runs.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use futures::future::BoxFuture;
+use lambda_http::Error;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{cell::RefCell, future::Future, sync::Arc, time::Duration};
+
+use crate::backend::{key_text, BatchGetOutput, Item, ObjectStore, TableStore};
+
+tokio::task_local! {
+    static OPERATIONS: RefCell<Vec<Operation>>;
+}
+
+/// The upload ID handed out for the uploads that a dry run doesn't make.
+const DRY_RUN_UPLOAD_ID: &str = "dry-run";
+
+/// One AWS operation made by a request.
+#[derive(Serialize)]
+pub struct Operation {
+    /// The AWS service: `dynamodb` or `s3`.
+    pub service: &'static str,
+
+    /// The name of the operation, after the AWS API action.
+    pub operation: &'static str,
+
+    /// The DynamoDB table or S3 bucket operated on.
+    pub resource: String,
+
+    /// Operation-specific details, such as keys.
+    pub details: Value,
+
+    /// Whether the operation was actually performed. Writes aren't.
+    pub performed: bool,
+}
+
+/// The result of a dry run.
+#[derive(Serialize)]
+pub struct Report {
+    /// The operations that the request made, in order.
+    pub operations: Vec<Operation>,
+}
+
+/// Extract the `dry_run` flag from a request payload, removing it so that the
+/// service doesn't see it.
+pub fn take_flag(payload: &mut Option<Value>) -> Result<bool, Error> {
+    let Some(Value::Object(fields)) = payload else {
+        return Ok(false);
+    };
+
+    match fields.remove("dry_run") {
+        None | Some(Value::Null) => Ok(false),
+        Some(Value::Bool(b)) => Ok(b),
+        Some(_) => Err("illegal `dry_run` parameter: must be a boolean".into()),
+    }
+}
+
+/// Run a future as a dry run, returning its output along with the operations
+/// that it made.
+pub async fn run<F: Future>(fut: F) -> (F::Output, Vec<Operation>) {
+    OPERATIONS
+        .scope(RefCell::new(Vec::new()), async move {
+            let output = fut.await;
+            (output, OPERATIONS.with(|ops| ops.take()))
+        })
+        .await
+}
+
+/// Whether the current request is a dry run.
+fn active() -> bool {
+    OPERATIONS.try_with(|_| ()).is_ok()
+}
+
+/// Log an operation, if the current request is a dry run.
+fn log(service: &'static str, operation: &'static str, resource: &str, details: Value) {
+    push(service, operation, resource, details, true)
+}
+
+/// Log a write that the current dry run is skipping.
+fn log_skipped(service: &'static str, operation: &'static str, resource: &str, details: Value) {
+    push(service, operation, resource, details, false)
+}
+
+fn push(
+    service: &'static str,
+    operation: &'static str,
+    resource: &str,
+    details: Value,
+    performed: bool,
+) {
+    let _ = OPERATIONS.try_with(|ops| {
+        ops.borrow_mut().push(Operation {
+            service,
+            operation,
+            resource: resource.to_owned(),
+            details,
+            performed,
+        })
+    });
+}
+
+fn key_json(key: &AttributeValue) -> Value {
+    key_text(key).map_or(Value::Null, |k| k.into())
+}
+
+/// A wrapper around the storage backends that logs operations during dry runs,
+/// and skips the writes.
+pub struct DryRunStore {
+    tables: Arc<dyn TableStore>,
+    objects: Arc<dyn ObjectStore>,
+}
+
+impl DryRunStore {
+    pub fn new(tables: Arc<dyn TableStore>, objects: Arc<dyn ObjectStore>) -> Self {
+        DryRunStore { tables, objects }
+    }
+}
+
+impl TableStore for DryRunStore {
+    fn get_item<'a>(
+        &'a self,
+        table: &'a str,
+        key_attr: &'a str,
+        key: AttributeValue,
+        projection: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Item>, Error>> {
+        log(
+            "dynamodb",
+            "GetItem",
+            table,
+            json!({ "key": { key_attr: key_json(&key) } }),
+        );
+        self.tables.get_item(table, key_attr, key, projection)
+    }
+
+    fn batch_get_items<'a>(
+        &'a self,
+        table: &'a str,
+        key_attr: &'a str,
+        keys: Vec<AttributeValue>,
+        projection: &'a str,
+    ) -> BoxFuture<'a, Result<BatchGetOutput, Error>> {
+        log(
+            "dynamodb",
+            "BatchGetItem",
+            table,
+            json!({
+                "key_attribute": key_attr,
+                "keys": keys.iter().map(key_json).collect::<Vec<_>>(),
+            }),
+        );
+        self.tables
+            .batch_get_items(table, key_attr, keys, projection)
+    }
+
+    fn query_items<'a>(
+        &'a self,
+        table: &'a str,
+        partition_attr: &'a str,
+        partition_value: AttributeValue,
+    ) -> BoxFuture<'a, Result<Vec<Item>, Error>> {
+        log(
+            "dynamodb",
+            "Query",
+            table,
+            json!({ "key": { partition_attr: key_json(&partition_value) } }),
+        );
+        self.tables
+            .query_items(table, partition_attr, partition_value)
+    }
+
+    fn query_index<'a>(
+        &'a self,
+        table: &'a str,
+        index: &'a str,
+        partition_attr: &'a str,
+        partition_value: AttributeValue,
+        projection: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<Item>, Error>> {
+        log(
+            "dynamodb",
+            "Query",
+            table,
+            json!({
+                "index": index,
+                "key": { partition_attr: key_json(&partition_value) },
+            }),
+        );
+        self.tables
+            .query_index(table, index, partition_attr, partition_value, projection)
+    }
+
+    fn put_item<'a>(
+        &'a self,
+        table: &'a str,
+        partition_attr: &'a str,
+        item: Item,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        if !active() {
+            return self.tables.put_item(table, partition_attr, item);
+        }
+
+        let key = item.get(partition_attr).map_or(Value::Null, key_json);
+        log_skipped(
+            "dynamodb",
+            "PutItem",
+            table,
+            json!({ "key": { partition_attr: key } }),
+        );
+        Box::pin(async { Ok(()) })
+    }
+}
+
+impl ObjectStore for DryRunStore {
+    fn get_object<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Vec<u8>>, Error>> {
+        log("s3", "GetObject", bucket, json!({ "key": key }));
+        self.objects.get_object(bucket, key)
+    }
+
+    fn get_object_start<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        max_bytes: usize,
+    ) -> BoxFuture<'a, Result<Option<Vec<u8>>, Error>> {
+        log(
+            "s3",
+            "GetObject",
+            bucket,
+            json!({ "key": key, "max_bytes": max_bytes }),
+        );
+        self.objects.get_object_start(bucket, key, max_bytes)
+    }
+
+    fn fits_url(&self, bucket: &str, key: &str) -> String {
+        log("s3", "OpenFits", bucket, json!({ "key": key }));
+        self.objects.fits_url(bucket, key)
+    }
+
+    fn create_upload<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        content_type: &'a str,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        if !active() {
+            return self.objects.create_upload(bucket, key, content_type);
+        }
+
+        log_skipped(
+            "s3",
+            "CreateMultipartUpload",
+            bucket,
+            json!({ "key": key, "content_type": content_type }),
+        );
+        Box::pin(async { Ok(DRY_RUN_UPLOAD_ID.to_owned()) })
+    }
+
+    fn upload_part<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        upload_id: &'a str,
+        part_number: i32,
+        data: Vec<u8>,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        if !active() {
+            return self
+                .objects
+                .upload_part(bucket, key, upload_id, part_number, data);
+        }
+
+        log_skipped(
+            "s3",
+            "UploadPart",
+            bucket,
+            json!({ "key": key, "part_number": part_number, "size": data.len() }),
+        );
+        Box::pin(async move { Ok(format!("{DRY_RUN_UPLOAD_ID}-{part_number}")) })
+    }
+
+    fn complete_upload<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        upload_id: &'a str,
+        parts: Vec<(i32, String)>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        if !active() {
+            return self.objects.complete_upload(bucket, key, upload_id, parts);
+        }
+
+        log_skipped(
+            "s3",
+            "CompleteMultipartUpload",
+            bucket,
+            json!({ "key": key, "parts": parts.len() }),
+        );
+        Box::pin(async { Ok(()) })
+    }
+
+    // Presigning doesn't contact S3, so there's nothing to report.
+
+    fn download_url<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        expires_in: Duration,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        self.objects.download_url(bucket, key, expires_in)
+    }
+
+    fn upload_url<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        content_type: &'a str,
+        expires_in: Duration,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        self.objects
+            .upload_url(bucket, key, content_type, expires_in)
+    }
+}
@@ -0,0 +1,175 @@
+//! The common response envelope.
+//!
+//! Every service wraps its result in an envelope that echoes the request as it
+//! was actually interpreted -- after defaults have been filled in and
+//! coordinates normalized -- along with a stable hash of that echo. Users can
+//! cite exactly what was asked of the service, and caches can key off the
+//! hash. The envelope also reports the DynamoDB read capacity that the request
+//! consumed; see `capacity.rs`. The envelopes of tabular results also report
+//! whether and why they were truncated; see `rowcap.rs`. Any data-quality
+//! warnings that the service recorded are listed too; see `warnings.rs`.
+//!
+//! The hash is the hex-encoded SHA-256 digest of the service name, a newline,
+//! and the canonical JSON serialization of the echoed request: object keys
+//! sorted, no whitespace, and numbers in serde_json's shortest round-trip
+//! form.
+//!
+//! Errors don't get the envelope. The HTTP servers report them as JSON objects
+//! with an `errorMessage` field, in the same shape that the Lambda runtime
+//! uses. Errors that carry machine-readable information, to help clients
+//! correct their requests, are [`DetailedError`]s, and add it in an
+//! `errorDetails` field.
+
+use lambda_http::Error;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+use crate::{
+    capacity,
+    rowcap::Truncation,
+    warnings::{self, Warning},
+};
+
+#[derive(Serialize)]
+pub struct Envelope<T> {
+    /// The name of the service that produced the result.
+    pub service: &'static str,
+
+    /// The interpreted request.
+    pub request: Value,
+
+    /// The hash of the interpreted request.
+    pub request_hash: String,
+
+    /// The DynamoDB read capacity units consumed by the request.
+    pub read_capacity_units: f64,
+
+    /// For tabular results, whether and why the result is incomplete.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncation: Option<Truncation>,
+
+    /// Data-quality warnings about the result.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<Warning>,
+
+    /// The service-specific result.
+    pub result: T,
+}
+
+/// Wrap a service result in the response envelope.
+pub fn wrap<R: Serialize, T: Serialize>(
+    service: &'static str,
+    request: &R,
+    result: T,
+) -> Result<Value, Error> {
+    wrap_inner(service, request, result, None)
+}
+
+/// Wrap a tabular service result in the response envelope, along with its
+/// truncation state.
+pub fn wrap_table<R: Serialize, T: Serialize>(
+    service: &'static str,
+    request: &R,
+    result: T,
+    truncation: Truncation,
+) -> Result<Value, Error> {
+    wrap_inner(service, request, result, Some(truncation))
+}
+
+fn wrap_inner<R: Serialize, T: Serialize>(
+    service: &'static str,
+    request: &R,
+    result: T,
+    truncation: Option<Truncation>,
+) -> Result<Value, Error> {
+    let request = serde_json::to_value(request)?;
+
+    Ok(serde_json::to_value(Envelope {
+        service,
+        request_hash: request_hash(service, &request),
+        request,
+        read_capacity_units: capacity::consumed(),
+        truncation,
+        warnings: warnings::current(),
+        result,
+    })?)
+}
+
+/// The hash of an interpreted request to a service, as reported in its
+/// response envelope.
+pub fn request_hash(service: &str, request: &Value) -> String {
+    let mut canonical = String::new();
+    write_canonical(request, &mut canonical);
+
+    let mut hasher = Sha256::new();
+    hasher.update(service.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(canonical.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+/// An error with machine-readable details.
+#[derive(Debug)]
+pub struct DetailedError {
+    pub message: String,
+    pub details: Value,
+}
+
+impl fmt::Display for DetailedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for DetailedError {}
+
+/// Build the JSON body reporting an error to an HTTP client.
+pub fn error_body(err: &Error) -> Value {
+    let mut body = serde_json::json!({ "errorMessage": err.to_string() });
+
+    if let Some(d) = err.downcast_ref::<DetailedError>() {
+        body["errorDetails"] = d.details.clone();
+    }
+
+    body
+}
+
+fn write_canonical(value: &Value, dest: &mut String) {
+    match value {
+        Value::Array(items) => {
+            dest.push('[');
+
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    dest.push(',');
+                }
+
+                write_canonical(item, dest);
+            }
+
+            dest.push(']');
+        }
+
+        Value::Object(map) => {
+            let mut keys: Vec<_> = map.keys().collect();
+            keys.sort();
+            dest.push('{');
+
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    dest.push(',');
+                }
+
+                dest.push_str(&Value::from(key.as_str()).to_string());
+                dest.push(':');
+                write_canonical(&map[key], dest);
+            }
+
+            dest.push('}');
+        }
+
+        scalar => dest.push_str(&scalar.to_string()),
+    }
+}
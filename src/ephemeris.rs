@@ -0,0 +1,76 @@
+//! Short ephemerides of moving objects.
+//!
+//! Asteroid and comet researchers want cutouts centered on where their object
+//! was when a plate was exposed. Rather than making them compute that for
+//! every plate, the cutout service accepts a short ephemeris -- a list of
+//! times and positions -- and interpolates it to the exposure midpoint.
+//!
+//! Times are Modified Julian Dates in UTC. Interpolation is linear in RA and
+//! declination, which is fine as long as the ephemeris is reasonably dense
+//! and doesn't pass too close to a pole. We don't extrapolate.
+
+use lambda_http::Error;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    coords::{delta_ra, normalize_ra},
+    validation,
+};
+
+/// The maximum number of points allowed in an ephemeris.
+pub const MAX_EPHEMERIS_POINTS: usize = 1000;
+
+/// One position in an ephemeris.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct EphemerisPoint {
+    pub mjd: f64,
+    pub ra_deg: f64,
+    pub dec_deg: f64,
+}
+
+/// Validate an ephemeris request parameter, normalizing its coordinates and
+/// sorting it by time.
+pub fn validate_ephemeris(
+    name: &str,
+    mut points: Vec<EphemerisPoint>,
+) -> Result<Vec<EphemerisPoint>, Error> {
+    if points.len() < 2 || points.len() > MAX_EPHEMERIS_POINTS {
+        return Err(format!(
+            "`{name}` must have between 2 and {MAX_EPHEMERIS_POINTS} points; got {}",
+            points.len()
+        )
+        .into());
+    }
+
+    for p in &mut points {
+        if !p.mjd.is_finite() {
+            return Err(format!("illegal time in `{name}`").into());
+        }
+
+        p.ra_deg = validation::ra(name, p.ra_deg)?;
+        p.dec_deg = validation::dec(name, p.dec_deg)?;
+    }
+
+    points.sort_by(|a, b| a.mjd.total_cmp(&b.mjd));
+
+    if points.windows(2).any(|w| w[0].mjd == w[1].mjd) {
+        return Err(format!("`{name}` has more than one point at the same time").into());
+    }
+
+    Ok(points)
+}
+
+/// Interpolate a validated ephemeris to the given time, returning the RA and
+/// declination. Returns None if the time is outside of the ephemeris.
+pub fn interpolate(points: &[EphemerisPoint], mjd: f64) -> Option<(f64, f64)> {
+    let i = points
+        .windows(2)
+        .position(|w| w[0].mjd <= mjd && mjd <= w[1].mjd)?;
+    let (a, b) = (&points[i], &points[i + 1]);
+    let frac = (mjd - a.mjd) / (b.mjd - a.mjd);
+
+    Some((
+        normalize_ra(a.ra_deg + frac * delta_ra(b.ra_deg, a.ra_deg)),
+        a.dec_deg + frac * (b.dec_deg - a.dec_deg),
+    ))
+}
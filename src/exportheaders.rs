@@ -0,0 +1,381 @@
+//! Bulk export of plate headers.
+//!
+//! Users doing offline astrometric analyses across many plates want the b01
+//! FITS headers that hold the astrometric solutions, without cutting out
+//! images from every plate. This service gathers the headers of a list of
+//! plates, or of the plates covering a sky position, and writes them to a tar
+//! archive in S3, returning a time-limited download URL as for staged results
+//! (see `staging.rs`). The archive has one member per plate, named like
+//! `b01268_b01.hdr`, holding its decompressed header.
+//!
+//! Plates covering a position are found with the same coarse binning that
+//! `queryexps` starts with, so the list is a superset of the plates that
+//! actually overlap the position. Plates without astrometric headers are
+//! listed in the response's `missing` field instead of the archive.
+//!
+//! The work is done in batches of plates, in a deterministic order. If the
+//! request runs short of time, or DynamoDB throttles it persistently, we
+//! finish the archive with the batches done so far and return a continuation
+//! token for the rest. Each continued request writes a new archive.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use flate2::read::GzDecoder;
+use lambda_http::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    io::Read,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    backend::{ObjectStore, TableStore},
+    config::{default_data_release, Config},
+    continuation::{decode_token, encode_token, Binding},
+    deadline::Deadline,
+    envelope,
+    features::FeatureOverrides,
+    gscbin::GscBinning,
+    mosaics::PlateId,
+    queryexps,
+    seriesdefaults::SeriesDefaults,
+    staging::Stager,
+    targets,
+    validation::{self, validate_fields},
+};
+
+/// The maximum number of plates in one request.
+pub const MAX_PLATES: usize = 10_000;
+
+/// The most exports that run at once in one instance. Each one holds a batch
+/// of decompressed headers and a multipart upload buffer, and they saturate
+/// DynamoDB and S3 bandwidth quickly anyway.
+pub const MAX_CONCURRENT: usize = 2;
+
+/// The number of plates whose headers we fetch at once. This is the most
+/// that DynamoDB's `BatchGetItem` allows.
+const BATCH_SIZE: usize = 100;
+
+/// The number of times that we resubmit plate keys that DynamoDB didn't
+/// process before giving up on a batch.
+const MAX_UNPROCESSED_RETRIES: usize = 5;
+
+/// The delay before the first resubmission of unprocessed keys. It doubles
+/// with each retry.
+const INITIAL_RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// The attribute that we need from the plates table.
+const PLATES_PROJECTION: &str = "plateId,astrometry.b01HeaderGz";
+
+/// Sync with `json-schemas/exportheaders_request.json`.
+#[derive(Deserialize, Serialize)]
+pub struct Request {
+    /// The plates to export.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    plate_ids: Vec<PlateId>,
+
+    /// Alternatively, a sky position; the plates covering it are exported.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ra_deg: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dec_deg: Option<f64>,
+
+    /// Or the name of a target, resolved into `ra_deg` and `dec_deg` before
+    /// the request is parsed; see `targets.rs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target_name: Option<String>,
+
+    /// The data release to query.
+    #[serde(default = "default_data_release")]
+    data_release: String,
+
+    /// A continuation token from a previous, truncated response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    continuation: Option<String>,
+
+    /// Overrides of the deployment's feature flags; see `features.rs`.
+    #[serde(default, skip_serializing_if = "FeatureOverrides::is_empty")]
+    features: FeatureOverrides,
+}
+
+impl Request {
+    /// Validate the request.
+    pub fn normalize(mut self, config: &Config) -> Result<Self, Error> {
+        config.check_release(&self.data_release)?;
+
+        validate_fields!(self {
+            ra_deg: validation::optional(validation::ra),
+            dec_deg: validation::optional(validation::dec),
+        });
+
+        match (self.plate_ids.is_empty(), self.ra_deg, self.dec_deg) {
+            (false, None, None) | (true, Some(_), Some(_)) => {}
+            _ => return Err(
+                "must specify either `plate_ids`, `target_name`, or both `ra_deg` and `dec_deg`"
+                    .into(),
+            ),
+        }
+
+        if self.plate_ids.len() > MAX_PLATES {
+            return Err(format!(
+                "too many plates: {} requested, but the maximum is {}",
+                self.plate_ids.len(),
+                MAX_PLATES
+            )
+            .into());
+        }
+
+        if let Some(token) = &self.continuation {
+            decode_token(config, &Binding::new("exportheaders", &self)?, token)?;
+        }
+
+        Ok(self)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Response {
+    /// A URL from which the tar archive of headers can be downloaded, without
+    /// credentials, for a limited time.
+    pub url: String,
+
+    /// The number of headers in the archive.
+    pub n_plates: usize,
+
+    /// The requested plates that have no astrometric header, and so aren't
+    /// in the archive.
+    pub missing: Vec<String>,
+
+    /// If true, the archive is incomplete, because we ran short of time or
+    /// were throttled.
+    pub truncated: bool,
+
+    /// If the archive is incomplete, a token that can be passed back to get
+    /// an archive of the remaining plates.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub continuation: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlatesResult {
+    plate_id: String,
+    astrometry: Option<PlatesAstrometryResult>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlatesAstrometryResult {
+    #[serde(default, with = "serde_bytes")]
+    b01_header_gz: Option<Vec<u8>>,
+}
+
+pub async fn handler(
+    req: Option<Value>,
+    config: &Config,
+    tables: &dyn TableStore,
+    objects: &dyn ObjectStore,
+    binning: &GscBinning,
+    series_defaults: &SeriesDefaults,
+    deadline: Deadline,
+) -> Result<Value, Error> {
+    let mut req = req.ok_or_else(|| -> Error { "no request payload".into() })?;
+    targets::resolve_position(&mut req, config, "ra_deg", "dec_deg").await?;
+    let request: Request = serde_json::from_value(req)?;
+    let request = request.normalize(config)?;
+    let echo = serde_json::to_value(&request)?;
+    let result = implementation(
+        request,
+        config,
+        tables,
+        objects,
+        binning,
+        series_defaults,
+        deadline,
+    )
+    .await?;
+    envelope::wrap("exportheaders", &echo, result)
+}
+
+/// Export the headers. The request must have been normalized.
+pub async fn implementation(
+    request: Request,
+    config: &Config,
+    tables: &dyn TableStore,
+    objects: &dyn ObjectStore,
+    binning: &GscBinning,
+    series_defaults: &SeriesDefaults,
+    deadline: Deadline,
+) -> Result<Response, Error> {
+    let binding = Binding::new("exportheaders", &request)?;
+    let plate_ids = match (request.ra_deg, request.dec_deg) {
+        (Some(ra), Some(dec)) => {
+            queryexps::candidate_plate_ids(
+                ra,
+                dec,
+                &request.data_release,
+                config,
+                objects,
+                binning,
+                series_defaults,
+            )
+            .await?
+        }
+
+        _ => {
+            let mut seen = HashSet::new();
+            request
+                .plate_ids
+                .into_iter()
+                .map(String::from)
+                .filter(|pid| seen.insert(pid.clone()))
+                .collect()
+        }
+    };
+
+    let batches: Vec<_> = plate_ids.chunks(BATCH_SIZE).collect();
+    let start = match &request.continuation {
+        Some(token) => decode_token(config, &binding, token)?.0,
+        None => 0,
+    };
+
+    let table_name = config.plates_table(&request.data_release);
+    let mut archive =
+        Stager::start_file(objects, config, "exportheaders", "tar", "application/x-tar").await?;
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut n_plates = 0;
+    let mut missing = Vec::new();
+    let mut continuation = None;
+
+    for (index, batch) in batches.iter().enumerate().skip(start) {
+        if index > start && deadline.is_near() {
+            continuation = Some(encode_token(config, &binding, index, 0)?);
+            break;
+        }
+
+        let Some(mut headers) = fetch_batch(tables, &table_name, batch).await? else {
+            continuation = Some(encode_token(config, &binding, index, 0)?);
+            break;
+        };
+
+        for pid in batch.iter() {
+            match headers.remove(pid) {
+                Some(header) => {
+                    archive
+                        .push_bytes(&tar_member(&format!("{pid}_b01.hdr"), &header, mtime)?)
+                        .await?;
+                    n_plates += 1;
+                }
+
+                None => missing.push(pid.clone()),
+            }
+        }
+    }
+
+    archive.push_bytes(&[0; 2 * TAR_BLOCK]).await?;
+    let staged = archive.finish().await?;
+
+    Ok(Response {
+        url: staged.url,
+        n_plates,
+        missing,
+        truncated: continuation.is_some(),
+        continuation,
+    })
+}
+
+/// Fetch and decompress the headers of a batch of plates, keyed by plate ID.
+/// Plates without headers are left out. If DynamoDB won't process all of the
+/// keys within our retries, returns `None`, so that the batch can be redone
+/// later.
+async fn fetch_batch(
+    tables: &dyn TableStore,
+    table_name: &str,
+    plate_ids: &[String],
+) -> Result<Option<HashMap<String, Vec<u8>>>, Error> {
+    let mut headers = HashMap::new();
+    let mut keys: Vec<_> = plate_ids
+        .iter()
+        .map(|pid| AttributeValue::S(pid.clone()))
+        .collect();
+    let mut n_retries = 0;
+    let mut backoff = INITIAL_RETRY_BACKOFF;
+
+    while !keys.is_empty() {
+        if n_retries > 0 {
+            if n_retries > MAX_UNPROCESSED_RETRIES {
+                eprintln!(
+                    "giving up on {} unprocessed plate keys after {} retries",
+                    keys.len(),
+                    MAX_UNPROCESSED_RETRIES
+                );
+                return Ok(None);
+            }
+
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+
+        let resp = tables
+            .batch_get_items(table_name, "plateId", keys, PLATES_PROJECTION)
+            .await?;
+
+        for item in resp.items {
+            let plate: PlatesResult = serde_dynamo::from_item(item)?;
+
+            if let Some(gz) = plate.astrometry.and_then(|a| a.b01_header_gz) {
+                let mut header = Vec::new();
+                GzDecoder::new(&gz[..])
+                    .read_to_end(&mut header)
+                    .map_err(|e| format!("plate `{}`: bad b01 header: {e}", plate.plate_id))?;
+                headers.insert(plate.plate_id, header);
+            }
+        }
+
+        keys = resp.unprocessed;
+        n_retries += 1;
+    }
+
+    Ok(Some(headers))
+}
+
+/// The block size of tar archives.
+const TAR_BLOCK: usize = 512;
+
+/// Build a member of a POSIX ustar archive: a header block followed by the
+/// data, padded out to a whole number of blocks.
+fn tar_member(name: &str, data: &[u8], mtime: u64) -> Result<Vec<u8>, Error> {
+    if name.len() > 100 {
+        return Err(format!("archive member name `{name}` is too long").into());
+    }
+
+    let n_blocks = data.len().div_ceil(TAR_BLOCK);
+    let mut member = vec![0; TAR_BLOCK * (1 + n_blocks)];
+    let header = &mut member[..TAR_BLOCK];
+
+    let mut put = |offset: usize, value: &[u8]| {
+        header[offset..offset + value.len()].copy_from_slice(value);
+    };
+
+    put(0, name.as_bytes());
+    put(100, b"0000644\0");
+    put(108, b"0000000\0");
+    put(116, b"0000000\0");
+    put(124, format!("{:011o}\0", data.len()).as_bytes());
+    put(136, format!("{:011o}\0", mtime).as_bytes());
+    put(148, b"        ");
+    put(156, b"0");
+    put(257, b"ustar\0");
+    put(263, b"00");
+
+    // The checksum is computed with its own field filled with spaces.
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+
+    member[TAR_BLOCK..TAR_BLOCK + data.len()].copy_from_slice(data);
+    Ok(member)
+}
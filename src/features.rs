@@ -0,0 +1,130 @@
+//! Feature flags.
+//!
+//! Risky changes to how the services work can be put behind a flag, so that
+//! they can be tried out by clients that opt in before they become the
+//! default, and switched off again quickly if they cause trouble. Each
+//! [`Feature`] has a built-in default, which a deployment can override with
+//! `DASCH_FEATURES`: a comma-separated list of feature names to enable, or of
+//! names prefixed with `-` to disable. Services that consult a flag accept a
+//! `features` request field, an object mapping feature names to booleans,
+//! that overrides the deployment's settings for that request. The `describe`
+//! service lists the flags and their settings in the deployment.
+//!
+//! To add a flag, add a variant to [`Feature`], list it in [`Feature::ALL`],
+//! and add the `features` field to the request schemas of the services that
+//! consult it. Once a feature has been the default for a while, retire its
+//! flag.
+
+use lambda_runtime::tracing::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+/// A behavior that can be switched on or off.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    /// Repair plate WCS headers with wcslib's `wcsfix` after parsing them;
+    /// see `WcsCollection::fix`.
+    WcsFix,
+
+    /// Resolve target names that aren't in the bundled table with CDS's
+    /// Sesame service; see `crate::targets`.
+    SesameLookup,
+}
+
+impl Feature {
+    /// All of the features.
+    pub const ALL: &'static [Feature] = &[Feature::WcsFix, Feature::SesameLookup];
+
+    /// The feature's name, as used in `DASCH_FEATURES` and requests.
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::WcsFix => "wcs_fix",
+            Feature::SesameLookup => "sesame_lookup",
+        }
+    }
+
+    /// Whether the feature is enabled if the deployment doesn't say.
+    fn default_enabled(self) -> bool {
+        match self {
+            Feature::WcsFix => true,
+            Feature::SesameLookup => false,
+        }
+    }
+}
+
+/// Per-request overrides of the feature flags, as found in requests'
+/// `features` fields.
+pub type FeatureOverrides = BTreeMap<Feature, bool>;
+
+/// The settings of the feature flags.
+#[derive(Clone, Debug, Default)]
+pub struct Features {
+    /// The features whose settings differ from their defaults, or that have
+    /// been set explicitly.
+    settings: FeatureOverrides,
+}
+
+impl Features {
+    /// Parse a `DASCH_FEATURES` setting. Unknown feature names are logged as
+    /// warnings, so that typos don't go unnoticed, and skipped, so that they
+    /// don't take the other settings down with them.
+    pub fn parse(text: &str) -> Self {
+        let mut settings = FeatureOverrides::new();
+
+        for item in text.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (name, enabled) = match item.strip_prefix('-') {
+                Some(name) => (name, false),
+                None => (item, true),
+            };
+
+            match Feature::ALL.iter().find(|f| f.name() == name) {
+                Some(feature) => {
+                    settings.insert(*feature, enabled);
+                }
+
+                None => warn!("ignoring unknown feature `{name}` in DASCH_FEATURES"),
+            }
+        }
+
+        Features { settings }
+    }
+
+    /// Whether a feature is enabled.
+    pub fn enabled(&self, feature: Feature) -> bool {
+        self.settings
+            .get(&feature)
+            .copied()
+            .unwrap_or_else(|| feature.default_enabled())
+    }
+
+    /// These settings, with a request's overrides applied.
+    pub fn with_overrides(&self, overrides: &FeatureOverrides) -> Self {
+        let mut settings = self.settings.clone();
+        settings.extend(overrides);
+        Features { settings }
+    }
+
+    /// The settings of all of the features, for the `describe` service.
+    pub fn describe(&self) -> Value {
+        let settings: Map<String, Value> = Feature::ALL
+            .iter()
+            .map(|f| (f.name().to_owned(), Value::Bool(self.enabled(*f))))
+            .collect();
+        Value::Object(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_skips_unknown_features() {
+        let features = Features::parse("-wcs_fix, bogus, sesame_lookup");
+        assert!(!features.enabled(Feature::WcsFix));
+        assert!(features.enabled(Feature::SesameLookup));
+        assert_eq!(features.settings.len(), 2);
+    }
+}
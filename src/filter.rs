@@ -0,0 +1,403 @@
+//! Row filter expressions for tabular results.
+//!
+//! Clients keep asking for one more request field to narrow down their results
+//! -- only long exposures, only certain series, no flagged rows -- and each
+//! one is bespoke. Instead, `querycat` and `queryexps` accept a `filter`
+//! field holding a small boolean expression over the output columns, and only
+//! return the rows that match it, like:
+//!
+//! ```text
+//! exptime >= 10 && series in ('a', 'b') && !(flags == 'deny_listed')
+//! ```
+//!
+//! A comparison is a column name, one of `==`, `!=`, `<`, `<=`, `>`, or `>=`,
+//! and a literal: a number, or a string in single or double quotes. A
+//! membership test is a column name, `in` or `not in`, and a parenthesized,
+//! comma-separated list of literals. These combine with `&&`, `||`, `!`, and
+//! parentheses, with the usual precedence.
+//!
+//! Comparisons with numbers are numeric, and comparisons with strings compare
+//! the cell text. A cell that's empty, or that isn't a number when compared
+//! with one, makes the comparison false, whatever the operator, like a SQL
+//! `NULL`. Filters see the cells as they're written out, so numeric
+//! comparisons of positions don't work with sexagesimal formatting.
+//!
+//! Filtering happens before the row cap is applied, so capped and continued
+//! results work as usual, and the cap counts the matching rows.
+
+use crate::envelope::DetailedError;
+
+/// The longest filter expression that we accept.
+pub const MAX_LENGTH: usize = 1000;
+
+/// A literal value in an expression.
+#[derive(Clone, Debug, PartialEq)]
+enum Literal {
+    Number(f64),
+    Text(String),
+}
+
+/// A comparison operator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// A parsed expression. Columns are identified by name when parsed, and by
+/// index once bound to the columns of a table.
+#[derive(Debug)]
+enum Expr<C> {
+    And(Box<Expr<C>>, Box<Expr<C>>),
+    Or(Box<Expr<C>>, Box<Expr<C>>),
+    Not(Box<Expr<C>>),
+    Compare(C, Op, Literal),
+    In(C, Vec<Literal>),
+}
+
+/// A filter expression, bound to the columns of a table.
+#[derive(Debug)]
+pub struct RowFilter {
+    expr: Expr<usize>,
+}
+
+impl RowFilter {
+    /// Parse a filter expression and bind it to a table with the given
+    /// columns.
+    pub fn new(text: &str, columns: &[&str]) -> Result<Self, DetailedError> {
+        let expr = bind(parse(text)?, columns)?;
+        Ok(RowFilter { expr })
+    }
+
+    /// Test whether a CSV row matches the filter.
+    pub fn matches(&self, row: &str) -> bool {
+        let cells: Vec<&str> = row.split(',').collect();
+        eval(&self.expr, &cells)
+    }
+}
+
+/// Check the syntax of a filter expression, without knowing its columns.
+pub fn validate(name: &str, text: Option<String>) -> Result<Option<String>, DetailedError> {
+    if let Some(text) = &text {
+        parse(text).map_err(|mut e| {
+            e.details["parameter"] = name.into();
+            e
+        })?;
+    }
+
+    Ok(text)
+}
+
+fn error(message: String, position: Option<usize>) -> DetailedError {
+    let mut details = serde_json::json!({ "parameter": "filter" });
+
+    if let Some(p) = position {
+        details["position"] = p.into();
+    }
+
+    DetailedError {
+        message: format!("illegal `filter` parameter: {message}"),
+        details,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Literal(Literal),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+    Comma,
+}
+
+/// Split an expression into tokens, each with its character position.
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>, DetailedError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        let next = chars.get(i + 1).copied();
+
+        let token = match (c, next) {
+            _ if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+
+            ('(', _) => Token::LParen,
+            (')', _) => Token::RParen,
+            (',', _) => Token::Comma,
+            ('&', Some('&')) => Token::And,
+            ('|', Some('|')) => Token::Or,
+            ('=', Some('=')) => Token::Op(Op::Eq),
+            ('!', Some('=')) => Token::Op(Op::Ne),
+            ('<', Some('=')) => Token::Op(Op::Le),
+            ('>', Some('=')) => Token::Op(Op::Ge),
+            ('!', _) => Token::Not,
+            ('<', _) => Token::Op(Op::Lt),
+            ('>', _) => Token::Op(Op::Gt),
+
+            ('\'' | '"', _) => {
+                let end = chars[i + 1..]
+                    .iter()
+                    .position(|&d| d == c)
+                    .ok_or_else(|| error("unterminated string".to_owned(), Some(start)))?;
+                let s: String = chars[i + 1..i + 1 + end].iter().collect();
+                i += end + 2;
+                tokens.push((start, Token::Literal(Literal::Text(s))));
+                continue;
+            }
+
+            _ if c.is_ascii_digit() || ((c == '-' || c == '.') && next.is_some()) => {
+                let len = chars[i + 1..]
+                    .iter()
+                    .position(|d| !(d.is_ascii_alphanumeric() || matches!(d, '.' | '+' | '-')))
+                    .unwrap_or(chars.len() - i - 1)
+                    + 1;
+                let s: String = chars[i..i + len].iter().collect();
+                let v: f64 = s
+                    .parse()
+                    .map_err(|_| error(format!("bad number `{s}`"), Some(start)))?;
+                i += len;
+                tokens.push((start, Token::Literal(Literal::Number(v))));
+                continue;
+            }
+
+            _ if c.is_ascii_alphabetic() || c == '_' => {
+                let len = chars[i..]
+                    .iter()
+                    .position(|d| !(d.is_ascii_alphanumeric() || *d == '_'))
+                    .unwrap_or(chars.len() - i);
+                let s: String = chars[i..i + len].iter().collect();
+                i += len;
+                tokens.push((start, Token::Ident(s)));
+                continue;
+            }
+
+            _ => return Err(error(format!("unexpected `{c}`"), Some(start))),
+        };
+
+        i += match token {
+            Token::And | Token::Or => 2,
+            Token::Op(Op::Eq | Op::Ne | Op::Le | Op::Ge) => 2,
+            _ => 1,
+        };
+        tokens.push((start, token));
+    }
+
+    Ok(tokens)
+}
+
+/// Parse an expression, with columns identified by name.
+fn parse(text: &str) -> Result<Expr<String>, DetailedError> {
+    if text.chars().count() > MAX_LENGTH {
+        return Err(error(
+            format!("must be at most {MAX_LENGTH} characters long"),
+            None,
+        ));
+    }
+
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        pos: 0,
+        len: text.chars().count(),
+    };
+    let expr = parser.or()?;
+
+    match parser.tokens.get(parser.pos) {
+        None => Ok(expr),
+        Some((p, _)) => Err(error("unexpected text".to_owned(), Some(*p))),
+    }
+}
+
+/// A recursive-descent parser.
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    len: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, t)| t)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens.get(self.pos).map_or(self.len, |(p, _)| *p)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).map(|(_, t)| t.clone());
+        self.pos += 1;
+        t
+    }
+
+    fn expect(&mut self, token: Token, what: &str) -> Result<(), DetailedError> {
+        let p = self.position();
+
+        if self.next() == Some(token) {
+            Ok(())
+        } else {
+            Err(error(format!("expected {what}"), Some(p)))
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr<String>, DetailedError> {
+        let mut expr = self.and()?;
+
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr<String>, DetailedError> {
+        let mut expr = self.unary()?;
+
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr<String>, DetailedError> {
+        let p = self.position();
+
+        match self.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+
+            Some(Token::LParen) => {
+                let expr = self.or()?;
+                self.expect(Token::RParen, "`)`")?;
+                Ok(expr)
+            }
+
+            Some(Token::Ident(column)) => self.test(column),
+
+            _ => Err(error(
+                "expected a column name, `!`, or `(`".to_owned(),
+                Some(p),
+            )),
+        }
+    }
+
+    /// Parse the rest of a comparison or membership test of a column.
+    fn test(&mut self, column: String) -> Result<Expr<String>, DetailedError> {
+        let p = self.position();
+
+        match self.next() {
+            Some(Token::Op(op)) => Ok(Expr::Compare(column, op, self.literal()?)),
+
+            Some(Token::Ident(kw)) if kw == "in" => Ok(Expr::In(column, self.list()?)),
+
+            Some(Token::Ident(kw)) if kw == "not" => {
+                self.expect(Token::Ident("in".to_owned()), "`in`")?;
+                Ok(Expr::Not(Box::new(Expr::In(column, self.list()?))))
+            }
+
+            _ => Err(error(
+                "expected a comparison operator, `in`, or `not in`".to_owned(),
+                Some(p),
+            )),
+        }
+    }
+
+    fn literal(&mut self) -> Result<Literal, DetailedError> {
+        let p = self.position();
+
+        match self.next() {
+            Some(Token::Literal(lit)) => Ok(lit),
+            _ => Err(error("expected a number or a string".to_owned(), Some(p))),
+        }
+    }
+
+    fn list(&mut self) -> Result<Vec<Literal>, DetailedError> {
+        self.expect(Token::LParen, "`(`")?;
+        let mut values = vec![self.literal()?];
+
+        while self.peek() == Some(&Token::Comma) {
+            self.pos += 1;
+            values.push(self.literal()?);
+        }
+
+        self.expect(Token::RParen, "`)`")?;
+        Ok(values)
+    }
+}
+
+/// Resolve the column names of an expression into indices.
+fn bind(expr: Expr<String>, columns: &[&str]) -> Result<Expr<usize>, DetailedError> {
+    let index = |name: String| {
+        columns.iter().position(|c| *c == name).ok_or_else(|| {
+            let mut e = error(
+                format!("unknown column `{name}` (columns: {})", columns.join(", ")),
+                None,
+            );
+            e.details["columns"] = columns.into();
+            e
+        })
+    };
+
+    Ok(match expr {
+        Expr::And(a, b) => Expr::And(Box::new(bind(*a, columns)?), Box::new(bind(*b, columns)?)),
+        Expr::Or(a, b) => Expr::Or(Box::new(bind(*a, columns)?), Box::new(bind(*b, columns)?)),
+        Expr::Not(a) => Expr::Not(Box::new(bind(*a, columns)?)),
+        Expr::Compare(c, op, lit) => Expr::Compare(index(c)?, op, lit),
+        Expr::In(c, lits) => Expr::In(index(c)?, lits),
+    })
+}
+
+fn eval(expr: &Expr<usize>, cells: &[&str]) -> bool {
+    match expr {
+        Expr::And(a, b) => eval(a, cells) && eval(b, cells),
+        Expr::Or(a, b) => eval(a, cells) || eval(b, cells),
+        Expr::Not(a) => !eval(a, cells),
+        Expr::Compare(c, op, lit) => compare(cells.get(*c).copied().unwrap_or(""), *op, lit),
+        Expr::In(c, lits) => {
+            let cell = cells.get(*c).copied().unwrap_or("");
+            lits.iter().any(|lit| compare(cell, Op::Eq, lit))
+        }
+    }
+}
+
+fn compare(cell: &str, op: Op, lit: &Literal) -> bool {
+    if cell.is_empty() {
+        return false;
+    }
+
+    let ordering = match lit {
+        Literal::Number(v) => match cell.parse::<f64>() {
+            Ok(x) => x.partial_cmp(v),
+            Err(_) => None,
+        },
+        Literal::Text(s) => Some(cell.cmp(s.as_str())),
+    };
+
+    let Some(ordering) = ordering else {
+        return false;
+    };
+
+    match op {
+        Op::Eq => ordering.is_eq(),
+        Op::Ne => ordering.is_ne(),
+        Op::Lt => ordering.is_lt(),
+        Op::Le => ordering.is_le(),
+        Op::Gt => ordering.is_gt(),
+        Op::Ge => ordering.is_ge(),
+    }
+}
@@ -1,6 +1,6 @@
 use anyhow::{bail, Result};
 use fitswcs_sys::cfitsio;
-use libc::{self, c_char, c_int, c_longlong, c_void, size_t};
+use libc::{self, c_char, c_int, c_long, c_longlong, c_void, size_t};
 use ndarray::{Array, Ix2};
 use std::{ffi::CString, io::Write, pin::Pin};
 
@@ -80,7 +80,7 @@ impl FitsFile {
                 &mut result.mem_buf,
                 &mut result.mem_size,
                 DELTASIZE,
-                libc::realloc as *const _,
+                zeroing_realloc as *const _,
                 &mut status
             ));
         }
@@ -147,7 +147,7 @@ impl FitsFile {
                 &mut status,
             ));
 
-            let wcs = wcs::WcsCollection::new_raw(header, nkeys)?;
+            let wcs = wcs::WcsCollection::new_raw(header, nkeys, Default::default())?;
             libc::free(header as *mut _);
             wcs
         };
@@ -155,12 +155,27 @@ impl FitsFile {
         Ok(wcs)
     }
 
+    /// Get the optimal number of image pixels to read at once, as judged by
+    /// CFITSIO from the size of its internal buffers.
+    pub fn optimal_read_pixels(&mut self) -> Result<usize> {
+        let mut ndata: c_long = 0;
+        let mut status = 0;
+
+        try_cfitsio!(unsafe { cfitsio::ffgrsz(self.handle, &mut ndata, &mut status) });
+
+        Ok(ndata.max(1) as usize)
+    }
+
     /// Read a rectangle of pixels from the image. We assume that the datatype
     /// is `c_short`. The pixel indices are 0-based, unlike how the underlying
     /// library expects.
     ///
-    /// For DASCH's compressed images, the optimal read strategy is to read
-    /// row-by-row, since each row is a "tile" in the compression mechanism.
+    /// We read the rectangle in bands of whole rows, sized so that each band
+    /// fits in CFITSIO's buffers, with one subset-read call per band. For
+    /// DASCH's compressed images, each image row is a "tile" in the
+    /// compression mechanism, and CFITSIO decompresses each tile that a band
+    /// touches exactly once, so this is as efficient as reading row-by-row
+    /// but with far fewer calls across the FFI boundary.
     pub fn read_rectangle(
         &mut self,
         x0: usize,
@@ -170,20 +185,27 @@ impl FitsFile {
     ) -> Result<Array<i16, Ix2>> {
         let mut arr = Array::uninit((height, width));
         let mut status = 0;
-        let img_width = self.get_dimensions()?[1];
-        let nelem = width as c_longlong;
-
-        for iy in 0..height {
-            let startelem = (y0 + iy) * img_width as usize + x0 + 1;
+        let dims = self.get_dimensions()?;
+        let naxes = [dims[1] as c_long, dims[0] as c_long];
+        let band_rows = usize::max(self.optimal_read_pixels()? / width.max(1), 1);
+        let inc: [c_long; 2] = [1, 1];
+
+        for iy in (0..height).step_by(band_rows) {
+            let n_rows = usize::min(band_rows, height - iy);
+            let blc = [(x0 + 1) as c_long, (y0 + iy + 1) as c_long];
+            let trc = [(x0 + width) as c_long, (y0 + iy + n_rows) as c_long];
             let ptr = arr.get_mut_ptr((iy, 0)).unwrap();
 
             try_cfitsio!(unsafe {
-                cfitsio::ffgpvi(
+                cfitsio::ffgsvi(
                     self.handle,
-                    1,                       // group - always 1
-                    startelem as c_longlong, // start pixel number
-                    nelem,                   // number of pixels to read
-                    0,                       // value to use for null/undefined
+                    1, // column number - ignored for images
+                    2, // number of image dimensions
+                    naxes.as_ptr(),
+                    blc.as_ptr(),
+                    trc.as_ptr(),
+                    inc.as_ptr(),
+                    0, // value to use for null/undefined
                     ptr as *mut _,
                     std::ptr::null_mut(), // output int: whether any null/undef values were encountered
                     &mut status,
@@ -194,14 +216,61 @@ impl FitsFile {
         Ok(unsafe { arr.assume_init() })
     }
 
-    /// Write a basic image header.
+    /// Write a basic image header, for pixels of type `T`.
     ///
     /// Hardcoding for DASCH's needs here.
-    pub fn write_square_image_header(&mut self, size: u64) -> Result<()> {
+    pub fn write_image_header<T: Pixel>(&mut self, width: u64, height: u64) -> Result<()> {
         let mut status = 0;
-        let naxes = [size as c_longlong, size as c_longlong];
+        let naxes = [width as c_longlong, height as c_longlong];
 
-        try_cfitsio!(unsafe { cfitsio::ffphpsll(self.handle, 16, 2, naxes.as_ptr(), &mut status) });
+        try_cfitsio!(unsafe {
+            cfitsio::ffphpsll(self.handle, T::BITPIX, 2, naxes.as_ptr(), &mut status)
+        });
+
+        Ok(())
+    }
+
+    /// Write a primary header with no image, for files whose images are all
+    /// in extensions.
+    pub fn write_null_header(&mut self) -> Result<()> {
+        let mut status = 0;
+
+        try_cfitsio!(unsafe {
+            cfitsio::ffphpsll(self.handle, 8, 0, std::ptr::null(), &mut status)
+        });
+
+        Ok(())
+    }
+
+    /// Append a new image HDU, for pixels of type `T`, and make it the
+    /// current HDU.
+    pub fn append_image<T: Pixel>(&mut self, width: u64, height: u64) -> Result<()> {
+        let mut status = 0;
+        let naxes = [width as c_longlong, height as c_longlong];
+
+        try_cfitsio!(unsafe {
+            cfitsio::ffcrimll(self.handle, T::BITPIX, 2, naxes.as_ptr(), &mut status)
+        });
+
+        Ok(())
+    }
+
+    /// Get the number of HDUs in the file.
+    pub fn num_hdus(&mut self) -> Result<u16> {
+        let mut nhdu: c_int = 0;
+        let mut status = 0;
+
+        try_cfitsio!(unsafe { cfitsio::ffthdu(self.handle, &mut nhdu, &mut status) });
+
+        Ok(nhdu as u16)
+    }
+
+    /// Copy the current HDU to a new HDU at the end of `dest`, which becomes
+    /// its current HDU. A primary image is copied as an image extension.
+    pub fn copy_hdu_to(&mut self, dest: &mut FitsFile) -> Result<()> {
+        let mut status = 0;
+
+        try_cfitsio!(unsafe { cfitsio::ffcopy(self.handle, dest.handle, 0, &mut status) });
 
         Ok(())
     }
@@ -272,16 +341,67 @@ impl FitsFile {
         Ok(())
     }
 
-    /// Write image pixels. We assume that the datatype is `c_short`. The pixel
-    /// indices are 0-based, unlike how the underlying library expects.
-    pub fn write_pixels(&mut self, data: &Array<i16, Ix2>) -> Result<()> {
+    /// Set an i64-valued header keyword in the current HDU.
+    pub fn set_i64_header<S: AsRef<str>>(&mut self, key: S, value: i64) -> Result<()> {
+        let key = CString::new(key.as_ref())?;
+        let mut status = 0;
+
+        try_cfitsio!(unsafe {
+            cfitsio::ffuky(
+                self.handle,
+                cfitsio::TLONGLONG,
+                key.as_ptr(),
+                &value as *const _ as *const _,
+                std::ptr::null(),
+                &mut status,
+            )
+        });
+
+        Ok(())
+    }
+
+    /// Append a `COMMENT` record to the current HDU. Long text is continued
+    /// over multiple records by CFITSIO.
+    pub fn add_comment<S: AsRef<str>>(&mut self, text: S) -> Result<()> {
+        let text = CString::new(text.as_ref())?;
+        let mut status = 0;
+
+        try_cfitsio!(unsafe { cfitsio::ffpcom(self.handle, text.as_ptr(), &mut status) });
+
+        Ok(())
+    }
+
+    /// Append a raw header record to the current HDU. The record should be
+    /// an 80-character FITS card; CFITSIO pads or truncates it as needed.
+    pub fn add_record<S: AsRef<str>>(&mut self, card: S) -> Result<()> {
+        let card = CString::new(card.as_ref())?;
+        let mut status = 0;
+
+        try_cfitsio!(unsafe { cfitsio::ffprec(self.handle, card.as_ptr(), &mut status) });
+
+        Ok(())
+    }
+
+    /// Append a `HISTORY` record to the current HDU. Long text is continued
+    /// over multiple records by CFITSIO.
+    pub fn add_history<S: AsRef<str>>(&mut self, text: S) -> Result<()> {
+        let text = CString::new(text.as_ref())?;
+        let mut status = 0;
+
+        try_cfitsio!(unsafe { cfitsio::ffphis(self.handle, text.as_ptr(), &mut status) });
+
+        Ok(())
+    }
+
+    /// Write image pixels into the current HDU.
+    pub fn write_pixels<T: Pixel>(&mut self, data: &Array<T, Ix2>) -> Result<()> {
         let mut status = 0;
         let startelem = [1 as c_longlong, 1]; // 1-based pixel indexing
 
         try_cfitsio!(unsafe {
             cfitsio::ffppxll(
                 self.handle,
-                cfitsio::TSHORT,
+                T::DATATYPE,
                 startelem.as_ptr(),
                 data.len() as c_longlong,
                 data.as_ptr() as *const _,
@@ -292,6 +412,19 @@ impl FitsFile {
         Ok(())
     }
 
+    /// Write the images created from now on, including a primary image, as
+    /// RICE tile-compressed images. The images must have integer pixels,
+    /// since RICE would quantize floating-point ones.
+    pub fn set_rice_compression(&mut self) -> Result<()> {
+        let mut status = 0;
+
+        try_cfitsio!(unsafe {
+            cfitsio::fits_set_compression_type(self.handle, cfitsio::RICE_1, &mut status)
+        });
+
+        Ok(())
+    }
+
     /// Consume a memory-buffered FITS file and write it into some Rust
     /// destination.
     ///
@@ -305,16 +438,42 @@ impl FitsFile {
             panic!("into_stream() with null mem_buf");
         }
 
+        // The buffer is grown in big steps, so it's usually larger than the
+        // file. The file ends with the padded data of the last HDU.
+        let mut nhdu: c_int = 0;
+        let mut headstart: c_longlong = 0;
+        let mut datastart: c_longlong = 0;
+        let mut dataend: c_longlong = 0;
+
+        unsafe {
+            try_cfitsio!(cfitsio::ffthdu(self.handle, &mut nhdu, &mut status));
+            try_cfitsio!(cfitsio::ffmahd(
+                self.handle,
+                nhdu,
+                std::ptr::null_mut(),
+                &mut status
+            ));
+            try_cfitsio!(cfitsio::ffflus(self.handle, &mut status));
+            try_cfitsio!(cfitsio::ffghadll(
+                self.handle,
+                &mut headstart,
+                &mut datastart,
+                &mut dataend,
+                &mut status
+            ));
+        }
+
+        let file_size = ((dataend as usize).div_ceil(2880) * 2880).min(self.mem_size);
+
         unsafe {
             // Ensure that any pending I/O is finished!
             try_cfitsio!(cfitsio::ffclos(self.handle, &mut status));
             self.handle = std::ptr::null_mut();
 
-            let slice =
-                std::slice::from_raw_parts(self.mem_buf as *const u8, self.mem_size as usize);
+            let slice = std::slice::from_raw_parts(self.mem_buf as *const u8, file_size);
             dest.write_all(slice)?;
 
-            libc::free(self.mem_buf);
+            free_buffer(self.mem_buf);
         }
 
         self.mem_buf = std::ptr::null_mut();
@@ -323,6 +482,86 @@ impl FitsFile {
     }
 }
 
+/// The size of the prefix of memory-file buffers in which [`zeroing_realloc`]
+/// records their sizes. It's big enough to keep the buffers aligned for any
+/// type.
+const BUFFER_PREFIX: usize = 16;
+
+/// The buffer reallocation function for memory-backed FITS files. It's like
+/// `realloc`, but zeros the new memory.
+///
+/// CFITSIO assumes that parts of a file that haven't been written yet read
+/// as zeros. In particular, when writing a tile-compressed image, it reads
+/// each tile's entry in the table before writing it, and a nonzero entry
+/// looks like a tile that's already there. With plain `realloc`, those parts
+/// of the buffer could hold leftovers from earlier allocations.
+///
+/// To know where the new memory starts, we need the old size of the buffer,
+/// which CFITSIO doesn't pass in, and which `malloc` can only tell us through
+/// nonportable extensions. So each allocation has a hidden prefix holding
+/// its size, and the buffers must be freed with [`free_buffer`].
+extern "C" fn zeroing_realloc(ptr: *mut c_void, size: size_t) -> *mut c_void {
+    unsafe {
+        let (base, old_size) = if ptr.is_null() {
+            (std::ptr::null_mut(), 0)
+        } else {
+            let base = (ptr as *mut u8).sub(BUFFER_PREFIX);
+            (base, *(base as *const usize))
+        };
+
+        let new_base = libc::realloc(base as *mut c_void, size + BUFFER_PREFIX) as *mut u8;
+
+        if new_base.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        *(new_base as *mut usize) = size;
+        let new_ptr = new_base.add(BUFFER_PREFIX);
+
+        if size > old_size {
+            std::ptr::write_bytes(new_ptr.add(old_size), 0, size - old_size);
+        }
+
+        new_ptr as *mut c_void
+    }
+}
+
+/// Free a buffer allocated by [`zeroing_realloc`].
+///
+/// # Safety
+///
+/// The pointer must be null, or have come from [`zeroing_realloc`] and not
+/// been freed already.
+unsafe fn free_buffer(ptr: *mut c_void) {
+    if !ptr.is_null() {
+        libc::free((ptr as *mut u8).sub(BUFFER_PREFIX) as *mut c_void);
+    }
+}
+
+/// A type that can be used for the pixels of the images that we write.
+pub trait Pixel {
+    /// The CFITSIO code for the in-memory datatype.
+    const DATATYPE: c_int;
+
+    /// The FITS `BITPIX` value for images of this type.
+    const BITPIX: c_int;
+}
+
+impl Pixel for u8 {
+    const DATATYPE: c_int = cfitsio::TBYTE;
+    const BITPIX: c_int = 8;
+}
+
+impl Pixel for i16 {
+    const DATATYPE: c_int = cfitsio::TSHORT;
+    const BITPIX: c_int = 16;
+}
+
+impl Pixel for f32 {
+    const DATATYPE: c_int = cfitsio::TFLOAT;
+    const BITPIX: c_int = -32;
+}
+
 impl Drop for FitsFile {
     fn drop(&mut self) {
         let mut status = 0;
@@ -339,7 +578,7 @@ impl Drop for FitsFile {
         // we to say.
         if !self.mem_buf.is_null() {
             unsafe {
-                libc::free(self.mem_buf);
+                free_buffer(self.mem_buf);
             }
             self.mem_buf = std::ptr::null_mut();
         }
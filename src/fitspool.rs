@@ -0,0 +1,155 @@
+//! Pooling of open FITS handles.
+//!
+//! Opening a mosaic through the S3 driver (see `s3fits.rs`) costs several S3
+//! round trips before we read any pixels: CFITSIO reads the primary HDU, then
+//! the header and tile index of the compressed image. On a warm Lambda
+//! instance, requests often hit the same mosaics, so we hang on to a few idle
+//! handles, along with their S3 buffers, and reuse them rather than reopening.
+//!
+//! CFITSIO is built in its reentrant mode, so different handles can be used
+//! from different threads at once, but a handle must only be used by one
+//! thread at a time. Worse, if a file is opened while a handle to it is
+//! already open, CFITSIO shares the underlying file state between the two
+//! handles, so they can't be used concurrently either. So, the pool hands out
+//! at most one handle per file at a time: a thread that wants a file that's
+//! checked out waits until it's returned. Checkouts block, so they must be
+//! made from blocking threads, not the async runtime.
+//!
+//! Handles are only returned to the pool by [`PooledFits::finish`]. One that
+//! is dropped without finishing, like when a read fails, is closed, in case
+//! the failure left it in a bad state.
+
+use anyhow::Result;
+use std::{
+    collections::{HashSet, VecDeque},
+    ops::{Deref, DerefMut},
+    sync::{Condvar, Mutex},
+};
+
+use crate::fitsfile::FitsFile;
+
+#[derive(Debug, Default)]
+struct PoolState {
+    /// Idle handles and the URLs that they were opened from, least recently
+    /// used first.
+    idle: VecDeque<(String, FitsFile)>,
+
+    /// The URLs whose handles are checked out.
+    in_use: HashSet<String>,
+}
+
+#[derive(Debug)]
+pub struct FitsPool {
+    max_idle: usize,
+    state: Mutex<PoolState>,
+    returned: Condvar,
+}
+
+impl FitsPool {
+    /// Create a pool that retains at most `max_idle` idle handles. If it's
+    /// zero, handles are never reused, but checkouts of the same file are
+    /// still serialized.
+    pub fn new(max_idle: usize) -> Self {
+        FitsPool {
+            max_idle,
+            state: Mutex::new(PoolState::default()),
+            returned: Condvar::new(),
+        }
+    }
+
+    /// Check out a handle to the FITS file at `url`, reusing an idle one if
+    /// there is one, and opening it with `open` otherwise. Blocks while
+    /// another handle to the same file is checked out.
+    pub fn checkout<F>(&self, url: &str, open: F) -> Result<PooledFits<'_>>
+    where
+        F: FnOnce() -> Result<FitsFile>,
+    {
+        let idle = {
+            let mut state = self.state.lock().unwrap();
+
+            while state.in_use.contains(url) {
+                state = self.returned.wait(state).unwrap();
+            }
+
+            state.in_use.insert(url.to_owned());
+            let idx = state.idle.iter().position(|(u, _)| u == url);
+            idx.and_then(|i| state.idle.remove(i)).map(|(_, fits)| fits)
+        };
+
+        // From here, the guard releases the checkout if anything fails.
+        let mut pooled = PooledFits {
+            pool: self,
+            url: url.to_owned(),
+            fits: idle,
+            released: false,
+        };
+
+        if pooled.fits.is_none() {
+            pooled.fits = Some(open()?);
+        }
+
+        Ok(pooled)
+    }
+
+    fn release(&self, url: &str, fits: Option<FitsFile>) {
+        let mut state = self.state.lock().unwrap();
+        state.in_use.remove(url);
+
+        if let Some(fits) = fits {
+            state.idle.push_back((url.to_owned(), fits));
+        }
+
+        // Close any evicted handle while still holding the lock, so that
+        // nobody can reopen its file until it's fully closed. Closing a
+        // read-only handle doesn't do any I/O, so this is quick.
+        if state.idle.len() > self.max_idle {
+            state.idle.pop_front();
+        }
+
+        drop(state);
+        self.returned.notify_all();
+    }
+}
+
+/// A handle checked out of a [`FitsPool`].
+#[derive(Debug)]
+pub struct PooledFits<'a> {
+    pool: &'a FitsPool,
+    url: String,
+    fits: Option<FitsFile>,
+    released: bool,
+}
+
+impl PooledFits<'_> {
+    /// Return the handle to the pool for reuse.
+    pub fn finish(mut self) {
+        let fits = self.fits.take();
+        self.pool.release(&self.url, fits);
+        self.released = true;
+    }
+}
+
+impl Deref for PooledFits<'_> {
+    type Target = FitsFile;
+
+    fn deref(&self) -> &FitsFile {
+        self.fits.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledFits<'_> {
+    fn deref_mut(&mut self) -> &mut FitsFile {
+        self.fits.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledFits<'_> {
+    fn drop(&mut self) {
+        // If we weren't finished, close the handle, but still release the
+        // checkout so that other threads can open the file.
+        if !self.released {
+            drop(self.fits.take());
+            self.pool.release(&self.url, None);
+        }
+    }
+}
@@ -0,0 +1,585 @@
+//! On-disk fixtures standing in for AWS data.
+//!
+//! A fixture directory mirrors the data that the services fetch from AWS:
+//!
+//! - `dynamodb/<table>/<key>.json` holds a JSON array of the items in `<table>`
+//!   whose partition key has the value `<key>`, in the "DynamoDB JSON" format
+//!   used by the AWS CLI. (For tables with unique partition keys, like the
+//!   plates table, the array has one element.)
+//! - `dynamodb/<table>/<index>/<key>.json` holds the same for the items of a
+//!   global secondary index of `<table>`, as projected into the index.
+//! - `s3/<bucket>/<key>` holds the contents of an S3 object.
+//!
+//! The [`Recorder`] wraps the live backends and writes out everything that
+//! they return in this format, so that fixtures can be generated by running
+//! requests through the oneshot binary with its `--record` option. The only
+//! exception is mosaic FITS files, which CFITSIO reads in pieces via its own
+//! driver; these are too big to record wholesale anyway, so small stand-ins
+//! must be placed in the fixture directory by hand.
+//!
+//! The [`FixtureStore`], available with the `fixtures` feature, serves data
+//! from a fixture directory. Items are returned in full, regardless of the
+//! requested projection. Objects uploaded to it are written to a scratch
+//! directory, not the fixture directory, and their download and upload URLs
+//! are just their paths there. Objects are read from the fixture directory if
+//! they're there, and the scratch directory otherwise. Likewise, items written
+//! to it are appended to the file for their partition key in the scratch
+//! directory, and the items read from a table include them. The recorder
+//! passes uploads and writes through without recording them.
+//!
+//! Reads from the fixture store report consumed capacity as DynamoDB would for
+//! eventually consistent reads, 0.5 units per 4 KiB or part thereof, using the
+//! length of the items' JSON as a stand-in for their stored size.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use futures::future::BoxFuture;
+use lambda_http::Error;
+use serde_json::Value;
+#[cfg(feature = "fixtures")]
+use std::{collections::HashMap, sync::Mutex};
+use std::{
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::backend::{item_to_json, key_text, BatchGetOutput, Item, ObjectStore, TableStore};
+#[cfg(feature = "fixtures")]
+use crate::{backend::item_from_json, capacity};
+
+fn table_item_path(dir: &Path, table: &str, key: &AttributeValue) -> Result<PathBuf, Error> {
+    let mut p = dir.join("dynamodb");
+    p.push(table);
+    p.push(format!("{}.json", key_text(key)?));
+    Ok(p)
+}
+
+fn index_item_path(
+    dir: &Path,
+    table: &str,
+    index: &str,
+    key: &AttributeValue,
+) -> Result<PathBuf, Error> {
+    let mut p = dir.join("dynamodb");
+    p.push(table);
+    p.push(index);
+    p.push(format!("{}.json", key_text(key)?));
+    Ok(p)
+}
+
+fn object_path(dir: &Path, bucket: &str, key: &str) -> PathBuf {
+    let mut p = dir.join("s3");
+    p.push(bucket);
+    p.push(key);
+    p
+}
+
+/// Wrap live storage backends, recording everything they return into a
+/// fixture directory.
+pub struct Recorder {
+    tables: Arc<dyn TableStore>,
+    objects: Arc<dyn ObjectStore>,
+    dir: PathBuf,
+}
+
+impl Recorder {
+    pub fn new<P: Into<PathBuf>>(
+        tables: Arc<dyn TableStore>,
+        objects: Arc<dyn ObjectStore>,
+        dir: P,
+    ) -> Self {
+        Recorder {
+            tables,
+            objects,
+            dir: dir.into(),
+        }
+    }
+
+    fn record_items(&self, path: PathBuf, items: &[Item]) -> Result<(), Error> {
+        fs::create_dir_all(path.parent().unwrap())?;
+        let json = Value::Array(items.iter().map(item_to_json).collect());
+        fs::write(path, serde_json::to_vec_pretty(&json)?)?;
+        Ok(())
+    }
+}
+
+impl TableStore for Recorder {
+    fn get_item<'a>(
+        &'a self,
+        table: &'a str,
+        key_attr: &'a str,
+        key: AttributeValue,
+        projection: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Item>, Error>> {
+        Box::pin(async move {
+            let item = self
+                .tables
+                .get_item(table, key_attr, key.clone(), projection)
+                .await?;
+
+            if let Some(ref item) = item {
+                let path = table_item_path(&self.dir, table, &key)?;
+                self.record_items(path, std::slice::from_ref(item))?;
+            }
+
+            Ok(item)
+        })
+    }
+
+    fn batch_get_items<'a>(
+        &'a self,
+        table: &'a str,
+        key_attr: &'a str,
+        keys: Vec<AttributeValue>,
+        projection: &'a str,
+    ) -> BoxFuture<'a, Result<BatchGetOutput, Error>> {
+        Box::pin(async move {
+            let output = self
+                .tables
+                .batch_get_items(table, key_attr, keys, projection)
+                .await?;
+
+            for item in &output.items {
+                if let Some(key) = item.get(key_attr) {
+                    let path = table_item_path(&self.dir, table, key)?;
+                    self.record_items(path, std::slice::from_ref(item))?;
+                }
+            }
+
+            Ok(output)
+        })
+    }
+
+    fn query_items<'a>(
+        &'a self,
+        table: &'a str,
+        partition_attr: &'a str,
+        partition_value: AttributeValue,
+    ) -> BoxFuture<'a, Result<Vec<Item>, Error>> {
+        Box::pin(async move {
+            let items = self
+                .tables
+                .query_items(table, partition_attr, partition_value.clone())
+                .await?;
+            let path = table_item_path(&self.dir, table, &partition_value)?;
+            self.record_items(path, &items)?;
+            Ok(items)
+        })
+    }
+
+    fn query_index<'a>(
+        &'a self,
+        table: &'a str,
+        index: &'a str,
+        partition_attr: &'a str,
+        partition_value: AttributeValue,
+        projection: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<Item>, Error>> {
+        Box::pin(async move {
+            let items = self
+                .tables
+                .query_index(
+                    table,
+                    index,
+                    partition_attr,
+                    partition_value.clone(),
+                    projection,
+                )
+                .await?;
+            let path = index_item_path(&self.dir, table, index, &partition_value)?;
+            self.record_items(path, &items)?;
+            Ok(items)
+        })
+    }
+
+    fn put_item<'a>(
+        &'a self,
+        table: &'a str,
+        partition_attr: &'a str,
+        item: Item,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.tables.put_item(table, partition_attr, item)
+    }
+}
+
+impl ObjectStore for Recorder {
+    fn get_object<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Vec<u8>>, Error>> {
+        Box::pin(async move {
+            let data = self.objects.get_object(bucket, key).await?;
+
+            if let Some(ref data) = data {
+                let path = object_path(&self.dir, bucket, key);
+                fs::create_dir_all(path.parent().unwrap())?;
+                fs::write(path, data)?;
+            }
+
+            Ok(data)
+        })
+    }
+
+    fn get_object_start<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        max_bytes: usize,
+    ) -> BoxFuture<'a, Result<Option<Vec<u8>>, Error>> {
+        // We can only record whole objects, so read the whole thing.
+        Box::pin(async move {
+            Ok(self.get_object(bucket, key).await?.map(|mut data| {
+                data.truncate(max_bytes);
+                data
+            }))
+        })
+    }
+
+    fn fits_url(&self, bucket: &str, key: &str) -> String {
+        eprintln!(
+            "note: not recording FITS file `{}`; provide a stand-in manually",
+            object_path(&self.dir, bucket, key).display()
+        );
+        self.objects.fits_url(bucket, key)
+    }
+
+    fn create_upload<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        content_type: &'a str,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        self.objects.create_upload(bucket, key, content_type)
+    }
+
+    fn upload_part<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        upload_id: &'a str,
+        part_number: i32,
+        data: Vec<u8>,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        self.objects
+            .upload_part(bucket, key, upload_id, part_number, data)
+    }
+
+    fn complete_upload<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        upload_id: &'a str,
+        parts: Vec<(i32, String)>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.objects.complete_upload(bucket, key, upload_id, parts)
+    }
+
+    fn download_url<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        expires_in: Duration,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        self.objects.download_url(bucket, key, expires_in)
+    }
+
+    fn upload_url<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        content_type: &'a str,
+        expires_in: Duration,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        self.objects
+            .upload_url(bucket, key, content_type, expires_in)
+    }
+}
+
+/// A storage backend that serves data from a fixture directory.
+#[cfg(feature = "fixtures")]
+pub struct FixtureStore {
+    dir: PathBuf,
+    scratch: PathBuf,
+
+    /// The parts of the multipart uploads in progress, keyed by upload ID.
+    uploads: Mutex<HashMap<String, HashMap<i32, Vec<u8>>>>,
+
+    /// Serializes writes of items into the scratch directory.
+    writes: Mutex<()>,
+}
+
+#[cfg(feature = "fixtures")]
+impl FixtureStore {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        FixtureStore {
+            dir: dir.into(),
+            scratch: std::env::temp_dir().join("dasch-science-lambda-uploads"),
+            uploads: Mutex::new(HashMap::new()),
+            writes: Mutex::new(()),
+        }
+    }
+
+    fn load_items(&self, table: &str, key: &AttributeValue) -> Result<Vec<Item>, Error> {
+        let mut items = load_items(table_item_path(&self.dir, table, key)?)?;
+        items.extend(load_items(table_item_path(&self.scratch, table, key)?)?);
+        Ok(items)
+    }
+}
+
+/// Load the items in a fixture file. A missing file holds no items.
+#[cfg(feature = "fixtures")]
+fn load_items(path: PathBuf) -> Result<Vec<Item>, Error> {
+    let text = match fs::read(&path) {
+        Ok(t) => t,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let json: Value = serde_json::from_slice(&text)?;
+
+    json.as_array()
+        .ok_or_else(|| -> Error { format!("fixture `{}` is not an array", path.display()).into() })?
+        .iter()
+        .map(item_from_json)
+        .collect()
+}
+
+/// Record the capacity that a read of some items would consume.
+#[cfg(feature = "fixtures")]
+fn charge_read(items: &[Item]) {
+    let bytes: usize = items
+        .iter()
+        .map(|i| item_to_json(i).to_string().len())
+        .sum();
+    capacity::record(0.5 * bytes.div_ceil(4096).max(1) as f64);
+}
+
+#[cfg(feature = "fixtures")]
+impl TableStore for FixtureStore {
+    fn get_item<'a>(
+        &'a self,
+        table: &'a str,
+        _key_attr: &'a str,
+        key: AttributeValue,
+        _projection: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Item>, Error>> {
+        Box::pin(async move {
+            let item = self.load_items(table, &key)?.into_iter().next();
+            charge_read(item.as_slice());
+            Ok(item)
+        })
+    }
+
+    fn batch_get_items<'a>(
+        &'a self,
+        table: &'a str,
+        _key_attr: &'a str,
+        keys: Vec<AttributeValue>,
+        _projection: &'a str,
+    ) -> BoxFuture<'a, Result<BatchGetOutput, Error>> {
+        Box::pin(async move {
+            let mut output = BatchGetOutput::default();
+
+            for key in keys {
+                let item = self.load_items(table, &key)?.into_iter().next();
+                charge_read(item.as_slice());
+                output.items.extend(item);
+            }
+
+            Ok(output)
+        })
+    }
+
+    fn query_items<'a>(
+        &'a self,
+        table: &'a str,
+        _partition_attr: &'a str,
+        partition_value: AttributeValue,
+    ) -> BoxFuture<'a, Result<Vec<Item>, Error>> {
+        Box::pin(async move {
+            let items = self.load_items(table, &partition_value)?;
+            charge_read(&items);
+            Ok(items)
+        })
+    }
+
+    fn query_index<'a>(
+        &'a self,
+        table: &'a str,
+        index: &'a str,
+        _partition_attr: &'a str,
+        partition_value: AttributeValue,
+        _projection: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<Item>, Error>> {
+        Box::pin(async move {
+            let items = load_items(index_item_path(&self.dir, table, index, &partition_value)?)?;
+            charge_read(&items);
+            Ok(items)
+        })
+    }
+
+    fn put_item<'a>(
+        &'a self,
+        table: &'a str,
+        partition_attr: &'a str,
+        item: Item,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let key = item.get(partition_attr).ok_or_else(|| -> Error {
+                format!("item for `{table}` has no `{partition_attr}` attribute").into()
+            })?;
+            let path = table_item_path(&self.scratch, table, key)?;
+
+            let _guard = self.writes.lock().unwrap();
+            let mut items = load_items(path.clone())?;
+            items.push(item);
+            fs::create_dir_all(path.parent().unwrap())?;
+            let json = Value::Array(items.iter().map(item_to_json).collect());
+            fs::write(path, serde_json::to_vec_pretty(&json)?)?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(feature = "fixtures")]
+impl ObjectStore for FixtureStore {
+    fn get_object<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Vec<u8>>, Error>> {
+        Box::pin(async move {
+            for dir in [&self.dir, &self.scratch] {
+                match fs::read(object_path(dir, bucket, key)) {
+                    Ok(data) => return Ok(Some(data)),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
+            Ok(None)
+        })
+    }
+
+    fn get_object_start<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        max_bytes: usize,
+    ) -> BoxFuture<'a, Result<Option<Vec<u8>>, Error>> {
+        Box::pin(async move {
+            for dir in [&self.dir, &self.scratch] {
+                match fs::File::open(object_path(dir, bucket, key)) {
+                    Ok(f) => {
+                        let mut data = Vec::new();
+                        f.take(max_bytes as u64).read_to_end(&mut data)?;
+                        return Ok(Some(data));
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
+            Ok(None)
+        })
+    }
+
+    fn fits_url(&self, bucket: &str, key: &str) -> String {
+        object_path(&self.dir, bucket, key).display().to_string()
+    }
+
+    fn create_upload<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        _content_type: &'a str,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        Box::pin(async move {
+            let upload_id = format!("{bucket}/{key}");
+            self.uploads
+                .lock()
+                .unwrap()
+                .insert(upload_id.clone(), HashMap::new());
+            Ok(upload_id)
+        })
+    }
+
+    fn upload_part<'a>(
+        &'a self,
+        _bucket: &'a str,
+        _key: &'a str,
+        upload_id: &'a str,
+        part_number: i32,
+        data: Vec<u8>,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        Box::pin(async move {
+            self.uploads
+                .lock()
+                .unwrap()
+                .get_mut(upload_id)
+                .ok_or_else(|| -> Error { format!("no such upload `{upload_id}`").into() })?
+                .insert(part_number, data);
+            Ok(format!("part{part_number}"))
+        })
+    }
+
+    fn complete_upload<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        upload_id: &'a str,
+        parts: Vec<(i32, String)>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let mut uploaded = self
+                .uploads
+                .lock()
+                .unwrap()
+                .remove(upload_id)
+                .ok_or_else(|| -> Error { format!("no such upload `{upload_id}`").into() })?;
+
+            let mut data = Vec::new();
+
+            for (n, _etag) in parts {
+                data.append(&mut uploaded.remove(&n).ok_or_else(|| -> Error {
+                    format!("upload `{upload_id}` has no part {n}").into()
+                })?);
+            }
+
+            let path = object_path(&self.scratch, bucket, key);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, data)?;
+            Ok(())
+        })
+    }
+
+    fn download_url<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        _expires_in: Duration,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        Box::pin(async move {
+            Ok(object_path(&self.scratch, bucket, key)
+                .display()
+                .to_string())
+        })
+    }
+
+    fn upload_url<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        _content_type: &'a str,
+        _expires_in: Duration,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        Box::pin(async move {
+            let path = object_path(&self.scratch, bucket, key);
+            fs::create_dir_all(path.parent().unwrap())?;
+            Ok(path.display().to_string())
+        })
+    }
+}
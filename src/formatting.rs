@@ -0,0 +1,246 @@
+//! Formatting of positions and magnitudes in tabular results.
+//!
+//! The tabular services used to print positions with a fixed six decimal
+//! places and magnitudes however they were stored, which suits nobody in
+//! particular: astrometric work wants more digits, and big summaries want
+//! fewer bytes. Requests can adjust this with a `formatting` field, described
+//! by [`Formatting`]. Each service keeps its old formats as the defaults.
+//!
+//! With `sexagesimal: true`, positions are written as `HH:MM:SS.ss` for RA
+//! and `+DD:MM:SS.s` for declination, and the positional columns become
+//! strings. In this mode `coord_decimals` gives the decimal places of the
+//! seconds of RA; declinations get one fewer, since a second of time is
+//! fifteen seconds of arc.
+//!
+//! Positions are ICRS, which matches the mean equator and equinox of J2000 to
+//! well within our precision. Comparing them with historical charts and
+//! logbooks, which use the equinox of their day, means precessing them, so
+//! with `equinox` set to a Julian year, positions are written precessed to
+//! that mean equinox with the IAU 1976 model. Only the written positions
+//! change: search positions are still J2000, as are positional offsets, and
+//! proper motions aren't applied, so catalog positions stay at their own
+//! epochs.
+//!
+//! Since the numbers alone don't say what frame they're in, tabular results
+//! describe their positional columns with a [`CoordinateFrame`].
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    coords::{normalize_ra, precess_from_j2000},
+    envelope::DetailedError,
+    validation,
+};
+
+/// The most decimal places that can be requested.
+pub const MAX_DECIMALS: usize = 12;
+
+/// The decimal places of the seconds of RA in sexagesimal output, if the
+/// request doesn't say.
+const DEFAULT_SEXAGESIMAL_DECIMALS: usize = 2;
+
+/// The range of equinoxes that positions can be precessed to, as Julian
+/// years, covering the charts and logbooks of the plate era.
+pub const MIN_EQUINOX: f64 = 1800.;
+pub const MAX_EQUINOX: f64 = 2100.;
+
+/// Formatting controls for tabular results.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct Formatting {
+    /// The number of decimal places of positions.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coord_decimals: Option<usize>,
+
+    /// The number of decimal places of magnitudes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mag_decimals: Option<usize>,
+
+    /// If true, write positions in sexagesimal notation.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub sexagesimal: bool,
+
+    /// If set, write positions precessed to the mean equinox of this Julian
+    /// year.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub equinox: Option<f64>,
+}
+
+/// A description of the positional columns of a tabular result.
+#[derive(Clone, Debug, Serialize)]
+pub struct CoordinateFrame {
+    /// The names of the RA and declination columns.
+    pub columns: [&'static str; 2],
+
+    /// The reference frame: `icrs`, or `fk5` if the positions were
+    /// precessed.
+    pub frame: &'static str,
+
+    /// The mean equinox of precessed positions, like `J1875.0`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub equinox: Option<String>,
+
+    /// The column giving the epoch of each position, if the positions are of
+    /// moving sources.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub epoch_column: Option<&'static str>,
+
+    /// How the positions are written: `deg` or `sexagesimal`.
+    pub notation: &'static str,
+}
+
+impl Formatting {
+    pub fn is_default(&self) -> bool {
+        *self == Formatting::default()
+    }
+
+    /// Validate a formatting request parameter.
+    pub fn validate(name: &str, mut value: Self) -> Result<Self, DetailedError> {
+        let decimals = validation::optional(|n, v| validation::range(n, v, 0..=MAX_DECIMALS));
+        value.coord_decimals = decimals(&format!("{name}.coord_decimals"), value.coord_decimals)?;
+        value.mag_decimals = decimals(&format!("{name}.mag_decimals"), value.mag_decimals)?;
+        value.equinox =
+            validation::optional(|n, v| validation::range(n, v, MIN_EQUINOX..=MAX_EQUINOX))(
+                &format!("{name}.equinox"),
+                value.equinox,
+            )?;
+        Ok(value)
+    }
+
+    /// Describe the positional columns of a result, named `columns`, whose
+    /// epochs are given by `epoch_column`, if any.
+    pub fn frame(
+        &self,
+        columns: [&'static str; 2],
+        epoch_column: Option<&'static str>,
+    ) -> CoordinateFrame {
+        CoordinateFrame {
+            columns,
+            frame: if self.equinox.is_some() {
+                "fk5"
+            } else {
+                "icrs"
+            },
+            equinox: self.equinox.map(|y| format!("J{y:.1}")),
+            epoch_column,
+            notation: if self.sexagesimal {
+                "sexagesimal"
+            } else {
+                "deg"
+            },
+        }
+    }
+
+    /// Precess a J2000 position to the requested equinox, if any.
+    pub fn precess(&self, ra_deg: f64, dec_deg: f64) -> (f64, f64) {
+        match self.equinox {
+            Some(year) => {
+                let jd = 2451545. + (year - 2000.) * 365.25;
+                let (ra, dec) = precess_from_j2000(jd, ra_deg, dec_deg);
+                (normalize_ra(ra), dec)
+            }
+
+            None => (ra_deg, dec_deg),
+        }
+    }
+
+    /// Format a J2000 position as two CSV cells, using `default_decimals`
+    /// decimal places if the request doesn't specify otherwise, and
+    /// precessing it if the request asks.
+    pub fn position(&self, ra_deg: f64, dec_deg: f64, default_decimals: usize) -> String {
+        let (ra_deg, dec_deg) = self.precess(ra_deg, dec_deg);
+
+        format!(
+            "{},{}",
+            self.ra(ra_deg, default_decimals),
+            self.dec(dec_deg, default_decimals)
+        )
+    }
+
+    /// Format an RA, in degrees.
+    pub fn ra(&self, ra_deg: f64, default_decimals: usize) -> String {
+        if self.sexagesimal {
+            let decimals = self.coord_decimals.unwrap_or(DEFAULT_SEXAGESIMAL_DECIMALS);
+            return sexagesimal(normalize_ra(ra_deg) / 15., decimals, 24);
+        }
+
+        format!(
+            "{:.*}",
+            self.coord_decimals.unwrap_or(default_decimals),
+            ra_deg
+        )
+    }
+
+    /// Format a declination, in degrees.
+    pub fn dec(&self, dec_deg: f64, default_decimals: usize) -> String {
+        if self.sexagesimal {
+            let decimals = self
+                .coord_decimals
+                .unwrap_or(DEFAULT_SEXAGESIMAL_DECIMALS)
+                .saturating_sub(1);
+            let sign = if dec_deg < 0. { '-' } else { '+' };
+            return format!("{sign}{}", sexagesimal(dec_deg.abs(), decimals, 360));
+        }
+
+        format!(
+            "{:.*}",
+            self.coord_decimals.unwrap_or(default_decimals),
+            dec_deg
+        )
+    }
+
+    /// Reformat a stored RA, given as text. If the request doesn't adjust
+    /// positions, or the text isn't a number, it's returned as-is. This
+    /// doesn't precess; see [`Self::position`].
+    pub fn ra_text(&self, text: String) -> String {
+        reformat(text, self.adjusts_positions(), |v| self.ra(v, 0))
+    }
+
+    /// Reformat a stored declination, given as text, like [`Self::ra_text`].
+    pub fn dec_text(&self, text: String) -> String {
+        reformat(text, self.adjusts_positions(), |v| self.dec(v, 0))
+    }
+
+    /// Reformat a stored magnitude, given as text, like [`Self::ra_text`].
+    pub fn mag_text(&self, text: String) -> String {
+        match self.mag_decimals {
+            Some(n) => reformat(text, true, |v| format!("{v:.n$}")),
+            None => text,
+        }
+    }
+
+    fn adjusts_positions(&self) -> bool {
+        self.sexagesimal || self.coord_decimals.is_some()
+    }
+}
+
+/// Apply `f` to text holding a number, if `adjust` is true. Text that isn't a
+/// number is returned as-is.
+fn reformat(text: String, adjust: bool, f: impl Fn(f64) -> String) -> String {
+    if !adjust {
+        return text;
+    }
+
+    match text.parse::<f64>() {
+        Ok(v) => f(v),
+        Err(_) => text,
+    }
+}
+
+/// Format a nonnegative value, in hours or degrees, as `DD:MM:SS.sss` with
+/// the given number of decimal places in the seconds, wrapping it modulo
+/// `wrap` units.
+fn sexagesimal(value: f64, decimals: usize, wrap: u64) -> String {
+    let scale = 10u64.pow(decimals as u32);
+    let total = (value * 3600. * scale as f64).round() as u64 % (wrap * 3600 * scale);
+    let frac = total % scale;
+    let secs = total / scale;
+
+    let (d, m, s) = (secs / 3600, (secs / 60) % 60, secs % 60);
+
+    if decimals == 0 {
+        format!("{d:02}:{m:02}:{s:02}")
+    } else {
+        format!("{d:02}:{m:02}:{s:02}.{frac:0decimals$}")
+    }
+}
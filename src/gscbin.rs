@@ -1,3 +1,7 @@
+use std::ops::RangeInclusive;
+
+use crate::coords::{normalize_ra, RaIntervals};
+
 /// Degree-to-radian conversion factor
 pub const D2R: f64 = 0.017453292519943295;
 
@@ -63,10 +67,16 @@ impl GscBinning {
         }
     }
 
+    /// The total number of bins in this binning.
+    pub fn n_bins(&self) -> usize {
+        let last = &self.master_index[self.dec_bins - 1];
+        last.start_bin + last.num_bins
+    }
+
     /// Given a declination in degrees, get the declination bin number for this
     /// binning. The result is between 0 and `dec_bins`.
     pub fn get_dec_bin(&self, dec: f64) -> usize {
-        if dec < -90. || dec > 90. {
+        if !(-90. ..=90.).contains(&dec) {
             panic!("illegal declination {dec}");
         }
 
@@ -81,16 +91,10 @@ impl GscBinning {
 
     /// Given a declination bin number (between 0 and `dec_bins`) and an RA in
     /// degrees, get the "total" bin number associated with the RA. The result
-    /// is between 0 and `total_gsc_bins`.
-    pub fn get_total_bin(&self, dec_bin: usize, mut ra_deg: f64) -> usize {
-        while ra_deg < 0. {
-            ra_deg += 360.;
-        }
-
-        while ra_deg > 360. {
-            ra_deg -= 360.;
-        }
-
+    /// is between 0 and `total_gsc_bins`. The RA is normalized, so that RA =
+    /// 360 lands in the same bin as RA = 0.
+    pub fn get_total_bin(&self, dec_bin: usize, ra_deg: f64) -> usize {
+        let ra_deg = normalize_ra(ra_deg);
         let bin_info = &self.master_index[dec_bin];
         let mut delta_bin = (ra_deg * bin_info.num_bins as f64 / 360.) as usize;
 
@@ -100,4 +104,70 @@ impl GscBinning {
 
         bin_info.start_bin + delta_bin
     }
+
+    /// Get the "total" bin containing a position, in degrees.
+    pub fn get_bin(&self, ra_deg: f64, dec_deg: f64) -> usize {
+        self.get_total_bin(self.get_dec_bin(dec_deg), ra_deg)
+    }
+
+    /// Get the RA/Dec box covered by a "total" bin, as `(ra_min, ra_max,
+    /// dec_min, dec_max)` in degrees.
+    pub fn get_bin_bounds(&self, total_bin: usize) -> (f64, f64, f64, f64) {
+        let dec_bin = self
+            .master_index
+            .partition_point(|b| b.start_bin <= total_bin)
+            .saturating_sub(1);
+        let bin_info = &self.master_index[dec_bin];
+        let ra_size = 360. / bin_info.num_bins as f64;
+        let ra_min = (total_bin - bin_info.start_bin) as f64 * ra_size;
+        let dec_min = dec_bin as f64 * self.bin_size - 90.;
+        (ra_min, ra_min + ra_size, dec_min, dec_min + self.bin_size)
+    }
+
+    /// Get the range of "total" bin numbers in the given declination bin that
+    /// cover the RA interval from `ra_min` to `ra_max`, in degrees. The
+    /// interval should not wrap: we should have `0 <= ra_min <= ra_max <=
+    /// 360`. Unlike `get_total_bin`, an `ra_max` of 360 is interpreted as the
+    /// end of the declination bin, not its start.
+    pub fn get_total_bin_range(
+        &self,
+        dec_bin: usize,
+        ra_min: f64,
+        ra_max: f64,
+    ) -> RangeInclusive<usize> {
+        let start = self.get_total_bin(dec_bin, ra_min);
+
+        let end = if ra_max >= 360. {
+            let bin_info = &self.master_index[dec_bin];
+            bin_info.start_bin + bin_info.num_bins - 1
+        } else {
+            self.get_total_bin(dec_bin, ra_max)
+        };
+
+        start..=end
+    }
+
+    /// Enumerate the "total" bins that overlap a cone of the given radius
+    /// around the specified position, all in degrees.
+    ///
+    /// The enumeration is actually done for the circumscribing RA/Dec box, so
+    /// the result may include some bins that don't quite overlap the cone.
+    /// Near the poles, the box covers all RAs. The result is sorted and has no
+    /// duplicates.
+    pub fn get_bins_in_cone(&self, ra_deg: f64, dec_deg: f64, radius_deg: f64) -> Vec<usize> {
+        let min_dec = f64::max(dec_deg - radius_deg, -90.0);
+        let max_dec = f64::min(dec_deg + radius_deg, 90.0);
+        let ra_intervals = RaIntervals::for_box(normalize_ra(ra_deg), dec_deg, radius_deg);
+        let mut bins = Vec::new();
+
+        for dec_bin in self.get_dec_bin(min_dec)..=self.get_dec_bin(max_dec) {
+            for ra_range in ra_intervals.iter() {
+                bins.extend(self.get_total_bin_range(dec_bin, *ra_range.start(), *ra_range.end()));
+            }
+        }
+
+        bins.sort_unstable();
+        bins.dedup();
+        bins
+    }
 }
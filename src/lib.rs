@@ -6,6 +6,8 @@
 //! `dasch-science-lambda-proxyevent`. The first two are useful for local
 //! testing. while the last has support for the more complex AWS API Gateway
 //! "proxy event" framework that we for our actual cloud deployment.
+//! `dasch-science-lambda-sqs` is a batch worker that takes its requests from an
+//! SQS queue instead; see `sqsworker.rs`.
 //!
 //! It was hard to find good examples of how a Rust Lambda implementation should
 //! look. Here's one good one:
@@ -17,56 +19,349 @@
 //!
 //! Annoyingly, the buffered response mechanism can *only* output JSON, so we
 //! can't emit CSV.
+//!
+//! Every service wraps its result in a common envelope that echoes the
+//! interpreted request; see `envelope.rs`.
 
-use lambda_runtime::{tracing, Error};
+use lambda_runtime::{
+    tracing::{self, Instrument},
+    Error,
+};
+use once_cell::sync::{Lazy, OnceCell};
 use serde_json::Value;
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
+mod admission;
+mod apiversion;
+mod backend;
+mod bufpool;
+mod capacity;
+mod checkbin;
+#[cfg(feature = "client")]
+pub mod client;
+mod config;
+mod continuation;
+mod coords;
+pub mod cors;
 mod cutout;
+mod deadline;
+mod denylist;
+mod diskcache;
+mod dryrun;
+mod envelope;
+mod ephemeris;
+mod exportheaders;
+mod features;
+mod filter;
 mod fitsfile;
+mod fitspool;
+pub mod fixtures;
+mod formatting;
 mod gscbin;
+mod lightcurve;
 mod mosaics;
+mod negcache;
+mod observing;
+mod overlapcache;
+mod photcal;
+mod platecache;
+mod poserr;
+mod preview;
+mod provenance;
+#[cfg(feature = "elasticache")]
+mod querycache;
 mod querycat;
+mod queryepoch;
 mod queryexps;
+mod querystring;
+pub mod ratelimit;
+mod recommend;
+mod refcats;
 mod refnums;
+mod registry;
+mod residuals;
+mod rowcap;
 mod s3buffer;
 mod s3fits;
+mod selftest;
+mod seriesdefaults;
+mod snapshot;
+mod soda;
+mod sqsworker;
+mod staging;
+mod stampstats;
+mod stepfunctions;
+mod targetlists;
+mod targets;
+mod timeutil;
+mod validation;
+mod warnings;
 mod wcs;
+mod xray;
 
-pub const ENVIRONMENT: &str = "dev";
-
-pub const BUCKET: &str = "dasch-prod-user";
+pub use admission::BusyError;
+pub use envelope::{error_body, DetailedError};
+pub use provenance::OPERATOR;
+pub use registry::ForbiddenError;
+pub use snapshot::PlatesSource;
 
+/// Shared state for the DASCH science data Lambda services.
+///
+/// Each Lambda function instance only ever handles one of our APIs, and the
+/// different APIs need different resources, so everything beyond the basic AWS
+/// configuration is constructed lazily upon first use. This keeps cold starts
+/// (which users see as first-request latency) as cheap as possible.
+///
+/// All access to DynamoDB and S3 goes through the `tables` and `objects`
+/// backends, which can be swapped out for on-disk fixtures; see
+/// [`crate::fixtures`].
 pub struct Services {
-    dc: aws_sdk_dynamodb::Client,
-    s3c: aws_sdk_s3::Client,
-    bin1: gscbin::GscBinning,
-    bin64: gscbin::GscBinning,
+    aws_config: aws_config::SdkConfig,
+    config: config::Config,
+    tables: Arc<dyn backend::TableStore>,
+    objects: Arc<dyn backend::ObjectStore>,
+    bin1: OnceCell<gscbin::GscBinning>,
+    bin64: OnceCell<gscbin::GscBinning>,
+    fits_driver: OnceCell<()>,
+    sfn: OnceCell<aws_sdk_sfn::Client>,
+    sns: OnceCell<aws_sdk_sns::Client>,
+    events: OnceCell<aws_sdk_eventbridge::Client>,
+    plates: platecache::PlateCache,
+    buffers: bufpool::BufferPool,
+    fits_pool: Arc<fitspool::FitsPool>,
+    admission: admission::Admission,
+    rate_limiter: ratelimit::RateLimiter,
+    cors: cors::Cors,
+    deny_list: tokio::sync::OnceCell<denylist::DenyList>,
+    series_defaults: tokio::sync::OnceCell<seriesdefaults::SeriesDefaults>,
 }
 
 impl Services {
     /// Create a state object for the DASCH science data Lambda services.
     pub async fn init() -> Result<Self, Error> {
-        tracing_subscriber::fmt()
-            .with_max_level(tracing::Level::INFO)
-            .with_target(false) // don't print the module name
-            .without_time() // don't print time (CloudWatch has it)
+        use tracing_subscriber::{filter::LevelFilter, prelude::*};
+
+        tracing_subscriber::registry()
+            .with(LevelFilter::INFO)
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .with_target(false) // don't print the module name
+                    .without_time(), // don't print time (CloudWatch has it)
+            )
+            .with(xray::XRayLayer::from_env())
             .init();
 
-        let config = aws_config::load_from_env().await;
+        let aws_config = aws_config::load_from_env().await;
+        let config = config::Config::from_env();
+        let store = Arc::new(backend::AwsStore::new(&aws_config, &config));
+        let tables: Arc<dyn backend::TableStore> = store.clone();
+
+        #[cfg(feature = "elasticache")]
+        let tables = querycache::CachedTableStore::wrap(tables, &config);
+
+        if config.token_secrets.is_empty() {
+            tracing::warn!(
+                "DASCH_TOKEN_SECRETS is not set, so truncated queries can't be continued"
+            );
+        }
+
+        #[cfg(not(feature = "elasticache"))]
+        if config.query_cache_url.is_some() {
+            tracing::warn!(
+                "DASCH_QUERY_CACHE_URL is set, but this build lacks the `elasticache` feature"
+            );
+        }
+
+        Ok(Self::with_backends(aws_config, config, tables, store))
+    }
+
+    /// Create a state object that serves all data from the fixture directory
+    /// `dir`, without contacting AWS at all. Logging is not set up.
+    #[cfg(feature = "fixtures")]
+    pub fn from_fixtures<P: Into<PathBuf>>(dir: P) -> Self {
+        let aws_config = aws_config::SdkConfig::builder().build();
+        let store = Arc::new(fixtures::FixtureStore::new(dir));
+
+        let config = config::Config {
+            token_secrets: vec!["dasch-fixture-token-secret".to_owned()],
+            ..Default::default()
+        };
+
+        // CFITSIO will open local files directly, so there's no need for our
+        // S3 driver.
+        let services = Self::with_backends(aws_config, config, store.clone(), store);
+        services.fits_driver.get_or_init(|| ());
+        services
+    }
+
+    fn with_backends(
+        aws_config: aws_config::SdkConfig,
+        config: config::Config,
+        tables: Arc<dyn backend::TableStore>,
+        objects: Arc<dyn backend::ObjectStore>,
+    ) -> Self {
+        let tables = snapshot::SnapshotTableStore::wrap(tables, objects.clone(), &config);
+        let store = Arc::new(dryrun::DryRunStore::new(tables, objects));
+        let (tables, objects) = negcache::NegativeCacheStore::wrap(store.clone(), store, &config);
+
+        Services {
+            admission: admission::Admission::new(&config, registry::HANDLERS),
+            rate_limiter: ratelimit::RateLimiter::new(&config),
+            cors: cors::Cors::new(&config),
+            fits_pool: Arc::new(fitspool::FitsPool::new(config.fits_pool_size)),
+            deny_list: Default::default(),
+            series_defaults: Default::default(),
+            aws_config,
+            config,
+            tables,
+            objects,
+            bin1: OnceCell::new(),
+            bin64: OnceCell::new(),
+            fits_driver: OnceCell::new(),
+            sfn: OnceCell::new(),
+            sns: OnceCell::new(),
+            events: OnceCell::new(),
+            plates: Default::default(),
+            buffers: Default::default(),
+        }
+    }
+
+    /// Record all of the data that we fetch into the fixture directory `dir`.
+    /// See [`crate::fixtures::Recorder`].
+    pub fn record_to<P: Into<PathBuf>>(self, dir: P) -> Self {
+        let recorder = Arc::new(fixtures::Recorder::new(
+            self.tables.clone(),
+            self.objects.clone(),
+            dir,
+        ));
+
+        Services {
+            tables: recorder.clone(),
+            objects: recorder,
+            ..self
+        }
+    }
+
+    /// Record cutout provenance into the DynamoDB table `table`, whatever the
+    /// configuration says. See `provenance.rs`.
+    pub fn with_provenance_table(mut self, table: &str) -> Self {
+        self.config.provenance_table = Some(table.to_owned());
+        self
+    }
 
-        s3fits::register(config.clone());
+    /// Cache `queryexps` overlap tests in the DynamoDB table `table`,
+    /// whatever the configuration says. See `overlapcache.rs`.
+    pub fn with_overlap_cache_table(mut self, table: &str) -> Self {
+        self.config.overlap_cache_table = Some(table.to_owned());
+        self
+    }
 
-        let dc = aws_sdk_dynamodb::Client::new(&config);
-        let s3c = aws_sdk_s3::Client::new(&config);
-        let bin1 = gscbin::GscBinning::new1();
-        let bin64 = gscbin::GscBinning::new64();
+    /// Read plate metadata from `source`, whatever the configuration says. See
+    /// `snapshot.rs`.
+    pub fn with_plates_source(mut self, source: PlatesSource) -> Self {
+        self.config.plates_source = source;
+        self.tables = snapshot::SnapshotTableStore::wrap(
+            self.tables.clone(),
+            self.objects.clone(),
+            &self.config,
+        );
+        self
+    }
+
+    /// Cache refcat queries in the Redis server at `url`, whatever the
+    /// configuration says. See `querycache.rs`.
+    #[cfg(feature = "elasticache")]
+    pub fn with_query_cache(mut self, url: &str) -> Self {
+        self.config.query_cache_url = Some(url.to_owned());
+        self.tables = querycache::CachedTableStore::wrap(self.tables.clone(), &self.config);
+        self
+    }
 
-        Ok(Services {
-            dc,
-            s3c,
-            bin1,
-            bin64,
-        })
+    /// The 1-degree GSC binning, used for the coverage-bin files.
+    fn bin1(&self) -> &gscbin::GscBinning {
+        self.bin1.get_or_init(gscbin::GscBinning::new1)
+    }
+
+    /// The 1/64-degree GSC binning, used for the refcat tables. This one is
+    /// relatively expensive to construct.
+    fn bin64(&self) -> &gscbin::GscBinning {
+        self.bin64.get_or_init(gscbin::GscBinning::new64)
+    }
+
+    /// Whether the client with the given identity may call the service named
+    /// by a Lambda function ARN. Admin-only services are restricted to the
+    /// API keys listed in the configuration, and to [`OPERATOR`]; everything
+    /// else is open. [`Self::dispatch_as`] checks this for every invocation.
+    /// Identities are as used by the rate limiter: `key:<API key ID>` for
+    /// clients with API keys.
+    pub fn is_authorized(&self, arn: &str, identity: &str) -> bool {
+        match registry::lookup(arn) {
+            Some(h) if h.admin_only => self.is_admin(identity),
+            _ => true,
+        }
+    }
+
+    fn is_admin(&self, identity: &str) -> bool {
+        identity == provenance::OPERATOR
+            || identity
+                .strip_prefix("key:")
+                .is_some_and(|k| self.config.admin_api_keys.iter().any(|a| a == k))
+    }
+
+    /// The per-client rate limiter. Only the proxy-event server, which knows
+    /// who its clients are, uses it.
+    pub fn rate_limiter(&self) -> &ratelimit::RateLimiter {
+        &self.rate_limiter
+    }
+
+    /// The CORS policy, applied by the proxy-event server.
+    pub fn cors(&self) -> &cors::Cors {
+        &self.cors
+    }
+
+    /// The plate deny-list, loaded on first use.
+    async fn deny_list(&self) -> Result<&denylist::DenyList, Error> {
+        self.deny_list
+            .get_or_try_init(|| denylist::DenyList::load(&self.config, &*self.objects))
+            .await
+    }
+
+    /// The per-series default cutout parameters, loaded on first use.
+    async fn series_defaults(&self) -> Result<&seriesdefaults::SeriesDefaults, Error> {
+        self.series_defaults
+            .get_or_try_init(|| seriesdefaults::SeriesDefaults::load(&self.config, &*self.objects))
+            .await
+    }
+
+    /// The per-series defaults, for services that only use them for the
+    /// placeholder coordinate values of the series. Those are a refinement, so
+    /// if the table can't be loaded, these services carry on with the
+    /// built-in placeholders rather than failing.
+    async fn series_placeholders(&self) -> &seriesdefaults::SeriesDefaults {
+        static BUILTIN: Lazy<seriesdefaults::SeriesDefaults> = Lazy::new(Default::default);
+
+        match self.series_defaults().await {
+            Ok(d) => d,
+            Err(e) => {
+                tracing::warn!("using only the built-in placeholder coordinates: {e}");
+                &BUILTIN
+            }
+        }
+    }
+
+    /// Make sure that our S3 driver is registered with CFITSIO. This must be
+    /// done before opening any `s3://` URLs.
+    fn ensure_fits_driver(&self) {
+        self.fits_driver.get_or_init(|| {
+            s3fits::register(
+                backend::s3_client_config(&self.aws_config, &self.config),
+                diskcache::DiskCache::from_config(&self.config),
+            )
+        });
     }
 
     /// Handle an invocation of one of the DASCH science APIs.
@@ -83,20 +378,336 @@ impl Services {
     /// `_HANDLER` environment variable should tell us what function we are, but
     /// with our deployment method, it's always set to `bootstrap`. This is almost
     /// surely all about my ignorance of how Lambda works.
-    pub async fn dispatch(&self, mut arn: String, payload: Option<Value>) -> Result<Value, Error> {
+    pub async fn dispatch(&self, arn: String, payload: Option<Value>) -> Result<Value, Error> {
+        self.dispatch_until(arn, payload, None).await
+    }
+
+    /// Like [`Self::dispatch`], but with a time limit for the invocation. The
+    /// long-running services will try to return partial results, rather than
+    /// run past it; see `deadline.rs`.
+    pub async fn dispatch_until(
+        &self,
+        arn: String,
+        payload: Option<Value>,
+        limit: Option<Instant>,
+    ) -> Result<Value, Error> {
+        self.dispatch_as(arn, payload, limit, provenance::ANONYMOUS)
+            .await
+    }
+
+    /// Like [`Self::dispatch_until`], for an invocation that may come from an
+    /// AWS Step Functions task. The request may be nested in the payload's
+    /// `Input` field, and if the payload carries a `TaskToken`, the outcome is
+    /// reported to the waiting task as well as returned. See
+    /// `stepfunctions.rs`.
+    pub async fn dispatch_task(
+        &self,
+        arn: String,
+        payload: Option<Value>,
+        limit: Option<Instant>,
+    ) -> Result<Value, Error> {
+        let (payload, token) = stepfunctions::unpack(payload)?;
+        let result = self.dispatch_until(arn, payload, limit).await;
+
+        if let Some(token) = token {
+            let client = self
+                .sfn
+                .get_or_init(|| aws_sdk_sfn::Client::new(&self.aws_config));
+            stepfunctions::report(client, &token, &result).await?;
+        }
+
+        result
+    }
+
+    /// Handle an SQS event, running each of its messages as a request and
+    /// saving the results to S3. The returned value lists the messages that
+    /// should be delivered again. See `sqsworker.rs`.
+    pub async fn dispatch_sqs(&self, event: Value, limit: Option<Instant>) -> Result<Value, Error> {
+        let messages = sqsworker::messages(&event)?;
+
+        let outcomes = futures::future::join_all(messages.iter().map(|msg| async move {
+            match self.run_job(msg, limit).await {
+                Ok(()) => None,
+                Err(e) => {
+                    tracing::warn!("batch message {} will be retried: {e}", msg.id);
+                    Some(msg.id.clone())
+                }
+            }
+        }))
+        .await;
+
+        Ok(sqsworker::batch_response(
+            outcomes.into_iter().flatten().collect(),
+        ))
+    }
+
+    /// Run one request from the batch queue. Errors are for requests that
+    /// should be retried.
+    async fn run_job(&self, msg: &sqsworker::Message, limit: Option<Instant>) -> Result<(), Error> {
+        let start = Instant::now();
+
+        let (output, service, result) = match serde_json::from_str::<sqsworker::Job>(&msg.body) {
+            Ok(job) => {
+                let output = job.output.unwrap_or_else(|| msg.id.clone());
+
+                let result = if output.is_empty() {
+                    Err("illegal `output` parameter: must not be empty".into())
+                } else {
+                    self.dispatch_until(job.service.clone(), Some(job.request), limit)
+                        .await
+                };
+
+                (output, Some(job.service), result)
+            }
+
+            Err(e) => (
+                msg.id.clone(),
+                None,
+                Err(format!("illegal batch message: {e}").into()),
+            ),
+        };
+
+        if let Err(e) = &result {
+            if e.is::<BusyError>() {
+                return result.map(|_| ());
+            }
+        }
+
+        let stats = sqsworker::Stats::new(&result, start.elapsed());
+
+        let (body, error) = match result {
+            Ok(envelope) => (envelope, None),
+            Err(e) => (error_body(&e), Some(e.to_string())),
+        };
+
+        let (key, url) = sqsworker::save(&*self.objects, &self.config, &output, &body).await?;
+        let completion = sqsworker::Completion {
+            message_id: &msg.id,
+            output: &output,
+            service: service.as_deref(),
+            succeeded: error.is_none(),
+            error,
+            bucket: self.config.results_bucket(),
+            key: &key,
+            url,
+            stats,
+        };
+
+        if let Some(topic_arn) = &self.config.batch_topic_arn {
+            let client = self
+                .sns
+                .get_or_init(|| aws_sdk_sns::Client::new(&self.aws_config));
+            sqsworker::notify_sns(client, topic_arn, &completion).await?;
+        }
+
+        if let Some(bus) = &self.config.batch_event_bus {
+            let client = self
+                .events
+                .get_or_init(|| aws_sdk_eventbridge::Client::new(&self.aws_config));
+            sqsworker::notify_eventbridge(client, bus, &completion).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Like [`Self::dispatch_until`], on behalf of the client with the given
+    /// identity, as used by [`Self::is_authorized`]. The identity is recorded
+    /// in cutout provenance records, and selects the records that the
+    /// `history` service reports; see `provenance.rs`.
+    ///
+    /// Admin-only services are refused with a [`ForbiddenError`] unless the
+    /// identity is authorized to call them; see [`Self::is_authorized`]. Since
+    /// [`Self::dispatch`] and friends run requests anonymously, only callers
+    /// that identify their clients can reach those services.
+    ///
+    /// Requests to any service may set `dry_run`, in which case the result is
+    /// replaced by a report of the AWS operations that the request made; see
+    /// `dryrun.rs`.
+    pub async fn dispatch_as(
+        &self,
+        mut arn: String,
+        mut payload: Option<Value>,
+        limit: Option<Instant>,
+        identity: &str,
+    ) -> Result<Value, Error> {
         // Local testing environment?
         if arn.ends_with(":test_function") {
             arn = std::env::var("DASCH_LOCALTEST_ARN").unwrap();
         }
 
-        if arn.ends_with("cutout") {
-            Ok(cutout::handler(payload, &self.dc).await?)
-        } else if arn.ends_with("querycat") {
-            Ok(querycat::handler(payload, &self.dc, &self.bin64).await?)
-        } else if arn.ends_with("queryexps") {
-            Ok(queryexps::handler(payload, &self.dc, &self.s3c, &self.bin1).await?)
+        let handler = registry::lookup(&arn)
+            .ok_or_else(|| -> Error { format!("unhandled function: {}", arn).into() })?;
+
+        if handler.admin_only && !self.is_admin(identity) {
+            return Err(ForbiddenError {
+                service: handler.name,
+            }
+            .into());
+        }
+
+        let dry_run = dryrun::take_flag(&mut payload)?;
+        let _permit = self.admission.admit(handler).await?;
+        let span = tracing::info_span!("handler", service = handler.name, dry_run);
+        let deadline = deadline::Deadline::before(limit, self.config.deadline_margin);
+
+        // The handler futures are big enough that another layer of wrapping
+        // overflows the stack in debug builds, so this one goes on the heap.
+        let invocation = warnings::collect(Box::pin(self.dispatch_to(
+            handler.name,
+            payload,
+            deadline,
+            identity,
+        )))
+        .instrument(span);
+
+        let (result, units) = if dry_run {
+            capacity::measure(async {
+                let (result, operations) = dryrun::run(invocation).await;
+                let mut envelope = result?;
+                envelope["result"] = serde_json::to_value(dryrun::Report { operations })?;
+                Ok(envelope)
+            })
+            .await
         } else {
-            Err(format!("unhandled function: {}", arn).into())
+            capacity::measure(invocation).await
+        };
+
+        if let Some(ns) = &self.config.metrics_namespace {
+            capacity::emit_metric(ns, handler.name, units);
         }
+
+        result
     }
+
+    async fn dispatch_to(
+        &self,
+        name: &str,
+        payload: Option<Value>,
+        deadline: deadline::Deadline,
+        identity: &str,
+    ) -> Result<Value, Error> {
+        match name {
+            "cutout" => {
+                self.ensure_fits_driver();
+                Ok(cutout::handler(
+                    payload,
+                    &self.config,
+                    &*self.tables,
+                    &*self.objects,
+                    &self.plates,
+                    &self.buffers,
+                    &self.fits_pool,
+                    self.deny_list().await?,
+                    self.series_defaults().await?,
+                    identity,
+                    deadline,
+                )
+                .await?)
+            }
+
+            "querycat" => {
+                Ok(
+                    querycat::handler(payload, &self.config, &*self.tables, self.bin64(), deadline)
+                        .await?,
+                )
+            }
+
+            "lightcurve" => {
+                Ok(
+                    lightcurve::handler(payload, &self.config, &*self.objects, self.bin64())
+                        .await?,
+                )
+            }
+
+            "queryexps" => Ok(queryexps::handler(
+                payload,
+                &self.config,
+                &*self.tables,
+                &*self.objects,
+                self.bin1(),
+                self.bin64(),
+                &self.plates,
+                self.deny_list().await?,
+                self.series_defaults().await?,
+                deadline,
+            )
+            .await?),
+
+            "queryepoch" => Ok(queryepoch::handler(
+                payload,
+                &self.config,
+                &*self.tables,
+                self.deny_list().await?,
+                self.series_placeholders().await,
+                deadline,
+            )
+            .await?),
+
+            "selftest" => {
+                self.ensure_fits_driver();
+                Ok(selftest::handler(payload, &self.config, &*self.tables, &*self.objects).await?)
+            }
+
+            "checkbin" => {
+                Ok(checkbin::handler(payload, &self.config, &*self.tables, self.bin64()).await?)
+            }
+
+            "targetlist" => Ok(targetlists::handler(payload, &self.config, &*self.objects).await?),
+
+            "residuals" => {
+                Ok(residuals::handler(payload, &self.config, &*self.tables, &self.plates).await?)
+            }
+
+            "photcal" => Ok(photcal::handler(payload, &self.config, &*self.tables).await?),
+
+            "history" => {
+                Ok(provenance::handler(payload, &self.config, &*self.tables, identity).await?)
+            }
+
+            "exportheaders" => Ok(exportheaders::handler(
+                payload,
+                &self.config,
+                &*self.tables,
+                &*self.objects,
+                self.bin1(),
+                self.series_placeholders().await,
+                deadline,
+            )
+            .await?),
+
+            "describe" => Ok(envelope::wrap(
+                "describe",
+                &Value::Object(Default::default()),
+                registry::describe(&self.config),
+            )?),
+
+            other => Err(format!("service `{}` is registered but not dispatched", other).into()),
+        }
+    }
+}
+
+/// Convert a Lambda invocation deadline, given in milliseconds since the Unix
+/// epoch, into an `Instant` for [`Services::dispatch_until`].
+pub fn lambda_deadline(deadline_ms: u64) -> Option<Instant> {
+    let deadline = UNIX_EPOCH + Duration::from_millis(deadline_ms);
+    let remaining = deadline
+        .duration_since(SystemTime::now())
+        .unwrap_or_default();
+    Instant::now().checked_add(remaining)
+}
+
+/// The names of all of the services that [`Services::dispatch`] can run.
+pub fn service_names() -> impl Iterator<Item = &'static str> {
+    registry::HANDLERS.iter().map(|h| h.name)
+}
+
+/// Build the request payload for the service that a Lambda function ARN
+/// refers to from URL query parameters; see `querystring.rs`.
+pub fn query_request<'a>(
+    arn: &str,
+    params: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> Result<Value, Error> {
+    let handler = registry::lookup(arn)
+        .ok_or_else(|| -> Error { format!("unhandled function: {}", arn).into() })?;
+    querystring::request_from_query(handler.request_schema, params)
 }
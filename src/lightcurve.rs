@@ -0,0 +1,218 @@
+//! The raw lightcurve service.
+//!
+//! The DASCH photometry pipeline writes every detection of every catalog
+//! source to per-bin photometry files in the data bucket, with far more detail
+//! than the summary tables carry: the flags of each detection on each image,
+//! the local calibration that it was measured with, and so on. Power users
+//! want all of it, so this service returns a source's rows from those files as
+//! CSV.
+//!
+//! The files are binned like the refcat tables, by the 64-way GSC binning of
+//! the sources' catalog positions, so a request gives the `ref_number` of the
+//! source, along with its catalog position, as reported by `querycat`, to find
+//! its bin. The file for each reference catalog and bin is a gzipped CSV file
+//! at `Config::photometry_key`, whose first column is `ref_number`. The file
+//! is decompressed as a stream, and only the rows of the requested source are
+//! kept, in the order of the file. The other columns are passed through as
+//! they are, so the output columns are whatever the pipeline wrote.
+//!
+//! Results are capped at a maximum number of rows; see `rowcap.rs`. The
+//! continuation token of a capped result records how many rows it returned.
+
+use flate2::read::GzDecoder;
+use lambda_http::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::{BufRead, BufReader};
+
+use crate::{
+    backend::ObjectStore,
+    config::{default_data_release, Config},
+    continuation::{decode_token, encode_token, Binding},
+    envelope,
+    gscbin::GscBinning,
+    refcats,
+    rowcap::{estimate_total, RowCap, Truncation, TruncationReason},
+    validation::{self, validate_fields},
+};
+
+/// Sync with `json-schemas/lightcurve_request.json`.
+#[derive(Deserialize, Serialize)]
+pub struct Request {
+    /// The reference catalog of the source.
+    refcat: String,
+
+    /// The numeric reference number of the source.
+    ref_number: u64,
+
+    /// The catalog RA of the source, which determines its bin.
+    ra_deg: f64,
+
+    /// The catalog declination of the source.
+    dec_deg: f64,
+
+    /// The data release to query.
+    #[serde(default = "default_data_release")]
+    data_release: String,
+
+    /// A continuation token from a previous, truncated response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    continuation: Option<String>,
+
+    /// The maximum number of rows to return, if lower than the server's cap.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_rows: Option<usize>,
+}
+
+impl Request {
+    /// Validate the request.
+    pub fn normalize(mut self, config: &Config) -> Result<Self, Error> {
+        if refcats::lookup(&self.refcat).is_none() {
+            return Err("illegal refcat parameter".into());
+        }
+
+        config.check_release(&self.data_release)?;
+
+        validate_fields!(self {
+            ra_deg: validation::ra,
+            dec_deg: validation::dec,
+            max_rows: validation::optional(|n, v| validation::range(n, v, 1..)),
+        });
+
+        if let Some(token) = &self.continuation {
+            decode_token(config, &Binding::new("lightcurve", &self)?, token)?;
+        }
+
+        Ok(self)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Response {
+    /// CSV-formatted rows of results, starting with a header row.
+    pub rows: Vec<String>,
+
+    /// If true, the results are incomplete, because they hit the row cap.
+    pub truncated: bool,
+
+    /// If the results are truncated, a token that can be passed back to
+    /// continue the query.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub continuation: Option<String>,
+
+    /// The truncation state, for the envelope.
+    #[serde(skip)]
+    pub truncation: Truncation,
+}
+
+impl Response {
+    fn new(rows: Vec<String>, truncation: Truncation) -> Self {
+        Response {
+            rows,
+            truncated: truncation.truncated,
+            continuation: truncation.continuation.clone(),
+            truncation,
+        }
+    }
+}
+
+pub async fn handler(
+    req: Option<Value>,
+    config: &Config,
+    objects: &dyn ObjectStore,
+    binning: &GscBinning,
+) -> Result<Value, Error> {
+    let request: Request =
+        serde_json::from_value(req.ok_or_else(|| -> Error { "no request payload".into() })?)?;
+    let request = request.normalize(config)?;
+    let echo = serde_json::to_value(&request)?;
+    let result = implementation(request, config, objects, binning).await?;
+    let truncation = result.truncation.clone();
+    envelope::wrap_table("lightcurve", &echo, result, truncation)
+}
+
+/// Get the raw photometry of a source. The request must have been normalized.
+pub async fn implementation(
+    request: Request,
+    config: &Config,
+    objects: &dyn ObjectStore,
+    binning: &GscBinning,
+) -> Result<Response, Error> {
+    let binding = Binding::new("lightcurve", &request)?;
+    let total_bin = binning.get_bin(request.ra_deg, request.dec_deg);
+    let key = config.photometry_key(&request.data_release, &request.refcat, total_bin);
+
+    let body = objects
+        .get_object(&config.bucket, &key)
+        .await?
+        .ok_or_else(|| -> Error {
+            format!(
+                "no {} photometry is available around RA = {}, Dec = {}",
+                request.refcat, request.ra_deg, request.dec_deg
+            )
+            .into()
+        })?;
+
+    let malformed = |e: &dyn std::fmt::Display| -> Error {
+        format!("malformed photometry file `{key}`: {e}").into()
+    };
+
+    let mut lines = BufReader::new(GzDecoder::new(&body[..])).lines();
+
+    let header = match lines.next() {
+        Some(line) => line.map_err(|e| malformed(&e))?,
+        None => return Err(malformed(&"it is empty")),
+    };
+
+    if header.split(',').next() != Some("ref_number") {
+        return Err(malformed(&"its first column is not `ref_number`"));
+    }
+
+    let skip = match &request.continuation {
+        Some(token) => decode_token(config, &binding, token)?.1,
+        None => 0,
+    };
+    let prefix = format!("{},", request.ref_number);
+    let mut rows = Vec::new();
+
+    for line in lines {
+        let line = line.map_err(|e| malformed(&e))?;
+
+        if line.starts_with(&prefix) {
+            rows.push(line);
+        }
+    }
+
+    if rows.is_empty() && skip == 0 {
+        return Err(format!(
+            "{} source {} has no photometry in its bin; check its position",
+            request.refcat, request.ref_number
+        )
+        .into());
+    }
+
+    rows.drain(..usize::min(skip, rows.len()));
+    let n_new = rows.len();
+    let mut cap = RowCap::new(config, request.max_rows, false);
+    let n_fit = cap.admit(n_new);
+    let n_rows = cap.n_rows();
+
+    let mut lines = Vec::with_capacity(n_fit + 1);
+    lines.push(header);
+    lines.extend(rows.into_iter().take(n_fit));
+
+    if n_fit < n_new {
+        return Ok(Response::new(
+            lines,
+            Truncation {
+                truncated: true,
+                reason: Some(TruncationReason::MaxRows),
+                n_rows,
+                estimated_total_rows: estimate_total(n_rows, n_new - n_fit, 1, 0),
+                continuation: Some(encode_token(config, &binding, 0, skip + n_fit)?),
+            },
+        ));
+    }
+
+    Ok(Response::new(lines, Truncation::complete(n_rows)))
+}
@@ -5,14 +5,17 @@
 //! to maintain those to keep data transfer sizes minimal.
 
 use anyhow::{bail, Result};
-use lambda_http::Error;
+use libc::c_int;
 use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
-    io::{prelude::*, ErrorKind},
+    fmt,
+    io::prelude::*,
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
-use crate::wcs::WcsCollection;
+use crate::wcs::{HeaderOptions, WcsCollection};
 
 pub const PIXELS_PER_MM: f64 = 90.9090;
 
@@ -95,6 +98,155 @@ pub static PLATE_SCALE_BY_SERIES: Lazy<HashMap<String, f64>> = Lazy::new(|| {
     .collect()
 });
 
+/// A validated DASCH plate identifier, like `b01268`: a series name followed
+/// by a plate number, zero-padded to five digits.
+///
+/// Parsing is lenient about case, whitespace, and padding, so `B 1268` parses
+/// to `b01268`, but the series must be one that we know about. This lets us
+/// give users precise errors for malformed IDs, rather than just failing to
+/// find them in the database.
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PlateId {
+    text: String,
+    series_len: usize,
+}
+
+impl PlateId {
+    /// The normalized text of the identifier.
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    /// The plate series.
+    pub fn series(&self) -> &str {
+        &self.text[..self.series_len]
+    }
+
+    /// The plate number within the series.
+    pub fn number(&self) -> u32 {
+        // Validated on construction.
+        self.text[self.series_len..].parse().unwrap()
+    }
+}
+
+impl std::str::FromStr for PlateId {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        let bad = || {
+            format!(
+                "malformed plate_id `{s}`: expected a series name followed by a plate number, like `b01268`"
+            )
+        };
+
+        let compact: String = s
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>()
+            .to_lowercase();
+        let series_len = compact.find(|c: char| c.is_ascii_digit()).ok_or_else(bad)?;
+        let (series, number) = compact.split_at(series_len);
+
+        if series.is_empty() || !series.chars().all(|c| c.is_ascii_lowercase()) {
+            return Err(bad());
+        }
+
+        if !number.chars().all(|c| c.is_ascii_digit()) {
+            return Err(bad());
+        }
+
+        let number: u32 = number.parse().map_err(|_| bad())?;
+
+        if !PLATE_SCALE_BY_SERIES.contains_key(series) {
+            return Err(format!("unknown plate series `{series}` in plate_id `{s}`"));
+        }
+
+        Ok(PlateId {
+            text: format!("{series}{number:05}"),
+            series_len,
+        })
+    }
+}
+
+impl TryFrom<String> for PlateId {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, String> {
+        s.parse()
+    }
+}
+
+impl From<PlateId> for String {
+    fn from(p: PlateId) -> String {
+        p.text
+    }
+}
+
+impl fmt::Display for PlateId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// Sentinel coordinate values that appear in the data in place of actual
+/// positions.
+///
+/// Every policy recognizes the built-in values, the placeholders observed in
+/// the plates and refcat tables. The series metadata table can list more,
+/// observed in the records of particular series (see `seriesdefaults.rs`),
+/// which only apply to the exposures of those series. The refcat tables just
+/// use [`BUILTIN_PLACEHOLDERS`]. A position is a placeholder if *either* of
+/// its coordinates matches.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlaceholderPolicy {
+    #[serde(default)]
+    ra_deg: Vec<f64>,
+
+    #[serde(default)]
+    dec_deg: Vec<f64>,
+}
+
+const BUILTIN_PLACEHOLDER_RA_DEG: [f64; 2] = [999., -99.];
+const BUILTIN_PLACEHOLDER_DEC_DEG: [f64; 2] = [99., -99.];
+
+impl PlaceholderPolicy {
+    /// Test whether the given position is a placeholder, rather than a real
+    /// position.
+    pub fn is_placeholder(&self, ra_deg: f64, dec_deg: f64) -> bool {
+        BUILTIN_PLACEHOLDER_RA_DEG.contains(&ra_deg)
+            || BUILTIN_PLACEHOLDER_DEC_DEG.contains(&dec_deg)
+            || self.ra_deg.contains(&ra_deg)
+            || self.dec_deg.contains(&dec_deg)
+    }
+}
+
+/// The policy with only the built-in placeholder values.
+pub static BUILTIN_PLACEHOLDERS: PlaceholderPolicy = PlaceholderPolicy {
+    ra_deg: Vec::new(),
+    dec_deg: Vec::new(),
+};
+
+/// The number of b01 headers that we have failed to load in this process. This
+/// is a diagnostic counter: nonzero values indicate database problems that
+/// need fixing.
+pub static UNREADABLE_HEADER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// An error indicating that a plate's stored astrometric header could not be
+/// read. This generally indicates that the database record is corrupted or
+/// truncated.
+#[derive(Debug)]
+pub struct UnreadableHeaderError(String);
+
+impl fmt::Display for UnreadableHeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "astrometry_unreadable: {}", self.0)
+    }
+}
+
+impl std::error::Error for UnreadableHeaderError {}
+
 /// The bin01 header is stored in the DynamoDB as bytes, which are gzipped text
 /// of an ASCII FITS header file. This file consists of 80-character lines of
 /// header text, separated by newlines, without a trailing newline.
@@ -106,51 +258,87 @@ pub static PLATE_SCALE_BY_SERIES: Lazy<HashMap<String, f64>> = Lazy::new(|| {
 /// We *also* need to hack the headers because wcslib only accepts our
 /// distortion terms if the `CTYPEn` values end with `-TPV`; it seems that the
 /// pipeline, which is based on wcstools/libwcs, generates non-standard headers.
-pub fn load_b01_header<R: Read>(mut src: R) -> Result<WcsCollection, Error> {
-    let mut header = Vec::new();
-    let mut n_rec = 0;
-    let mut buf = vec![0; 80];
-
-    loop {
-        // The final record does not have a newline character,
-        // so we can't read in chunks of 81.
-
-        if let Err(e) = src.read_exact(&mut buf[..]) {
-            if e.kind() == ErrorKind::UnexpectedEof {
-                break;
-            } else {
-                return Err(e.into());
-            }
+///
+/// Many early-pipeline headers also have irregular values, like old-style dates
+/// and nonstandard unit strings, that degrade or break the transforms. After
+/// parsing, we let wcslib's `wcsfix` repair these; the fixes that it applied
+/// are available from [`WcsCollection::fixes`].
+///
+/// We're careful to detect corrupted data: an invalid or truncated gzip
+/// stream, or a header that isn't a whole number of records, yields an
+/// [`UnreadableHeaderError`] rather than a partial header.
+///
+/// The `options` control how strictly wcslib parses the header, whether it
+/// reports the keywords that it rejects, and whether the header is repaired;
+/// see [`HeaderOptions`].
+pub fn load_b01_header<R: Read>(
+    src: R,
+    options: HeaderOptions,
+) -> Result<WcsCollection, UnreadableHeaderError> {
+    let result = parse_b01_header(src, options);
+
+    if let Err(ref e) = result {
+        UNREADABLE_HEADER_COUNT.fetch_add(1, Ordering::Relaxed);
+        eprintln!("failed to load b01 header: {}", e);
+    }
+
+    result
+}
+
+fn parse_b01_header<R: Read>(
+    mut src: R,
+    options: HeaderOptions,
+) -> Result<WcsCollection, UnreadableHeaderError> {
+    let mut text = Vec::new();
+
+    src.read_to_end(&mut text)
+        .map_err(|e| UnreadableHeaderError(format!("could not decompress header: {}", e)))?;
+
+    if text.is_empty() {
+        return Err(UnreadableHeaderError("header is empty".to_owned()));
+    }
+
+    // The final record does not have a newline character.
+    let n_rec = (text.len() + 1) / 81;
+
+    if n_rec * 81 != text.len() + 1 {
+        return Err(UnreadableHeaderError(format!(
+            "header is {} bytes long, which is not a whole number of records (truncated?)",
+            text.len()
+        )));
+    }
+
+    let mut header = Vec::with_capacity(n_rec * 80);
+
+    for (i_rec, chunk) in text.chunks(81).enumerate() {
+        let (buf, sep) = chunk.split_at(80);
+
+        if !sep.is_empty() && sep[0] != b'\n' {
+            return Err(UnreadableHeaderError(format!(
+                "malformatted ASCII-FITS header: expected newline after record {}, got {:x}",
+                i_rec, sep[0]
+            )));
         }
 
+        let start = header.len();
+        header.extend_from_slice(buf);
+
         // TAN/TPV hack. With the rigid FITS keyword structure, we know exactly where to
         // look:
         if buf.starts_with(b"CTYPE") && buf[15..].starts_with(b"-TAN") {
-            buf[15..19].clone_from_slice(b"-TPV");
+            header[start + 15..start + 19].clone_from_slice(b"-TPV");
         }
+    }
 
-        header.append(&mut buf);
-        n_rec += 1;
-        buf.resize(80, 0); // the `append` truncates `buf`
-
-        if let Err(e) = src.read_exact(&mut buf[..1]) {
-            if e.kind() == ErrorKind::UnexpectedEof {
-                break;
-            } else {
-                return Err(e.into());
-            }
-        }
+    let mut wcs =
+        unsafe { WcsCollection::new_raw(header.as_ptr() as *const _, n_rec as c_int, options) }
+            .map_err(|e| UnreadableHeaderError(format!("could not parse header: {}", e)))?;
 
-        if buf[0] != b'\n' {
-            return Err(format!(
-                "malformatted ASCII-FITS header: expected newline, got {:x}",
-                buf[0]
-            )
-            .into());
-        }
+    if !options.skip_fix {
+        wcs.fix();
     }
 
-    Ok(unsafe { WcsCollection::new_raw(header.as_ptr() as *const _, n_rec) }?)
+    Ok(wcs)
 }
 
 /// DASCH WCS headers are constructed as follows: if there's only one solution,
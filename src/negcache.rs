@@ -0,0 +1,342 @@
+//! Brief caching of lookups that found nothing.
+//!
+//! A misbehaving client -- typically a daschlab session retrying in a tight
+//! loop -- can ask for the same nonexistent plate, or search the same empty
+//! patch of sky, over and over. Each attempt costs a DynamoDB read or a couple
+//! of S3 GETs that we know will come back empty. So the [`NegativeCacheStore`]
+//! wraps the storage backends and remembers, for `Config::negative_cache_ttl`,
+//! which table items and S3 objects turned out not to exist, answering
+//! repeated lookups of them without contacting AWS. Setting the TTL to zero
+//! turns this off.
+//!
+//! Only misses are cached: lookups that find something, and queries, always go
+//! through. The TTL is kept short so that newly ingested plates and bins show
+//! up quickly, and writes through the store forget any cached miss of what
+//! they write. Misses of target lists and staged results are never cached,
+//! since clients upload target lists behind our back, and may well check on
+//! them before they're done.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use futures::future::BoxFuture;
+use lambda_http::Error;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    backend::{key_text, BatchGetOutput, Item, ObjectStore, TableStore},
+    config::Config,
+};
+
+/// The maximum number of misses to remember.
+const MAX_ENTRIES: usize = 4096;
+
+/// A set of recent misses, keyed by table or bucket and item or object key.
+#[derive(Debug)]
+struct Misses {
+    ttl: Duration,
+    entries: Mutex<HashMap<(String, String), Instant>>,
+}
+
+impl Misses {
+    fn new(ttl: Duration) -> Self {
+        Misses {
+            ttl,
+            entries: Default::default(),
+        }
+    }
+
+    /// Whether a lookup recently found nothing.
+    fn contains(&self, place: &str, key: &str) -> bool {
+        let entries = self.entries.lock().unwrap();
+
+        entries
+            .get(&(place.to_owned(), key.to_owned()))
+            .is_some_and(|t| t.elapsed() < self.ttl)
+    }
+
+    /// Record that a lookup found nothing.
+    fn insert(&self, place: &str, key: &str) {
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, t| t.elapsed() < self.ttl);
+        }
+
+        if entries.len() >= MAX_ENTRIES {
+            // Still full: evict the oldest miss. This is a linear scan, but it
+            // should be rare.
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, t)| **t)
+                .map(|(k, _)| k.clone());
+
+            if let Some(k) = oldest {
+                entries.remove(&k);
+            }
+        }
+
+        entries.insert((place.to_owned(), key.to_owned()), Instant::now());
+    }
+
+    /// Forget a miss, because the thing has been written.
+    fn remove(&self, place: &str, key: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(&(place.to_owned(), key.to_owned()));
+    }
+}
+
+/// A wrapper around the storage backends that remembers lookups that found
+/// nothing.
+pub struct NegativeCacheStore {
+    tables: Arc<dyn TableStore>,
+    objects: Arc<dyn ObjectStore>,
+
+    /// The buckets and key prefixes of the objects not to cache.
+    uncached: Vec<(String, String)>,
+    items: Misses,
+    blobs: Misses,
+}
+
+impl NegativeCacheStore {
+    /// Wrap the backends, if negative caching is enabled in `config`.
+    pub fn wrap(
+        tables: Arc<dyn TableStore>,
+        objects: Arc<dyn ObjectStore>,
+        config: &Config,
+    ) -> (Arc<dyn TableStore>, Arc<dyn ObjectStore>) {
+        if config.negative_cache_ttl.is_zero() {
+            return (tables, objects);
+        }
+
+        let store = Arc::new(NegativeCacheStore {
+            tables,
+            objects,
+            uncached: vec![
+                (
+                    config.results_bucket().to_owned(),
+                    config.target_lists_prefix.clone(),
+                ),
+                (
+                    config.results_bucket().to_owned(),
+                    config.results_prefix.clone(),
+                ),
+            ],
+            items: Misses::new(config.negative_cache_ttl),
+            blobs: Misses::new(config.negative_cache_ttl),
+        });
+
+        (store.clone(), store)
+    }
+}
+
+impl TableStore for NegativeCacheStore {
+    fn get_item<'a>(
+        &'a self,
+        table: &'a str,
+        key_attr: &'a str,
+        key: AttributeValue,
+        projection: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Item>, Error>> {
+        Box::pin(async move {
+            let key_text = key_text(&key)?.to_owned();
+
+            if self.items.contains(table, &key_text) {
+                return Ok(None);
+            }
+
+            let item = self
+                .tables
+                .get_item(table, key_attr, key, projection)
+                .await?;
+
+            if item.is_none() {
+                self.items.insert(table, &key_text);
+            }
+
+            Ok(item)
+        })
+    }
+
+    fn batch_get_items<'a>(
+        &'a self,
+        table: &'a str,
+        key_attr: &'a str,
+        keys: Vec<AttributeValue>,
+        projection: &'a str,
+    ) -> BoxFuture<'a, Result<BatchGetOutput, Error>> {
+        Box::pin(async move {
+            let mut wanted = HashSet::new();
+            let mut to_fetch = Vec::with_capacity(keys.len());
+
+            for key in keys {
+                let text = key_text(&key)?;
+
+                if !self.items.contains(table, text) {
+                    wanted.insert(text.to_owned());
+                    to_fetch.push(key);
+                }
+            }
+
+            if to_fetch.is_empty() {
+                return Ok(BatchGetOutput::default());
+            }
+
+            let output = self
+                .tables
+                .batch_get_items(table, key_attr, to_fetch, projection)
+                .await?;
+
+            // Whatever wasn't returned, and wasn't left unprocessed, doesn't
+            // exist.
+            for found in output
+                .items
+                .iter()
+                .filter_map(|item| item.get(key_attr))
+                .chain(&output.unprocessed)
+            {
+                if let Ok(text) = key_text(found) {
+                    wanted.remove(text);
+                }
+            }
+
+            for missing in wanted {
+                self.items.insert(table, &missing);
+            }
+
+            Ok(output)
+        })
+    }
+
+    fn query_items<'a>(
+        &'a self,
+        table: &'a str,
+        partition_attr: &'a str,
+        partition_value: AttributeValue,
+    ) -> BoxFuture<'a, Result<Vec<Item>, Error>> {
+        self.tables
+            .query_items(table, partition_attr, partition_value)
+    }
+
+    fn query_index<'a>(
+        &'a self,
+        table: &'a str,
+        index: &'a str,
+        partition_attr: &'a str,
+        partition_value: AttributeValue,
+        projection: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<Item>, Error>> {
+        self.tables
+            .query_index(table, index, partition_attr, partition_value, projection)
+    }
+
+    fn put_item<'a>(
+        &'a self,
+        table: &'a str,
+        partition_attr: &'a str,
+        item: Item,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        if let Some(Ok(key)) = item.get(partition_attr).map(key_text) {
+            self.items.remove(table, key);
+        }
+
+        self.tables.put_item(table, partition_attr, item)
+    }
+}
+
+impl ObjectStore for NegativeCacheStore {
+    fn get_object<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Vec<u8>>, Error>> {
+        if self
+            .uncached
+            .iter()
+            .any(|(b, prefix)| b == bucket && key.starts_with(prefix.as_str()))
+        {
+            return self.objects.get_object(bucket, key);
+        }
+
+        Box::pin(async move {
+            if self.blobs.contains(bucket, key) {
+                return Ok(None);
+            }
+
+            let data = self.objects.get_object(bucket, key).await?;
+
+            if data.is_none() {
+                self.blobs.insert(bucket, key);
+            }
+
+            Ok(data)
+        })
+    }
+
+    fn get_object_start<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        max_bytes: usize,
+    ) -> BoxFuture<'a, Result<Option<Vec<u8>>, Error>> {
+        self.objects.get_object_start(bucket, key, max_bytes)
+    }
+
+    fn fits_url(&self, bucket: &str, key: &str) -> String {
+        self.objects.fits_url(bucket, key)
+    }
+
+    fn create_upload<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        content_type: &'a str,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        self.objects.create_upload(bucket, key, content_type)
+    }
+
+    fn upload_part<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        upload_id: &'a str,
+        part_number: i32,
+        data: Vec<u8>,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        self.objects
+            .upload_part(bucket, key, upload_id, part_number, data)
+    }
+
+    fn complete_upload<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        upload_id: &'a str,
+        parts: Vec<(i32, String)>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.blobs.remove(bucket, key);
+        self.objects.complete_upload(bucket, key, upload_id, parts)
+    }
+
+    fn download_url<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        expires_in: Duration,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        self.objects.download_url(bucket, key, expires_in)
+    }
+
+    fn upload_url<'a>(
+        &'a self,
+        bucket: &'a str,
+        key: &'a str,
+        content_type: &'a str,
+        expires_in: Duration,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        self.objects
+            .upload_url(bucket, key, content_type, expires_in)
+    }
+}
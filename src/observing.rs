@@ -0,0 +1,77 @@
+//! Observing conditions of exposures.
+//!
+//! Photometric systematics studies need to know how much atmosphere each
+//! exposure looked through, and how far from the meridian, which depend on
+//! where the telescope was. The Harvard plates were taken at several stations
+//! -- Cambridge, Oak Ridge, Arequipa, Bloemfontein, and others -- and each
+//! series was mostly taken at one of them, so the series metadata table (see
+//! `seriesdefaults.rs`) can give the [`ObservingSite`] of each series.
+//!
+//! Given a site and the exposure midpoint, we compute the hour angle and
+//! airmass of a J2000 position. The position is first precessed to the
+//! equinox of date with the IAU 1976 model, since over a century of plates the
+//! difference amounts to more than a degree. The sidereal time is the IAU 1982
+//! GMST, treating UTC as UT1, which is good to a second -- far better than the
+//! logged exposure times. We ignore nutation, aberration, and refraction,
+//! which are all well below what matters here.
+//!
+//! The airmass uses the formula of Pickering (2002, DIO 12, 1), which stays
+//! sensible down to the horizon. Positions below the horizon have no airmass.
+
+use serde::Deserialize;
+
+use crate::{
+    coords::{delta_ra, precess_from_j2000},
+    gscbin::D2R,
+};
+
+/// The location of an observing station.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ObservingSite {
+    /// The geodetic latitude, in degrees, positive north.
+    pub latitude_deg: f64,
+
+    /// The longitude, in degrees, positive east.
+    pub longitude_deg: f64,
+}
+
+/// The observing conditions of a position at some moment.
+#[derive(Clone, Copy, Debug)]
+pub struct Conditions {
+    /// The hour angle, in hours, in the range [-12, 12).
+    pub hour_angle_hours: f64,
+
+    /// The airmass, if the position is above the horizon.
+    pub airmass: Option<f64>,
+}
+
+impl ObservingSite {
+    /// Compute the observing conditions of a J2000 position at a UTC Julian
+    /// Date.
+    pub fn conditions(&self, jd: f64, ra_deg: f64, dec_deg: f64) -> Conditions {
+        let (ra_date, dec_date) = precess_from_j2000(jd, ra_deg, dec_deg);
+        let lst = gmst_deg(jd) + self.longitude_deg;
+        let hour_angle = delta_ra(lst, ra_date);
+
+        let lat = D2R * self.latitude_deg;
+        let dec = D2R * dec_date;
+        let sin_alt = lat.sin() * dec.sin() + lat.cos() * dec.cos() * f64::cos(D2R * hour_angle);
+        let alt_deg = sin_alt.clamp(-1., 1.).asin() / D2R;
+
+        let airmass = (alt_deg > 0.)
+            .then(|| 1. / f64::sin(D2R * (alt_deg + 244. / (165. + 47. * alt_deg.powf(1.1)))));
+
+        Conditions {
+            hour_angle_hours: hour_angle / 15.,
+            airmass,
+        }
+    }
+}
+
+/// The Greenwich mean sidereal time at a Julian Date, in degrees.
+fn gmst_deg(jd: f64) -> f64 {
+    let d = jd - 2451545.;
+    let t = d / 36525.;
+    280.46061837 + 360.98564736629 * d + 0.000387933 * t * t - t * t * t / 38710000.
+}
@@ -0,0 +1,150 @@
+//! Caching of `queryexps` overlap tests.
+//!
+//! Most of the work of an exposure query goes into constructing WCS for the
+//! candidate exposures and testing whether the search point lands on them, and
+//! most candidates in a coverage bin turn out not to cover any given point in
+//! it. Popular regions of the sky are searched over and over again, so if
+//! `Config::overlap_cache_table` is set, we remember which exposures miss
+//! which parts of the sky, and skip them in later searches: we don't build
+//! their WCS, and don't even fetch their plate records if none of the plate's
+//! candidate exposures remain.
+//!
+//! The coverage bins are much too coarse for this, since the plates listed in
+//! a bin nearly always overlap some of it, so the cache is organized by the
+//! 1/64° GSC bins instead. Its DynamoDB table is partitioned by the `bin`
+//! number, with one item per bin, whose `misses` attribute lists the
+//! exposures known to miss that bin entirely, as `PLATEID/SOLNUM/EXPNUM`.
+//!
+//! An entry is only added when a search finds that an exposure misses the
+//! search point by a wide margin -- well over the size of the bin -- so that
+//! we never skip an exposure that covers part of the bin. Entries are tied to
+//! a solution number, so solving a plate doesn't leave stale entries behind,
+//! and the table name can include the data release. Updates are best-effort:
+//! concurrent searches in the same bin can overwrite each other's additions,
+//! which just means that some tests get redone. None of this changes the
+//! result of a search, so continuation tokens keep working as the cache
+//! fills in.
+
+use lambda_runtime::tracing;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+use crate::{backend::TableStore, config::Config, coords::angular_separation, gscbin::GscBinning};
+
+/// How far beyond the radius of a bin an exposure has to be from its center
+/// to be recorded as missing it, as a multiple of the radius. Plates are
+/// distorted enough that we can't be too precise.
+pub const MISS_MARGIN: f64 = 2.;
+
+/// The most entries that we'll keep for a bin, to stay well within the
+/// DynamoDB item size limit.
+const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Deserialize, Serialize)]
+struct CacheItem {
+    bin: usize,
+    #[serde(default)]
+    misses: Vec<String>,
+}
+
+/// The overlap cache entries of the bin containing a search point.
+pub struct OverlapCache {
+    table: String,
+    bin: usize,
+
+    /// The center and radius of the bin, in degrees.
+    region: (f64, f64, f64),
+
+    /// The exposures known to miss the bin.
+    misses: HashSet<String>,
+}
+
+impl OverlapCache {
+    /// Load the cache entries of the bin containing a search point, if overlap
+    /// tests are cached. `binning` must be the 1/64° binning. If the entries
+    /// can't be loaded, we log a warning and start from scratch.
+    pub async fn load(
+        config: &Config,
+        tables: &dyn TableStore,
+        binning: &GscBinning,
+        release: &str,
+        ra_deg: f64,
+        dec_deg: f64,
+    ) -> Option<Self> {
+        let table = config.overlap_cache_table(release)?;
+        let bin = binning.get_bin(ra_deg, dec_deg);
+        let key = serde_dynamo::to_attribute_value(bin).ok()?;
+
+        let misses = match tables.get_item(&table, "bin", key, "misses").await {
+            Ok(Some(item)) => match serde_dynamo::from_item::<_, CacheItem>(item) {
+                Ok(item) => item.misses.into_iter().collect(),
+                Err(e) => {
+                    tracing::warn!("ignoring malformed overlap cache entry for bin {bin}: {e}");
+                    HashSet::new()
+                }
+            },
+            Ok(None) => HashSet::new(),
+            Err(e) => {
+                tracing::warn!("failed to read overlap cache `{table}`: {e}");
+                HashSet::new()
+            }
+        };
+
+        let (ra_min, ra_max, dec_min, dec_max) = binning.get_bin_bounds(bin);
+        let ra = 0.5 * (ra_min + ra_max);
+        let dec = 0.5 * (dec_min + dec_max);
+        let radius = [dec_min, dec_max]
+            .into_iter()
+            .map(|d| angular_separation(ra, dec, ra_min, d))
+            .fold(0., f64::max);
+
+        Some(OverlapCache {
+            table,
+            bin,
+            region: (ra, dec, radius),
+            misses,
+        })
+    }
+
+    /// The center and radius of the bin, in degrees.
+    pub fn region(&self) -> (f64, f64, f64) {
+        self.region
+    }
+
+    /// Whether an exposure is known to miss the bin.
+    pub fn is_miss(&self, plate_id: &str, sol_num: i8, exp_num: i8) -> bool {
+        self.misses.contains(&miss_key(plate_id, sol_num, exp_num))
+    }
+
+    /// Record newly discovered misses.
+    pub async fn record(&self, tables: &dyn TableStore, new_misses: Vec<String>) {
+        if new_misses.is_empty() || self.misses.len() >= MAX_ENTRIES {
+            return;
+        }
+
+        let mut misses: Vec<_> = self.misses.iter().cloned().collect();
+        misses.extend(new_misses);
+        misses.sort_unstable();
+        misses.dedup();
+        misses.truncate(MAX_ENTRIES);
+
+        let item = CacheItem {
+            bin: self.bin,
+            misses,
+        };
+
+        let result = match serde_dynamo::to_item(item) {
+            Ok(item) => tables.put_item(&self.table, "bin", item).await,
+            Err(e) => Err(e.into()),
+        };
+
+        if let Err(e) = result {
+            tracing::warn!("failed to update overlap cache `{}`: {e}", self.table);
+        }
+    }
+}
+
+/// The cache entry of an exposure.
+pub fn miss_key(plate_id: &str, sol_num: i8, exp_num: i8) -> String {
+    format!("{plate_id}/{sol_num}/{exp_num}")
+}
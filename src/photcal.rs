@@ -0,0 +1,130 @@
+//! The photometric calibration metadata service.
+//!
+//! The DASCH pipeline calibrates the photometry of each astrometric solution
+//! of a plate against a reference catalog, fitting a zero point and a color
+//! term. Lightcurve users need to know how good those fits were to interpret
+//! the magnitudes, so this service returns a summary of them.
+//!
+//! The summaries live in the calibration table, which is partitioned by
+//! `plateId` and holds one item per solution and reference catalog. A request
+//! gets all of a plate's calibrations, or just those of one solution.
+//! Calibrations that failed are still listed, but without their fit values.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use lambda_http::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    backend::TableStore,
+    config::{default_data_release, Config},
+    envelope,
+    mosaics::PlateId,
+};
+
+/// Sync with `json-schemas/photcal_request.json`.
+#[derive(Deserialize, Serialize)]
+pub struct Request {
+    plate_id: PlateId,
+    /// The solution to report on; if unset, report on all of them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    solution_number: Option<usize>,
+    /// The data release to query.
+    #[serde(default = "default_data_release")]
+    data_release: String,
+}
+
+impl Request {
+    /// Validate the request.
+    pub fn normalize(self, config: &Config) -> Result<Self, Error> {
+        config.check_release(&self.data_release)?;
+        Ok(self)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Response {
+    /// The calibrations, sorted by solution number and then reference catalog.
+    pub calibrations: Vec<Calibration>,
+}
+
+/// The photometric calibration of one solution against one reference catalog.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct Calibration {
+    /// The astrometric solution number (0-based).
+    pub solution_number: usize,
+
+    /// The reference catalog that the calibration is based on.
+    pub refcat: String,
+
+    /// The photometric zero point, in magnitudes.
+    #[serde(rename(deserialize = "zeroPoint"))]
+    pub zero_point_mag: Option<f64>,
+
+    /// The color term of the fit.
+    pub color_term: Option<f64>,
+
+    /// The RMS residual of the calibrators about the fit, in magnitudes.
+    pub rms_mag: Option<f64>,
+
+    /// The number of calibrator stars used in the fit.
+    #[serde(default)]
+    pub n_calibrators: usize,
+}
+
+pub async fn handler(
+    req: Option<Value>,
+    config: &Config,
+    tables: &dyn TableStore,
+) -> Result<Value, Error> {
+    let request: Request =
+        serde_json::from_value(req.ok_or_else(|| -> Error { "no request payload".into() })?)?;
+    let request = request.normalize(config)?;
+    let echo = serde_json::to_value(&request)?;
+    let result = implementation(request, config, tables).await?;
+    envelope::wrap("photcal", &echo, result)
+}
+
+/// Get the calibrations of a plate. The request must have been normalized.
+pub async fn implementation(
+    request: Request,
+    config: &Config,
+    tables: &dyn TableStore,
+) -> Result<Response, Error> {
+    let items = tables
+        .query_items(
+            &config.calibration_table(&request.data_release),
+            "plateId",
+            AttributeValue::S(request.plate_id.to_string()),
+        )
+        .await?;
+
+    if items.is_empty() {
+        return Err(format!(
+            "plate `{}` has no photometric calibrations",
+            request.plate_id
+        )
+        .into());
+    }
+
+    let mut calibrations: Vec<Calibration> = serde_dynamo::from_items(items)?;
+    calibrations
+        .sort_by(|a, b| (a.solution_number, &a.refcat).cmp(&(b.solution_number, &b.refcat)));
+
+    if let Some(solnum) = request.solution_number {
+        let mut valid: Vec<_> = calibrations.iter().map(|c| c.solution_number).collect();
+        valid.dedup();
+        calibrations.retain(|c| c.solution_number == solnum);
+
+        if calibrations.is_empty() {
+            return Err(format!(
+                "plate `{}` has no photometric calibrations for solution #{} (calibrated solutions: {:?})",
+                request.plate_id, solnum, valid
+            )
+            .into());
+        }
+    }
+
+    Ok(Response { calibrations })
+}
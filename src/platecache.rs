@@ -0,0 +1,78 @@
+//! A small cache of plates-table records for warm Lambda instances.
+//!
+//! Interactive sessions tend to hit the same plates over and over -- e.g.,
+//! requesting cutouts for one plate at a series of positions -- and the records
+//! that we fetch can be several kilobytes thanks to the gzipped WCS headers. So
+//! we hold on to recently fetched records for a little while.
+//!
+//! The different services fetch different projections of the plates table, so
+//! records are keyed by the projection expression and table name (which
+//! depends on the data release) as well as the plate ID. A record fetched with
+//! one projection is never used to service another.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::backend::Item;
+
+/// How long a cached record remains valid.
+const TTL: Duration = Duration::from_secs(300);
+
+/// The maximum number of records to keep around.
+const MAX_ENTRIES: usize = 1024;
+
+#[derive(Debug)]
+struct Entry {
+    item: Item,
+    fetched: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct PlateCache {
+    entries: Mutex<HashMap<(&'static str, String, String), Entry>>,
+}
+
+impl PlateCache {
+    /// Get a cached record, if we have a sufficiently fresh one.
+    pub fn get(&self, projection: &'static str, table: &str, plate_id: &str) -> Option<Item> {
+        let entries = self.entries.lock().unwrap();
+
+        entries
+            .get(&(projection, table.to_owned(), plate_id.to_owned()))
+            .filter(|e| e.fetched.elapsed() < TTL)
+            .map(|e| e.item.clone())
+    }
+
+    /// Record a freshly fetched record.
+    pub fn insert(&self, projection: &'static str, table: &str, plate_id: String, item: Item) {
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, e| e.fetched.elapsed() < TTL);
+        }
+
+        if entries.len() >= MAX_ENTRIES {
+            // Still full: evict the oldest record. This is a linear scan, but
+            // the cache is small and this should be rare.
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, e)| e.fetched)
+                .map(|(k, _)| k.clone());
+
+            if let Some(k) = oldest {
+                entries.remove(&k);
+            }
+        }
+
+        entries.insert(
+            (projection, table.to_owned(), plate_id),
+            Entry {
+                item,
+                fetched: Instant::now(),
+            },
+        );
+    }
+}
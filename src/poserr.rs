@@ -0,0 +1,118 @@
+//! Estimating the astrometric uncertainty of a plate at a position.
+//!
+//! Users who want to measure the position of their target on a plate need to
+//! know how well the plate's astrometric solution pins down positions there.
+//! The stored residuals of the solution's reference stars (see
+//! `residuals.rs`) tell us that, but solutions of photographic plates get worse
+//! toward the plate edges, where the optical distortions are strongest, so a
+//! single RMS doesn't describe the whole plate.
+//!
+//! We model the squared total residual of a star as growing quadratically with
+//! its distance from the plate center:
+//!
+//! ```text
+//! σ²(ρ) = a + b ρ²
+//! ```
+//!
+//! where `ρ` is the distance normalized by the half-diagonal of the plate, and
+//! fit `a` and `b` to the reference stars by least squares, constraining both
+//! to be non-negative. The estimated uncertainty at a position is then `σ(ρ)`,
+//! in arcseconds. Since the residuals include the centroiding errors of the
+//! reference stars, this is somewhat conservative.
+//!
+//! Solutions with fewer than [`MIN_STARS`] stored residuals don't get
+//! estimates, and neither do exposures without real astrometric solutions,
+//! whose approximate WCS is no good for astrometry.
+
+/// The fewest reference stars that we'll fit the model to.
+pub const MIN_STARS: usize = 3;
+
+/// The fitted radial model of a solution's residuals.
+#[derive(Clone, Copy, Debug)]
+pub struct RadialErrorModel {
+    /// The squared residual at the plate center, in arcsec².
+    a: f64,
+
+    /// The growth of the squared residual to the plate corners, in arcsec².
+    b: f64,
+
+    /// The half-diagonal of the plate, in pixels.
+    half_diagonal: f64,
+}
+
+impl RadialErrorModel {
+    /// Fit the model to a solution's residuals. The star positions are in
+    /// pixels on a plate image with the given dimensions. Returns `None` if
+    /// there are too few stars, or the residuals are inconsistent.
+    pub fn fit(
+        x_pix: &[f64],
+        y_pix: &[f64],
+        d_ra_asec: &[f64],
+        d_dec_asec: &[f64],
+        width: usize,
+        height: usize,
+    ) -> Option<Self> {
+        let n = x_pix.len();
+
+        if n < MIN_STARS || [y_pix, d_ra_asec, d_dec_asec].iter().any(|v| v.len() != n) {
+            return None;
+        }
+
+        let center_x = 0.5 * (width as f64 - 1.);
+        let center_y = 0.5 * (height as f64 - 1.);
+        let half_diagonal = 0.5 * f64::sqrt((width * width + height * height) as f64);
+
+        if half_diagonal <= 0. {
+            return None;
+        }
+
+        // Accumulate the sums of the normal equations, with u = ρ² and
+        // v = the squared total residual.
+        let mut s_u = 0.;
+        let mut s_uu = 0.;
+        let mut s_v = 0.;
+        let mut s_uv = 0.;
+
+        for i in 0..n {
+            let u = (f64::powi(x_pix[i] - center_x, 2) + f64::powi(y_pix[i] - center_y, 2))
+                / (half_diagonal * half_diagonal);
+            let v = d_ra_asec[i] * d_ra_asec[i] + d_dec_asec[i] * d_dec_asec[i];
+            s_u += u;
+            s_uu += u * u;
+            s_v += v;
+            s_uv += u * v;
+        }
+
+        let n = n as f64;
+        let det = n * s_uu - s_u * s_u;
+        let mut b = if det > 0. {
+            (n * s_uv - s_u * s_v) / det
+        } else {
+            0.
+        };
+        let mut a = (s_v - b * s_u) / n;
+
+        // Fall back to one-parameter fits if either coefficient comes out
+        // negative.
+        if b < 0. {
+            b = 0.;
+            a = s_v / n;
+        } else if a < 0. {
+            a = 0.;
+            b = if s_uu > 0. { s_uv / s_uu } else { 0. };
+        }
+
+        (a.is_finite() && b.is_finite()).then_some(RadialErrorModel {
+            a,
+            b,
+            half_diagonal,
+        })
+    }
+
+    /// The estimated positional uncertainty, in arcseconds, at a given
+    /// distance from the plate center, in pixels.
+    pub fn at(&self, center_dist_pix: f64) -> f64 {
+        let rho = center_dist_pix / self.half_diagonal;
+        f64::sqrt(self.a + self.b * rho * rho)
+    }
+}
@@ -0,0 +1,104 @@
+//! Preview images of cutouts.
+//!
+//! Web front-ends that just want to show a plate don't want a FITS decoding
+//! stack, so a cutout request can set `output_format` to get the stamp as an
+//! 8-bit grayscale PNG or JPEG instead. The pixels are mapped to gray levels
+//! with the suggested asinh stretch of the stamp's statistics (see
+//! `stampstats.rs`), so that previews of different plates look alike without
+//! any tuning.
+//!
+//! Image rows run from the top down, while FITS rows run from the bottom up,
+//! so the rows are flipped: with the default resampled grid, north is up and
+//! east is left, as on the sky. Null pixels are black.
+
+use ndarray::{Array, Ix2};
+use serde::{Deserialize, Serialize};
+use std::os::raw::c_int;
+
+use crate::stampstats::AsinhStretch;
+
+/// The format of a cutout's image.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// A FITS file, encoded according to the request's `compression`.
+    #[default]
+    Fits,
+
+    /// A stretched 8-bit grayscale PNG.
+    Png,
+
+    /// A stretched 8-bit grayscale JPEG.
+    Jpeg,
+}
+
+impl OutputFormat {
+    pub fn is_default(&self) -> bool {
+        *self == OutputFormat::Fits
+    }
+}
+
+/// The JPEG quality setting. Plate noise compresses badly, but previews don't
+/// need to be precise.
+const JPEG_QUALITY: u8 = 85;
+
+/// An upper bound on the size of a preview of `npix` pixels, in bytes. A PNG
+/// stores incompressible data raw, with a filter byte per row and a little
+/// framing; a JPEG of noise at our quality setting takes well under two bytes
+/// per pixel.
+pub fn max_bytes(npix: usize) -> usize {
+    2 * npix + 4096
+}
+
+/// Encode a stamp as a preview image. `null_mask` is nonzero for the null
+/// pixels. If the stamp has no statistics, because all of its pixels are
+/// null, the preview is black.
+pub fn encode(
+    format: OutputFormat,
+    data: &Array<i16, Ix2>,
+    null_mask: &Array<c_int, Ix2>,
+    stretch: Option<&AsinhStretch>,
+) -> anyhow::Result<Vec<u8>> {
+    let (height, width) = data.dim();
+    let mut levels = Vec::with_capacity(width * height);
+
+    for i in (0..height).rev() {
+        let flags = null_mask.row(i);
+        levels.extend(
+            data.row(i)
+                .iter()
+                .zip(flags)
+                .map(|(v, flag)| match stretch {
+                    Some(s) if *flag == 0 => (255. * s.level(*v as f64)).round() as u8,
+                    _ => 0,
+                }),
+        );
+    }
+
+    let mut dest = Vec::new();
+
+    match format {
+        OutputFormat::Png => {
+            let mut encoder = png::Encoder::new(&mut dest, width as u32, height as u32);
+            encoder.set_color(png::ColorType::Grayscale);
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut writer = encoder.write_header()?;
+            writer.write_image_data(&levels)?;
+            writer.finish()?;
+        }
+
+        OutputFormat::Jpeg => {
+            let encoder = jpeg_encoder::Encoder::new(&mut dest, JPEG_QUALITY);
+            encoder.encode(
+                &levels,
+                width as u16,
+                height as u16,
+                jpeg_encoder::ColorType::Luma,
+            )?;
+        }
+
+        OutputFormat::Fits => unreachable!("FITS output isn't a preview"),
+    }
+
+    Ok(dest)
+}
@@ -0,0 +1,201 @@
+//! Provenance records of cutout requests, and the history service.
+//!
+//! If `Config::provenance_table` is set, each successful cutout request
+//! (other than an estimate) is logged to that DynamoDB table: who asked, for
+//! which plate, solution, and center, when, and how long it took. Data
+//! management uses the table to attribute usage. The table is partitioned by
+//! the `requester` attribute, the client identity as used by the rate limiter
+//! (`key:<API key ID>`, `ip:<address>`, or `anonymous`), and sorted by
+//! `requestId`, which begins with a Unix timestamp.
+//!
+//! Each record also stores the interpreted request, as echoed in the response
+//! envelope, and its hash. Cutouts are deterministic, so passing the stored
+//! request back to the cutout service re-creates the same product, apart from
+//! the processing date in its FITS headers. The
+//! `history` service lets users list their own records for this purpose,
+//! newest first, optionally only those with a given request hash. Anonymous
+//! clients share one identity, so they can't use it.
+//!
+//! Recording is best-effort: if a record can't be written, we log a warning
+//! and return the cutout anyway.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use lambda_http::Error;
+use lambda_runtime::tracing;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+use crate::{
+    backend::TableStore,
+    config::Config,
+    envelope, staging,
+    timeutil::UtcTime,
+    validation::{self, validate_fields},
+};
+
+/// The identity of clients that we can't identify.
+pub const ANONYMOUS: &str = "anonymous";
+
+/// The identity of the operator's own command-line tools, which run with the
+/// operator's AWS credentials and so may call admin-only services. Network
+/// clients are never given this identity.
+pub const OPERATOR: &str = "operator";
+
+/// The most records that `history` returns.
+pub const MAX_LIMIT: usize = 1000;
+
+fn default_limit() -> usize {
+    100
+}
+
+/// The essentials of a cutout request, as recorded.
+pub struct CutoutSummary<'a> {
+    pub plate_id: &'a str,
+    pub solution_number: usize,
+    pub center_deg: Option<(f64, f64)>,
+    pub data_release: &'a str,
+}
+
+/// Record a successful cutout request, if provenance recording is enabled.
+/// `echo` is the interpreted request.
+pub async fn record_cutout(
+    config: &Config,
+    tables: &dyn TableStore,
+    identity: &str,
+    summary: CutoutSummary<'_>,
+    echo: &Value,
+    elapsed: Duration,
+) {
+    let Some(table) = config.provenance_table() else {
+        return;
+    };
+
+    let mut record = json!({
+        "requester": identity,
+        "requestId": staging::new_name(),
+        "requestTime": format!("{}Z", UtcTime::now().iso()),
+        "service": "cutout",
+        "plateId": summary.plate_id,
+        "solutionNumber": summary.solution_number,
+        "dataRelease": summary.data_release,
+        "request": echo.to_string(),
+        "requestHash": envelope::request_hash("cutout", echo),
+        "durationMs": elapsed.as_millis() as u64,
+    });
+
+    if let Some((ra, dec)) = summary.center_deg {
+        record["centerRaDeg"] = ra.into();
+        record["centerDecDeg"] = dec.into();
+    }
+
+    let result = match serde_dynamo::to_item(record) {
+        Ok(item) => tables.put_item(&table, "requester", item).await,
+        Err(e) => Err(e.into()),
+    };
+
+    if let Err(e) = result {
+        tracing::warn!("failed to record cutout provenance in `{table}`: {e}");
+    }
+}
+
+/// Sync with `json-schemas/history_request.json`.
+#[derive(Deserialize, Serialize)]
+pub struct Request {
+    /// The maximum number of records to return.
+    #[serde(default = "default_limit")]
+    limit: usize,
+
+    /// If set, only return the records of requests with this hash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_hash: Option<String>,
+}
+
+impl Request {
+    /// Validate the request.
+    fn normalize(mut self) -> Result<Self, Error> {
+        validate_fields!(self {
+            limit: |n, v| validation::range(n, v, 1..=MAX_LIMIT),
+        });
+
+        Ok(self)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Response {
+    /// The records, newest first.
+    pub records: Vec<Record>,
+}
+
+/// One provenance record.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct Record {
+    pub request_id: String,
+    pub request_time: String,
+    pub service: String,
+    pub plate_id: String,
+    pub solution_number: usize,
+    #[serde(default)]
+    pub center_ra_deg: Option<f64>,
+    #[serde(default)]
+    pub center_dec_deg: Option<f64>,
+    pub data_release: String,
+    pub duration_ms: u64,
+    pub request_hash: String,
+
+    /// The interpreted request, which is stored as JSON text.
+    #[serde(deserialize_with = "json_text")]
+    pub request: Value,
+}
+
+fn json_text<'de, D: Deserializer<'de>>(d: D) -> Result<Value, D::Error> {
+    let text = String::deserialize(d)?;
+    serde_json::from_str(&text).map_err(serde::de::Error::custom)
+}
+
+pub async fn handler(
+    req: Option<Value>,
+    config: &Config,
+    tables: &dyn TableStore,
+    identity: &str,
+) -> Result<Value, Error> {
+    let request: Request =
+        serde_json::from_value(req.ok_or_else(|| -> Error { "no request payload".into() })?)?;
+    let request = request.normalize()?;
+    let echo = serde_json::to_value(&request)?;
+    let result = implementation(request, config, tables, identity).await?;
+    envelope::wrap("history", &echo, result)
+}
+
+/// List the provenance records of a client. The request must have been
+/// normalized.
+pub async fn implementation(
+    request: Request,
+    config: &Config,
+    tables: &dyn TableStore,
+    identity: &str,
+) -> Result<Response, Error> {
+    let table = config
+        .provenance_table()
+        .ok_or("this deployment does not record request history")?;
+
+    if identity == ANONYMOUS {
+        return Err("request history is only available to identified clients".into());
+    }
+
+    let items = tables
+        .query_items(&table, "requester", AttributeValue::S(identity.to_owned()))
+        .await?;
+
+    let mut records: Vec<Record> = serde_dynamo::from_items(items)?;
+
+    if let Some(hash) = &request.request_hash {
+        records.retain(|r| &r.request_hash == hash);
+    }
+
+    records.sort_by(|a, b| (&b.request_time, &b.request_id).cmp(&(&a.request_time, &a.request_id)));
+    records.truncate(request.limit);
+    Ok(Response { records })
+}
@@ -0,0 +1,301 @@
+//! Read-through caching of catalog queries in Redis/ElastiCache.
+//!
+//! Interactive workloads tend to hit the same handful of fields over and over,
+//! and every `querycat` request re-reads the same refcat bins from DynamoDB.
+//! The refcat tables never change within a data release, so bin contents can
+//! be cached indefinitely. If the crate is built with the `elasticache`
+//! feature and `DASCH_QUERY_CACHE_URL` is set to a `redis://` or `rediss://`
+//! URL, [`CachedTableStore`] sits in front of the DynamoDB backend and keeps
+//! the results of partition queries in the cache.
+//!
+//! Only `query_items` of the refcat tables is cached; the point lookups of
+//! the plates table are cheap already, and other tables, like the provenance
+//! table read by `history`, change as the services run, so their queries go
+//! straight to the inner store. The cache is strictly an optimization: if it
+//! can't be reached, or holds garbage, we log a warning and go to DynamoDB. A
+//! failed cache operation also drops the shared connection, so that the next
+//! request reconnects rather than reusing a connection that ElastiCache has
+//! closed.
+//!
+//! DAX isn't supported, since there's no DAX client for Rust.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use futures::future::BoxFuture;
+use lambda_http::Error;
+use lambda_runtime::tracing::{info_span, warn, Instrument};
+use redis::{aio::MultiplexedConnection, AsyncCommands};
+use serde_json::Value;
+use std::{collections::HashSet, sync::Arc};
+
+use crate::{
+    backend::{item_from_json, item_to_json, key_text, BatchGetOutput, Item, TableStore},
+    config::Config,
+    refcats,
+};
+
+/// A [`TableStore`] that caches partition queries in Redis.
+pub struct CachedTableStore {
+    inner: Arc<dyn TableStore>,
+    client: redis::Client,
+    tables: HashSet<String>,
+    ttl_secs: u64,
+    conn: tokio::sync::Mutex<Option<MultiplexedConnection>>,
+}
+
+impl CachedTableStore {
+    /// Wrap `inner` with the cache configured in `config`, if any.
+    pub fn wrap(inner: Arc<dyn TableStore>, config: &Config) -> Arc<dyn TableStore> {
+        let Some(url) = &config.query_cache_url else {
+            return inner;
+        };
+
+        match redis::Client::open(url.as_str()) {
+            Ok(client) => Arc::new(CachedTableStore {
+                inner,
+                client,
+                tables: Self::refcat_tables(config),
+                ttl_secs: config.query_cache_ttl.as_secs(),
+                conn: Default::default(),
+            }),
+
+            Err(e) => {
+                warn!("query cache disabled: bad URL `{url}`: {e}");
+                inner
+            }
+        }
+    }
+
+    /// The names of the refcat tables of all of the data releases, the only
+    /// ones whose queries are cached.
+    fn refcat_tables(config: &Config) -> HashSet<String> {
+        config
+            .data_releases
+            .iter()
+            .flat_map(|release| {
+                refcats::names()
+                    .into_iter()
+                    .map(move |refcat| config.refcat_table(release, refcat))
+            })
+            .collect()
+    }
+
+    async fn connection(&self) -> Result<MultiplexedConnection, Error> {
+        let mut conn = self.conn.lock().await;
+
+        if let Some(conn) = &*conn {
+            return Ok(conn.clone());
+        }
+
+        let new = self.client.get_multiplexed_tokio_connection().await?;
+        *conn = Some(new.clone());
+        Ok(new)
+    }
+
+    /// Forget the shared connection after an error, so that the next cache
+    /// operation opens a new one.
+    async fn reset(&self) {
+        self.conn.lock().await.take();
+    }
+
+    async fn lookup(&self, key: &str) -> Result<Option<Vec<Item>>, Error> {
+        let data: Option<Vec<u8>> = self.connection().await?.get(key).await?;
+
+        let Some(data) = data else {
+            return Ok(None);
+        };
+
+        let items: Vec<Value> = serde_json::from_slice(&data)?;
+        Ok(Some(
+            items.iter().map(item_from_json).collect::<Result<_, _>>()?,
+        ))
+    }
+
+    async fn store(&self, key: &str, items: &[Item]) -> Result<(), Error> {
+        let data = serde_json::to_vec(&items.iter().map(item_to_json).collect::<Vec<_>>())?;
+        self.connection()
+            .await?
+            .set_ex::<_, _, ()>(key, data, self.ttl_secs)
+            .await?;
+        Ok(())
+    }
+}
+
+impl TableStore for CachedTableStore {
+    fn get_item<'a>(
+        &'a self,
+        table: &'a str,
+        key_attr: &'a str,
+        key: AttributeValue,
+        projection: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Item>, Error>> {
+        self.inner.get_item(table, key_attr, key, projection)
+    }
+
+    fn batch_get_items<'a>(
+        &'a self,
+        table: &'a str,
+        key_attr: &'a str,
+        keys: Vec<AttributeValue>,
+        projection: &'a str,
+    ) -> BoxFuture<'a, Result<BatchGetOutput, Error>> {
+        self.inner
+            .batch_get_items(table, key_attr, keys, projection)
+    }
+
+    fn query_items<'a>(
+        &'a self,
+        table: &'a str,
+        partition_attr: &'a str,
+        partition_value: AttributeValue,
+    ) -> BoxFuture<'a, Result<Vec<Item>, Error>> {
+        if !self.tables.contains(table) {
+            return self
+                .inner
+                .query_items(table, partition_attr, partition_value);
+        }
+
+        Box::pin(async move {
+            let key = format!(
+                "dasch:query:{table}:{partition_attr}:{}",
+                key_text(&partition_value)?
+            );
+
+            let span = info_span!("cache", operation = "get", key = key.as_str());
+
+            match self.lookup(&key).instrument(span).await {
+                Ok(Some(items)) => return Ok(items),
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("query cache lookup failed: {e}");
+                    self.reset().await;
+                }
+            }
+
+            let items = self
+                .inner
+                .query_items(table, partition_attr, partition_value)
+                .await?;
+
+            let span = info_span!("cache", operation = "set", key = key.as_str());
+
+            if let Err(e) = self.store(&key, &items).instrument(span).await {
+                warn!("query cache store failed: {e}");
+                self.reset().await;
+            }
+
+            Ok(items)
+        })
+    }
+
+    fn query_index<'a>(
+        &'a self,
+        table: &'a str,
+        index: &'a str,
+        partition_attr: &'a str,
+        partition_value: AttributeValue,
+        projection: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<Item>, Error>> {
+        self.inner
+            .query_index(table, index, partition_attr, partition_value, projection)
+    }
+
+    fn put_item<'a>(
+        &'a self,
+        table: &'a str,
+        partition_attr: &'a str,
+        item: Item,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.inner.put_item(table, partition_attr, item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A table store that only answers partition queries, with one item.
+    #[derive(Default)]
+    struct OneItemStore {
+        n_queries: AtomicUsize,
+    }
+
+    impl TableStore for OneItemStore {
+        fn get_item<'a>(
+            &'a self,
+            _table: &'a str,
+            _key_attr: &'a str,
+            _key: AttributeValue,
+            _projection: &'a str,
+        ) -> BoxFuture<'a, Result<Option<Item>, Error>> {
+            Box::pin(async { Ok(None) })
+        }
+
+        fn batch_get_items<'a>(
+            &'a self,
+            _table: &'a str,
+            _key_attr: &'a str,
+            _keys: Vec<AttributeValue>,
+            _projection: &'a str,
+        ) -> BoxFuture<'a, Result<BatchGetOutput, Error>> {
+            Box::pin(async { Ok(BatchGetOutput::default()) })
+        }
+
+        fn query_items<'a>(
+            &'a self,
+            _table: &'a str,
+            partition_attr: &'a str,
+            partition_value: AttributeValue,
+        ) -> BoxFuture<'a, Result<Vec<Item>, Error>> {
+            self.n_queries.fetch_add(1, Ordering::SeqCst);
+            let item = Item::from([(partition_attr.to_owned(), partition_value)]);
+            Box::pin(async move { Ok(vec![item]) })
+        }
+
+        fn query_index<'a>(
+            &'a self,
+            _table: &'a str,
+            _index: &'a str,
+            _partition_attr: &'a str,
+            _partition_value: AttributeValue,
+            _projection: &'a str,
+        ) -> BoxFuture<'a, Result<Vec<Item>, Error>> {
+            Box::pin(async { Ok(Vec::new()) })
+        }
+
+        fn put_item<'a>(
+            &'a self,
+            _table: &'a str,
+            _partition_attr: &'a str,
+            _item: Item,
+        ) -> BoxFuture<'a, Result<(), Error>> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn unreachable_cache_falls_back() {
+        let inner = Arc::new(OneItemStore::default());
+
+        // Nothing listens on port 1, so every connection attempt is refused.
+        let store = CachedTableStore {
+            inner: inner.clone(),
+            client: redis::Client::open("redis://127.0.0.1:1/").unwrap(),
+            tables: HashSet::from(["refcat".to_owned()]),
+            ttl_secs: 60,
+            conn: Default::default(),
+        };
+
+        for _ in 0..2 {
+            let items = store
+                .query_items("refcat", "gscBinIndex", AttributeValue::N("42".to_owned()))
+                .await
+                .unwrap();
+            assert_eq!(items.len(), 1);
+            assert_eq!(items[0]["gscBinIndex"], AttributeValue::N("42".to_owned()));
+            assert!(store.conn.lock().await.is_none());
+        }
+
+        assert_eq!(inner.n_queries.load(Ordering::SeqCst), 2);
+    }
+}
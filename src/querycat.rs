@@ -1,14 +1,91 @@
+//! The refcat query API service.
+//!
+//! The output columns of each catalog, and how they're derived from the
+//! DynamoDB attributes, are defined in `refcats.rs`.
+//!
+//! If the request sets `neighbor_radius_arcsec`, an extra `n_neighbors` column
+//! counts the other refcat sources within that radius of each result, to help
+//! users flag crowded or blended sources. The count only considers sources in
+//! the same 1/64-degree bin as the result, since those are the ones that we've
+//! fetched, so it may be an underestimate near bin edges.
+//!
+//! The `formatting` request field adjusts how the position and magnitude
+//! columns are written, and can precess the positions to another equinox; see
+//! `formatting.rs`. The `filter` field selects rows with an expression over
+//! the output columns; see `filter.rs`.
+//!
+//! Searches that cross RA = 0 cover bins at both ends of each declination row.
+//! Sources right on the boundary may be stored in both, so we deduplicate the
+//! results by source ID. This only applies within one response: if a query is
+//! continued, the continuation could repeat a source from an earlier part.
+//!
+//! Results are capped at a maximum number of rows; see `rowcap.rs`. The
+//! continuation token of a capped result records the bin that it stopped in,
+//! and how many of that bin's rows it returned.
+//!
+//! Instead of everything in a box, a request can ask for the `nearest` N
+//! sources to the search position, for identification work where there's no
+//! natural search radius. We start with a small cone and keep doubling its
+//! radius, reading only the bins that we haven't read yet, until it contains N
+//! sources that pass the filter, or reaches `radius_arcsec`, which then acts
+//! as an upper limit. The results are sorted by distance. Sources with
+//! placeholder positions have no distance, so they're left out. The number of
+//! results is bounded, so these queries are never capped or continued, but if
+//! time runs short we stop expanding and return what we have, marked as
+//! truncated.
+
 // TODO? we should probably move to serde-dynamo for strongly-typed handling
 
 use aws_sdk_dynamodb::types::AttributeValue;
 use lambda_http::Error;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-
-use crate::gscbin::D2R;
-use crate::refnums::refnum_to_text;
-
-const EXTERNAL_COLUMNS: &[&str] = &[
+use std::{
+    collections::HashSet,
+    ops::Bound::{Excluded, Included},
+};
+
+use crate::{
+    apiversion::{self, default_api_version, project_rows},
+    backend::TableStore,
+    config::{default_data_release, Config},
+    continuation::{decode_token, encode_token, Binding},
+    coords::{angular_separation, delta_ra},
+    deadline::Deadline,
+    envelope,
+    features::FeatureOverrides,
+    filter::{self, RowFilter},
+    formatting::{CoordinateFrame, Formatting},
+    gscbin::D2R,
+    mosaics::BUILTIN_PLACEHOLDERS,
+    refcats::{self, Catalog, Format},
+    refnums::refnum_to_text,
+    rowcap::{estimate_total, RowCap, Truncation, TruncationReason},
+    targets,
+    validation::{self, validate_fields},
+    warnings,
+};
+
+/// The maximum search box half-size, in arcseconds.
+pub const MAX_RADIUS_ARCSEC: f64 = 3600.;
+
+/// The most sources that a `nearest` query can ask for.
+pub const MAX_NEAREST: usize = 1000;
+
+/// The radius of the first cone searched by a `nearest` query, in
+/// arcseconds.
+const NEAREST_START_RADIUS_ARCSEC: f64 = 30.;
+
+/// The largest cone searched by a `nearest` query, in arcseconds, if the
+/// request doesn't give a `radius_arcsec`.
+pub const DEFAULT_NEAREST_RADIUS_ARCSEC: f64 = 600.;
+
+/// The maximum neighbor-counting radius, in arcseconds. Much larger than this
+/// and the bin-edge effects would dominate.
+pub const MAX_NEIGHBOR_RADIUS_ARCSEC: f64 = 60.;
+
+/// The output columns of version 1 of the API.
+const V1_COLUMNS: &[&str] = &[
     "ref_text",
     "ref_number",
     "gscBinIndex",
@@ -28,283 +105,615 @@ const EXTERNAL_COLUMNS: &[&str] = &[
     "class",
 ];
 
-const INTERNAL_COLUMNS: &[&str] = &[
-    "refText",
-    "refNumber",
-    "gscBinIndex",
-    "ra",
-    "dec",
-    "draAsec",
-    "ddecAsec",
-    "posEpoch",
-    "raPM",
-    "decPM",
-    "raSigmaPM",
-    "decSigmaPM",
-    "stdmag",
-    "color",
-    "vFlag",
-    "magFlag",
-    "class",
-];
-
 /// Sync with `json-schemas/querycat_request.json`, which then needs to be
 /// synced into S3.
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct Request {
     refcat: String,
     ra_deg: f64,
     dec_deg: f64,
-    radius_arcsec: f64,
+    /// The search box half-size, or the largest cone to search for the
+    /// `nearest` sources. Filled in by normalization.
+    #[serde(default)]
+    radius_arcsec: Option<f64>,
+    /// If set, return this many of the sources nearest to the position.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nearest: Option<usize>,
+    /// The name of the target, resolved into `ra_deg` and `dec_deg` before the
+    /// request is parsed; see `targets.rs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target_name: Option<String>,
+    /// The data release to query.
+    #[serde(default = "default_data_release")]
+    data_release: String,
+    /// If set, count each result's refcat neighbors within this radius.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    neighbor_radius_arcsec: Option<f64>,
+    /// A continuation token from a previous, truncated response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    continuation: Option<String>,
+    /// The maximum number of rows to return, if lower than the server's cap.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_rows: Option<usize>,
+    /// Overrides of the deployment's feature flags; see `features.rs`.
+    #[serde(default, skip_serializing_if = "FeatureOverrides::is_empty")]
+    features: FeatureOverrides,
+    /// How to format positions and magnitudes; see `formatting.rs`.
+    #[serde(default, skip_serializing_if = "Formatting::is_default")]
+    formatting: Formatting,
+    /// An expression selecting the rows to return; see `filter.rs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    filter: Option<String>,
+    /// The version of the response format to use; see `apiversion.rs`.
+    #[serde(
+        default = "default_api_version",
+        skip_serializing_if = "apiversion::is_current"
+    )]
+    api_version: u32,
+}
+
+#[derive(Serialize)]
+pub struct Response {
+    /// CSV-formatted rows of results, starting with a header row.
+    pub rows: Vec<String>,
+
+    /// If true, the results are incomplete, because we ran short of time or
+    /// hit the row cap.
+    pub truncated: bool,
+
+    /// If the results are truncated, a token that can be passed back to
+    /// continue the query.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub continuation: Option<String>,
+
+    /// The frame of the positional columns.
+    pub coordinates: CoordinateFrame,
+
+    /// The truncation state, for the envelope.
+    #[serde(skip)]
+    pub truncation: Truncation,
+}
+
+impl Response {
+    fn new(rows: Vec<String>, truncation: Truncation, formatting: &Formatting) -> Self {
+        Response {
+            rows,
+            truncated: truncation.truncated,
+            continuation: truncation.continuation.clone(),
+            // Every catalog has these columns.
+            coordinates: formatting.frame(["raDeg", "decDeg"], Some("posEpoch")),
+            truncation,
+        }
+    }
+}
+
+impl Request {
+    /// Validate the request and normalize its coordinates.
+    pub fn normalize(mut self, config: &Config) -> Result<Self, Error> {
+        if refcats::lookup(&self.refcat).is_none() {
+            return Err("illegal refcat parameter".into());
+        }
+
+        config.check_release(&self.data_release)?;
+
+        match (self.nearest, self.radius_arcsec) {
+            (None, None) => return Err("must specify `radius_arcsec` or `nearest`".into()),
+            (Some(_), None) => self.radius_arcsec = Some(DEFAULT_NEAREST_RADIUS_ARCSEC),
+            _ => {}
+        }
+
+        validate_fields!(self {
+            api_version: apiversion::validate,
+            ra_deg: validation::ra,
+            dec_deg: validation::dec,
+            radius_arcsec: validation::optional(|n, v| validation::range(
+                n,
+                v,
+                (Excluded(0.), Excluded(MAX_RADIUS_ARCSEC))
+            )),
+            nearest: validation::optional(|n, v| validation::range(n, v, 1..=MAX_NEAREST)),
+            neighbor_radius_arcsec: validation::optional(|n, v| {
+                validation::range(n, v, (Excluded(0.), Included(MAX_NEIGHBOR_RADIUS_ARCSEC)))
+            }),
+            max_rows: validation::optional(|n, v| validation::range(n, v, 1..)),
+            formatting: Formatting::validate,
+            filter: filter::validate,
+        });
+
+        // Version 1 only knew the catalogs with the legacy columns.
+        if !matches!(self.refcat.as_str(), "apass" | "atlas") {
+            apiversion::require(self.api_version, 2, &format!("refcat `{}`", self.refcat))?;
+        }
+
+        if self.neighbor_radius_arcsec.is_some() {
+            apiversion::require(self.api_version, 2, "`neighbor_radius_arcsec`")?;
+        }
+
+        if self.max_rows.is_some() {
+            apiversion::require(self.api_version, 2, "`max_rows`")?;
+        }
+
+        if self.target_name.is_some() {
+            apiversion::require(self.api_version, 2, "`target_name`")?;
+        }
+
+        if !self.formatting.is_default() {
+            apiversion::require(self.api_version, 2, "`formatting`")?;
+        }
+
+        if self.filter.is_some() {
+            apiversion::require(self.api_version, 2, "`filter`")?;
+        }
+
+        if self.nearest.is_some() {
+            apiversion::require(self.api_version, 2, "`nearest`")?;
+
+            if self.max_rows.is_some() || self.continuation.is_some() {
+                return Err("`nearest` can't be combined with `max_rows` or `continuation`".into());
+            }
+        }
+
+        if let Some(token) = &self.continuation {
+            decode_token(config, &Binding::new("querycat", &self)?, token)?;
+        }
+
+        Ok(self)
+    }
+
+    /// The search radius, in degrees. Only valid after normalization.
+    fn radius_deg(&self) -> f64 {
+        self.radius_arcsec.unwrap_or(DEFAULT_NEAREST_RADIUS_ARCSEC) / 3600.
+    }
 }
 
 pub async fn handler(
     req: Option<Value>,
-    dc: &aws_sdk_dynamodb::Client,
+    config: &Config,
+    tables: &dyn TableStore,
     binning: &crate::gscbin::GscBinning,
+    deadline: Deadline,
 ) -> Result<Value, Error> {
-    Ok(serde_json::to_value(
-        implementation(
-            serde_json::from_value(req.ok_or_else(|| -> Error { "no request payload".into() })?)?,
-            dc,
-            binning,
-        )
-        .await?,
-    )?)
+    let mut req = req.ok_or_else(|| -> Error { "no request payload".into() })?;
+    targets::resolve_position(&mut req, config, "ra_deg", "dec_deg").await?;
+    let request: Request = serde_json::from_value(req)?;
+    let request = request.normalize(config)?;
+    let echo = serde_json::to_value(&request)?;
+    let version = request.api_version;
+    let result = implementation(request, config, tables, binning, deadline).await?;
+
+    if version < 2 {
+        if result.truncated {
+            return Err(apiversion::TRUNCATED_V1.into());
+        }
+
+        return apiversion::respond(
+            version,
+            "querycat",
+            &echo,
+            project_rows(result.rows, V1_COLUMNS)?,
+        );
+    }
+
+    let truncation = result.truncation.clone();
+    envelope::wrap_table("querycat", &echo, result, truncation)
 }
 
+/// Query a reference catalog. The request must have been normalized.
 pub async fn implementation(
     request: Request,
-    dc: &aws_sdk_dynamodb::Client,
+    config: &Config,
+    tables: &dyn TableStore,
     binning: &crate::gscbin::GscBinning,
-) -> Result<Vec<String>, Error> {
+    deadline: Deadline,
+) -> Result<Response, Error> {
+    let binding = Binding::new("querycat", &request)?;
     let mut lines = Vec::new();
+    let cat_table = config.refcat_table(&request.data_release, &request.refcat);
+    let radius_deg = request.radius_deg();
 
-    // Validation
-
-    match request.refcat.as_ref() {
-        "apass" | "atlas" => {}
-        _ => {
-            return Err("illegal refcat parameter".into());
-        }
-    }
-
-    // Use this logic style to catch NaNs:
-    if !(request.ra_deg >= 0. && request.ra_deg <= 360.) {
-        return Err("illegal ra_deg parameter".into());
-    }
+    // We checked the name during normalization.
+    let catalog = refcats::lookup(&request.refcat).unwrap();
 
-    if !(request.dec_deg >= -90. && request.dec_deg <= 90.) {
-        return Err("illegal dec_deg parameter".into());
-    }
+    let mut header = catalog
+        .columns
+        .iter()
+        .map(|c| c.external)
+        .collect::<Vec<_>>()
+        .join(",");
 
-    if !(request.radius_arcsec > 0. && request.radius_arcsec < 3600.) {
-        return Err("illegal radius_arcsec parameter".into());
+    if request.neighbor_radius_arcsec.is_some() {
+        header.push_str(",n_neighbors");
     }
 
-    let cat_table = format!("dasch-{}-dr7-refcat-{}", super::ENVIRONMENT, request.refcat);
-    let radius_deg = request.radius_arcsec / 3600.0;
-    let min_dec = f64::max(request.dec_deg - radius_deg, -90.0);
-    let max_dec = f64::min(request.dec_deg + radius_deg, 90.0);
-    let bin0 = binning.get_dec_bin(min_dec);
-    let bin1 = binning.get_dec_bin(max_dec);
-
-    let cos_dec = f64::min(f64::cos(min_dec * D2R), f64::cos(max_dec * D2R));
-
-    let (ra_bound_1, ra_bound_2) = if cos_dec <= 0. {
-        ((0., 360.0), None)
-    } else {
-        let search_radius_ra = radius_deg / cos_dec;
-        let min_ra = request.ra_deg - search_radius_ra;
-        let max_ra = request.ra_deg + search_radius_ra;
-
-        if min_ra <= 0. && max_ra >= 360. {
-            // We cover all RA's, which might happen with a reasonable radius if
-            // we're right at the poles. This is OK.
-            ((0., 360.0), None)
-        } else if min_ra < 0. {
-            // We need to break our search into two RA chunks:
-            // (0, naive-max) and (wrapped-naive-min, 360)
-            ((0., max_ra), Some((min_ra + 360., 360.)))
-        } else if max_ra > 360. {
-            // Analogous to the previous case
-            ((min_ra, 360.), Some((0., max_ra - 360.)))
-        } else {
-            ((min_ra, max_ra), None)
-        }
+    let row_filter = match &request.filter {
+        Some(text) => Some(RowFilter::new(
+            text,
+            &header.split(',').collect::<Vec<_>>(),
+        )?),
+        None => None,
     };
 
-    lines.push(EXTERNAL_COLUMNS.join(","));
+    lines.push(header);
 
-    for ibin in bin0..=bin1 {
-        lines = read_dec_bin(
+    if let Some(n) = request.nearest {
+        return nearest(
             lines,
-            &cat_table,
-            ibin,
-            ra_bound_1.0,
-            ra_bound_1.1,
+            n,
             &request,
-            dc,
+            catalog,
+            &cat_table,
+            row_filter.as_ref(),
+            tables,
             binning,
+            deadline,
+        )
+        .await;
+    }
+
+    let mut seen = HashSet::new();
+    let bins = binning.get_bins_in_cone(request.ra_deg, request.dec_deg, radius_deg);
+    let n_bins = bins.len();
+    let (start, mut skip) = match &request.continuation {
+        Some(token) => decode_token(config, &binding, token)?,
+        None => (0, 0),
+    };
+    let mut cap = RowCap::new(config, request.max_rows, false);
+
+    for (i, total_bin) in bins.into_iter().enumerate().skip(start) {
+        // Always make some progress, so that continuing is never futile.
+        if i > start && deadline.is_near() {
+            let n_rows = cap.n_rows();
+
+            return Ok(Response::new(
+                lines,
+                Truncation {
+                    truncated: true,
+                    reason: Some(TruncationReason::Deadline),
+                    n_rows,
+                    estimated_total_rows: estimate_total(n_rows, 0, i - start, n_bins - i),
+                    continuation: Some(encode_token(config, &binding, i, 0)?),
+                },
+                &request.formatting,
+            ));
+        }
+
+        let n_before = lines.len();
+        lines = read_bin(
+            lines, &mut seen, None, catalog, &cat_table, total_bin, &request, tables,
         )
         .await?;
 
-        if let Some(b2) = ra_bound_2 {
-            lines =
-                read_dec_bin(lines, &cat_table, ibin, b2.0, b2.1, &request, dc, binning).await?;
+        if let Some(f) = &row_filter {
+            let kept: Vec<_> = lines.drain(n_before..).filter(|r| f.matches(r)).collect();
+            lines.extend(kept);
         }
+
+        // When continuing a capped result, drop the rows of this bin that
+        // we've already returned.
+        let n_skip = usize::min(skip, lines.len() - n_before);
+        lines.drain(n_before..n_before + n_skip);
+
+        let n_new = lines.len() - n_before;
+        let n_fit = cap.admit(n_new);
+
+        if n_fit < n_new {
+            lines.truncate(n_before + n_fit);
+            let n_rows = cap.n_rows();
+
+            return Ok(Response::new(
+                lines,
+                Truncation {
+                    truncated: true,
+                    reason: Some(TruncationReason::MaxRows),
+                    n_rows,
+                    estimated_total_rows: estimate_total(
+                        n_rows,
+                        n_new - n_fit,
+                        i + 1 - start,
+                        n_bins - i - 1,
+                    ),
+                    continuation: Some(encode_token(config, &binding, i, skip + n_fit)?),
+                },
+                &request.formatting,
+            ));
+        }
+
+        skip = 0;
     }
 
-    Ok(lines)
+    let n_rows = cap.n_rows();
+    Ok(Response::new(
+        lines,
+        Truncation::complete(n_rows),
+        &request.formatting,
+    ))
 }
 
-async fn read_dec_bin(
+/// Find the sources nearest to the search position, expanding the search
+/// cone as needed; see the top of this file.
+#[allow(clippy::too_many_arguments)]
+async fn nearest(
     mut lines: Vec<String>,
-    cat_table: &str,
-    dec_bin: usize,
-    box_ra_min: f64,
-    box_ra_max: f64,
+    n: usize,
     request: &Request,
-    dc: &aws_sdk_dynamodb::Client,
+    catalog: &Catalog,
+    cat_table: &str,
+    row_filter: Option<&RowFilter>,
+    tables: &dyn TableStore,
     binning: &crate::gscbin::GscBinning,
+    deadline: Deadline,
+) -> Result<Response, Error> {
+    let max_radius_deg = request.radius_deg();
+    let mut radius_deg = f64::min(NEAREST_START_RADIUS_ARCSEC / 3600., max_radius_deg);
+    let mut bins_read = HashSet::new();
+    let mut seen = HashSet::new();
+    let mut found: Vec<(f64, String)> = Vec::new();
+    let mut reason = None;
+
+    loop {
+        for total_bin in binning.get_bins_in_cone(request.ra_deg, request.dec_deg, radius_deg) {
+            if !bins_read.insert(total_bin) {
+                continue;
+            }
+
+            let mut distances = Vec::new();
+            let rows = read_bin(
+                Vec::new(),
+                &mut seen,
+                Some(&mut distances),
+                catalog,
+                cat_table,
+                total_bin,
+                request,
+                tables,
+            )
+            .await?;
+
+            found.extend(
+                distances
+                    .into_iter()
+                    .zip(rows)
+                    .filter(|(_, row)| row_filter.is_none_or(|f| f.matches(row))),
+            );
+        }
+
+        let n_within = found.iter().filter(|(d, _)| *d <= radius_deg).count();
+
+        if n_within >= n || radius_deg >= max_radius_deg {
+            break;
+        }
+
+        if deadline.is_near() {
+            reason = Some(TruncationReason::Deadline);
+            break;
+        }
+
+        radius_deg = f64::min(2. * radius_deg, max_radius_deg);
+    }
+
+    // Sources outside of the cone might not be the nearest ones, since we
+    // haven't read all of the bins that are closer.
+    found.retain(|(d, _)| *d <= radius_deg);
+    found.sort_by(|a, b| a.0.total_cmp(&b.0));
+    found.truncate(n);
+    lines.extend(found.into_iter().map(|(_, row)| row));
+
+    let n_rows = lines.len() - 1;
+
+    let truncation = match reason {
+        Some(reason) => Truncation {
+            truncated: true,
+            reason: Some(reason),
+            n_rows,
+            estimated_total_rows: n,
+            continuation: None,
+        },
+        None => Truncation::complete(n_rows),
+    };
+
+    Ok(Response::new(lines, truncation, &request.formatting))
+}
+
+/// Read one bin of the catalog, appending the matching sources to `lines`,
+/// except for those whose IDs are already in `seen`. If `distances` is given,
+/// sources without real positions are skipped, and the distance of each
+/// appended source from the search position, in degrees, is appended to it.
+#[allow(clippy::too_many_arguments)]
+async fn read_bin(
+    mut lines: Vec<String>,
+    seen: &mut HashSet<String>,
+    mut distances: Option<&mut Vec<f64>>,
+    catalog: &Catalog,
+    cat_table: &str,
+    total_bin: usize,
+    request: &Request,
+    tables: &dyn TableStore,
 ) -> Result<Vec<String>, Error> {
-    let tbin0 = binning.get_total_bin(dec_bin, box_ra_min);
-    let tbin1 = binning.get_total_bin(dec_bin, box_ra_max);
     let mut cells = Vec::new();
+    let radius_deg = request.radius_deg();
 
-    let radius_deg = request.radius_arcsec / 3600.0;
-
-    // For computing RA separations below -- the "effective" RA of the search
-    // center might need to vary if we've partitioned the search into two
-    // sub-boxes in RA.
-    let eff_search_ra = request.ra_deg
-        + if request.ra_deg < box_ra_min {
-            // Our box has RA ~ 359 while the search center has RA ~ 1.
-            360.
-        } else if request.ra_deg > box_ra_max {
-            // Our box has RA ~ 1 while the search center has RA ~ 359.
-            -360.
-        } else {
-            0.
-        };
+    let items = tables
+        .query_items(
+            cat_table,
+            "gscBinIndex",
+            AttributeValue::N(total_bin.to_string()),
+        )
+        .await?;
 
-    for itbin in tbin0..=tbin1 {
-        let mut stream = dc
-            .query()
-            .table_name(cat_table)
-            .expression_attribute_names("#p", "gscBinIndex")
-            .expression_attribute_values(":bin", AttributeValue::N(itbin.to_string()))
-            .key_condition_expression("#p = :bin")
-            .into_paginator()
-            .items()
-            .send();
-
-        while let Some(item) = stream.next().await {
-            let item = item?;
-            cells.clear();
-
-            let ra_deg = item
-                .get("ra")
-                .and_then(|av| av.as_n().ok())
-                .and_then(|text| text.parse::<f64>().ok());
-
-            let dec_deg = item
-                .get("dec")
-                .and_then(|av| av.as_n().ok())
-                .and_then(|text| text.parse::<f64>().ok());
-
-            let (ra_deg, dec_deg) = match (ra_deg, dec_deg) {
-                (Some(r), Some(d)) => (r, d),
-                _ => continue,
-            };
+    // All of the real source positions in the bin, for neighbor counting.
+    let positions: Vec<(f64, f64)> = match request.neighbor_radius_arcsec {
+        Some(_) => items
+            .iter()
+            .filter_map(item_position)
+            .filter(|(r, d)| !BUILTIN_PLACEHOLDERS.is_placeholder(*r, *d))
+            .collect(),
+        None => Vec::new(),
+    };
 
-            // Now we can evaluate if this source actually matches the
-            // positional search. Note that we're actually evaluating a box, not
-            // a conical radius.
-            //
-            // Unlike "classical" querycat, we ignore the uncertainty introduced
-            // by the proper motion term.
+    for item in items {
+        cells.clear();
 
-            // If the limiting values go unphysical, no problem.
-            if dec_deg < request.dec_deg - radius_deg || dec_deg > request.dec_deg + radius_deg {
-                continue;
-            }
+        let (ra_deg, dec_deg) = match item_position(&item) {
+            Some((r, d)) => (Some(r), Some(d)),
+            None => (None, None),
+        };
 
-            let factor = (D2R * dec_deg).cos();
+        // Sources with placeholder positions can't be tested against the
+        // search box, but they're in the bin that we're searching, so we
+        // report them with their positional columns left empty.
+
+        let sep = match (ra_deg, dec_deg) {
+            (Some(r), Some(d)) if BUILTIN_PLACEHOLDERS.is_placeholder(r, d) => None,
+            (Some(r), Some(d)) => match box_separation(request, radius_deg, r, d) {
+                Some(sep) => Some(sep),
+                None => continue,
+            },
+            _ => continue,
+        };
 
-            // If the search box spans the RA = 0 = 360 line, this function will
-            // be called twice to handle the wraparound, so we can also be
-            // cavalier with the limits here.
+        let distance = match (ra_deg, dec_deg, sep) {
+            (Some(r), Some(d), Some(_)) => {
+                Some(angular_separation(request.ra_deg, request.dec_deg, r, d))
+            }
+            _ => None,
+        };
 
-            let (min_ra, max_ra) = if factor <= 0. {
-                (0., 360.)
-            } else {
-                (
-                    eff_search_ra - radius_deg / factor,
-                    eff_search_ra + radius_deg / factor,
-                )
-            };
+        if distances.is_some() && distance.is_none() {
+            warnings::tally(
+                "positions_unknown",
+                "catalog sources without measured positions were left out of the nearest-source search",
+                1,
+            );
+            continue;
+        }
 
-            if ra_deg < min_ra || ra_deg > max_ra {
+        if let Some(AttributeValue::N(id) | AttributeValue::S(id)) = item.get(catalog.id_attribute)
+        {
+            if !seen.insert(id.clone()) {
                 continue;
             }
+        }
 
-            let mut delta_ra = request.ra_deg - ra_deg;
+        // Precessed positions have to be computed, so they can't just be
+        // reformatted from the stored text.
+        let precessed = request
+            .formatting
+            .equinox
+            .and(ra_deg.zip(dec_deg))
+            .map(|(r, d)| request.formatting.precess(r, d));
+
+        for col in catalog.columns {
+            let attr = || match item.get(col.internal) {
+                Some(AttributeValue::N(s)) | Some(AttributeValue::S(s)) => s.clone(),
+                _ => String::new(),
+            };
 
-            if delta_ra < -180. {
-                delta_ra += 360.;
-            } else if delta_ra > 180. {
-                delta_ra -= 360.;
-            }
+            cells.push(match col.format {
+                Format::Raw => attr(),
+
+                Format::RefText => item
+                    .get(col.internal)
+                    .and_then(|av| av.as_n().ok())
+                    .and_then(|text| text.parse::<u64>().ok())
+                    .map(refnum_to_text)
+                    .unwrap_or_else(|| "UNDEFINED".to_owned()),
+
+                Format::Ra | Format::Dec if sep.is_none() => String::new(),
+                Format::Ra => match precessed {
+                    Some((r, _)) => request.formatting.ra(r, 6),
+                    None => request.formatting.ra_text(attr()),
+                },
+                Format::Dec => match precessed {
+                    Some((_, d)) => request.formatting.dec(d, 6),
+                    None => request.formatting.dec_text(attr()),
+                },
+                Format::Magnitude => request.formatting.mag_text(attr()),
+                Format::RaOffset => sep.map(|s| format!("{}", s.0)).unwrap_or_default(),
+                Format::DecOffset => sep.map(|s| format!("{}", s.1)).unwrap_or_default(),
+                Format::Epoch => {
+                    let epoch = item
+                        .get(col.internal)
+                        .and_then(|av| av.as_n().ok())
+                        .and_then(|text| text.parse::<f64>().ok())
+                        .unwrap_or(catalog.epoch);
+                    format!("{epoch:.3}")
+                }
+            });
+        }
 
-            let factor = (D2R * 0.5 * (dec_deg + request.dec_deg)).cos();
+        if let Some(r) = request.neighbor_radius_arcsec {
+            let n = match (ra_deg, dec_deg, sep) {
+                (Some(ra), Some(dec), Some(_)) => {
+                    let r_deg = r / 3600.;
+
+                    // This counts the source itself, so subtract one.
+                    let n = positions
+                        .iter()
+                        .filter(|(r2, d2)| angular_separation(ra, dec, *r2, *d2) <= r_deg)
+                        .count();
+                    n.saturating_sub(1).to_string()
+                }
 
-            let sep = (
-                3600. * factor * delta_ra,
-                3600. * (request.dec_deg - dec_deg),
-            );
+                _ => String::new(),
+            };
 
-            for col in INTERNAL_COLUMNS {
-                match *col {
-                    "refText" => {
-                        let val = item
-                            .get("refNumber")
-                            .and_then(|av| av.as_n().ok())
-                            .and_then(|text| text.parse::<u64>().ok())
-                            .map(|n| refnum_to_text(n))
-                            .unwrap_or_else(|| "UNDEFINED".to_owned());
-                        cells.push(val);
-                    }
-
-                    "draAsec" => {
-                        cells.push(format!("{}", sep.0));
-                    }
-
-                    "ddecAsec" => {
-                        cells.push(format!("{}", sep.1));
-                    }
-
-                    "posEpoch" => {
-                        cells.push("2000.000".to_string());
-                    }
-
-                    _ => match item.get(*col) {
-                        None => {
-                            cells.push("".to_string());
-                        }
-
-                        Some(val) => match val {
-                            AttributeValue::N(s) => cells.push(s.clone()),
-                            AttributeValue::S(s) => cells.push(s.clone()),
-                            _ => cells.push("".to_string()),
-                        },
-                    },
-                }
-            }
+            cells.push(n);
+        }
 
-            lines.push(cells.join(","));
+        if let (Some(distances), Some(d)) = (distances.as_deref_mut(), distance) {
+            distances.push(d);
         }
+
+        lines.push(cells.join(","));
     }
 
     Ok(lines)
 }
+
+/// Get the position of a refcat source, if it has one.
+pub fn item_position(item: &crate::backend::Item) -> Option<(f64, f64)> {
+    let coord = |name: &str| {
+        item.get(name)
+            .and_then(|av| av.as_n().ok())
+            .and_then(|text| text.parse::<f64>().ok())
+    };
+
+    Some((coord("ra")?, coord("dec")?))
+}
+
+/// Determine whether a source lands in the search box. If so, return its RA
+/// and Dec offsets from the search center, in arcseconds.
+///
+/// Note that we're actually evaluating a box, not a conical radius. Unlike
+/// "classical" querycat, we ignore the uncertainty introduced by the proper
+/// motion term.
+fn box_separation(
+    request: &Request,
+    radius_deg: f64,
+    ra_deg: f64,
+    dec_deg: f64,
+) -> Option<(f64, f64)> {
+    // If the limiting values go unphysical, no problem.
+    if dec_deg < request.dec_deg - radius_deg || dec_deg > request.dec_deg + radius_deg {
+        return None;
+    }
+
+    // If the search box spans the RA = 0 = 360 line, the bin search will be
+    // split in two to handle the wraparound. Working with the wrapped RA
+    // difference means that we don't need to worry about which side of the
+    // line we're on.
+
+    let factor = (D2R * dec_deg).cos();
+    let delta_ra = delta_ra(request.ra_deg, ra_deg);
+
+    if factor > 0. && delta_ra.abs() > radius_deg / factor {
+        return None;
+    }
+
+    let factor = (D2R * 0.5 * (dec_deg + request.dec_deg)).cos();
+
+    Some((
+        3600. * factor * delta_ra,
+        3600. * (request.dec_deg - dec_deg),
+    ))
+}
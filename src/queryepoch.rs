@@ -0,0 +1,387 @@
+//! The exposure-by-date query API service.
+//!
+//! Given a window of Julian Dates, query all known exposures whose midpoints
+//! fall within it, anywhere on the sky. This serves users studying particular
+//! historical events -- novae, eclipses, and so on -- who start from a time
+//! rather than a position, and for whom `queryexps` is no help.
+//!
+//! The plates table has a global secondary index, named by
+//! `Config::plates_date_index`, whose partition key is the `expDay`
+//! attribute: the integer part of the Julian Date of the midpoint of the
+//! plate's first exposure. Julian days begin at noon UT, so all of a night's
+//! plates share a day number. We query the index once for each day that the
+//! window touches, and report the exposures whose midpoints are within the
+//! window, which is inclusive at its start and exclusive at its end, so that
+//! consecutive windows can be tiled without repeats. Plates are found by the
+//! day of their first exposure, so the later exposures of plates exposed over
+//! several nights can be missed if the window doesn't include that day.
+//! Plates whose exposure dates are unknown aren't in the index at all.
+//!
+//! The index must project the `plateId`, `plateNumber`, `series`, and
+//! `astrometry` attributes of the plates table.
+//!
+//! Rows are ordered by exposure midpoint. The `ra` and `dec` columns give the
+//! exposure center as recorded in the database, and are empty if it's unknown
+//! or a placeholder of the plate's series (see
+//! [`crate::mosaics::PlaceholderPolicy`]). The `expdate`, `expmjd`, `expjd`,
+//! and `flags` columns are as in `queryexps`, although the only flag reported
+//! here is `deny_listed`. The `formatting` request field adjusts how positions
+//! are written, and can precess them to another equinox; see
+//! [`crate::formatting`].
+//!
+//! Results are capped at a maximum number of rows; see `rowcap.rs`. The
+//! continuation token of a capped result records the day that it stopped in,
+//! and how many of that day's rows it returned.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use lambda_http::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::ops::Bound::{Excluded, Included};
+
+use crate::{
+    backend::TableStore,
+    config::{default_data_release, Config},
+    continuation::{decode_token, encode_token, Binding},
+    deadline::Deadline,
+    denylist::{DenyList, DenyMode},
+    envelope,
+    formatting::{CoordinateFrame, Formatting},
+    rowcap::{estimate_total, RowCap, Truncation, TruncationReason},
+    seriesdefaults::SeriesDefaults,
+    timeutil::UtcTime,
+    validation::{self, validate_fields},
+};
+
+/// The longest window that can be queried, in days.
+pub const MAX_WINDOW_DAYS: f64 = 31.;
+
+/// The earliest Julian Date that can be queried, in 1858. No DASCH plates are
+/// this old, but this rules out confusion with MJDs.
+pub const MIN_JD: f64 = 2_400_000.;
+
+/// The latest Julian Date that can be queried, in 2050.
+pub const MAX_JD: f64 = 2_470_000.;
+
+/// The output columns.
+const COLUMNS: &[&str] = &[
+    "series", "platenum", "expnum", "ra", "dec", "exptime", "expdate", "expmjd", "expjd", "flags",
+];
+
+/// The attributes that we need from the date index.
+const INDEX_PROJECTION: &str = "plateId,plateNumber,series,astrometry.exposures";
+
+/// Sync with `json-schemas/queryepoch_request.json`.
+#[derive(Deserialize, Serialize)]
+pub struct Request {
+    /// The start of the window, as a Julian Date.
+    jd_start: f64,
+
+    /// The end of the window, as a Julian Date.
+    jd_end: f64,
+
+    /// The data release to query.
+    #[serde(default = "default_data_release")]
+    data_release: String,
+
+    /// How to handle deny-listed plates.
+    #[serde(default)]
+    deny_list: DenyMode,
+
+    /// A continuation token from a previous, truncated response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    continuation: Option<String>,
+
+    /// The maximum number of rows to return, if lower than the server's cap.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_rows: Option<usize>,
+
+    /// How to format positions; see `formatting.rs`.
+    #[serde(default, skip_serializing_if = "Formatting::is_default")]
+    formatting: Formatting,
+}
+
+impl Request {
+    /// Validate the request.
+    pub fn normalize(mut self, config: &Config) -> Result<Self, Error> {
+        config.check_release(&self.data_release)?;
+
+        validate_fields!(self {
+            jd_start: |n, v| validation::range(n, v, MIN_JD..=MAX_JD),
+            max_rows: validation::optional(|n, v| validation::range(n, v, 1..)),
+            formatting: Formatting::validate,
+        });
+
+        let start = self.jd_start;
+
+        validate_fields!(self {
+            jd_end: |n, v| validation::range(
+                n,
+                v,
+                (Excluded(start), Included(start + MAX_WINDOW_DAYS))
+            ),
+        });
+
+        if let Some(token) = &self.continuation {
+            decode_token(config, &Binding::new("queryepoch", &self)?, token)?;
+        }
+
+        Ok(self)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Response {
+    /// CSV-formatted rows of results, starting with a header row.
+    pub rows: Vec<String>,
+
+    /// If true, the results are incomplete, because we ran short of time or
+    /// hit the row cap.
+    pub truncated: bool,
+
+    /// If the results are truncated, a token that can be passed back to
+    /// continue the query.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub continuation: Option<String>,
+
+    /// The frame of the positional columns.
+    pub coordinates: CoordinateFrame,
+
+    /// The truncation state, for the envelope.
+    #[serde(skip)]
+    pub truncation: Truncation,
+}
+
+impl Response {
+    fn new(rows: Vec<String>, truncation: Truncation, formatting: &Formatting) -> Self {
+        Response {
+            rows,
+            truncated: truncation.truncated,
+            continuation: truncation.continuation.clone(),
+            coordinates: formatting.frame(["ra", "dec"], None),
+            truncation,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlatesResult {
+    astrometry: Option<PlatesAstrometryResult>,
+    plate_id: String,
+    plate_number: usize,
+    series: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlatesAstrometryResult {
+    exposures: Vec<Option<PlatesExposureResult>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlatesExposureResult {
+    dec_deg: Option<f64>,
+    dur_min: Option<f64>,
+    midpoint_date: Option<String>,
+    number: i8,
+    ra_deg: Option<f64>,
+}
+
+pub async fn handler(
+    req: Option<Value>,
+    config: &Config,
+    tables: &dyn TableStore,
+    deny_list: &DenyList,
+    series_defaults: &SeriesDefaults,
+    deadline: Deadline,
+) -> Result<Value, Error> {
+    let request: Request =
+        serde_json::from_value(req.ok_or_else(|| -> Error { "no request payload".into() })?)?;
+    let request = request.normalize(config)?;
+    let echo = serde_json::to_value(&request)?;
+    let result = implementation(
+        request,
+        config,
+        tables,
+        deny_list,
+        series_defaults,
+        deadline,
+    )
+    .await?;
+    let truncation = result.truncation.clone();
+    envelope::wrap_table("queryepoch", &echo, result, truncation)
+}
+
+/// Query the exposures in a window of time. The request must have been
+/// normalized.
+pub async fn implementation(
+    request: Request,
+    config: &Config,
+    tables: &dyn TableStore,
+    deny_list: &DenyList,
+    series_defaults: &SeriesDefaults,
+    deadline: Deadline,
+) -> Result<Response, Error> {
+    let binding = Binding::new("queryepoch", &request)?;
+    let mut lines = vec![COLUMNS.join(",")];
+    let table_name = config.plates_table(&request.data_release);
+    let days: Vec<i64> =
+        (request.jd_start.floor() as i64..=request.jd_end.floor() as i64).collect();
+    let n_days = days.len();
+    let (start, mut skip) = match &request.continuation {
+        Some(token) => decode_token(config, &binding, token)?,
+        None => (0, 0),
+    };
+    let mut cap = RowCap::new(config, request.max_rows, false);
+
+    for (i, day) in days.into_iter().enumerate().skip(start) {
+        // Always make some progress, so that continuing is never futile.
+        if i > start && deadline.is_near() {
+            let n_rows = cap.n_rows();
+
+            return Ok(Response::new(
+                lines,
+                Truncation {
+                    truncated: true,
+                    reason: Some(TruncationReason::Deadline),
+                    n_rows,
+                    estimated_total_rows: estimate_total(n_rows, 0, i - start, n_days - i),
+                    continuation: Some(encode_token(config, &binding, i, 0)?),
+                },
+                &request.formatting,
+            ));
+        }
+
+        let mut rows = read_day(
+            &request,
+            config,
+            tables,
+            &table_name,
+            deny_list,
+            series_defaults,
+            day,
+        )
+        .await?;
+
+        // When continuing a capped result, drop the rows of this day that
+        // we've already returned.
+        rows.drain(..usize::min(skip, rows.len()));
+
+        let n_new = rows.len();
+        let n_fit = cap.admit(n_new);
+        lines.extend(rows.into_iter().take(n_fit));
+
+        if n_fit < n_new {
+            let n_rows = cap.n_rows();
+
+            return Ok(Response::new(
+                lines,
+                Truncation {
+                    truncated: true,
+                    reason: Some(TruncationReason::MaxRows),
+                    n_rows,
+                    estimated_total_rows: estimate_total(
+                        n_rows,
+                        n_new - n_fit,
+                        i + 1 - start,
+                        n_days - i - 1,
+                    ),
+                    continuation: Some(encode_token(config, &binding, i, skip + n_fit)?),
+                },
+                &request.formatting,
+            ));
+        }
+
+        skip = 0;
+    }
+
+    let n_rows = cap.n_rows();
+    Ok(Response::new(
+        lines,
+        Truncation::complete(n_rows),
+        &request.formatting,
+    ))
+}
+
+/// Get the result rows for the plates indexed under one day, in order.
+async fn read_day(
+    request: &Request,
+    config: &Config,
+    tables: &dyn TableStore,
+    table_name: &str,
+    deny_list: &DenyList,
+    series_defaults: &SeriesDefaults,
+    day: i64,
+) -> Result<Vec<String>, Error> {
+    let placeholders = series_defaults.placeholders();
+
+    let items = tables
+        .query_index(
+            table_name,
+            &config.plates_date_index,
+            "expDay",
+            AttributeValue::N(day.to_string()),
+            INDEX_PROJECTION,
+        )
+        .await?;
+
+    // Sort keys are (JD, plate ID, exposure number), so that the order is
+    // deterministic, as continuations require.
+    let mut keyed = Vec::new();
+
+    for item in items {
+        let plate: PlatesResult = serde_dynamo::from_item(item)?;
+        let denied = deny_list.reason(&plate.plate_id).is_some();
+
+        if denied && request.deny_list == DenyMode::Omit {
+            continue;
+        }
+
+        let flags_text = if denied { "deny_listed" } else { "" };
+        let exposures = plate.astrometry.map(|a| a.exposures).unwrap_or_default();
+        let policy = placeholders.get(&plate.series);
+
+        for exp in exposures.into_iter().flatten() {
+            let Some(time) = exp.midpoint_date.as_deref().and_then(UtcTime::parse) else {
+                continue;
+            };
+
+            let jd = time.jd();
+
+            if jd < request.jd_start || jd >= request.jd_end {
+                continue;
+            }
+
+            let center_text = match (exp.ra_deg, exp.dec_deg) {
+                (Some(ra), Some(dec)) if !policy.is_placeholder(ra, dec) => {
+                    request.formatting.position(ra, dec, 6)
+                }
+                _ => ",".to_owned(),
+            };
+            let exptime_text = exp.dur_min.map(|d| format!("{:.2}", d)).unwrap_or_default();
+
+            let row = format!(
+                "{},{},{},{},{},{},{:.5},{:.5},{}",
+                plate.series,
+                plate.plate_number,
+                exp.number,
+                center_text, // 2 columns
+                exptime_text,
+                exp.midpoint_date.as_deref().unwrap_or(""),
+                time.mjd(),
+                jd,
+                flags_text,
+            );
+            keyed.push(((jd, plate.plate_id.clone(), exp.number), row));
+        }
+    }
+
+    keyed.sort_by(|(a, _), (b, _)| {
+        a.0.total_cmp(&b.0)
+            .then_with(|| a.1.cmp(&b.1))
+            .then_with(|| a.2.cmp(&b.2))
+    });
+    Ok(keyed.into_iter().map(|(_, row)| row).collect())
+}
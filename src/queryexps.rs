@@ -9,29 +9,352 @@
 //! anything that we can't easily do ourselves. We reuse the same set of
 //! sky-binned CSV files that that API uses to narrow down the list of plates to
 //! search.
+//!
+//! Some exposures only have placeholder values for their positions, which can
+//! differ by series (see [`crate::mosaics::PlaceholderPolicy`]). If such an
+//! exposure has no real astrometric solution, we can't tell whether it
+//! overlaps the search point, so we report it with empty position and distance
+//! columns.
+//!
+//! Matches based on approximate WCS -- which we construct for exposures that
+//! lack an astrometric solution, from their logged center and the nominal
+//! plate scale of their series -- are only as good as those assumptions. For
+//! these, the `approx*` columns report the assumed field size in degrees and
+//! the assumed plate size in centimeters, so that users can judge how far to
+//! trust the overlap. These columns are empty for other matches.
+//!
+//! The `centerdist` and `edgedist` columns give distances on the plate, in
+//! centimeters. The `centerdistdeg` and `edgedistdeg` columns give the same
+//! distances converted to degrees using the WCS pixel scale at the search
+//! point. This conversion is only approximate, since the scale varies across
+//! the plate, but it's what observers usually want to reason with.
+//!
+//! Exposures and solutions are identified by `expnum` and `solnum`, which
+//! aren't always easy to relate to each other. The `has_solution` column is 1
+//! if the astrometry record includes a solution for the row's exposure, and 0
+//! otherwise, regardless of whether we could use that solution. The
+//! `exposure_index` column gives the zero-based position of the exposure when
+//! the plate's exposures are ordered by number, or is empty if the exposure
+//! isn't in the astrometry record.
+//!
+//! The `formatting` request field adjusts how the `ra` and `dec` columns are
+//! written, and can precess them to another equinox; see
+//! [`crate::formatting`].
+//!
+//! The `expdate` column gives the exposure midpoint as recorded in the
+//! database, in ISO 8601 UTC. The `expmjd` and `expjd` columns give the same
+//! time as a Modified Julian Date and a Julian Date, as computed by
+//! `timeutil.rs`. They're empty if the date is missing or can't be parsed.
+//!
+//! The `centersepdeg` and `centerpadeg` columns give the angular separation
+//! between the exposure center and the search point, and the position angle
+//! of the search point as seen from the center (east of north), both in
+//! degrees. These are computed on the sphere, not from pixel distances, which
+//! makes them suitable for defining selection functions.
+//!
+//! The `bkglevel` and `satfrac` columns give basic statistics of the plate
+//! scan: the median pixel value of the mosaic, in ADU, and the fraction of its
+//! pixels that are saturated. Empty or badly fogged plates stand out in these,
+//! so clients can use them to exclude such plates. They're precomputed when the
+//! mosaic is ingested, and are empty if the plate has no mosaic or the
+//! statistics haven't been computed for it.
+//!
+//! Cutout requests identify a plate's exposures by solution number. So that
+//! clients don't have to work out the pairing themselves, the response's
+//! `solutions` field maps, for each plate with result rows, every solution
+//! number to the number of the exposure that it belongs to.
+//!
+//! The `poserrarcsec` column gives an estimate of the astrometric uncertainty
+//! of the plate at the search point, in arcseconds, so that users can tell
+//! whether the plate is usable for astrometry of their target. It's based on
+//! the stored residuals of the solution's reference stars and the distance of
+//! the search point from the plate center, as described in `poserr.rs`, and is
+//! empty for matches without a real astrometric solution or stored residuals.
+//!
+//! The `airmass` and `hourangle` columns give the airmass and hour angle, in
+//! hours, of the search point at the exposure midpoint, for studies of
+//! photometric systematics. They need to know where the plate was taken, so
+//! they're empty for series without an observing site in the series metadata
+//! table, as well as for exposures without usable dates. The airmass is also
+//! empty if the search point was below the horizon. See `observing.rs`.
+//!
+//! The `recommended` column is 1 for the exposure of each plate that we'd
+//! recommend using, if the search point lands on more than one, and 0 for the
+//! others, so that clients don't have to come up with their own ways of
+//! choosing. The heuristic is documented and versioned in `recommend.rs`.
+//!
+//! The final `flags` column holds a semicolon-separated list of data-quality
+//! flags for the row. These are `astrometry_unreadable`, which indicates that
+//! the plate's stored astrometric header is corrupted, so that only approximate
+//! WCS could be used; and `deny_listed`, which indicates that the plate is on
+//! the deny-list of known-bad plates (see [`crate::denylist`]). Deny-listed
+//! plates are left out entirely if the request's `deny_list` field is `omit`.
+//!
+//! The coverage bins come in two formats. The legacy CSV files only list the
+//! plate, solution, and exposure numbers of each entry. The v2 binary files
+//! (see [`parse_v2_bin`]) add the approximate center of each exposure, which
+//! lets us rule out some exposures without solutions before fetching their
+//! plate records. This only prunes the candidate list: the exposures that
+//! survive it, and all exposures with solutions, are still fetched and tested
+//! against their WCS as usual, so the results are the same either way. We use
+//! a bin's v2 file if there is one, and its CSV file otherwise, so that the v2
+//! files can be rolled out gradually. While they are, each instance remembers
+//! which bins have no v2 file, so that it only looks for each one once; new v2
+//! files are picked up by new instances.
+//!
+//! The outcomes of the WCS overlap tests can be cached, so that repeated
+//! searches of the same region skip the exposures that are known to miss it;
+//! see `overlapcache.rs`.
+//!
+//! If the request's `estimate` field is true, we only read the coverage bins,
+//! and return an [`Estimate`] of how big the result will be, rather than the
+//! result itself. This is cheap, and lets clients warn users about big queries
+//! before they make them. The estimate ignores the request's `filter`, which
+//! selects result rows with an expression over the output columns; see
+//! `filter.rs`.
+//!
+//! If the request's `stage_results` field is true, the rows are streamed into
+//! a CSV file in S3 as they're produced, rather than being returned, and the
+//! response's `staged` field gives a URL from which to download it (see
+//! [`crate::staging`]). This is meant for big queries, whose results won't fit
+//! in a Lambda response. Truncation and continuation work as usual, with each
+//! continued request staging its rows to a new file.
+//!
+//! Inline results are capped at a maximum number of rows, and staged ones can
+//! be; see `rowcap.rs`. The continuation token of a capped result records the
+//! batch of plates that it stopped in, and how many of that batch's rows it
+//! returned.
 
 use anyhow::Result;
 use aws_sdk_dynamodb::types::AttributeValue;
-use aws_sdk_s3;
 use flate2::read::GzDecoder;
+use futures::stream::{self, StreamExt};
 use lambda_http::Error;
-use serde::Deserialize;
+use once_cell::sync::Lazy;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
-use tokio::io::AsyncBufReadExt;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    io::BufRead,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use crate::{
-    mosaics::{load_b01_header, wcslib_solnum, PIXELS_PER_MM, PLATE_SCALE_BY_SERIES},
-    wcs::WcsCollection,
-    BUCKET,
+    apiversion::{self, default_api_version, project_rows},
+    backend::{ObjectStore, TableStore},
+    config::{default_data_release, Config},
+    continuation::{decode_token, encode_token, Binding},
+    coords::{angular_separation, position_angle},
+    deadline::Deadline,
+    denylist::{DenyList, DenyMode},
+    envelope,
+    features::FeatureOverrides,
+    filter::{self, RowFilter},
+    formatting::{CoordinateFrame, Formatting},
+    mosaics::{
+        load_b01_header, wcslib_solnum, PlaceholderPolicy, PlateId, PIXELS_PER_MM,
+        PLATE_SCALE_BY_SERIES,
+    },
+    observing::ObservingSite,
+    overlapcache::{miss_key, OverlapCache, MISS_MARGIN},
+    platecache::PlateCache,
+    poserr::RadialErrorModel,
+    recommend::{self, Astrometry, Candidate},
+    rowcap::{estimate_total, RowCap, Truncation, TruncationReason},
+    seriesdefaults::{SeriesDefaults, SeriesPlaceholders},
+    staging::{Staged, Stager},
+    targets,
+    timeutil::UtcTime,
+    validation::{self, validate_fields},
+    warnings,
+    wcs::{Wcs, WcsCollection},
 };
 
+/// The output columns of version 1 of the API.
+const V1_COLUMNS: &[&str] = &[
+    "series",
+    "platenum",
+    "scannum",
+    "mosnum",
+    "expnum",
+    "solnum",
+    "class",
+    "ra",
+    "dec",
+    "exptime",
+    "expdate",
+    "epoch",
+    "wcssource",
+    "scandate",
+    "mosdate",
+    "centerdist",
+    "edgedist",
+];
+
 /// Sync with `json-schemas/queryexps_request.json`, which then needs to be
 /// synced into S3.
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct Request {
     pub ra_deg: f64,
     pub dec_deg: f64,
+
+    /// The name of the target, resolved into `ra_deg` and `dec_deg` before the
+    /// request is parsed; see `targets.rs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_name: Option<String>,
+
+    /// The data release to query.
+    #[serde(default = "default_data_release")]
+    pub data_release: String,
+
+    /// How to handle deny-listed plates.
+    #[serde(default)]
+    pub deny_list: DenyMode,
+
+    /// A continuation token from a previous, truncated response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub continuation: Option<String>,
+
+    /// If true, estimate the size of the result instead of computing it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub estimate: bool,
+
+    /// If true, write the result rows to S3 instead of returning them.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stage_results: bool,
+
+    /// The maximum number of rows to return, if lower than the server's cap.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_rows: Option<usize>,
+
+    /// Overrides of the deployment's feature flags; see `features.rs`.
+    #[serde(default, skip_serializing_if = "FeatureOverrides::is_empty")]
+    pub features: FeatureOverrides,
+
+    /// How to format positions; see `formatting.rs`.
+    #[serde(default, skip_serializing_if = "Formatting::is_default")]
+    pub formatting: Formatting,
+
+    /// An expression selecting the rows to return; see `filter.rs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+
+    /// The version of the response format to use; see `apiversion.rs`.
+    #[serde(
+        default = "default_api_version",
+        skip_serializing_if = "apiversion::is_current"
+    )]
+    pub api_version: u32,
+}
+
+impl Request {
+    /// Validate the request and normalize its coordinates.
+    pub fn normalize(mut self, config: &Config) -> Result<Self, Error> {
+        config.check_release(&self.data_release)?;
+
+        validate_fields!(self {
+            api_version: apiversion::validate,
+            ra_deg: validation::ra,
+            dec_deg: validation::dec,
+            max_rows: validation::optional(|n, v| validation::range(n, v, 1..)),
+            formatting: Formatting::validate,
+            filter: filter::validate,
+        });
+
+        if self.estimate {
+            apiversion::require(self.api_version, 2, "`estimate`")?;
+        }
+
+        if self.stage_results {
+            apiversion::require(self.api_version, 2, "`stage_results`")?;
+        }
+
+        if self.max_rows.is_some() {
+            apiversion::require(self.api_version, 2, "`max_rows`")?;
+        }
+
+        if self.target_name.is_some() {
+            apiversion::require(self.api_version, 2, "`target_name`")?;
+        }
+
+        if !self.formatting.is_default() {
+            apiversion::require(self.api_version, 2, "`formatting`")?;
+        }
+
+        if self.filter.is_some() {
+            apiversion::require(self.api_version, 2, "`filter`")?;
+        }
+
+        if let Some(token) = &self.continuation {
+            decode_token(config, &Binding::new("queryexps", &self)?, token)?;
+        }
+
+        Ok(self)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Response {
+    /// CSV-formatted rows of results, starting with a header row. Empty if
+    /// the results were staged to S3.
+    pub rows: Vec<String>,
+
+    /// Where to find the results, if they were staged to S3.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub staged: Option<Staged>,
+
+    /// If true, the results are incomplete, because we were unable to
+    /// retrieve all of the relevant plate records (most likely due to
+    /// DynamoDB throttling), because we ran short of time, or because we hit
+    /// the row cap.
+    pub truncated: bool,
+
+    /// If we ran short of time or hit the row cap, a token that can be passed
+    /// back to continue the query. Records lost to throttling aren't covered
+    /// by it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub continuation: Option<String>,
+
+    /// For each plate with result rows, the exposures that its astrometric
+    /// solutions belong to, in solution-number order.
+    pub solutions: BTreeMap<String, Vec<SolutionExposure>>,
+
+    /// The version of the heuristic behind the `recommended` column; see
+    /// `recommend.rs`.
+    pub recommendation_version: u32,
+
+    /// The frame of the positional columns.
+    pub coordinates: CoordinateFrame,
+
+    /// The truncation state, for the envelope.
+    #[serde(skip)]
+    pub truncation: Truncation,
+}
+
+/// The pairing of an astrometric solution with an exposure.
+#[derive(Serialize)]
+pub struct SolutionExposure {
+    pub solution_number: usize,
+
+    /// The exposure number, if the astrometry record identifies it.
+    pub exposure_number: Option<i8>,
+}
+
+/// The result of an estimate-mode request.
+#[derive(Serialize)]
+pub struct Estimate {
+    /// The number of candidate plates, each of which needs to be looked up
+    /// in the plates table.
+    pub n_plates: usize,
+
+    /// The number of candidate exposures. This is an upper bound on the
+    /// number of result rows, not counting the header.
+    pub max_rows: usize,
+
+    /// A rough upper bound on the size of the result, in bytes.
+    pub approx_max_bytes: usize,
 }
 
 #[derive(Deserialize)]
@@ -53,6 +376,8 @@ struct PlatesAstrometryResult {
     n_solutions: Option<usize>,
     rotation_delta: Option<isize>,
     exposures: Vec<Option<PlatesExposureResult>>,
+    #[serde(default)]
+    residuals: Vec<Option<PlatesResidualsResult>>,
 }
 
 #[derive(Deserialize)]
@@ -68,6 +393,15 @@ struct PlatesExposureResult {
     ra_deg: Option<f64>,
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlatesResidualsResult {
+    x_pix: Vec<f64>,
+    y_pix: Vec<f64>,
+    d_ra_asec: Vec<f64>,
+    d_dec_asec: Vec<f64>,
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct PlatesMosaicResult {
@@ -76,60 +410,249 @@ struct PlatesMosaicResult {
     creation_date: String,
     mos_num: i8,
     scan_num: i8,
+    background_level: Option<f64>,
+    saturation_fraction: Option<f64>,
 }
 
-#[derive(Debug)]
+/// The attributes that we need from the plates table.
+const PLATES_PROJECTION: &str = "astrometry.b01HeaderGz,\
+    astrometry.exposures,\
+    astrometry.nSolutions,\
+    astrometry.residuals,\
+    astrometry.rotationDelta,\
+    mosaic.b01Height,\
+    mosaic.b01Width,\
+    mosaic.backgroundLevel,\
+    mosaic.creationDate,\
+    mosaic.mosNum,\
+    mosaic.saturationFraction,\
+    mosaic.scanNum,\
+    plateId,\
+    plateNumber,\
+    series";
+
+/// The maximum number of keys allowed in a DynamoDB BatchGetItem request.
+const MAX_PER_BATCH: usize = 100;
+
+/// The maximum number of BatchGetItem requests that we'll have in flight at
+/// once.
+const MAX_BATCHES_IN_FLIGHT: usize = 8;
+
+/// The maximum number of times that we'll resubmit unprocessed keys in one
+/// batch before giving up.
+const MAX_UNPROCESSED_RETRIES: usize = 5;
+
+/// The delay before the first resubmission of unprocessed keys. The delay
+/// doubles with each subsequent retry.
+const INITIAL_RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// How far from the search point, in degrees, we look for coverage bins that
+/// might index overlapping plates.
+const BIN_SEARCH_TOLERANCE_DEG: f64 = 0.05;
+
+/// A generous size for one CSV result row, for estimates. Typical rows are
+/// 120--150 bytes.
+const APPROX_BYTES_PER_ROW: usize = 200;
+
+#[derive(Debug, Eq, PartialEq)]
 struct SolExp {
     sol_num: i8,
     exp_num: i8,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn handler(
     req: Option<Value>,
-    dc: &aws_sdk_dynamodb::Client,
-    s3: &aws_sdk_s3::Client,
+    config: &Config,
+    tables: &dyn TableStore,
+    objects: &dyn ObjectStore,
     binning: &crate::gscbin::GscBinning,
+    fine_binning: &crate::gscbin::GscBinning,
+    plates: &PlateCache,
+    deny_list: &DenyList,
+    series_defaults: &SeriesDefaults,
+    deadline: Deadline,
 ) -> Result<Value, Error> {
-    Ok(serde_json::to_value(
-        implementation(
-            serde_json::from_value(req.ok_or_else(|| -> Error { "no request payload".into() })?)?,
-            dc,
-            s3,
+    let mut req = req.ok_or_else(|| -> Error { "no request payload".into() })?;
+    targets::resolve_position(&mut req, config, "ra_deg", "dec_deg").await?;
+    let request: Request = serde_json::from_value(req)?;
+    let request = request.normalize(config)?;
+    let echo = serde_json::to_value(&request)?;
+
+    if request.estimate {
+        let result = estimate(
+            request,
+            config,
+            objects,
             binning,
+            deny_list,
+            series_defaults,
         )
-        .await?,
-    )?)
+        .await?;
+        return envelope::wrap("queryexps", &echo, result);
+    }
+
+    let version = request.api_version;
+    let result = implementation(
+        request,
+        config,
+        tables,
+        objects,
+        binning,
+        fine_binning,
+        plates,
+        deny_list,
+        series_defaults,
+        deadline,
+    )
+    .await?;
+
+    if version < 2 {
+        if result.truncated {
+            return Err(apiversion::TRUNCATED_V1.into());
+        }
+
+        return apiversion::respond(
+            version,
+            "queryexps",
+            &echo,
+            project_rows(result.rows, V1_COLUMNS)?,
+        );
+    }
+
+    let truncation = result.truncation.clone();
+    envelope::wrap_table("queryexps", &echo, result, truncation)
 }
 
-pub async fn implementation(
-    request: Request,
-    dc: &aws_sdk_dynamodb::Client,
-    s3: &aws_sdk_s3::Client,
+/// Get the candidate plates and solution/exposure pairs from the coarse
+/// binning.
+///
+/// At high declinations and near bin boundaries, plates that overlap the
+/// search point can be indexed in neighboring bins, so we check every bin
+/// within a small tolerance of the search point. The same plate+solexp may
+/// then show up more than once, so we deduplicate.
+async fn load_candidates(
+    request: &Request,
+    config: &Config,
+    objects: &dyn ObjectStore,
     binning: &crate::gscbin::GscBinning,
-) -> Result<Vec<String>, Error> {
-    // Early validation, with NaN-sensitive logic
+    series_defaults: &SeriesDefaults,
+) -> Result<HashMap<String, Vec<SolExp>>, Error> {
+    let bins = binning.get_bins_in_cone(request.ra_deg, request.dec_deg, BIN_SEARCH_TOLERANCE_DEG);
+    let placeholders = series_defaults.placeholders();
+    let mut candidates: HashMap<String, Vec<SolExp>> = HashMap::new();
 
-    if !(request.ra_deg >= 0. && request.ra_deg <= 360.) {
-        return Err("illegal ra_deg parameter".into());
-    }
+    for total_bin in bins {
+        for entry in load_bin(request, config, objects, total_bin).await? {
+            if rules_out(request, &entry, &placeholders) {
+                continue;
+            }
 
-    if !(request.dec_deg >= -90. && request.dec_deg <= 90.) {
-        return Err("illegal dec_deg parameter".into());
+            let solexps = candidates.entry(entry.plate_id).or_default();
+
+            if !solexps.contains(&entry.solexp) {
+                solexps.push(entry.solexp);
+            }
+        }
     }
 
-    // Get the approximate list of plates from the coarse binning.
+    Ok(candidates)
+}
 
-    let dec_bin = binning.get_dec_bin(request.dec_deg);
-    let total_bin = binning.get_total_bin(dec_bin, request.ra_deg);
-    let s3_key = format!("dasch-dr7-coverage-bins/{}.csv", total_bin);
+/// Get the IDs of the plates that may overlap a sky position, according to
+/// the coarse binning, in sorted order. These are the plates that a query
+/// would fetch, so some of them may not actually overlap it.
+pub async fn candidate_plate_ids(
+    ra_deg: f64,
+    dec_deg: f64,
+    data_release: &str,
+    config: &Config,
+    objects: &dyn ObjectStore,
+    binning: &crate::gscbin::GscBinning,
+    series_defaults: &SeriesDefaults,
+) -> Result<Vec<String>, Error> {
+    let request = Request {
+        ra_deg,
+        dec_deg,
+        target_name: None,
+        data_release: data_release.to_owned(),
+        deny_list: DenyMode::default(),
+        continuation: None,
+        estimate: false,
+        stage_results: false,
+        max_rows: None,
+        features: FeatureOverrides::new(),
+        formatting: Formatting::default(),
+        filter: None,
+        api_version: apiversion::CURRENT,
+    };
 
-    let resp = s3.get_object().bucket(BUCKET).key(&s3_key).send().await?;
-    let body = resp.body.into_async_read();
-    let mut lines = body.lines();
+    let mut ids: Vec<_> = load_candidates(&request, config, objects, binning, series_defaults)
+        .await?
+        .into_keys()
+        .collect();
+    ids.sort();
+    Ok(ids)
+}
 
-    let mut candidates: HashMap<String, Vec<SolExp>> = HashMap::new();
+/// One entry of a coverage-bin file.
+struct BinEntry {
+    plate_id: String,
+    solexp: SolExp,
+
+    /// The approximate center of the exposure, if known. Only v2 files have
+    /// these.
+    center: Option<(f64, f64)>,
+}
+
+/// The magic number at the start of a v2 coverage-bin file.
+const COVERAGE_V2_MAGIC: &[u8] = b"DCB\x02";
+
+/// The size of a v2 entry, not counting the plate ID.
+const COVERAGE_V2_ENTRY_SIZE: usize = 2 + 3 * 8;
+
+/// The largest plate dimension that we might see for a plate without a
+/// solution, in millimeters. The largest DASCH plates are 14x17 inches, but
+/// mosaics include some margin around the plate, so we're generous.
+const MAX_PLATE_SIZE_MM: f64 = 508.;
+
+/// The keys of the v2 coverage-bin files that this instance has found not to
+/// exist, so that it doesn't look for them again.
+static MISSING_V2_BINS: Lazy<Mutex<HashSet<String>>> = Lazy::new(Default::default);
+
+/// Load one coverage bin, preferring the v2 format if it's available. A bin
+/// with no file in either format just means that no plates cover it.
+async fn load_bin(
+    request: &Request,
+    config: &Config,
+    objects: &dyn ObjectStore,
+    total_bin: usize,
+) -> Result<Vec<BinEntry>, Error> {
+    let key = config.coverage_bin_v2_key(&request.data_release, total_bin);
+
+    if !MISSING_V2_BINS.lock().unwrap().contains(&key) {
+        if let Some(body) = objects.get_object(&config.bucket, &key).await? {
+            return parse_v2_bin(&body).map_err(|e| format!("coverage bin `{key}`: {e}").into());
+        }
+
+        MISSING_V2_BINS.lock().unwrap().insert(key);
+    }
+
+    let key = config.coverage_bin_key(&request.data_release, total_bin);
 
-    while let Some(line) = lines.next_line().await? {
+    match objects.get_object(&config.bucket, &key).await? {
+        Some(body) => parse_csv_bin(&body),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Parse a legacy CSV coverage-bin file. Each line gives a plate ID, solution
+/// number, and exposure number. Malformed lines are ignored.
+fn parse_csv_bin(body: &[u8]) -> Result<Vec<BinEntry>, Error> {
+    let mut entries = Vec::new();
+
+    for line in body.lines() {
+        let line = line?;
         let mut pieces = line.split(',');
         let plateid = pieces.next();
         let sol_num = pieces.next();
@@ -151,16 +674,141 @@ pub async fn implementation(
             Err(_) => continue,
         };
 
-        let solexps = candidates.entry(plateid.to_owned()).or_default();
-        solexps.push(SolExp { sol_num, exp_num });
+        entries.push(BinEntry {
+            plate_id: plateid.to_owned(),
+            solexp: SolExp { sol_num, exp_num },
+            center: None,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Parse a v2 coverage-bin file. After the magic number, each entry is:
+///
+/// - the length of the plate ID (u8), followed by the ID in ASCII
+/// - the solution number and exposure number (i8 each)
+/// - the approximate RA and declination of the exposure center, and its
+///   epoch as a Julian year (f64 each), NaN if unknown
+///
+/// All numbers are little-endian. The epochs are for future use.
+fn parse_v2_bin(body: &[u8]) -> Result<Vec<BinEntry>, String> {
+    let mut rest = body
+        .strip_prefix(COVERAGE_V2_MAGIC)
+        .ok_or("not a v2 coverage-bin file")?;
+    let mut entries = Vec::new();
+
+    while let Some((&id_len, tail)) = rest.split_first() {
+        let id_len = id_len as usize;
+
+        if tail.len() < id_len + COVERAGE_V2_ENTRY_SIZE {
+            return Err("truncated entry".to_owned());
+        }
+
+        let (id, tail) = tail.split_at(id_len);
+        let (data, tail) = tail.split_at(COVERAGE_V2_ENTRY_SIZE);
+        rest = tail;
+
+        let plate_id = std::str::from_utf8(id).map_err(|_| "non-text plate ID")?;
+        let f64_at = |i: usize| f64::from_le_bytes(data[i..i + 8].try_into().unwrap());
+        let (ra, dec) = (f64_at(2), f64_at(10));
+
+        entries.push(BinEntry {
+            plate_id: plate_id.to_owned(),
+            solexp: SolExp {
+                sol_num: data[0] as i8,
+                exp_num: data[1] as i8,
+            },
+            center: (ra.is_finite() && dec.is_finite()).then_some((ra, dec)),
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Decide whether a coverage-bin entry can't possibly match the search point,
+/// so that we don't need to fetch its plate record. We can only tell for
+/// exposures without solutions, which will be checked with approximate WCS
+/// centered on the same position that the bin file gives. Those can't match
+/// if the search point is farther away than the corner of the biggest plate
+/// that we might assume.
+fn rules_out(request: &Request, entry: &BinEntry, placeholders: &SeriesPlaceholders) -> bool {
+    if entry.solexp.sol_num >= 0 {
+        return false;
+    }
+
+    let Some((ra, dec)) = entry.center else {
+        return false;
+    };
+
+    let Ok(plate_id) = entry.plate_id.parse::<PlateId>() else {
+        return false;
+    };
+
+    if placeholders.get(plate_id.series()).is_placeholder(ra, dec) {
+        return false;
+    }
+
+    let Some(scale) = PLATE_SCALE_BY_SERIES.get(plate_id.series()).copied() else {
+        return false;
+    };
+
+    // Plate scales are in arcseconds per millimeter.
+    let max_sep_deg = MAX_PLATE_SIZE_MM * std::f64::consts::FRAC_1_SQRT_2 * scale / 3600.;
+    angular_separation(request.ra_deg, request.dec_deg, ra, dec) > max_sep_deg
+}
+
+/// Estimate the size of the result of a query. The request must have been
+/// normalized.
+pub async fn estimate(
+    request: Request,
+    config: &Config,
+    objects: &dyn ObjectStore,
+    binning: &crate::gscbin::GscBinning,
+    deny_list: &DenyList,
+    series_defaults: &SeriesDefaults,
+) -> Result<Estimate, Error> {
+    let mut candidates =
+        load_candidates(&request, config, objects, binning, series_defaults).await?;
+
+    if request.deny_list == DenyMode::Omit {
+        candidates.retain(|p, _| deny_list.reason(p).is_none());
     }
 
+    let max_rows = candidates.values().map(|solexps| solexps.len()).sum();
+
+    Ok(Estimate {
+        n_plates: candidates.len(),
+        max_rows,
+        approx_max_bytes: (max_rows + 1) * APPROX_BYTES_PER_ROW,
+    })
+}
+
+/// Query exposures. The request must have been normalized. `fine_binning`
+/// must be the 1/64° binning, which organizes the overlap cache.
+#[allow(clippy::too_many_arguments)]
+pub async fn implementation(
+    request: Request,
+    config: &Config,
+    tables: &dyn TableStore,
+    objects: &dyn ObjectStore,
+    binning: &crate::gscbin::GscBinning,
+    fine_binning: &crate::gscbin::GscBinning,
+    plates: &PlateCache,
+    deny_list: &DenyList,
+    series_defaults: &SeriesDefaults,
+    deadline: Deadline,
+) -> Result<Response, Error> {
+    let binding = Binding::new("queryexps", &request)?;
+    let coordinates = request.formatting.frame(["ra", "dec"], None);
+    let mut candidates =
+        load_candidates(&request, config, objects, binning, series_defaults).await?;
     eprintln!("Coarse bin query got {} plates", candidates.len());
 
     // Get the detailed plate information. DynamoDB provides a batch_get_item
     // endpoint that manages to meet our needs, but it's annoying to use.
 
-    let mut rows = vec!["series,\
+    let header = "series,\
         platenum,\
         scannum,\
         mosnum,\
@@ -176,94 +824,467 @@ pub async fn implementation(
         scandate,\
         mosdate,\
         centerdist,\
-        edgedist"
-        .to_owned()];
-
-    let base_builder = aws_sdk_dynamodb::types::KeysAndAttributes::builder().projection_expression(
-        "astrometry.b01HeaderGz,\
-        astrometry.exposures,\
-        astrometry.nSolutions,\
-        astrometry.rotationDelta,\
-        mosaic.b01Height,\
-        mosaic.b01Width,\
-        mosaic.creationDate,\
-        mosaic.mosNum,\
-        mosaic.scanNum,\
-        plateId,\
-        plateNumber,\
-        series",
-    );
-
-    let table_name = format!("dasch-{}-dr7-plates", super::ENVIRONMENT);
-    let mut unprocessed_keys: Option<HashMap<String, aws_sdk_dynamodb::types::KeysAndAttributes>> =
-        None;
-    let mut remaining_ids = candidates.keys();
-    const MAX_PER_BATCH: usize = 100;
-    let mut all_submitted = false;
-
-    loop {
-        // Continue from previous iteration, maybe. We can pass
-        // `unprocessed_keys` straight to `set_request_items()`, but once we do
-        // that there's no way to mutate it, so we can't "top off" our request.
-        // The type structure of this API is pretty gnarly.
-
-        let mut keys = unprocessed_keys
-            .take()
-            .and_then(|mut t| t.remove(&table_name))
-            .map(|kv| kv.keys)
-            .unwrap_or_default();
+        edgedist,\
+        centerdistdeg,\
+        edgedistdeg,\
+        approxwidthdeg,\
+        approxheightdeg,\
+        approxwidthcm,\
+        approxheightcm,\
+        has_solution,\
+        exposure_index,\
+        expmjd,\
+        expjd,\
+        centersepdeg,\
+        centerpadeg,\
+        bkglevel,\
+        satfrac,\
+        poserrarcsec,\
+        airmass,\
+        hourangle,\
+        recommended,\
+        flags";
+
+    let row_filter = match &request.filter {
+        Some(text) => Some(RowFilter::new(
+            text,
+            &header.split(',').collect::<Vec<_>>(),
+        )?),
+        None => None,
+    };
+    let row_filter = Arc::new(row_filter);
+
+    let mut sink = if request.stage_results {
+        RowSink::Staged(Stager::start(objects, config, "queryexps", header).await?)
+    } else {
+        RowSink::Inline(vec![header.to_owned()])
+    };
 
-        // Top up our request to the maximum count. (Amazon says that if your
-        // requests don't get fully filled, you should back off the size of your
-        // batch requests. I don't think that will be a problem for us?)
-
-        while !all_submitted && keys.len() < MAX_PER_BATCH {
-            if let Some(pid) = remaining_ids.next() {
-                // I see no better way to do this ...
-                let mut k = HashMap::with_capacity(1);
-                k.insert("plateId".to_owned(), AttributeValue::S(pid.to_owned()));
-                keys.push(k);
-            } else {
-                all_submitted = true;
+    let table_name = config.plates_table(&request.data_release);
+
+    // The lookups are pipelined: we keep several batch requests in flight at
+    // once, and as each one completes, its plates are handed off for the WCS
+    // checks, which are spread across the rayon thread pool. Regions with
+    // thousands of candidate plates are slow if we do all of this serially.
+    // Each plate's WCS objects are built and used within a single thread.
+    //
+    // The batches are processed in a fixed order, so that if we run short of
+    // time, the work that remains can be described by a continuation token
+    // giving the number of batches already done. The results are collected
+    // in the same order, sorted by plate ID, so that the output is
+    // deterministic. We don't let too many processed batches pile up before
+    // handing them to the sink, so that staged results don't accumulate in
+    // memory.
+
+    let mut plate_ids: Vec<String> = candidates
+        .keys()
+        .filter(|p| request.deny_list != DenyMode::Omit || deny_list.reason(p).is_none())
+        .cloned()
+        .collect();
+    plate_ids.sort_unstable();
+
+    // Exposures that the overlap cache knows to miss the search point are
+    // dropped, and so are plates with none left, but only once the batches
+    // have been laid out, so that continuation tokens don't depend on the
+    // state of the cache.
+
+    let cache = OverlapCache::load(
+        config,
+        tables,
+        fine_binning,
+        &request.data_release,
+        request.ra_deg,
+        request.dec_deg,
+    )
+    .await;
+
+    if let Some(cache) = &cache {
+        for (plate_id, solexps) in candidates.iter_mut() {
+            solexps.retain(|se| !cache.is_miss(plate_id, se.sol_num, se.exp_num));
+        }
+    }
+
+    let request = Arc::new(request);
+    let sites = series_defaults.sites();
+    let placeholders = series_defaults.placeholders();
+    let candidates = Arc::new(candidates);
+    let cache = Arc::new(cache);
+    let id_batches: Vec<Vec<String>> = plate_ids
+        .chunks(MAX_PER_BATCH)
+        .map(|c| c.to_vec())
+        .collect();
+
+    let n_batches = id_batches.len();
+    let (start, skip) = match &request.continuation {
+        Some(token) => decode_token(config, &binding, token)?,
+        None => (0, 0),
+    };
+    let mut cap = RowCap::new(config, request.max_rows, request.stage_results);
+
+    // When continuing a capped result, the rows of its last batch that it
+    // returned are skipped.
+    let skip_for = |index: usize| if index == start { skip } else { 0 };
+
+    let mut batches = stream::iter(id_batches.into_iter().skip(start))
+        .map(|mut ids| {
+            ids.retain(|p| !candidates[p].is_empty());
+            fetch_batch(tables, &table_name, plates, ids)
+        })
+        .buffered(MAX_BATCHES_IN_FLIGHT);
+
+    let mut processors = VecDeque::new();
+    let mut solutions = BTreeMap::new();
+    let mut new_misses = Vec::new();
+
+    let mut throttled = false;
+    let mut deadline_token = None;
+    let mut capped = None;
+    let mut n_done = start;
+
+    while let Some(chunk) = batches.next().await {
+        let (chunk, chunk_truncated) = chunk?;
+        throttled |= chunk_truncated;
+        let index = n_done;
+        n_done += 1;
+        let request = request.clone();
+        let candidates = candidates.clone();
+        let row_filter = row_filter.clone();
+        let cache = cache.clone();
+        let sites = sites.clone();
+        let placeholders = placeholders.clone();
+        let mut chunk: Vec<_> = chunk
+            .into_iter()
+            .map(|item| {
+                let denied = deny_list.reason(&item.plate_id).is_some();
+                (item, denied)
+            })
+            .collect();
+        chunk.sort_unstable_by(|a, b| a.0.plate_id.cmp(&b.0.plate_id));
+
+        let processor = tokio::task::spawn_blocking(move || {
+            chunk
+                .into_par_iter()
+                .map(|(item, denied)| {
+                    // "Impossible" to get a plate ID that's not in our candidates list:
+                    let solexps = candidates.get(&item.plate_id).unwrap();
+                    let plate_id = item.plate_id.clone();
+                    let sols = solution_exposures(&item);
+                    let mut rows = Vec::new();
+                    let mut misses = Vec::new();
+                    let site = sites.get(&item.series);
+                    let policy = placeholders.get(&item.series);
+                    process_one(
+                        &request,
+                        item,
+                        &solexps[..],
+                        denied,
+                        site,
+                        policy,
+                        cache.as_ref().as_ref(),
+                        &mut rows,
+                        &mut misses,
+                    );
+
+                    if let Some(f) = row_filter.as_ref() {
+                        rows.retain(|r| f.matches(r));
+                    }
+
+                    let sols = (!rows.is_empty()).then_some((plate_id, sols));
+                    (rows, sols, misses)
+                })
+                .collect::<Vec<_>>()
+        });
+        processors.push_back((index, processor));
+
+        if processors.len() > MAX_BATCHES_IN_FLIGHT {
+            let (index, processor) = processors.pop_front().unwrap();
+            let cut = drain(
+                processor,
+                &mut sink,
+                &mut solutions,
+                &mut new_misses,
+                &mut cap,
+                skip_for(index),
+            );
+
+            if let Some(cut) = cut.await? {
+                capped = Some((index, cut));
                 break;
             }
         }
 
-        if all_submitted && keys.is_empty() {
+        if n_done < n_batches && deadline.is_near() {
+            deadline_token = Some(encode_token(config, &binding, n_done, 0)?);
             break;
         }
+    }
+
+    // If we broke out early, this cancels the lookups still in flight.
+    drop(batches);
 
-        // Ready to submit
+    while capped.is_none() {
+        let Some((index, processor)) = processors.pop_front() else {
+            break;
+        };
+
+        let cut = drain(
+            processor,
+            &mut sink,
+            &mut solutions,
+            &mut new_misses,
+            &mut cap,
+            skip_for(index),
+        );
+        capped = cut.await?.map(|cut| (index, cut));
+    }
 
-        let resp = dc
-            .batch_get_item()
-            .request_items(
-                &table_name,
-                base_builder.clone().set_keys(Some(keys)).build()?,
-            )
-            .send()
+    let n_rows = cap.n_rows();
+
+    let truncation = if let Some((index, cut)) = capped {
+        Truncation {
+            truncated: true,
+            reason: Some(TruncationReason::MaxRows),
+            n_rows,
+            estimated_total_rows: estimate_total(
+                n_rows,
+                cut.n_remaining,
+                index + 1 - start,
+                n_batches - index - 1,
+            ),
+            continuation: Some(encode_token(config, &binding, index, cut.n_returned)?),
+        }
+    } else if let Some(token) = deadline_token {
+        Truncation {
+            truncated: true,
+            reason: Some(TruncationReason::Deadline),
+            n_rows,
+            estimated_total_rows: estimate_total(n_rows, 0, n_done - start, n_batches - n_done),
+            continuation: Some(token),
+        }
+    } else if throttled {
+        Truncation {
+            truncated: true,
+            reason: Some(TruncationReason::Throttled),
+            ..Truncation::complete(n_rows)
+        }
+    } else {
+        Truncation::complete(n_rows)
+    };
+
+    if let Some(cache) = cache.as_ref() {
+        cache.record(tables, new_misses).await;
+    }
+
+    let (rows, staged) = match sink {
+        RowSink::Inline(rows) => (rows, None),
+        RowSink::Staged(stager) => (Vec::new(), Some(stager.finish().await?)),
+    };
+
+    Ok(Response {
+        rows,
+        staged,
+        truncated: truncation.truncated,
+        continuation: truncation.continuation.clone(),
+        solutions,
+        recommendation_version: recommend::VERSION,
+        coordinates,
+        truncation,
+    })
+}
+
+/// The output of processing one batch of plates: for each plate, its result
+/// rows; if there are any, its solution-exposure pairings; and its newly
+/// discovered overlap cache entries.
+type ProcessedBatch = Vec<(
+    Vec<String>,
+    Option<(String, Vec<SolutionExposure>)>,
+    Vec<String>,
+)>;
+
+/// Where a batch was cut off by the row cap.
+struct Cut {
+    /// The number of the batch's rows that have been returned, in this
+    /// response or earlier ones.
+    n_returned: usize,
+
+    /// The number of the batch's rows that haven't been returned.
+    n_remaining: usize,
+}
+
+/// Wait for a batch to be processed, and pass its results along, after
+/// skipping the first `skip` rows. If the row cap is reached, returns where
+/// the batch was cut off.
+async fn drain(
+    processor: tokio::task::JoinHandle<ProcessedBatch>,
+    sink: &mut RowSink<'_>,
+    solutions: &mut BTreeMap<String, Vec<SolutionExposure>>,
+    new_misses: &mut Vec<String>,
+    cap: &mut RowCap,
+    skip: usize,
+) -> Result<Option<Cut>, Error> {
+    let batch = processor.await?;
+    let n_total: usize = batch.iter().map(|(rows, _, _)| rows.len()).sum();
+    let mut to_skip = skip;
+    let mut n_returned = 0;
+
+    for (mut plate_rows, plate_solutions, plate_misses) in batch {
+        new_misses.extend(plate_misses);
+
+        let n_skip = usize::min(to_skip, plate_rows.len());
+        plate_rows.drain(..n_skip);
+        to_skip -= n_skip;
+        n_returned += n_skip;
+
+        let n_new = plate_rows.len();
+        let n_fit = cap.admit(n_new);
+        plate_rows.truncate(n_fit);
+        n_returned += n_fit;
+
+        // The flags are the last column.
+        if plate_rows.iter().any(|r| {
+            r.rsplit(',')
+                .next()
+                .unwrap_or_default()
+                .contains("astrometry_unreadable")
+        }) {
+            warnings::tally(
+                "approximate_wcs",
+                "plates whose stored astrometry is unreadable were matched with approximate WCS",
+                1,
+            );
+        }
+
+        if !plate_rows.is_empty() {
+            solutions.extend(plate_solutions);
+        }
+
+        sink.push(plate_rows).await?;
+
+        if n_fit < n_new {
+            return Ok(Some(Cut {
+                n_returned,
+                n_remaining: n_total - n_returned,
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Where result rows go as they're produced.
+enum RowSink<'a> {
+    Inline(Vec<String>),
+    Staged(Stager<'a>),
+}
+
+impl RowSink<'_> {
+    async fn push(&mut self, mut rows: Vec<String>) -> Result<(), Error> {
+        match self {
+            RowSink::Inline(all) => all.append(&mut rows),
+            RowSink::Staged(stager) => stager.push(rows).await?,
+        }
+
+        Ok(())
+    }
+}
+
+/// Fetch the plate records for one batch of plate IDs.
+///
+/// Records that are in our warm-instance cache are taken from there. DynamoDB
+/// may not process all of our keys in one go, in which case it hands them back
+/// as "unprocessed", typically because we're being throttled. We resubmit
+/// those with exponential backoff, up to a fixed number of retries. If we run
+/// out of retries, we return what we have, and the returned flag is set to
+/// indicate that the results are incomplete.
+async fn fetch_batch(
+    tables: &dyn TableStore,
+    table_name: &str,
+    plates: &PlateCache,
+    plate_ids: Vec<String>,
+) -> Result<(Vec<PlatesResult>, bool), Error> {
+    let mut items = Vec::with_capacity(plate_ids.len());
+    let mut keys = Vec::new();
+    let mut n_retries = 0;
+    let mut backoff = INITIAL_RETRY_BACKOFF;
+
+    for pid in plate_ids {
+        if let Some(item) = plates.get(PLATES_PROJECTION, table_name, &pid) {
+            items.push(item);
+        } else {
+            keys.push(AttributeValue::S(pid));
+        }
+    }
+
+    while !keys.is_empty() {
+        if n_retries > 0 {
+            if n_retries > MAX_UNPROCESSED_RETRIES {
+                eprintln!(
+                    "giving up on {} unprocessed plate keys after {} retries",
+                    keys.len(),
+                    MAX_UNPROCESSED_RETRIES
+                );
+                return Ok((serde_dynamo::from_items(items)?, true));
+            }
+
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+
+        let resp = tables
+            .batch_get_items(table_name, "plateId", keys, PLATES_PROJECTION)
             .await?;
 
-        let mut chunk: Vec<PlatesResult> = serde_dynamo::from_items(
-            resp.responses
-                .unwrap()
-                .remove(&table_name)
-                .unwrap_or_default(),
-        )?;
+        for item in resp.items {
+            if let Some(AttributeValue::S(pid)) = item.get("plateId") {
+                plates.insert(PLATES_PROJECTION, table_name, pid.clone(), item.clone());
+            }
 
-        for item in chunk.drain(..) {
-            // "Impossible" to get a plate ID that's not in our candidates list:
-            let solexps = candidates.get(&item.plate_id).unwrap();
-            process_one(&request, item, &solexps[..], &mut rows);
+            items.push(item);
         }
 
-        unprocessed_keys = resp.unprocessed_keys;
+        keys = resp.unprocessed;
+        n_retries += 1;
     }
 
-    Ok(rows)
+    Ok((serde_dynamo::from_items(items)?, false))
 }
 
-fn process_one(req: &Request, plate: PlatesResult, solexps: &[SolExp], rows: &mut Vec<String>) {
+/// Pair up a plate's astrometric solutions with its exposures. The exposure
+/// list is sorted so that its first entries correspond to the solutions.
+fn solution_exposures(plate: &PlatesResult) -> Vec<SolutionExposure> {
+    let Some(astrom) = plate.astrometry.as_ref() else {
+        return Vec::new();
+    };
+
+    (0..astrom.n_solutions.unwrap_or(0))
+        .map(|i| SolutionExposure {
+            solution_number: i,
+            exposure_number: astrom
+                .exposures
+                .get(i)
+                .and_then(|e| e.as_ref())
+                .map(|e| e.number),
+        })
+        .collect()
+}
+
+/// Check the candidate exposures of a plate against the search point, adding
+/// rows for those that it lands on. If overlap tests are cached, the exposures
+/// found to miss the cache bin are added to `misses`. `site` is where the
+/// plate's series was taken, if we know, and `placeholders` the placeholder
+/// positions of the series.
+#[allow(clippy::too_many_arguments)]
+fn process_one(
+    req: &Request,
+    plate: PlatesResult,
+    solexps: &[SolExp],
+    denied: bool,
+    site: Option<&ObservingSite>,
+    placeholders: &PlaceholderPolicy,
+    cache: Option<&OverlapCache>,
+    rows: &mut Vec<String>,
+    misses: &mut Vec<String>,
+) {
     // First order of business is to prepare to construct a WCS object for every
     // solexp that we need to check. Even if we have some precise astrometric
     // solutions, we might *also* have catalog-only exposures for which we need
@@ -272,14 +1293,35 @@ fn process_one(req: &Request, plate: PlatesResult, solexps: &[SolExp], rows: &mu
     let mos = plate.mosaic.as_ref();
     let astrom = plate.astrometry.as_ref();
 
+    // If the stored header is corrupt, we flag the plate's rows so that the
+    // problem doesn't go unnoticed. We'll fall back to approximate WCS if we
+    // can.
+
+    let mut flags = Vec::new();
+
     let mut solved_wcs = astrom.map(|a| &a.b01_header_gz).and_then(|gzh| {
         if gzh.is_empty() {
             None
         } else {
-            load_b01_header(GzDecoder::new(&gzh[..])).ok()
+            match load_b01_header(GzDecoder::new(&gzh[..]), Default::default()) {
+                Ok(wcs) => Some(wcs),
+                Err(_) => {
+                    flags.push("astrometry_unreadable");
+                    None
+                }
+            }
         }
     });
 
+    if denied {
+        flags.push("deny_listed");
+    }
+
+    let flags_text = flags.join(";");
+
+    // The rows are completed once we know which exposure to recommend.
+    let mut pending = Vec::new();
+
     let n_solutions = if solved_wcs.is_none() {
         0
     } else {
@@ -315,6 +1357,15 @@ fn process_one(req: &Request, plate: PlatesResult, solexps: &[SolExp], rows: &mu
         .get(&plate.series)
         .map(|pl| pl / PIXELS_PER_MM / 3600.);
 
+    // The exposure numbers in order, for the `exposure_index` column. The
+    // first `n_solutions` entries of the exposure list are the ones that have
+    // solutions.
+
+    let exposures = astrom.map(|a| &a.exposures[..]).unwrap_or(&[]);
+    let n_recorded_solutions = astrom.and_then(|a| a.n_solutions).unwrap_or(0);
+    let mut exp_numbers: Vec<i8> = exposures.iter().flatten().map(|e| e.number).collect();
+    exp_numbers.sort_unstable();
+
     // Finally we're ready to go
 
     for solexp in solexps {
@@ -325,6 +1376,10 @@ fn process_one(req: &Request, plate: PlatesResult, solexps: &[SolExp], rows: &mu
         let mut this_width = width;
         let mut this_height = height;
         let mut this_exp = None;
+        let mut position_unknown = false;
+        let mut approx_scale = None;
+        let mut has_solution =
+            solexp.sol_num >= 0 && (solexp.sol_num as usize) < n_recorded_solutions;
 
         if solexp.sol_num >= 0 && (solexp.sol_num as usize) < n_solutions {
             // Yay, we have real WCS for this one. We can only get here if
@@ -338,7 +1393,7 @@ fn process_one(req: &Request, plate: PlatesResult, solexps: &[SolExp], rows: &mu
         // in exposure order, and also contains null rows.
 
         if solexp.exp_num >= 0 {
-            for maybe_exp in astrom.map(|a| &a.exposures[..]).unwrap_or(&[]) {
+            for (i, maybe_exp) in exposures.iter().enumerate() {
                 if let Some(exp) = maybe_exp {
                     if exp.number != solexp.exp_num {
                         continue;
@@ -347,35 +1402,42 @@ fn process_one(req: &Request, plate: PlatesResult, solexps: &[SolExp], rows: &mu
                     // We have a match!
 
                     this_exp = maybe_exp.as_ref();
+                    has_solution = i < n_recorded_solutions;
+
+                    // Some exposures have placeholder values instead of
+                    // actual positions. We should strip them out of the
+                    // DynamoDB.
+
+                    let center = match (exp.ra_deg, exp.dec_deg) {
+                        (Some(ra), Some(dec)) if placeholders.is_placeholder(ra, dec) => {
+                            position_unknown = true;
+                            None
+                        }
+                        (Some(ra), Some(dec)) => Some((ra, dec)),
+                        _ => None,
+                    };
 
                     // If we don't have a real WCS solution yet, we may be able
                     // to do an approximate test based on the coarse exposure
                     // data. This only works if we have a pixel scale and if the
-                    // exposure has useful centering information.
-
-                    if this_wcs.is_none() && !pixel_scale.is_none() {
-                        // Every exposure of interest *should* have useful
-                        // RA/Dec info since otherwise it shouldn't be in our
-                        // bin list, but let's check.
-
-                        if let (Some(ra), Some(dec)) = (exp.ra_deg, exp.dec_deg) {
-                            // These are all placeholder values observed in the
-                            // data. We should strip them out of the DynamoDB:
-
-                            if ra != 999. && ra != -99. && dec != 99. && dec != -99. {
-                                // We found the exposure, and we can and should use it for
-                                // WCS.
-
-                                let ps = pixel_scale.unwrap(); // checked above
-                                let crpix = 0.5 * (naxis_for_approx as f64 + 1.);
-                                maybe_temp_wcs =
-                                    Some(WcsCollection::new_tan(ra, dec, crpix, crpix, ps));
-                                this_wcs = maybe_temp_wcs.as_mut();
-                                this_wcslib_solnum = 0;
-                                this_width = naxis_for_approx;
-                                this_height = naxis_for_approx;
-                            }
-                        }
+                    // exposure has useful centering information. Every
+                    // exposure of interest *should* have useful RA/Dec info
+                    // since otherwise it shouldn't be in our bin list, but
+                    // let's check.
+
+                    if let (true, Some(ps), Some((ra, dec))) =
+                        (this_wcs.is_none(), pixel_scale, center)
+                    {
+                        // We found the exposure, and we can and should use it
+                        // for WCS.
+
+                        let crpix = 0.5 * (naxis_for_approx as f64 + 1.);
+                        maybe_temp_wcs = Some(WcsCollection::new_tan(ra, dec, crpix, crpix, ps));
+                        this_wcs = maybe_temp_wcs.as_mut();
+                        this_wcslib_solnum = 0;
+                        this_width = naxis_for_approx;
+                        this_height = naxis_for_approx;
+                        approx_scale = Some(ps);
                     }
 
                     // Regardless of how well that all went, we're done
@@ -386,58 +1448,81 @@ fn process_one(req: &Request, plate: PlatesResult, solexps: &[SolExp], rows: &mu
         }
 
         // We tried our best. There *should* always be a WCS to use, but if not,
-        // treat this plate+solexp as a non-match: ignore it.
+        // treat this plate+solexp as a non-match: ignore it. The exception is
+        // if the exposure's position is a known placeholder value: then we
+        // report the match with an unknown position, since we can't rule it
+        // out.
+
+        let geometry = match this_wcs.map(|w| w.get(this_wcslib_solnum)) {
+            Some(Ok(mut w)) => match check_overlap(req, &mut w, this_width, this_height) {
+                Some(g) => Some(g),
+                None => {
+                    if let Some(cache) = cache {
+                        if misses_region(&mut w, this_width, this_height, cache.region()) {
+                            misses.push(miss_key(&plate.plate_id, solexp.sol_num, solexp.exp_num));
+                        }
+                    }
 
-        let mut this_wcs = match this_wcs.map(|w| w.get(this_wcslib_solnum)) {
-            Some(Ok(w)) => w,
-            _ => continue,
-        };
+                    continue;
+                }
+            },
 
-        // Finally we can check whether this plate+solexp actually intersects
-        // with the point of interest!
+            _ if position_unknown => None,
 
-        let (x, y) = match this_wcs.world_to_pixel_scalar(req.ra_deg, req.dec_deg) {
-            Ok(Some(c)) => c,
             _ => continue,
         };
 
-        if x < -0.5 || x > (this_width as f64 - 0.5) || y < -0.5 || y > (this_height as f64 - 0.5) {
-            continue;
-        }
-
-        // The point of interest actually intersects the plate! Gather the data
-        // to report it.
+        // The point of interest (possibly) intersects the plate! Gather the
+        // data to report it.
 
         let scan_num = mos.map(|m| m.scan_num).unwrap_or(-1);
         let mos_num = mos.map(|m| m.mos_num).unwrap_or(-1);
         let plate_class = "";
 
-        let center_x = 0.5 * (this_width as f64 - 1.);
-        let center_y = 0.5 * (this_height as f64 - 1.);
-        let center_text = this_wcs
-            .pixel_to_world_scalar(center_x, center_y)
-            .map(|(r, d)| format!("{:.6},{:.6}", r, d))
-            .unwrap_or_else(|_e| ",".to_owned());
-
-        // Distance between search point and plate center, in cm. This is
-        // straightforward to calculate in pixel space, because pixels per cm is
-        // a constant. NB: can't use hypot() here right now because it triggers
-        // an undefined glibc symbol version in the Amazon OS image.
-        let center_dist = f64::sqrt(f64::powi(x - center_x, 2) + f64::powi(y - center_y, 2))
-            / (10. * PIXELS_PER_MM);
-
-        // Distance between search point and closest plate edge, in cm. Really
-        // what we mean here is the "mosaic edge".
-        let edge_dist = f64::min(
-            x + 0.5,
-            f64::min(
-                y + 0.5,
-                f64::min(
-                    this_width as f64 - (0.5 + x),
-                    this_height as f64 - (0.5 + y),
-                ),
-            ),
-        ) / (10. * PIXELS_PER_MM);
+        let (center_text, dist_text) = match &geometry {
+            Some(g) => {
+                let deg_text = match g.pixel_scale {
+                    Some(ps) => format!(
+                        "{:.3},{:.3}",
+                        g.center_dist * 10. * PIXELS_PER_MM * ps,
+                        g.edge_dist * 10. * PIXELS_PER_MM * ps
+                    ),
+                    None => ",".to_owned(),
+                };
+
+                (
+                    g.center
+                        .map(|(r, d)| req.formatting.position(r, d, 6))
+                        .unwrap_or_else(|| ",".to_owned()),
+                    format!("{:.1},{:.1},{}", g.center_dist, g.edge_dist, deg_text),
+                )
+            }
+            None => (",".to_owned(), ",,,".to_owned()),
+        };
+
+        // The approximate WCS assumes a square plate, but we report both
+        // dimensions in case that changes.
+
+        let approx_text = match approx_scale {
+            Some(ps) => {
+                let w_cm = this_width as f64 / (10. * PIXELS_PER_MM);
+                let h_cm = this_height as f64 / (10. * PIXELS_PER_MM);
+                format!(
+                    "{:.3},{:.3},{:.1},{:.1}",
+                    this_width as f64 * ps,
+                    this_height as f64 * ps,
+                    w_cm,
+                    h_cm
+                )
+            }
+            None => ",,,".to_owned(),
+        };
+
+        let has_solution_text = if has_solution { "1" } else { "0" };
+        let exposure_index_text = this_exp
+            .and_then(|e| exp_numbers.iter().position(|n| *n == e.number))
+            .map(|i| i.to_string())
+            .unwrap_or_default();
 
         let exptime_text = this_exp
             .and_then(|e| e.dur_min)
@@ -447,6 +1532,34 @@ fn process_one(req: &Request, plate: PlatesResult, solexps: &[SolExp], rows: &mu
             .and_then(|e| e.midpoint_date.as_ref())
             .map(|s| s.as_ref())
             .unwrap_or("");
+        let midpoint = this_exp
+            .and_then(|e| e.midpoint_date.as_deref())
+            .and_then(UtcTime::parse);
+        let (expmjd_text, expjd_text) = midpoint
+            .map(|t| (format!("{:.5}", t.mjd()), format!("{:.5}", t.jd())))
+            .unwrap_or_default();
+        let conditions_text = match (site, midpoint) {
+            (Some(site), Some(t)) => {
+                let c = site.conditions(t.jd(), req.ra_deg, req.dec_deg);
+                format!(
+                    "{},{:.3}",
+                    c.airmass.map(|x| format!("{x:.3}")).unwrap_or_default(),
+                    c.hour_angle_hours
+                )
+            }
+            _ => ",".to_owned(),
+        };
+        let sep_text = geometry
+            .as_ref()
+            .and_then(|g| g.center)
+            .map(|(r, d)| {
+                format!(
+                    "{:.4},{:.2}",
+                    angular_separation(r, d, req.ra_deg, req.dec_deg),
+                    position_angle(r, d, req.ra_deg, req.dec_deg)
+                )
+            })
+            .unwrap_or_else(|| ",".to_owned());
         let epoch = 2000.0;
         let wcs_source = this_exp
             .and_then(|e| e.center_source.as_ref())
@@ -454,26 +1567,180 @@ fn process_one(req: &Request, plate: PlatesResult, solexps: &[SolExp], rows: &mu
             .unwrap_or("".to_owned());
         let scandate = ""; // TODO: need to import this into the DB
         let mosdate = mos.map(|m| m.creation_date.as_ref()).unwrap_or("");
+        let bkglevel_text = mos
+            .and_then(|m| m.background_level)
+            .map(|b| format!("{:.1}", b))
+            .unwrap_or_default();
+        let satfrac_text = mos
+            .and_then(|m| m.saturation_fraction)
+            .map(|f| format!("{:.5}", f))
+            .unwrap_or_default();
 
-        let row = format!(
-            "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{:.1},{:.1}",
-            plate.series,
-            plate.plate_number,
-            scan_num,
-            mos_num,
-            solexp.exp_num,
-            solexp.sol_num,
-            plate_class,
-            center_text, // 2 columns
-            exptime_text,
-            expdate_text,
-            epoch,
-            wcs_source,
-            scandate,
-            mosdate,
-            center_dist,
-            edge_dist,
-        );
-        rows.push(row);
+        // Only real solutions get uncertainty estimates. The residuals are
+        // sorted to match the solutions.
+        let poserr_text = match (&geometry, approx_scale) {
+            (Some(g), None) => astrom
+                .and_then(|a| a.residuals.get(solexp.sol_num as usize))
+                .and_then(|r| r.as_ref())
+                .and_then(|r| {
+                    RadialErrorModel::fit(
+                        &r.x_pix,
+                        &r.y_pix,
+                        &r.d_ra_asec,
+                        &r.d_dec_asec,
+                        this_width,
+                        this_height,
+                    )
+                })
+                .map(|m| format!("{:.2}", m.at(g.center_dist * 10. * PIXELS_PER_MM)))
+                .unwrap_or_default(),
+            _ => String::new(),
+        };
+
+        pending.push((
+            Candidate {
+                astrometry: match (&geometry, approx_scale) {
+                    (None, _) => Astrometry::Unknown,
+                    (Some(_), Some(_)) => Astrometry::Approximate,
+                    (Some(_), None) => Astrometry::Solved,
+                },
+                edge_dist_cm: geometry.as_ref().map(|g| g.edge_dist),
+                exptime_min: this_exp.and_then(|e| e.dur_min),
+            },
+            format!(
+                "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                plate.series,
+                plate.plate_number,
+                scan_num,
+                mos_num,
+                solexp.exp_num,
+                solexp.sol_num,
+                plate_class,
+                center_text, // 2 columns
+                exptime_text,
+                expdate_text,
+                epoch,
+                wcs_source,
+                scandate,
+                mosdate,
+                dist_text,   // 4 columns
+                approx_text, // 4 columns
+                has_solution_text,
+                exposure_index_text,
+                expmjd_text,
+                expjd_text,
+                sep_text, // 2 columns
+                bkglevel_text,
+                satfrac_text,
+                poserr_text,
+                conditions_text, // 2 columns
+            ),
+        ));
+    }
+
+    let candidates: Vec<_> = pending.iter().map(|(c, _)| c.clone()).collect();
+    let best = recommend::best(&candidates);
+
+    for (i, (_, row)) in pending.into_iter().enumerate() {
+        let recommended = if best == Some(i) { 1 } else { 0 };
+        rows.push(format!("{row},{recommended},{flags_text}"));
+    }
+}
+
+/// Check whether an exposure with the given WCS and dimensions misses a
+/// circular region of the sky by a wide margin; see [`MISS_MARGIN`]. We
+/// measure from the region's center to the closest point of the plate in pixel
+/// space, which is close enough given the margin.
+fn misses_region(wcs: &mut Wcs, width: usize, height: usize, region: (f64, f64, f64)) -> bool {
+    let (ra, dec, radius) = region;
+
+    let (x, y) = match wcs.world_to_pixel_scalar(ra, dec) {
+        Ok(Some(c)) => c,
+        _ => return false,
+    };
+
+    let edge_x = x.clamp(-0.5, width as f64 - 0.5);
+    let edge_y = y.clamp(-0.5, height as f64 - 0.5);
+
+    match wcs.pixel_to_world_scalar(edge_x, edge_y) {
+        Ok((edge_ra, edge_dec)) => {
+            angular_separation(ra, dec, edge_ra, edge_dec) > MISS_MARGIN * radius
+        }
+        Err(_) => false,
     }
 }
+
+/// Positional information about an exposure that overlaps the search point.
+struct OverlapGeometry {
+    /// The RA and Dec of the exposure center, in degrees, if they could be
+    /// computed.
+    center: Option<(f64, f64)>,
+
+    /// The distance between the search point and the plate center, in cm.
+    center_dist: f64,
+
+    /// The distance between the search point and the closest plate edge, in cm.
+    edge_dist: f64,
+
+    /// The pixel scale at the search point, in degrees per pixel, if it could
+    /// be computed.
+    pixel_scale: Option<f64>,
+}
+
+/// Check whether the search point lands on an exposure with the given WCS and
+/// dimensions. If so, compute some information about where it lands.
+fn check_overlap(
+    req: &Request,
+    wcs: &mut Wcs,
+    width: usize,
+    height: usize,
+) -> Option<OverlapGeometry> {
+    let (x, y) = match wcs.world_to_pixel_scalar(req.ra_deg, req.dec_deg) {
+        Ok(Some(c)) => c,
+        _ => return None,
+    };
+
+    if x < -0.5 || x > (width as f64 - 0.5) || y < -0.5 || y > (height as f64 - 0.5) {
+        return None;
+    }
+
+    let center_x = 0.5 * (width as f64 - 1.);
+    let center_y = 0.5 * (height as f64 - 1.);
+    let center = wcs.pixel_to_world_scalar(center_x, center_y).ok();
+
+    // Distance between search point and plate center, in cm. This is
+    // straightforward to calculate in pixel space, because pixels per cm is
+    // a constant. NB: can't use hypot() here right now because it triggers
+    // an undefined glibc symbol version in the Amazon OS image.
+    let center_dist =
+        f64::sqrt(f64::powi(x - center_x, 2) + f64::powi(y - center_y, 2)) / (10. * PIXELS_PER_MM);
+
+    // Distance between search point and closest plate edge, in cm. Really
+    // what we mean here is the "mosaic edge".
+    let edge_dist = f64::min(
+        x + 0.5,
+        f64::min(
+            y + 0.5,
+            f64::min(width as f64 - (0.5 + x), height as f64 - (0.5 + y)),
+        ),
+    ) / (10. * PIXELS_PER_MM);
+
+    // The local pixel scale, as the geometric mean of the scales along the
+    // two pixel axes.
+    let pixel_scale = (|| {
+        let (ra0, dec0) = wcs.pixel_to_world_scalar(x, y).ok()?;
+        let (ra1, dec1) = wcs.pixel_to_world_scalar(x + 1., y).ok()?;
+        let (ra2, dec2) = wcs.pixel_to_world_scalar(x, y + 1.).ok()?;
+        let sx = angular_separation(ra0, dec0, ra1, dec1);
+        let sy = angular_separation(ra0, dec0, ra2, dec2);
+        let ps = (sx * sy).sqrt();
+        ps.is_finite().then_some(ps)
+    })();
+
+    Some(OverlapGeometry {
+        center,
+        center_dist,
+        edge_dist,
+        pixel_scale,
+    })
+}
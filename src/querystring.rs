@@ -0,0 +1,110 @@
+//! Requests given as URL query parameters.
+//!
+//! So that the services can be tried out from a browser or `curl`, and linked
+//! to from documentation, the proxy-event server accepts `GET` requests whose
+//! query parameters are the fields of the request:
+//!
+//! ```text
+//! /querycat?refcat=apass&ra_deg=10.5&dec_deg=20.3&radius_arcsec=30
+//! ```
+//!
+//! Query parameters are all text, so we use the service's request schema to
+//! decide what they mean. Fields whose type is `string` are taken literally;
+//! other values are parsed as JSON, so that numbers, booleans, and even whole
+//! objects can be given, and are also taken literally if that fails, in which
+//! case the service will complain about them as usual. Fields of nested
+//! objects are named with dots, as in `formatting.sexagesimal=true`. Array
+//! fields can be given as JSON arrays, or by repeating the parameter once per
+//! element, as in `solution_numbers=0&solution_numbers=1`.
+//!
+//! IVOA SODA clients send upper-case parameter names, like `CIRCLE`, so a
+//! top-level parameter that isn't a field of the request is matched to a field
+//! ignoring case; see `soda.rs`.
+
+use lambda_http::Error;
+use serde_json::{Map, Value};
+use std::{borrow::Cow, collections::BTreeMap};
+
+/// Build a request from query parameters, given the service's request
+/// schema.
+pub fn request_from_query<'a>(
+    schema: &str,
+    params: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> Result<Value, Error> {
+    let schema: Value = serde_json::from_str(schema)?;
+
+    // Gather repeated parameters, in a deterministic order.
+    let mut grouped: BTreeMap<Cow<str>, Vec<&str>> = BTreeMap::new();
+
+    for (key, value) in params {
+        let lower = key.to_lowercase();
+        let properties = &schema["properties"];
+
+        let key = if properties.get(key).is_none() && properties.get(&lower).is_some() {
+            Cow::Owned(lower)
+        } else {
+            Cow::Borrowed(key)
+        };
+
+        grouped.entry(key).or_default().push(value);
+    }
+
+    let mut request = Map::new();
+
+    for (key, values) in grouped {
+        let mut fields = &mut request;
+        let mut field_schema = Some(&schema);
+        let mut path = key.split('.').peekable();
+
+        while let Some(name) = path.next() {
+            field_schema = field_schema.and_then(|s| s["properties"].get(name));
+
+            if path.peek().is_none() {
+                fields.insert(name.to_owned(), field_value(&key, field_schema, &values)?);
+                break;
+            }
+
+            let parent = fields
+                .entry(name)
+                .or_insert_with(|| Value::Object(Map::new()));
+
+            fields = parent.as_object_mut().ok_or_else(|| -> Error {
+                format!("conflicting query parameters for `{name}`").into()
+            })?;
+        }
+    }
+
+    Ok(Value::Object(request))
+}
+
+/// Interpret the values of one query parameter.
+fn field_value(key: &str, schema: Option<&Value>, values: &[&str]) -> Result<Value, Error> {
+    let field_type = schema.and_then(|s| s["type"].as_str());
+
+    if field_type == Some("array") {
+        if let [text] = values {
+            if text.starts_with('[') {
+                return Ok(scalar(None, text));
+            }
+        }
+
+        let items = schema.and_then(|s| s.get("items"));
+        return Ok(Value::Array(
+            values.iter().map(|text| scalar(items, text)).collect(),
+        ));
+    }
+
+    match values {
+        [text] => Ok(scalar(schema, text)),
+        _ => Err(format!("query parameter `{key}` is given more than once").into()),
+    }
+}
+
+/// Interpret the text of a single value.
+fn scalar(schema: Option<&Value>, text: &str) -> Value {
+    if schema.and_then(|s| s["type"].as_str()) == Some("string") {
+        return Value::String(text.to_owned());
+    }
+
+    serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.to_owned()))
+}
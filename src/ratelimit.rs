@@ -0,0 +1,275 @@
+//! Per-client rate limiting.
+//!
+//! Our deployments are shared by many users, and a single runaway notebook
+//! hammering the API in a loop can crowd everyone else out. The proxy-event
+//! server therefore identifies each client from the API Gateway request
+//! context -- by API key if it has one, or its source IP otherwise -- and
+//! enforces two budgets per client:
+//!
+//! - a limit on the number of requests that it may have in flight at once on
+//!   one instance, and
+//! - a limit on the number of result rows that it may receive within a time
+//!   window.
+//!
+//! Requests that exceed either budget are rejected with a [`RateLimitError`],
+//! which the server reports as an HTTP 429 response. The budgets are tracked
+//! per Lambda instance, so they're only approximate: with N warm instances, a
+//! client may get up to N times the configured allowance. That's fine for our
+//! purposes, which are to slow down runaways, not to meter usage precisely.
+//!
+//! A budget of zero means that it isn't enforced. Both budgets default to zero.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde_json::Value;
+
+use crate::config::Config;
+
+/// If we're tracking more identities than this, forget about idle ones.
+const MAX_TRACKED_IDENTITIES: usize = 10_000;
+
+/// The per-instance rate limiter.
+#[derive(Debug)]
+pub struct RateLimiter {
+    max_concurrent: u32,
+    max_rows: u64,
+    window: Duration,
+    clients: Mutex<HashMap<String, ClientState>>,
+}
+
+#[derive(Debug)]
+struct ClientState {
+    in_flight: u32,
+    window_start: Instant,
+    rows: u64,
+}
+
+/// The error returned when a client has exceeded one of its budgets.
+#[derive(Debug)]
+pub struct RateLimitError {
+    pub identity: String,
+
+    /// Which budget was exceeded: `concurrency` or `rows`.
+    pub budget: &'static str,
+
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for RateLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = match self.budget {
+            "concurrency" => "too many concurrent requests",
+            _ => "too many result rows requested recently",
+        };
+
+        write!(
+            f,
+            "rate limited: {} from this client; retry after {} ms",
+            what,
+            self.retry_after.as_millis()
+        )
+    }
+}
+
+impl std::error::Error for RateLimitError {}
+
+/// A request that has been admitted by the rate limiter. The request counts
+/// against its client's concurrency budget until this is dropped.
+#[derive(Debug)]
+pub struct RateGuard<'a> {
+    limiter: &'a RateLimiter,
+    identity: String,
+}
+
+impl RateLimiter {
+    pub fn new(config: &Config) -> Self {
+        RateLimiter {
+            max_concurrent: config.rate_max_concurrent,
+            max_rows: config.rate_max_rows,
+            window: config.rate_window,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether any budget is enforced at all.
+    pub fn is_enabled(&self) -> bool {
+        self.max_concurrent > 0 || self.max_rows > 0
+    }
+
+    /// Admit a request from the client with the given identity, if it is
+    /// within its budgets.
+    pub fn begin(&self, identity: &str) -> Result<RateGuard<'_>, RateLimitError> {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+
+        if clients.len() > MAX_TRACKED_IDENTITIES {
+            let window = self.window;
+            clients.retain(|_, c| c.in_flight > 0 || now - c.window_start < window);
+        }
+
+        let client = clients
+            .entry(identity.to_owned())
+            .or_insert_with(|| ClientState {
+                in_flight: 0,
+                window_start: now,
+                rows: 0,
+            });
+
+        if now - client.window_start >= self.window {
+            client.window_start = now;
+            client.rows = 0;
+        }
+
+        if self.max_rows > 0 && client.rows >= self.max_rows {
+            return Err(RateLimitError {
+                identity: identity.to_owned(),
+                budget: "rows",
+                retry_after: self.window.saturating_sub(now - client.window_start),
+            });
+        }
+
+        if self.max_concurrent > 0 && client.in_flight >= self.max_concurrent {
+            return Err(RateLimitError {
+                identity: identity.to_owned(),
+                budget: "concurrency",
+                retry_after: Duration::from_secs(1),
+            });
+        }
+
+        client.in_flight += 1;
+
+        Ok(RateGuard {
+            limiter: self,
+            identity: identity.to_owned(),
+        })
+    }
+}
+
+impl RateGuard<'_> {
+    /// Charge the rows of a service response against the client's row budget.
+    /// The charge applies to the *next* request, since this one has already
+    /// done its work.
+    pub fn charge(&self, response: &Value) {
+        let n = result_rows(response);
+
+        if n == 0 {
+            return;
+        }
+
+        let mut clients = self.limiter.clients.lock().unwrap();
+
+        if let Some(client) = clients.get_mut(&self.identity) {
+            client.rows = client.rows.saturating_add(n);
+        }
+    }
+}
+
+impl Drop for RateGuard<'_> {
+    fn drop(&mut self) {
+        let mut clients = self.limiter.clients.lock().unwrap();
+
+        if let Some(client) = clients.get_mut(&self.identity) {
+            client.in_flight = client.in_flight.saturating_sub(1);
+        }
+    }
+}
+
+/// Count the data rows in an enveloped service response. The tabular services
+/// return CSV lines, either as the result itself or in its `rows` field, with
+/// a header line first. Results staged to S3 report their row count instead.
+fn result_rows(response: &Value) -> u64 {
+    let result = &response["result"];
+
+    if let Some(n) = result["staged"]["n_rows"].as_u64() {
+        return n;
+    }
+
+    let rows = match result {
+        Value::Array(a) => a,
+        _ => match &result["rows"] {
+            Value::Array(a) => a,
+            _ => return 0,
+        },
+    };
+
+    rows.len().saturating_sub(1) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn limiter(max_concurrent: u32, max_rows: u64, window: Duration) -> RateLimiter {
+        RateLimiter::new(&Config {
+            rate_max_concurrent: max_concurrent,
+            rate_max_rows: max_rows,
+            rate_window: window,
+            ..Default::default()
+        })
+    }
+
+    /// A tabular response with `n` data rows.
+    fn response(n: usize) -> Value {
+        let mut rows = vec!["header".to_owned()];
+        rows.extend((0..n).map(|i| i.to_string()));
+        json!({ "result": { "rows": rows } })
+    }
+
+    #[test]
+    fn concurrency_limit() {
+        let limiter = limiter(2, 0, Duration::from_secs(60));
+        let first = limiter.begin("a").unwrap();
+        let _second = limiter.begin("a").unwrap();
+
+        let err = limiter.begin("a").unwrap_err();
+        assert_eq!(err.budget, "concurrency");
+
+        // Finishing a request frees up its slot.
+        drop(first);
+        assert!(limiter.begin("a").is_ok());
+    }
+
+    #[test]
+    fn row_limit_and_window_reset() {
+        let limiter = limiter(0, 10, Duration::from_millis(100));
+
+        // The charge applies to the next request.
+        limiter.begin("a").unwrap().charge(&response(10));
+
+        let err = limiter.begin("a").unwrap_err();
+        assert_eq!(err.budget, "rows");
+        assert!(err.retry_after <= Duration::from_millis(100));
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(limiter.begin("a").is_ok());
+    }
+
+    #[test]
+    fn clients_are_isolated() {
+        let limiter = limiter(1, 5, Duration::from_secs(60));
+        let _busy = limiter.begin("a").unwrap();
+        assert!(limiter.begin("a").is_err());
+        assert!(limiter.begin("b").is_ok());
+
+        limiter.begin("c").unwrap().charge(&response(5));
+        assert!(limiter.begin("c").is_err());
+        assert!(limiter.begin("d").is_ok());
+    }
+
+    #[test]
+    fn zero_budgets_are_unlimited() {
+        let limiter = limiter(0, 0, Duration::from_secs(60));
+        assert!(!limiter.is_enabled());
+
+        let guards: Vec<_> = (0..100).map(|_| limiter.begin("a").unwrap()).collect();
+        guards[0].charge(&response(1000));
+        assert!(limiter.begin("a").is_ok());
+    }
+}
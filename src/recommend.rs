@@ -0,0 +1,85 @@
+//! Recommending the best exposure of a plate.
+//!
+//! A search point often lands on more than one exposure of the same plate:
+//! multiple-exposure plates were common, and an exposure may be matched both
+//! through its astrometric solution and through approximate WCS. Clients that
+//! want one image per plate need to choose between them, and it's better that
+//! they all choose the same way. So `queryexps` marks, for each plate, the
+//! exposure that we'd recommend in its `recommended` column.
+//!
+//! The heuristic is versioned, so that clients can tell when its choices might
+//! change; the current version is reported as [`VERSION`] in the `queryexps`
+//! limits and responses. Version 1 prefers, in order:
+//!
+//! 1. exposures matched through a real astrometric solution, then those
+//!    matched through approximate WCS, then those with unknown positions;
+//! 2. exposures in which the search point is at least [`EDGE_MARGIN_CM`] from
+//!    the edge of the mosaic, since plate images degrade toward their edges;
+//! 3. longer exposures, which go deeper;
+//! 4. exposures in which the search point is farther from the edge;
+//!
+//! and finally the first exposure in the order that the rows are reported.
+//! Changes to any of these rules must bump the version.
+
+use std::cmp::Ordering;
+
+/// The version of the recommendation heuristic.
+pub const VERSION: u32 = 1;
+
+/// How far from the mosaic edge, in cm, the search point has to be for an
+/// exposure not to be penalized.
+pub const EDGE_MARGIN_CM: f64 = 2.;
+
+/// How well we know where an exposure lies on the sky.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Astrometry {
+    /// The exposure's position is a placeholder.
+    Unknown,
+
+    /// We only have approximate WCS, from the logged center.
+    Approximate,
+
+    /// We have a real astrometric solution.
+    Solved,
+}
+
+/// What we know about one exposure of a plate that overlaps the search point.
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    pub astrometry: Astrometry,
+
+    /// The distance between the search point and the closest mosaic edge, in
+    /// cm, if known.
+    pub edge_dist_cm: Option<f64>,
+
+    /// The duration of the exposure, in minutes, if known.
+    pub exptime_min: Option<f64>,
+}
+
+impl Candidate {
+    /// Compare two candidates, with greater being better.
+    fn compare(&self, other: &Self) -> Ordering {
+        let clear = |c: &Self| c.edge_dist_cm.is_some_and(|d| d >= EDGE_MARGIN_CM);
+        let value = |v: Option<f64>| v.unwrap_or(f64::NEG_INFINITY);
+
+        self.astrometry
+            .cmp(&other.astrometry)
+            .then(clear(self).cmp(&clear(other)))
+            .then(value(self.exptime_min).total_cmp(&value(other.exptime_min)))
+            .then(value(self.edge_dist_cm).total_cmp(&value(other.edge_dist_cm)))
+    }
+}
+
+/// Choose the recommended exposure among a plate's candidates, returning its
+/// index, or `None` if there are no candidates.
+pub fn best(candidates: &[Candidate]) -> Option<usize> {
+    let mut best: Option<usize> = None;
+
+    for (i, c) in candidates.iter().enumerate() {
+        if best.is_none_or(|b| c.compare(&candidates[b]) == Ordering::Greater) {
+            best = Some(i);
+        }
+    }
+
+    best
+}
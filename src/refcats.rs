@@ -0,0 +1,266 @@
+//! The registry of reference catalogs that `querycat` can search.
+//!
+//! Each catalog has a table mapping the attributes stored in its DynamoDB
+//! table to the columns of the `querycat` output. Most columns are copied
+//! straight from an attribute, but some are computed, and those are described
+//! by their [`Format`]. Adding a catalog, like Gaia, should only
+//! require adding an entry here, and maybe a new `Format` if it needs some
+//! new kind of computed column.
+//!
+//! The column tables are also published by the `describe` service, so that
+//! clients know the types and units of the columns they'll get.
+//!
+//! Besides the catalogs ingested by the legacy DASCH pipeline, which share a
+//! schema, we have 2MASS, for targets that are bright in the infrared but not
+//! in APASS or ATLAS, and Tycho-2, for stars too bright for those to measure
+//! well. These tables use their catalogs' own identifiers, and have their own
+//! columns, but are partitioned by `gscBinIndex` and store positions in `ra`
+//! and `dec` like the others.
+//!
+//! Catalogs don't all give positions at the same epoch: the legacy APASS
+//! catalog uses J2000, but ATLAS-refcat2 inherits the J2015.5 epoch of Gaia
+//! DR2. Each catalog records its native epoch, which is what the `posEpoch`
+//! column reports, unless a source has its own `posEpoch` attribute. Proper
+//! motions have to be propagated from that epoch, not from J2000. 2MASS has no
+//! proper motions, and its sources were observed between 1997 and 2001, so
+//! its table gives each source its observation epoch.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use serde::Serialize;
+
+use crate::{backend::Item, refnums::refnum_to_text};
+
+/// The type of the values in a column.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColumnType {
+    String,
+    Integer,
+    Float,
+}
+
+/// How the value of a column is produced.
+#[derive(Clone, Copy, Debug)]
+pub enum Format {
+    /// Copy the attribute's value as-is. Missing values, and values that
+    /// aren't numbers or strings, are empty.
+    Raw,
+
+    /// Render the attribute, a reference number, as reference text; see
+    /// [`crate::refnums`].
+    RefText,
+
+    /// The source's RA, like `Raw`, but empty if the source's position is a
+    /// placeholder. Requests can change its formatting; see
+    /// [`crate::formatting`].
+    Ra,
+
+    /// The source's declination, like [`Format::Ra`].
+    Dec,
+
+    /// A magnitude, like `Raw`, but requests can change its formatting.
+    Magnitude,
+
+    /// The source's RA offset from the search center, in arcseconds.
+    RaOffset,
+
+    /// The source's declination offset from the search center, in arcseconds.
+    DecOffset,
+
+    /// The epoch of the source's position, in years: the attribute's value
+    /// if the source has one, or else the catalog's native epoch.
+    Epoch,
+}
+
+/// One column of `querycat` output.
+#[derive(Debug, Serialize)]
+pub struct Column {
+    /// The name of the DynamoDB attribute that the value comes from. Computed
+    /// columns don't read an attribute, but still have a descriptive name.
+    #[serde(skip)]
+    pub internal: &'static str,
+
+    /// The name of the column in the output.
+    #[serde(rename = "name")]
+    pub external: &'static str,
+
+    #[serde(rename = "type")]
+    pub ty: ColumnType,
+
+    /// The units of the values, if they have any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<&'static str>,
+
+    #[serde(skip)]
+    pub format: Format,
+}
+
+/// A reference catalog.
+#[derive(Debug)]
+pub struct Catalog {
+    /// The catalog name, as used in requests and table names.
+    pub name: &'static str,
+
+    /// The DynamoDB attribute that uniquely identifies a source.
+    pub id_attribute: &'static str,
+
+    /// The epoch at which the catalog gives source positions, as a Julian
+    /// year.
+    pub epoch: f64,
+
+    /// The output columns, in order.
+    pub columns: &'static [Column],
+}
+
+const fn column(
+    internal: &'static str,
+    external: &'static str,
+    ty: ColumnType,
+    unit: Option<&'static str>,
+    format: Format,
+) -> Column {
+    Column {
+        internal,
+        external,
+        ty,
+        unit,
+        format,
+    }
+}
+
+/// The columns of the catalogs ingested by the legacy DASCH pipeline, which
+/// all share a schema.
+const LEGACY_COLUMNS: &[Column] = {
+    use ColumnType::*;
+    use Format::*;
+
+    &[
+        column("refNumber", "ref_text", String, None, RefText),
+        column("refNumber", "ref_number", Integer, None, Raw),
+        column("gscBinIndex", "gscBinIndex", Integer, None, Raw),
+        column("ra", "raDeg", Float, Some("deg"), Ra),
+        column("dec", "decDeg", Float, Some("deg"), Dec),
+        column("draAsec", "draAsec", Float, Some("arcsec"), RaOffset),
+        column("ddecAsec", "ddecAsec", Float, Some("arcsec"), DecOffset),
+        column("posEpoch", "posEpoch", Float, Some("yr"), Epoch),
+        column("raPM", "pmRaMasyr", Float, Some("mas/yr"), Raw),
+        column("decPM", "pmDecMasyr", Float, Some("mas/yr"), Raw),
+        column("raSigmaPM", "uPMRaMasyr", Float, Some("mas/yr"), Raw),
+        column("decSigmaPM", "uPMDecMasyr", Float, Some("mas/yr"), Raw),
+        column("stdmag", "stdmag", Float, Some("mag"), Magnitude),
+        column("color", "color", Float, Some("mag"), Magnitude),
+        column("vFlag", "vFlag", Integer, None, Raw),
+        column("magFlag", "magFlag", Integer, None, Raw),
+        column("class", "class", Integer, None, Raw),
+    ]
+};
+
+/// The columns of the 2MASS Point Source Catalog.
+const TWOMASS_COLUMNS: &[Column] = {
+    use ColumnType::*;
+    use Format::*;
+
+    &[
+        column("designation", "designation", String, None, Raw),
+        column("gscBinIndex", "gscBinIndex", Integer, None, Raw),
+        column("ra", "raDeg", Float, Some("deg"), Ra),
+        column("dec", "decDeg", Float, Some("deg"), Dec),
+        column("draAsec", "draAsec", Float, Some("arcsec"), RaOffset),
+        column("ddecAsec", "ddecAsec", Float, Some("arcsec"), DecOffset),
+        column("posEpoch", "posEpoch", Float, Some("yr"), Epoch),
+        column("jMag", "jmag", Float, Some("mag"), Magnitude),
+        column("jMagSigma", "uJmag", Float, Some("mag"), Raw),
+        column("hMag", "hmag", Float, Some("mag"), Magnitude),
+        column("hMagSigma", "uHmag", Float, Some("mag"), Raw),
+        column("kMag", "kmag", Float, Some("mag"), Magnitude),
+        column("kMagSigma", "uKmag", Float, Some("mag"), Raw),
+        column("phQual", "phQual", String, None, Raw),
+        column("ccFlag", "ccFlag", String, None, Raw),
+    ]
+};
+
+/// The columns of the Tycho-2 catalog.
+const TYCHO2_COLUMNS: &[Column] = {
+    use ColumnType::*;
+    use Format::*;
+
+    &[
+        column("tycId", "tycId", String, None, Raw),
+        column("hip", "hip", Integer, None, Raw),
+        column("gscBinIndex", "gscBinIndex", Integer, None, Raw),
+        column("ra", "raDeg", Float, Some("deg"), Ra),
+        column("dec", "decDeg", Float, Some("deg"), Dec),
+        column("draAsec", "draAsec", Float, Some("arcsec"), RaOffset),
+        column("ddecAsec", "ddecAsec", Float, Some("arcsec"), DecOffset),
+        column("posEpoch", "posEpoch", Float, Some("yr"), Epoch),
+        column("raPM", "pmRaMasyr", Float, Some("mas/yr"), Raw),
+        column("decPM", "pmDecMasyr", Float, Some("mas/yr"), Raw),
+        column("raSigmaPM", "uPMRaMasyr", Float, Some("mas/yr"), Raw),
+        column("decSigmaPM", "uPMDecMasyr", Float, Some("mas/yr"), Raw),
+        column("btMag", "btmag", Float, Some("mag"), Magnitude),
+        column("btMagSigma", "uBtmag", Float, Some("mag"), Raw),
+        column("vtMag", "vtmag", Float, Some("mag"), Magnitude),
+        column("vtMagSigma", "uVtmag", Float, Some("mag"), Raw),
+    ]
+};
+
+pub const CATALOGS: &[Catalog] = &[
+    Catalog {
+        name: "apass",
+        id_attribute: "refNumber",
+        epoch: 2000.,
+        columns: LEGACY_COLUMNS,
+    },
+    Catalog {
+        name: "atlas",
+        id_attribute: "refNumber",
+        epoch: 2015.5,
+        columns: LEGACY_COLUMNS,
+    },
+    Catalog {
+        name: "2mass",
+        id_attribute: "designation",
+        epoch: 2000.,
+        columns: TWOMASS_COLUMNS,
+    },
+    Catalog {
+        name: "tycho2",
+        id_attribute: "tycId",
+        epoch: 2000.,
+        columns: TYCHO2_COLUMNS,
+    },
+];
+
+impl Catalog {
+    /// The text identifying a source, for reports: its reference text if
+    /// the catalog has reference numbers, or else its ID attribute.
+    pub fn source_text(&self, item: &Item) -> String {
+        let id = item.get(self.id_attribute);
+
+        if self
+            .columns
+            .iter()
+            .any(|c| matches!(c.format, Format::RefText))
+        {
+            id.and_then(|av| av.as_n().ok())
+                .and_then(|text| text.parse::<u64>().ok())
+                .map(refnum_to_text)
+                .unwrap_or_else(|| "UNDEFINED".to_owned())
+        } else {
+            match id {
+                Some(AttributeValue::N(s)) | Some(AttributeValue::S(s)) => s.clone(),
+                _ => "UNDEFINED".to_owned(),
+            }
+        }
+    }
+}
+
+/// Find a catalog by name.
+pub fn lookup(name: &str) -> Option<&'static Catalog> {
+    CATALOGS.iter().find(|c| c.name == name)
+}
+
+/// The names of all of the catalogs.
+pub fn names() -> Vec<&'static str> {
+    CATALOGS.iter().map(|c| c.name).collect()
+}
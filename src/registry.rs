@@ -0,0 +1,281 @@
+//! The registry of services that we provide.
+//!
+//! Every service that `Services::dispatch` knows how to run is listed here,
+//! along with a machine-readable description of it. The `describe` service
+//! returns these descriptions so that clients like daschlab can figure out
+//! what a given server version supports.
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, fmt};
+
+use crate::{
+    apiversion, config::Config, cutout, exportheaders, provenance, querycat, queryepoch, recommend,
+    refcats, residuals, targetlists,
+};
+
+/// A description of one service.
+#[derive(Serialize)]
+pub struct HandlerInfo {
+    /// The service name. Lambda function ARNs end with this name.
+    pub name: &'static str,
+
+    /// A short description.
+    pub description: &'static str,
+
+    /// The JSON schema of the request payload.
+    #[serde(serialize_with = "serialize_schema")]
+    pub request_schema: &'static str,
+
+    /// The formats of the result data that the service can produce.
+    pub output_formats: &'static [&'static str],
+
+    /// Service-specific limits on requests.
+    #[serde(serialize_with = "serialize_limits")]
+    pub limits: fn() -> Value,
+
+    /// The memory that one invocation may need, in MiB, which is reserved
+    /// from the per-instance budget before it runs; see `admission.rs`. Cheap
+    /// services declare zero and aren't admission-controlled.
+    pub memory_cost_mib: u32,
+
+    /// The most invocations that may run at once in one instance, if limited.
+    /// Invocations beyond this wait briefly, and are then rejected as busy.
+    pub max_concurrency: Option<usize>,
+
+    /// If true, the service is only for administrators: only clients with API
+    /// keys listed in `Config::admin_api_keys`, and the operator's own
+    /// command-line tools, may call it. `Services::dispatch_as` rejects
+    /// everyone else with a [`ForbiddenError`].
+    pub admin_only: bool,
+}
+
+/// The error returned when a client calls an admin-only service that it isn't
+/// authorized for.
+#[derive(Debug)]
+pub struct ForbiddenError {
+    pub service: &'static str,
+}
+
+impl fmt::Display for ForbiddenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the `{}` service is only available to administrators",
+            self.service
+        )
+    }
+}
+
+impl std::error::Error for ForbiddenError {}
+
+pub const HANDLERS: &[HandlerInfo] = &[
+    HandlerInfo {
+        name: "cutout",
+        description: "Extract an image cutout from a plate mosaic",
+        request_schema: include_str!("../json-schemas/cutout_request.json"),
+        output_formats: &[
+            "fits+gzip+base64",
+            "fits+rice+base64",
+            "png+base64",
+            "jpeg+base64",
+        ],
+        limits: || {
+            json!({
+                "output_size_pixels": cutout::OUTPUT_IMAGE_FULLSIZE,
+                "max_output_dimension_pixels": cutout::MAX_OUTPUT_DIMENSION,
+                "max_output_pixels": cutout::MAX_OUTPUT_NPIX,
+                "max_response_bytes": cutout::MAX_RESPONSE_BYTES,
+                "output_pixel_scale_deg": cutout::OUTPUT_IMAGE_PIXSCALE,
+                "min_pixel_scale_arcsec": cutout::MIN_PIXEL_SCALE_ARCSEC,
+                "max_pixel_scale_arcsec": cutout::MAX_PIXEL_SCALE_ARCSEC,
+                "max_source_pixels": cutout::MAX_SOURCE_NPIX,
+            })
+        },
+        memory_cost_mib: cutout::MEMORY_COST_MIB,
+        max_concurrency: None,
+        admin_only: false,
+    },
+    HandlerInfo {
+        name: "querycat",
+        description: "Search for reference catalog sources in an RA/Dec box",
+        request_schema: include_str!("../json-schemas/querycat_request.json"),
+        output_formats: &["csv-rows"],
+        limits: || {
+            json!({
+                "refcats": refcats::names(),
+                "refcat_columns": refcats::CATALOGS
+                    .iter()
+                    .map(|c| (c.name, c.columns))
+                    .collect::<BTreeMap<_, _>>(),
+                "refcat_epochs": refcats::CATALOGS
+                    .iter()
+                    .map(|c| (c.name, c.epoch))
+                    .collect::<BTreeMap<_, _>>(),
+                "max_radius_arcsec": querycat::MAX_RADIUS_ARCSEC,
+                "max_neighbor_radius_arcsec": querycat::MAX_NEIGHBOR_RADIUS_ARCSEC,
+                "max_nearest": querycat::MAX_NEAREST,
+                "default_nearest_radius_arcsec": querycat::DEFAULT_NEAREST_RADIUS_ARCSEC,
+            })
+        },
+        memory_cost_mib: 0,
+        max_concurrency: None,
+        admin_only: false,
+    },
+    HandlerInfo {
+        name: "lightcurve",
+        description:
+            "Get the detection-level photometry of a catalog source from the raw photometry files",
+        request_schema: include_str!("../json-schemas/lightcurve_request.json"),
+        output_formats: &["csv-rows"],
+        limits: || json!({ "refcats": refcats::names() }),
+        memory_cost_mib: 0,
+        max_concurrency: None,
+        admin_only: false,
+    },
+    HandlerInfo {
+        name: "queryexps",
+        description: "Search for exposures overlapping the specified coordinates",
+        request_schema: include_str!("../json-schemas/queryexps_request.json"),
+        output_formats: &["csv-rows"],
+        limits: || json!({ "recommendation_version": recommend::VERSION }),
+        memory_cost_mib: 0,
+        max_concurrency: None,
+        admin_only: false,
+    },
+    HandlerInfo {
+        name: "queryepoch",
+        description: "Search for exposures taken within a window of Julian Dates",
+        request_schema: include_str!("../json-schemas/queryepoch_request.json"),
+        output_formats: &["csv-rows"],
+        limits: || {
+            json!({
+                "max_window_days": queryepoch::MAX_WINDOW_DAYS,
+                "min_jd": queryepoch::MIN_JD,
+                "max_jd": queryepoch::MAX_JD,
+            })
+        },
+        memory_cost_mib: 0,
+        max_concurrency: None,
+        admin_only: false,
+    },
+    HandlerInfo {
+        name: "selftest",
+        description: "Run an end-to-end self-test of the services",
+        request_schema: include_str!("../json-schemas/selftest_request.json"),
+        output_formats: &["json"],
+        limits: || json!({}),
+        memory_cost_mib: 0,
+        max_concurrency: Some(1),
+        admin_only: false,
+    },
+    HandlerInfo {
+        name: "checkbin",
+        description: "Check that a reference catalog bin only holds sources that belong in it",
+        request_schema: include_str!("../json-schemas/checkbin_request.json"),
+        output_formats: &["json"],
+        limits: || json!({ "refcats": refcats::names() }),
+        memory_cost_mib: 0,
+        max_concurrency: Some(1),
+        admin_only: true,
+    },
+    HandlerInfo {
+        name: "targetlist",
+        description: "Upload a list of targets for batch operations",
+        request_schema: include_str!("../json-schemas/targetlist_request.json"),
+        output_formats: &["json"],
+        limits: || {
+            json!({
+                "max_targets": targetlists::MAX_TARGETS,
+                "content_type": targetlists::CONTENT_TYPE,
+            })
+        },
+        memory_cost_mib: 0,
+        max_concurrency: None,
+        admin_only: false,
+    },
+    HandlerInfo {
+        name: "residuals",
+        description: "Get the astrometric fit residuals of a plate solution",
+        request_schema: include_str!("../json-schemas/residuals_request.json"),
+        output_formats: &["csv-rows", "png+base64"],
+        limits: || {
+            json!({
+                "plot_size_pixels": residuals::PLOT_SIZE,
+                "max_arrow_length_pixels": residuals::ARROW_LENGTH,
+            })
+        },
+        memory_cost_mib: 0,
+        max_concurrency: None,
+        admin_only: false,
+    },
+    HandlerInfo {
+        name: "photcal",
+        description: "Get the photometric calibration summaries of a plate's solutions",
+        request_schema: include_str!("../json-schemas/photcal_request.json"),
+        output_formats: &["json"],
+        limits: || json!({}),
+        memory_cost_mib: 0,
+        max_concurrency: None,
+        admin_only: false,
+    },
+    HandlerInfo {
+        name: "history",
+        description: "List the caller's recorded cutout requests, newest first",
+        request_schema: include_str!("../json-schemas/history_request.json"),
+        output_formats: &["json"],
+        limits: || json!({ "max_limit": provenance::MAX_LIMIT }),
+        memory_cost_mib: 0,
+        max_concurrency: None,
+        admin_only: false,
+    },
+    HandlerInfo {
+        name: "exportheaders",
+        description: "Export the b01 FITS headers of many plates as a tar archive",
+        request_schema: include_str!("../json-schemas/exportheaders_request.json"),
+        output_formats: &["tar+url"],
+        limits: || json!({ "max_plates": exportheaders::MAX_PLATES }),
+        memory_cost_mib: 0,
+        max_concurrency: Some(exportheaders::MAX_CONCURRENT),
+        admin_only: false,
+    },
+    HandlerInfo {
+        name: "describe",
+        description: "Describe the services offered by this server",
+        request_schema: include_str!("../json-schemas/describe_request.json"),
+        output_formats: &["json"],
+        limits: || json!({}),
+        memory_cost_mib: 0,
+        max_concurrency: None,
+        admin_only: false,
+    },
+];
+
+/// Find the service that a Lambda function ARN refers to.
+pub fn lookup(arn: &str) -> Option<&'static HandlerInfo> {
+    HANDLERS.iter().find(|h| arn.ends_with(h.name))
+}
+
+/// The result of the `describe` service.
+pub fn describe(config: &Config) -> Value {
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "data_releases": config.data_releases,
+        "api_versions": {
+            "oldest": apiversion::OLDEST,
+            "current": apiversion::CURRENT,
+        },
+        "features": config.features.describe(),
+        "handlers": HANDLERS,
+    })
+}
+
+fn serialize_schema<S: serde::Serializer>(schema: &&'static str, s: S) -> Result<S::Ok, S::Error> {
+    // The schemas are checked into the repository, so they'd better be valid.
+    let v: Value = serde_json::from_str(schema).expect("request schema should be valid JSON");
+    v.serialize(s)
+}
+
+fn serialize_limits<S: serde::Serializer>(limits: &fn() -> Value, s: S) -> Result<S::Ok, S::Error> {
+    limits().serialize(s)
+}
@@ -0,0 +1,368 @@
+//! The astrometric residuals service.
+//!
+//! Before measuring positions on a cutout, users want to know how well the
+//! plate's astrometric solution actually fits. When the pipeline solves a
+//! plate, it records the residuals of the reference stars used in the fit:
+//! where each star lands on the plate, and how far its position according to
+//! the solution is from its catalog position. This service returns those
+//! residuals for one solution, either as a table or as a quiver plot.
+//!
+//! The residuals are stored in the plates table, in the
+//! `astrometry.residuals` list, which is sorted to match the solutions like
+//! `astrometry.exposures`. Each entry holds parallel lists of the stars' pixel
+//! coordinates and RA and declination offsets. We can't recompute residuals
+//! from the refcat and the WCS on the fly, because that needs the positions
+//! at which the stars were measured on the plate, which aren't stored anywhere
+//! that we can get to. So solutions without stored residuals are an error.
+//!
+//! The quiver plot is a grayscale PNG showing the whole plate, with a dot at
+//! each star and a line from it along the star's residual, RA offsets
+//! horizontal and declination offsets vertical. The lines are scaled so that
+//! the longest one is [`ARROW_LENGTH`] pixels; the response reports the scale.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use lambda_http::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    backend::TableStore,
+    config::{default_data_release, Config},
+    envelope,
+    mosaics::PlateId,
+    platecache::PlateCache,
+};
+
+/// The width and height of the quiver plot, in pixels.
+pub const PLOT_SIZE: usize = 512;
+
+/// The length of the longest line in the quiver plot, in pixels.
+pub const ARROW_LENGTH: f64 = 48.;
+
+/// The blank border around the plate in the quiver plot, in pixels.
+const PLOT_MARGIN: usize = 16;
+
+/// Sync with `json-schemas/residuals_request.json`.
+#[derive(Deserialize, Serialize)]
+pub struct Request {
+    plate_id: PlateId,
+    solution_number: usize,
+    /// The data release to query.
+    #[serde(default = "default_data_release")]
+    data_release: String,
+    /// How to present the residuals.
+    #[serde(default)]
+    format: ResidualsFormat,
+}
+
+/// How the residuals service presents its results.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResidualsFormat {
+    /// CSV-formatted rows, one per reference star.
+    #[default]
+    Table,
+
+    /// A quiver plot, as a Base64-encoded PNG image.
+    Png,
+}
+
+#[derive(Serialize)]
+pub struct Response {
+    /// The number of reference stars in the fit.
+    pub n_stars: usize,
+
+    /// The root-mean-square total residual of the stars, in arcseconds.
+    pub rms_arcsec: f64,
+
+    /// In table format, CSV-formatted rows of residuals, starting with a
+    /// header row.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rows: Option<Vec<String>>,
+
+    /// In PNG format, the Base64-encoded quiver plot.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub png: Option<String>,
+
+    /// In PNG format, the residual represented by one pixel of line length in
+    /// the quiver plot, in arcseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arrow_scale_arcsec: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlatesResult {
+    astrometry: Option<PlatesAstrometryResult>,
+    mosaic: Option<PlatesMosaicResult>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlatesAstrometryResult {
+    /// Plates with only approximate, logbook-based astrometry lack this.
+    #[serde(default)]
+    n_solutions: usize,
+    #[serde(default)]
+    residuals: Vec<Option<PlatesResidualsResult>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlatesResidualsResult {
+    x_pix: Vec<f64>,
+    y_pix: Vec<f64>,
+    d_ra_asec: Vec<f64>,
+    d_dec_asec: Vec<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlatesMosaicResult {
+    b01_height: usize,
+    b01_width: usize,
+}
+
+/// The attributes that we need from the plates table.
+const PLATES_PROJECTION: &str = "astrometry.nSolutions,\
+    astrometry.residuals,\
+    mosaic.b01Height,\
+    mosaic.b01Width";
+
+impl Request {
+    /// Validate the request.
+    pub fn normalize(self, config: &Config) -> Result<Self, Error> {
+        config.check_release(&self.data_release)?;
+        Ok(self)
+    }
+}
+
+pub async fn handler(
+    req: Option<Value>,
+    config: &Config,
+    tables: &dyn TableStore,
+    plates: &PlateCache,
+) -> Result<Value, Error> {
+    let request: Request =
+        serde_json::from_value(req.ok_or_else(|| -> Error { "no request payload".into() })?)?;
+    let request = request.normalize(config)?;
+    let echo = serde_json::to_value(&request)?;
+    let result = implementation(request, config, tables, plates).await?;
+    envelope::wrap("residuals", &echo, result)
+}
+
+/// Get the residuals of a solution. The request must have been normalized.
+pub async fn implementation(
+    request: Request,
+    config: &Config,
+    tables: &dyn TableStore,
+    plates: &PlateCache,
+) -> Result<Response, Error> {
+    let plates_table = config.plates_table(&request.data_release);
+
+    let item = match plates.get(PLATES_PROJECTION, &plates_table, request.plate_id.as_str()) {
+        Some(item) => item,
+
+        None => {
+            let item = tables
+                .get_item(
+                    &plates_table,
+                    "plateId",
+                    AttributeValue::S(request.plate_id.to_string()),
+                    PLATES_PROJECTION,
+                )
+                .await?
+                .ok_or_else(|| -> Error {
+                    format!("no such plate_id `{}`", request.plate_id).into()
+                })?;
+
+            plates.insert(
+                PLATES_PROJECTION,
+                &plates_table,
+                request.plate_id.to_string(),
+                item.clone(),
+            );
+            item
+        }
+    };
+
+    let item: PlatesResult = serde_dynamo::from_item(item)?;
+    let astrom_data = item
+        .astrometry
+        .filter(|a| a.n_solutions > 0)
+        .ok_or_else(|| -> Error {
+            format!(
+                "plate `{}` has no registered astrometric solutions",
+                request.plate_id
+            )
+            .into()
+        })?;
+
+    if request.solution_number >= astrom_data.n_solutions {
+        return Err(format!(
+            "requested astrometric solution #{} (0-based) for plate `{}` but it only has {} solutions",
+            request.solution_number, request.plate_id, astrom_data.n_solutions
+        )
+        .into());
+    }
+
+    let resid = astrom_data
+        .residuals
+        .into_iter()
+        .nth(request.solution_number)
+        .flatten()
+        .ok_or_else(|| -> Error {
+            format!(
+                "plate `{}` solution #{} has no stored astrometric residuals",
+                request.plate_id, request.solution_number
+            )
+            .into()
+        })?;
+
+    let n_stars = resid.x_pix.len();
+
+    if [&resid.y_pix, &resid.d_ra_asec, &resid.d_dec_asec]
+        .iter()
+        .any(|v| v.len() != n_stars)
+    {
+        return Err(format!(
+            "plate `{}` solution #{} has inconsistent stored residuals",
+            request.plate_id, request.solution_number
+        )
+        .into());
+    }
+
+    let sum_sq: f64 = resid
+        .d_ra_asec
+        .iter()
+        .zip(&resid.d_dec_asec)
+        .map(|(dra, ddec)| dra * dra + ddec * ddec)
+        .sum();
+    let rms_arcsec = if n_stars == 0 {
+        0.
+    } else {
+        (sum_sq / n_stars as f64).sqrt()
+    };
+
+    let mut response = Response {
+        n_stars,
+        rms_arcsec,
+        rows: None,
+        png: None,
+        arrow_scale_arcsec: None,
+    };
+
+    match request.format {
+        ResidualsFormat::Table => {
+            let mut rows = vec!["x_pix,y_pix,dra_arcsec,ddec_arcsec".to_owned()];
+
+            for i in 0..n_stars {
+                rows.push(format!(
+                    "{},{},{},{}",
+                    resid.x_pix[i], resid.y_pix[i], resid.d_ra_asec[i], resid.d_dec_asec[i]
+                ));
+            }
+
+            response.rows = Some(rows);
+        }
+
+        ResidualsFormat::Png => {
+            let mosaic = item.mosaic.ok_or_else(|| -> Error {
+                format!(
+                    "plate `{}` has no registered FITS mosaic information, so its residuals can't be plotted",
+                    request.plate_id
+                )
+                .into()
+            })?;
+
+            let (png, scale) = quiver_plot(&resid, mosaic.b01_width, mosaic.b01_height)?;
+            response.png = Some(STANDARD.encode(png));
+            response.arrow_scale_arcsec = Some(scale);
+        }
+    }
+
+    Ok(response)
+}
+
+/// Draw the quiver plot of a solution's residuals, returning the PNG data and
+/// the residual per pixel of line length.
+fn quiver_plot(
+    resid: &PlatesResidualsResult,
+    plate_width: usize,
+    plate_height: usize,
+) -> Result<(Vec<u8>, f64), Error> {
+    let mut pixels = vec![255u8; PLOT_SIZE * PLOT_SIZE];
+
+    let max_resid = resid
+        .d_ra_asec
+        .iter()
+        .zip(&resid.d_dec_asec)
+        // Not hypot(); see the note in `queryexps::check_overlap`.
+        .map(|(dra, ddec)| f64::sqrt(dra * dra + ddec * ddec))
+        .fold(0., f64::max);
+    let scale = if max_resid > 0. {
+        max_resid / ARROW_LENGTH
+    } else {
+        1.
+    };
+
+    // Fit the whole plate into the plot, preserving its aspect ratio. Plate
+    // pixel Y increases upwards, but PNG rows go downwards.
+    let zoom = (PLOT_SIZE - 2 * PLOT_MARGIN) as f64 / usize::max(plate_width, plate_height) as f64;
+    let mut plot = |x: isize, y: isize| {
+        if (0..PLOT_SIZE as isize).contains(&x) && (0..PLOT_SIZE as isize).contains(&y) {
+            pixels[y as usize * PLOT_SIZE + x as usize] = 0;
+        }
+    };
+
+    for i in 0..resid.x_pix.len() {
+        let x0 = (PLOT_MARGIN as f64 + resid.x_pix[i] * zoom).round() as isize;
+        let y0 = (PLOT_SIZE as f64 - PLOT_MARGIN as f64 - resid.y_pix[i] * zoom).round() as isize;
+        let x1 = x0 + (resid.d_ra_asec[i] / scale).round() as isize;
+        let y1 = y0 - (resid.d_dec_asec[i] / scale).round() as isize;
+
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                plot(x0 + dx, y0 + dy);
+            }
+        }
+
+        // Bresenham's line algorithm.
+        let (sx, sy) = (if x1 > x0 { 1 } else { -1 }, if y1 > y0 { 1 } else { -1 });
+        let (ax, ay) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (mut x, mut y, mut err) = (x0, y0, ax + ay);
+
+        loop {
+            plot(x, y);
+
+            if x == x1 && y == y1 {
+                break;
+            }
+
+            if 2 * err >= ay {
+                err += ay;
+                x += sx;
+            }
+
+            if 2 * err <= ax {
+                err += ax;
+                y += sy;
+            }
+        }
+    }
+
+    Ok((encode_png(&pixels, PLOT_SIZE, PLOT_SIZE)?, scale))
+}
+
+/// Encode an 8-bit grayscale image as a PNG file.
+fn encode_png(pixels: &[u8], width: usize, height: usize) -> Result<Vec<u8>, Error> {
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(pixels)?;
+    writer.finish()?;
+    Ok(png)
+}
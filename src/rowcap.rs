@@ -0,0 +1,129 @@
+//! Capping the number of rows in tabular results.
+//!
+//! The tabular services, `querycat` and `queryexps`, can produce far more
+//! rows than fit in a Lambda response, which used to make big queries fail
+//! outright. Now, inline results are capped at `Config::max_rows` rows, and
+//! requests may set a lower cap with their `max_rows` field. Staged results
+//! (see `staging.rs`) don't have the payload limit, so they're only capped if
+//! the request asks.
+//!
+//! A capped result is truncated just like one that runs short of time (see
+//! `deadline.rs`), and comes with a continuation token that picks up exactly
+//! where it left off, even if that's partway through a unit of work.
+//!
+//! The envelope of a tabular result has a `truncation` section, described by
+//! [`Truncation`], that says whether and why the result was cut short, and
+//! estimates the total number of rows that the query will produce. The
+//! estimate extrapolates from the units of work that have been done, so it's
+//! rough until most of the work has been done.
+
+use serde::Serialize;
+
+use crate::config::Config;
+
+/// A limit on the number of data rows in a result.
+#[derive(Clone, Copy, Debug)]
+pub struct RowCap {
+    limit: Option<usize>,
+    n_rows: usize,
+}
+
+impl RowCap {
+    /// Set up the cap for a request, given the `max_rows` that it asked for,
+    /// if any, and whether its results are staged rather than inline.
+    pub fn new(config: &Config, requested: Option<usize>, staged: bool) -> Self {
+        let limit = match (requested, staged) {
+            (Some(n), false) => Some(usize::min(n, config.max_rows)),
+            (Some(n), true) => Some(n),
+            (None, false) => Some(config.max_rows),
+            (None, true) => None,
+        };
+
+        RowCap { limit, n_rows: 0 }
+    }
+
+    /// Account for `n` more rows, returning how many of them fit under the
+    /// cap. The rest should be dropped.
+    pub fn admit(&mut self, n: usize) -> usize {
+        let n_fit = match self.limit {
+            Some(limit) => usize::min(n, limit.saturating_sub(self.n_rows)),
+            None => n,
+        };
+
+        self.n_rows += n_fit;
+        n_fit
+    }
+
+    /// The number of rows admitted so far.
+    pub fn n_rows(&self) -> usize {
+        self.n_rows
+    }
+}
+
+/// Why a result was cut short.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TruncationReason {
+    /// The request ran short of time.
+    Deadline,
+
+    /// The result reached its row cap.
+    MaxRows,
+
+    /// Some records couldn't be retrieved, most likely due to DynamoDB
+    /// throttling. These aren't covered by the continuation token.
+    Throttled,
+}
+
+/// The truncation state of a tabular result, as reported in the envelope.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Truncation {
+    /// Whether the result is incomplete.
+    pub truncated: bool,
+
+    /// Why the result is incomplete, if it is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<TruncationReason>,
+
+    /// The number of data rows in this result.
+    pub n_rows: usize,
+
+    /// The estimated number of data rows in this result and all of its
+    /// continuations. Exact if the result is complete.
+    pub estimated_total_rows: usize,
+
+    /// If the result can be continued, the token to continue it with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub continuation: Option<String>,
+}
+
+impl Truncation {
+    /// The state of a complete result.
+    pub fn complete(n_rows: usize) -> Self {
+        Truncation {
+            n_rows,
+            estimated_total_rows: n_rows,
+            ..Default::default()
+        }
+    }
+}
+
+/// Estimate the total number of rows that a truncated query will produce.
+/// This response has `n_rows` rows from `units_done` units of work, and we
+/// know that `known_remaining` more rows are pending from a unit that was cut
+/// short. We assume that the `units_remaining` units that haven't been
+/// started will be like the ones that have been done.
+pub fn estimate_total(
+    n_rows: usize,
+    known_remaining: usize,
+    units_done: usize,
+    units_remaining: usize,
+) -> usize {
+    let per_unit = if units_done == 0 {
+        0.
+    } else {
+        (n_rows + known_remaining) as f64 / units_done as f64
+    };
+
+    n_rows + known_remaining + (per_unit * units_remaining as f64).round() as usize
+}
@@ -13,11 +13,17 @@
 //! This suggests a three-segment buffer, with one segment for each region of
 //! the file that we care about. The first segment can be a small buffer; the
 //! second bigger; and the third should be biggest.
+//!
+//! If the local disk cache is enabled (see `diskcache.rs`), the buffers are
+//! refilled from it where they can be.
 
 use anyhow::{bail, Result};
 use aws_sdk_s3::operation::get_object::builders::GetObjectFluentBuilder;
+use lambda_runtime::tracing::{info_span, Instrument};
 use std::io::Write;
 
+use crate::diskcache::CachedObject;
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 enum BufferKind {
     A,
@@ -60,6 +66,7 @@ impl Buffer {
     async fn read_into<W: Write>(
         &mut self,
         get: GetObjectFluentBuilder,
+        cached: Option<&CachedObject>,
         mut offset: u64,
         mut nbytes: usize,
         mut dest: W,
@@ -94,15 +101,11 @@ impl Buffer {
         //eprintln!("+s3buf {:?} fetching @ {}", self.kind, offset);
 
         // If we need more than our buffer fits, just grow the buffer.
-        let end_byte = offset + usize::max(self.data.capacity(), nbytes) as u64 - 1;
+        let end = offset + usize::max(self.data.capacity(), nbytes) as u64;
 
-        let mut result = get
-            .range(format!("bytes={}-{}", offset, end_byte))
-            .send()
-            .await?;
-
-        while let Some(bytes) = result.body.try_next().await? {
-            self.data.extend_from_slice(&bytes);
+        match cached {
+            Some(object) => object.read_range(get, offset, end, &mut self.data).await?,
+            None => get_range(get, offset, end, &mut self.data).await?,
         }
 
         if self.data.len() < nbytes {
@@ -114,11 +117,43 @@ impl Buffer {
     }
 }
 
+/// Fetch the bytes from `start` up to, but not including, `end` from S3,
+/// appending them to `dest`. If the object ends first, we get fewer bytes.
+pub async fn get_range(
+    get: GetObjectFluentBuilder,
+    start: u64,
+    end: u64,
+    dest: &mut Vec<u8>,
+) -> Result<()> {
+    let range = format!("bytes={}-{}", start, end - 1);
+    let span = info_span!(
+        "aws",
+        aws.service = "S3",
+        aws.operation = "GetObject",
+        range = range.as_str()
+    );
+
+    async {
+        let mut result = get.range(&range).send().await?;
+
+        while let Some(bytes) = result.body.try_next().await? {
+            dest.extend_from_slice(&bytes);
+        }
+
+        Ok(())
+    }
+    .instrument(span)
+    .await
+}
+
 #[derive(Debug)]
 pub struct S3Buffer {
     buf_a: Buffer,
     buf_b: Buffer,
     buf_c: Buffer,
+
+    /// If set, reads go through the local disk cache; see `diskcache.rs`.
+    cached: Option<CachedObject>,
 }
 
 impl Default for S3Buffer {
@@ -127,11 +162,17 @@ impl Default for S3Buffer {
             buf_a: Buffer::new(BufferKind::A),
             buf_b: Buffer::new(BufferKind::B),
             buf_c: Buffer::new(BufferKind::C),
+            cached: None,
         }
     }
 }
 
 impl S3Buffer {
+    /// Read through the local disk cache from now on.
+    pub fn use_disk_cache(&mut self, object: CachedObject) {
+        self.cached = Some(object);
+    }
+
     pub async fn read_into<W: Write>(
         &mut self,
         get: GetObjectFluentBuilder,
@@ -139,6 +180,8 @@ impl S3Buffer {
         nbytes: usize,
         dest: W,
     ) -> Result<()> {
+        let cached = self.cached.as_ref();
+
         let buf = {
             if self.buf_a.empty_or_overlaps(offset, nbytes) {
                 &mut self.buf_a
@@ -155,7 +198,7 @@ impl S3Buffer {
             }
         };
 
-        buf.read_into(get, offset, nbytes, dest).await?;
+        buf.read_into(get, cached, offset, nbytes, dest).await?;
         Ok(())
     }
 }
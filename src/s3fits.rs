@@ -1,13 +1,20 @@
 use anyhow::{anyhow, Error};
-use aws_config::SdkConfig;
-use aws_sdk_s3;
 use fitswcs_sys::cfitsio;
 use libc::{c_char, c_int, c_long, c_longlong, c_void};
 use once_cell::sync::{Lazy, OnceCell};
-use std::{collections::HashMap, ffi::CStr, future::Future, io::Cursor, sync::Mutex};
+use std::{
+    collections::HashMap,
+    ffi::CStr,
+    future::Future,
+    io::Cursor,
+    sync::{Arc, Mutex},
+};
 use tokio::runtime;
 
-use crate::s3buffer::S3Buffer;
+use crate::{
+    diskcache::{CachedObject, DiskCache},
+    s3buffer::S3Buffer,
+};
 
 #[derive(Debug)]
 struct S3State {
@@ -19,7 +26,10 @@ struct S3State {
 }
 
 impl S3State {
-    fn new_from_fitsurl<S: AsRef<str>>(config: &SdkConfig, fitsurl: S) -> Result<Self, Error> {
+    fn new_from_fitsurl<S: AsRef<str>>(
+        config: &aws_sdk_s3::Config,
+        fitsurl: S,
+    ) -> Result<Self, Error> {
         let fitsurl = fitsurl.as_ref();
 
         let (bucket, key) = fitsurl
@@ -27,7 +37,7 @@ impl S3State {
             .ok_or_else(|| anyhow!("invalid filename: no slash"))?;
 
         Ok(S3State {
-            client: aws_sdk_s3::Client::new(config),
+            client: aws_sdk_s3::Client::from_conf(config.clone()),
             bucket: bucket.to_owned(),
             key: key.to_owned(),
             offset: 0,
@@ -36,9 +46,15 @@ impl S3State {
     }
 }
 
-static AWS_CONFIG: OnceCell<SdkConfig> = OnceCell::new();
+static AWS_CONFIG: OnceCell<aws_sdk_s3::Config> = OnceCell::new();
+static DISK_CACHE: OnceCell<DiskCache> = OnceCell::new();
 static HANDLE_COUNTER: Lazy<Mutex<c_int>> = Lazy::new(|| Mutex::new(0));
-static HANDLES: Lazy<Mutex<HashMap<c_int, S3State>>> = Lazy::new(|| Mutex::new(Default::default()));
+/// The state of each open handle. Each has its own lock, so that the global
+/// lock is only held to look up a handle, not during its (slow) S3 I/O, and
+/// handles used by different threads don't wait on each other.
+type HandleMap = HashMap<c_int, Arc<Mutex<S3State>>>;
+
+static HANDLES: Lazy<Mutex<HandleMap>> = Lazy::new(|| Mutex::new(Default::default()));
 
 /// Given a FITS handle from the CFITSIO layer, invoke an closure with
 /// its corresponding S3State object.
@@ -46,9 +62,8 @@ fn with_handle<F>(handle: c_int, inner: F) -> c_int
 where
     F: FnOnce(&mut S3State) -> c_int,
 {
-    let mut ht = HANDLES.lock().unwrap();
-    let state = match ht.get_mut(&handle) {
-        Some(s) => s,
+    let state = match HANDLES.lock().unwrap().get(&handle) {
+        Some(s) => s.clone(),
 
         None => {
             eprintln!("S3 op failed: no such open handle #{}", handle);
@@ -56,7 +71,8 @@ where
         }
     };
 
-    inner(state)
+    let mut state = state.lock().unwrap();
+    inner(&mut state)
 }
 
 /// Spin up a temporary runtime to invoke an asynchronous function that returns
@@ -156,7 +172,7 @@ pub unsafe extern "C" fn s3fits_driver_fitsopen(
 
     {
         let mut ht = HANDLES.lock().unwrap();
-        ht.insert(handle, state);
+        ht.insert(handle, Arc::new(Mutex::new(state)));
     }
 
     0
@@ -173,7 +189,9 @@ pub extern "C" fn s3fits_driver_fitstruncate(_driverhandle: c_int, _filesize: c_
     0
 }
 
-pub extern "C" fn s3fits_driver_fitsclose(_driverhandle: c_int) -> c_int {
+/// Close a handle, freeing its state and buffers.
+pub extern "C" fn s3fits_driver_fitsclose(driverhandle: c_int) -> c_int {
+    HANDLES.lock().unwrap().remove(&driverhandle);
     0
 }
 
@@ -206,6 +224,14 @@ pub extern "C" fn s3fits_driver_size(driverhandle: c_int, sizex: *mut c_longlong
                 *sizex = cl as c_longlong;
             }
 
+            // CFITSIO gets the size right after opening the file, so this is
+            // when we can start reading through the disk cache, which needs
+            // to know where the object ends.
+            if let Some(cache) = DISK_CACHE.get() {
+                let object = CachedObject::new(cache, &state.bucket, &state.key, cl as u64);
+                state.buffer.use_disk_cache(object);
+            }
+
             Ok(())
         })
     })
@@ -269,9 +295,14 @@ pub extern "C" fn s3fits_driver_fitswrite(
     0
 }
 
-pub fn register(config: SdkConfig) {
+/// Register the driver, which reads through `cache` if it's set up.
+pub fn register(config: aws_sdk_s3::Config, cache: Option<DiskCache>) {
     let _ = AWS_CONFIG.set(config);
 
+    if let Some(cache) = cache {
+        let _ = DISK_CACHE.set(cache);
+    }
+
     let result = unsafe {
         cfitsio::fits_register_driver(
             c"s3://".as_ptr(),
@@ -0,0 +1,274 @@
+//! The self-test "service".
+//!
+//! This isn't a user-facing API. It exercises the main moving parts of the
+//! other services end-to-end against one known-good plate, so that scheduled
+//! canary invocations can tell us if a deployed environment is broken. Each
+//! check is timed, and a failing check doesn't stop the later ones from
+//! running, unless they depend on its results.
+//!
+//! The plate to use is taken from the `plate_id` field of the payload, if
+//! provided, or the `DASCH_SELFTEST_PLATE_ID` environment variable otherwise.
+//! It must have a mosaic and at least one astrometric solution.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use flate2::read::GzDecoder;
+use lambda_http::Error;
+use lambda_runtime::tracing;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::Instant;
+
+use crate::{
+    backend::{ObjectStore, TableStore},
+    config::{default_data_release, Config},
+    envelope,
+    fitsfile::FitsFile,
+    mosaics::{load_b01_header, wcslib_solnum, PlateId},
+};
+
+/// The size of the square of pixels that we read from the mosaic.
+const READ_SIZE: usize = 8;
+
+/// The maximum acceptable error in the WCS round trip, in pixels.
+const ROUND_TRIP_TOLERANCE: f64 = 1e-3;
+
+#[derive(Deserialize, Serialize, Default)]
+pub struct Request {
+    plate_id: Option<PlateId>,
+    data_release: Option<String>,
+}
+
+impl Request {
+    /// Fill in the defaults and validate the request.
+    fn normalize(self, config: &Config) -> Result<Self, Error> {
+        let plate_id = match self.plate_id {
+            Some(p) => p,
+            None => std::env::var("DASCH_SELFTEST_PLATE_ID")
+                .map_err(|_| -> Error {
+                    "no plate_id given and $DASCH_SELFTEST_PLATE_ID is not set".into()
+                })?
+                .parse()?,
+        };
+
+        let release = self.data_release.unwrap_or_else(default_data_release);
+        config.check_release(&release)?;
+
+        Ok(Request {
+            plate_id: Some(plate_id),
+            data_release: Some(release),
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct Response {
+    /// Whether all of the checks passed.
+    pub passed: bool,
+
+    /// The plate that was tested.
+    pub plate_id: String,
+
+    /// The individual checks.
+    pub checks: Vec<Check>,
+
+    /// The total time taken, in milliseconds.
+    pub elapsed_ms: f64,
+}
+
+#[derive(Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub passed: bool,
+    pub elapsed_ms: f64,
+
+    /// A description of the failure, if the check failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlatesResult {
+    astrometry: Option<PlatesAstrometryResult>,
+    mosaic: Option<PlatesMosaicResult>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlatesAstrometryResult {
+    #[serde(with = "serde_bytes")]
+    b01_header_gz: Vec<u8>,
+    n_solutions: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlatesMosaicResult {
+    b01_height: usize,
+    b01_width: usize,
+    s3_key_template: String,
+}
+
+/// The attributes that we need from the plates table.
+const PLATES_PROJECTION: &str = "astrometry.b01HeaderGz,\
+    astrometry.nSolutions,\
+    mosaic.b01Height,\
+    mosaic.b01Width,\
+    mosaic.s3KeyTemplate";
+
+pub async fn handler(
+    req: Option<Value>,
+    config: &Config,
+    tables: &dyn TableStore,
+    objects: &dyn ObjectStore,
+) -> Result<Value, Error> {
+    let request: Request = match req {
+        Some(v) => serde_json::from_value(v)?,
+        None => Request::default(),
+    };
+
+    let request = request.normalize(config)?;
+    let echo = serde_json::to_value(&request)?;
+    let result = implementation(request, config, tables, objects).await?;
+    envelope::wrap("selftest", &echo, result)
+}
+
+/// Run the self-test. The request must have been normalized.
+pub async fn implementation(
+    request: Request,
+    config: &Config,
+    tables: &dyn TableStore,
+    objects: &dyn ObjectStore,
+) -> Result<Response, Error> {
+    let plate_id = request.plate_id.map(String::from).unwrap_or_default();
+    let release = request.data_release.unwrap_or_else(default_data_release);
+
+    let t0 = Instant::now();
+    let mut checks = Vec::new();
+
+    // DynamoDB read.
+
+    let t = Instant::now();
+    let plates_table = config.plates_table(&release);
+
+    let plate = async {
+        let item = tables
+            .get_item(
+                &plates_table,
+                "plateId",
+                AttributeValue::S(plate_id.clone()),
+                PLATES_PROJECTION,
+            )
+            .await?
+            .ok_or_else(|| -> Error { format!("no such plate_id `{plate_id}`").into() })?;
+
+        let plate: PlatesResult = serde_dynamo::from_item(item)?;
+
+        match (plate.mosaic, plate.astrometry) {
+            (Some(m), Some(a)) if a.n_solutions > 0 => Ok((m, a)),
+            _ => Err(format!("plate `{plate_id}` lacks a mosaic or astrometric solutions").into()),
+        }
+    }
+    .await;
+
+    let (mos, astrom) = match record(&mut checks, "dynamodb_read", t, plate) {
+        Some(x) => x,
+        None => return Ok(finish(plate_id, checks, t0)),
+    };
+
+    // Read a few pixels from the center of the mosaic, through CFITSIO.
+
+    let t = Instant::now();
+    let s3path = mos
+        .s3_key_template
+        .replace("{bin}", "01")
+        .replace("{tnx}", "_tnx");
+    let url = objects.fits_url(&config.bucket, &s3path);
+    let x0 = mos.b01_width.saturating_sub(READ_SIZE) / 2;
+    let y0 = mos.b01_height.saturating_sub(READ_SIZE) / 2;
+
+    // Keep the S3 reads made by CFITSIO in this request's trace.
+    let span = tracing::Span::current();
+
+    let pixels = tokio::task::spawn_blocking(move || -> Result<(), Error> {
+        let _entered = span.enter();
+        let mut fits = FitsFile::open(url)?;
+        fits.move_to_hdu(1)?;
+        let data = fits.read_rectangle(x0, y0, READ_SIZE, READ_SIZE)?;
+
+        if data.iter().all(|&v| v == 0) {
+            return Err("mosaic pixels are all zero".into());
+        }
+
+        Ok(())
+    })
+    .await
+    .map_err(|e| -> Error { e.into() })
+    .and_then(|r| r);
+
+    record(&mut checks, "fits_read", t, pixels);
+
+    // WCS round trip at the center of the mosaic.
+
+    let t = Instant::now();
+
+    let round_trip = (|| -> Result<(), Error> {
+        let mut wcs = load_b01_header(
+            GzDecoder::new(&astrom.b01_header_gz[..]),
+            Default::default(),
+        )?;
+        let mut wcs = wcs.get(wcslib_solnum(0, astrom.n_solutions)?)?;
+
+        let x = 0.5 * (mos.b01_width as f64 - 1.);
+        let y = 0.5 * (mos.b01_height as f64 - 1.);
+        let (ra, dec) = wcs.pixel_to_world_scalar(x, y)?;
+        let (x2, y2) = wcs
+            .world_to_pixel_scalar(ra, dec)?
+            .ok_or_else(|| -> Error { "world-to-pixel transform failed".into() })?;
+
+        let err = f64::max((x2 - x).abs(), (y2 - y).abs());
+
+        if err.is_nan() || err > ROUND_TRIP_TOLERANCE {
+            return Err(format!("round-trip error of {err} pixels is too large").into());
+        }
+
+        Ok(())
+    })();
+
+    record(&mut checks, "wcs_round_trip", t, round_trip);
+
+    Ok(finish(plate_id, checks, t0))
+}
+
+/// Record the outcome of one check, returning its result if it passed.
+fn record<T>(
+    checks: &mut Vec<Check>,
+    name: &'static str,
+    start: Instant,
+    result: Result<T, Error>,
+) -> Option<T> {
+    let elapsed_ms = start.elapsed().as_secs_f64() * 1000.;
+
+    let (value, error) = match result {
+        Ok(v) => (Some(v), None),
+        Err(e) => (None, Some(e.to_string())),
+    };
+
+    checks.push(Check {
+        name,
+        passed: error.is_none(),
+        elapsed_ms,
+        error,
+    });
+
+    value
+}
+
+fn finish(plate_id: String, checks: Vec<Check>, start: Instant) -> Response {
+    Response {
+        passed: checks.iter().all(|c| c.passed),
+        plate_id,
+        checks,
+        elapsed_ms: start.elapsed().as_secs_f64() * 1000.,
+    }
+}
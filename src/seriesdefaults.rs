@@ -0,0 +1,151 @@
+//! Per-series default cutout parameters, and other series metadata.
+//!
+//! The DASCH plate series span more than an order of magnitude in plate scale,
+//! so a cutout made with our standard output pixel scale and size can be a
+//! tiny, heavily oversampled corner of a patrol plate, or a broad, undersampled
+//! view of a plate from a long-focus telescope. The series defaults table lets
+//! us choose, for each series, the output pixel scale and stamp size that a
+//! cutout gets when the request doesn't say, so that stamps from different
+//! series come out comparable by default. It's a JSON object in the data bucket
+//! mapping series names to their defaults, any of which can be left out:
+//!
+//! ```json
+//! { "rh": { "pixel_scale_arcsec": 4.0, "width_pixels": 417, "height_pixels": 417 } }
+//! ```
+//!
+//! An entry can also give the `site` where the series was taken, as a
+//! latitude and longitude, which `queryexps` uses to compute the airmass and
+//! hour angle of its results; see `observing.rs`:
+//!
+//! ```json
+//! { "b": { "site": { "latitude_deg": -16.40, "longitude_deg": -71.55 } } }
+//! ```
+//!
+//! An entry can also list the `placeholders` that the series' records use in
+//! place of unknown positions, beyond the ones that we already know about (see
+//! [`PlaceholderPolicy`]). Exposures of the series with either coordinate in
+//! these lists are reported as having unknown positions. The lists don't
+//! apply to other series, or to the refcat tables:
+//!
+//! ```json
+//! { "b": { "placeholders": { "ra_deg": [888.0], "dec_deg": [-888.0] } } }
+//! ```
+//!
+//! The defaults only apply to API version 2 requests, since version 1 always
+//! made 835×835 stamps at the standard scale. Response envelopes echo the
+//! parameters actually used, so that cutouts can be re-created exactly even
+//! after the table changes.
+//!
+//! Like the deny-list, the table is loaded once per warm instance, so changes
+//! take effect as instances are recycled. If it doesn't exist, every series
+//! gets the standard parameters, and only the built-in placeholders.
+
+use lambda_http::Error;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    backend::ObjectStore,
+    config::Config,
+    mosaics::{PlaceholderPolicy, BUILTIN_PLACEHOLDERS},
+    observing::ObservingSite,
+};
+
+/// The default cutout parameters of one series.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CutoutDefaults {
+    /// The output pixel scale, in arcseconds per pixel.
+    #[serde(default)]
+    pub pixel_scale_arcsec: Option<f64>,
+
+    /// The width of the output image, in pixels.
+    #[serde(default)]
+    pub width_pixels: Option<usize>,
+
+    /// The height of the output image, in pixels.
+    #[serde(default)]
+    pub height_pixels: Option<usize>,
+}
+
+/// The placeholder coordinate values of the series that list any.
+#[derive(Debug, Default)]
+pub struct SeriesPlaceholders(HashMap<String, PlaceholderPolicy>);
+
+impl SeriesPlaceholders {
+    /// Get the placeholder policy of a plate series. Series that don't list
+    /// any placeholders only have the built-in ones.
+    pub fn get(&self, series: &str) -> &PlaceholderPolicy {
+        self.0.get(series).unwrap_or(&BUILTIN_PLACEHOLDERS)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SeriesDefaults {
+    series: HashMap<String, CutoutDefaults>,
+    sites: Arc<HashMap<String, ObservingSite>>,
+    placeholders: Arc<SeriesPlaceholders>,
+}
+
+impl SeriesDefaults {
+    /// Load the table from the data bucket.
+    pub async fn load(config: &Config, objects: &dyn ObjectStore) -> Result<Self, Error> {
+        let data = match objects
+            .get_object(&config.bucket, &config.series_defaults_key)
+            .await?
+        {
+            Some(d) => d,
+            None => return Ok(SeriesDefaults::default()),
+        };
+
+        let malformed = |e: serde_json::Error| -> Error {
+            format!("malformed series defaults table: {e}").into()
+        };
+
+        let table: HashMap<String, Map<String, Value>> =
+            serde_json::from_slice(&data).map_err(malformed)?;
+        let mut series = HashMap::new();
+        let mut sites = HashMap::new();
+        let mut placeholders = HashMap::new();
+
+        for (name, mut fields) in table {
+            if let Some(p) = fields.remove("placeholders") {
+                placeholders.insert(name.clone(), serde_json::from_value(p).map_err(malformed)?);
+            }
+
+            if let Some(site) = fields.remove("site") {
+                sites.insert(
+                    name.clone(),
+                    serde_json::from_value(site).map_err(malformed)?,
+                );
+            }
+
+            series.insert(
+                name,
+                serde_json::from_value(Value::Object(fields)).map_err(malformed)?,
+            );
+        }
+
+        Ok(SeriesDefaults {
+            series,
+            sites: Arc::new(sites),
+            placeholders: Arc::new(SeriesPlaceholders(placeholders)),
+        })
+    }
+
+    /// Get the default cutout parameters of a plate series, if it has any.
+    pub fn cutout(&self, series: &str) -> Option<&CutoutDefaults> {
+        self.series.get(series)
+    }
+
+    /// The observing sites of the series that have them, by series name.
+    pub fn sites(&self) -> Arc<HashMap<String, ObservingSite>> {
+        self.sites.clone()
+    }
+
+    /// The placeholder coordinate values of the series.
+    pub fn placeholders(&self) -> Arc<SeriesPlaceholders> {
+        self.placeholders.clone()
+    }
+}
@@ -0,0 +1,233 @@
+//! Serving plate metadata from an S3 snapshot of the plates table.
+//!
+//! The cutout and queryexps services can't do anything without the plates
+//! table, so a DynamoDB outage, or a table migration, takes them down. To keep
+//! them going, the plate items can also be read from a snapshot in S3, stored
+//! in the data bucket under `Config::plates_snapshot_prefix`, with one object
+//! per plate, named `<plate ID>.json`. Each object holds one line of a
+//! DynamoDB export in the DynamoDB JSON format, `{"Item": {...}}`, so a
+//! snapshot can be made by splitting up an export.
+//!
+//! `Config::plates_source` chooses where plate lookups go; see
+//! [`PlatesSource`]. Only lookups by plate ID can be served from a snapshot.
+//! Queries of the plates table, such as those of `queryepoch`, fail in
+//! snapshot mode, and the other tables are always read from DynamoDB, so
+//! services that need them won't work during an outage regardless.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use futures::future::{try_join_all, BoxFuture};
+use lambda_http::Error;
+use lambda_runtime::tracing::warn;
+use serde_json::Value;
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    backend::{item_from_json, key_text, BatchGetOutput, Item, ObjectStore, TableStore},
+    config::Config,
+    warnings,
+};
+
+/// Where plate metadata comes from.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PlatesSource {
+    /// Only read plates from DynamoDB.
+    #[default]
+    DynamoDb,
+
+    /// Read plates from DynamoDB, falling back to the snapshot if a lookup
+    /// fails.
+    Fallback,
+
+    /// Only read plates from the snapshot, leaving DynamoDB alone.
+    Snapshot,
+}
+
+impl PlatesSource {
+    /// Parse the `DASCH_PLATES_SOURCE` setting.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        match text.trim().to_lowercase().as_str() {
+            "dynamodb" => Ok(PlatesSource::DynamoDb),
+            "fallback" => Ok(PlatesSource::Fallback),
+            "snapshot" => Ok(PlatesSource::Snapshot),
+            other => Err(format!(
+                "unknown plates source `{other}` (expected `dynamodb`, `fallback`, or `snapshot`)"
+            )),
+        }
+    }
+}
+
+/// A [`TableStore`] that reads the plates tables from S3 snapshots when
+/// configured to.
+pub struct SnapshotTableStore {
+    inner: Arc<dyn TableStore>,
+    objects: Arc<dyn ObjectStore>,
+    source: PlatesSource,
+    bucket: String,
+
+    /// The snapshot key prefix of each plates table, by table name.
+    prefixes: HashMap<String, String>,
+}
+
+impl SnapshotTableStore {
+    /// Wrap `inner` to read plates as configured in `config`.
+    pub fn wrap(
+        inner: Arc<dyn TableStore>,
+        objects: Arc<dyn ObjectStore>,
+        config: &Config,
+    ) -> Arc<dyn TableStore> {
+        if config.plates_source == PlatesSource::DynamoDb {
+            return inner;
+        }
+
+        let prefixes = config
+            .data_releases
+            .iter()
+            .map(|r| (config.plates_table(r), config.plates_snapshot_prefix(r)))
+            .collect();
+
+        Arc::new(SnapshotTableStore {
+            inner,
+            objects,
+            source: config.plates_source,
+            bucket: config.bucket.clone(),
+            prefixes,
+        })
+    }
+
+    /// Read a plate from the snapshot of the table whose snapshot has the
+    /// given prefix.
+    async fn load(&self, prefix: &str, key: &AttributeValue) -> Result<Option<Item>, Error> {
+        let object_key = format!("{prefix}{}.json", key_text(key)?);
+
+        let Some(data) = self.objects.get_object(&self.bucket, &object_key).await? else {
+            return Ok(None);
+        };
+
+        let json: Value = serde_json::from_slice(&data)?;
+        let item = json.get("Item").ok_or_else(|| -> Error {
+            format!("plates snapshot object `{object_key}` has no `Item`").into()
+        })?;
+        Ok(Some(item_from_json(item)?))
+    }
+
+    /// Read some plates from the snapshot.
+    async fn load_all(&self, prefix: &str, keys: &[AttributeValue]) -> Result<Vec<Item>, Error> {
+        let items = try_join_all(keys.iter().map(|k| self.load(prefix, k))).await?;
+        Ok(items.into_iter().flatten().collect())
+    }
+
+    /// Log that a lookup is falling back to the snapshot.
+    fn note_fallback(&self, table: &str, e: &Error) {
+        warn!("reading `{table}` from its S3 snapshot after DynamoDB failed: {e}");
+        warnings::add(
+            "plates_snapshot",
+            "plate metadata was read from a snapshot, which may be out of date, because the database was unavailable",
+        );
+    }
+}
+
+impl TableStore for SnapshotTableStore {
+    fn get_item<'a>(
+        &'a self,
+        table: &'a str,
+        key_attr: &'a str,
+        key: AttributeValue,
+        projection: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Item>, Error>> {
+        Box::pin(async move {
+            let Some(prefix) = self.prefixes.get(table) else {
+                return self.inner.get_item(table, key_attr, key, projection).await;
+            };
+
+            if self.source == PlatesSource::Fallback {
+                match self
+                    .inner
+                    .get_item(table, key_attr, key.clone(), projection)
+                    .await
+                {
+                    Ok(item) => return Ok(item),
+                    Err(e) => self.note_fallback(table, &e),
+                }
+            }
+
+            self.load(prefix, &key).await
+        })
+    }
+
+    fn batch_get_items<'a>(
+        &'a self,
+        table: &'a str,
+        key_attr: &'a str,
+        keys: Vec<AttributeValue>,
+        projection: &'a str,
+    ) -> BoxFuture<'a, Result<BatchGetOutput, Error>> {
+        Box::pin(async move {
+            let Some(prefix) = self.prefixes.get(table) else {
+                return self
+                    .inner
+                    .batch_get_items(table, key_attr, keys, projection)
+                    .await;
+            };
+
+            if self.source == PlatesSource::Fallback {
+                match self
+                    .inner
+                    .batch_get_items(table, key_attr, keys.clone(), projection)
+                    .await
+                {
+                    Ok(output) => return Ok(output),
+                    Err(e) => self.note_fallback(table, &e),
+                }
+            }
+
+            Ok(BatchGetOutput {
+                items: self.load_all(prefix, &keys).await?,
+                unprocessed: Vec::new(),
+            })
+        })
+    }
+
+    fn query_items<'a>(
+        &'a self,
+        table: &'a str,
+        partition_attr: &'a str,
+        partition_value: AttributeValue,
+    ) -> BoxFuture<'a, Result<Vec<Item>, Error>> {
+        if self.source == PlatesSource::Snapshot && self.prefixes.contains_key(table) {
+            return Box::pin(async move { Err(unsupported(table)) });
+        }
+
+        self.inner
+            .query_items(table, partition_attr, partition_value)
+    }
+
+    fn query_index<'a>(
+        &'a self,
+        table: &'a str,
+        index: &'a str,
+        partition_attr: &'a str,
+        partition_value: AttributeValue,
+        projection: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<Item>, Error>> {
+        if self.source == PlatesSource::Snapshot && self.prefixes.contains_key(table) {
+            return Box::pin(async move { Err(unsupported(table)) });
+        }
+
+        self.inner
+            .query_index(table, index, partition_attr, partition_value, projection)
+    }
+
+    fn put_item<'a>(
+        &'a self,
+        table: &'a str,
+        partition_attr: &'a str,
+        item: Item,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.inner.put_item(table, partition_attr, item)
+    }
+}
+
+fn unsupported(table: &str) -> Error {
+    format!("`{table}` is being served from a snapshot, which can't be queried; try again later")
+        .into()
+}
@@ -0,0 +1,231 @@
+//! IVOA SODA-style cutout shapes.
+//!
+//! Virtual Observatory tools, like pyvo and TOPCAT, describe cutouts with the
+//! shapes of the Server-side Operations for Data Access (SODA) standard rather
+//! than a center and a size in pixels. So a cutout request can give a `circle`,
+//! as `"RA DEC RADIUS"`, or a `polygon`, as `"RA1 DEC1 RA2 DEC2 ..."`, in ICRS
+//! degrees, as the text of the SODA parameters of the same names. Upper-case
+//! query parameters, as SODA clients send them, are accepted too; see
+//! `querystring.rs`.
+//!
+//! We can only make rectangular cutouts, so a shape is converted into the
+//! smallest stamp, centered on the shape, whose TAN projection contains it: a
+//! circle into a square that contains it, and a polygon into the bounding box
+//! of its vertices. The conversion happens when the request is normalized,
+//! once the output pixel scale is known, and fills in the request's center
+//! and size, so that the request echoed in the response shows the stamp that
+//! was made.
+
+use lambda_http::Error;
+use std::ops::Bound::{Excluded, Included};
+
+use crate::{coords::normalize_ra, gscbin::D2R, validation};
+
+/// The largest circle radius, in degrees. In practice, the limits on the
+/// output image size are reached well before this.
+pub const MAX_RADIUS_DEG: f64 = 10.;
+
+/// The most vertices that a polygon can have.
+pub const MAX_POLYGON_VERTICES: usize = 100;
+
+/// A cutout shape.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Shape {
+    Circle {
+        ra_deg: f64,
+        dec_deg: f64,
+        radius_deg: f64,
+    },
+
+    /// The vertices of a polygon, as RA and declination pairs.
+    Polygon(Vec<(f64, f64)>),
+}
+
+/// The region of the sky that contains a shape: a center, and the half-width
+/// and half-height of a box around it on the tangent plane, in degrees.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    pub center_ra_deg: f64,
+    pub center_dec_deg: f64,
+    pub half_width_deg: f64,
+    pub half_height_deg: f64,
+}
+
+/// Split the text of a SODA parameter into numbers.
+fn numbers(field: &str, text: &str) -> Result<Vec<f64>, Error> {
+    text.split_whitespace()
+        .map(|word| {
+            word.parse::<f64>().map_err(|_| -> Error {
+                format!("illegal `{field}` parameter: `{word}` is not a number").into()
+            })
+        })
+        .collect()
+}
+
+impl Shape {
+    /// Parse the text of a SODA `CIRCLE` parameter: the RA and declination of
+    /// the center, and the radius, all in degrees.
+    pub fn circle(field: &str, text: &str) -> Result<Self, Error> {
+        let [ra, dec, radius] = numbers(field, text)?[..] else {
+            return Err(format!(
+                "illegal `{field}` parameter: must be three numbers, `RA DEC RADIUS`; got `{text}`"
+            )
+            .into());
+        };
+
+        let radius_limits = (Excluded(0.), Included(MAX_RADIUS_DEG));
+
+        Ok(Shape::Circle {
+            ra_deg: validation::ra(field, ra)?,
+            dec_deg: validation::dec(field, dec)?,
+            radius_deg: validation::range(field, radius, radius_limits)?,
+        })
+    }
+
+    /// Parse the text of a SODA `POLYGON` parameter: the RAs and declinations
+    /// of at least three vertices, in degrees.
+    pub fn polygon(field: &str, text: &str) -> Result<Self, Error> {
+        let values = numbers(field, text)?;
+        let n_vertices = values.len() / 2;
+
+        if values.len() % 2 != 0 || !(3..=MAX_POLYGON_VERTICES).contains(&n_vertices) {
+            return Err(format!(
+                "illegal `{field}` parameter: must be between 3 and {MAX_POLYGON_VERTICES} pairs of numbers, `RA1 DEC1 RA2 DEC2 ...`; got `{text}`"
+            )
+            .into());
+        }
+
+        let vertices = values
+            .chunks(2)
+            .map(|pair| {
+                Ok((
+                    validation::ra(field, pair[0])?,
+                    validation::dec(field, pair[1])?,
+                ))
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Shape::Polygon(vertices))
+    }
+
+    /// Compute the region containing the shape.
+    pub fn region(&self, field: &str) -> Result<Region, Error> {
+        match self {
+            Shape::Circle {
+                ra_deg,
+                dec_deg,
+                radius_deg,
+            } => {
+                let half_size = (D2R * radius_deg).tan() / D2R;
+
+                Ok(Region {
+                    center_ra_deg: *ra_deg,
+                    center_dec_deg: *dec_deg,
+                    half_width_deg: half_size,
+                    half_height_deg: half_size,
+                })
+            }
+
+            Shape::Polygon(vertices) => {
+                let too_big = || -> Error {
+                    format!("illegal `{field}` parameter: the polygon must fit within a hemisphere")
+                        .into()
+                };
+
+                // Project the vertices around their mean direction, then
+                // recenter on the middle of their bounding box, so that the
+                // box is as small as it can be.
+                let mut sum = [0.; 3];
+
+                for (ra, dec) in vertices {
+                    let v = unit_vector(*ra, *dec);
+                    sum.iter_mut().zip(v).for_each(|(s, c)| *s += c);
+                }
+
+                let (ra0, dec0) = direction(sum).ok_or_else(too_big)?;
+                let (min, max) = bounding_box(ra0, dec0, vertices).ok_or_else(too_big)?;
+                let (ra0, dec0) =
+                    deproject(ra0, dec0, 0.5 * (min.0 + max.0), 0.5 * (min.1 + max.1));
+                let (min, max) = bounding_box(ra0, dec0, vertices).ok_or_else(too_big)?;
+
+                Ok(Region {
+                    center_ra_deg: ra0,
+                    center_dec_deg: dec0,
+                    half_width_deg: f64::max(-min.0, max.0),
+                    half_height_deg: f64::max(-min.1, max.1),
+                })
+            }
+        }
+    }
+}
+
+fn unit_vector(ra_deg: f64, dec_deg: f64) -> [f64; 3] {
+    let (ra, dec) = (D2R * ra_deg, D2R * dec_deg);
+    [dec.cos() * ra.cos(), dec.cos() * ra.sin(), dec.sin()]
+}
+
+/// The RA and declination of a vector, unless it's too short to have a
+/// meaningful direction.
+fn direction(v: [f64; 3]) -> Option<(f64, f64)> {
+    let norm = v.iter().map(|c| c * c).sum::<f64>().sqrt();
+
+    if norm < 1e-6 {
+        return None;
+    }
+
+    let ra = normalize_ra(f64::atan2(v[1], v[0]) / D2R);
+    let dec = (v[2] / norm).clamp(-1., 1.).asin() / D2R;
+    Some((ra, dec))
+}
+
+/// Project a position onto the plane tangent at `(ra0, dec0)`, returning the
+/// standard coordinates in degrees, with east and north positive, unless
+/// it's too far from the tangent point to project.
+fn project(ra0: f64, dec0: f64, ra: f64, dec: f64) -> Option<(f64, f64)> {
+    let (dec0, dec, dra) = (D2R * dec0, D2R * dec, D2R * (ra - ra0));
+    let cos_c = dec0.sin() * dec.sin() + dec0.cos() * dec.cos() * dra.cos();
+
+    if cos_c < 1e-3 {
+        return None;
+    }
+
+    let xi = dec.cos() * dra.sin() / cos_c;
+    let eta = (dec0.cos() * dec.sin() - dec0.sin() * dec.cos() * dra.cos()) / cos_c;
+    Some((xi / D2R, eta / D2R))
+}
+
+/// Invert [`project`].
+fn deproject(ra0: f64, dec0: f64, xi: f64, eta: f64) -> (f64, f64) {
+    let (xi, eta, dec0_rad) = (D2R * xi, D2R * eta, D2R * dec0);
+    let rho = xi.hypot(eta);
+
+    if rho == 0. {
+        return (ra0, dec0);
+    }
+
+    let c = rho.atan();
+    let dec = (c.cos() * dec0_rad.sin() + eta * c.sin() * dec0_rad.cos() / rho)
+        .clamp(-1., 1.)
+        .asin();
+    let dra = f64::atan2(
+        xi * c.sin(),
+        rho * dec0_rad.cos() * c.cos() - eta * dec0_rad.sin() * c.sin(),
+    );
+
+    (normalize_ra(ra0 + dra / D2R), dec / D2R)
+}
+
+/// The corners of the bounding box of the vertices projected around
+/// `(ra0, dec0)`.
+fn bounding_box(ra0: f64, dec0: f64, vertices: &[(f64, f64)]) -> Option<((f64, f64), (f64, f64))> {
+    let mut min = (f64::INFINITY, f64::INFINITY);
+    let mut max = (f64::NEG_INFINITY, f64::NEG_INFINITY);
+
+    for (ra, dec) in vertices {
+        let (xi, eta) = project(ra0, dec0, *ra, *dec)?;
+        min = (min.0.min(xi), min.1.min(eta));
+        max = (max.0.max(xi), max.1.max(eta));
+    }
+
+    Some((min, max))
+}
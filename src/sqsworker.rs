@@ -0,0 +1,222 @@
+//! Batch processing of requests from an SQS queue.
+//!
+//! For high-throughput jobs, it's cheaper to drop requests into an SQS queue
+//! and have a Lambda function work through them than to push them all through
+//! API Gateway. Each message body is one request, as in the manifests of the
+//! bulk CLI:
+//!
+//! ```json
+//! {"service": "cutout", "output": "m31/a10000", "request": {"plate_id": "a10000", ...}}
+//! ```
+//!
+//! The response envelope, or the error body if the request fails, is written
+//! as JSON to `Config::batch_results_prefix` + `output` + `.json` in the
+//! results bucket. The `output` defaults to the SQS message ID; giving one
+//! makes retries of the same message overwrite the same object.
+//!
+//! So that downstream workflows can react to finished jobs without polling,
+//! each completion or failure is then announced: as a message on the SNS topic
+//! `Config::batch_topic_arn`, and as an event on the EventBridge bus
+//! `Config::batch_event_bus`, if they're set. The announcement is a
+//! [`Completion`], identifying the job by its message ID and `output`, giving
+//! the S3 location of the result and a download URL, and reporting some
+//! statistics. EventBridge events come from the source `dasch.science`, with
+//! the detail type `DASCH Batch Job Completed` or `DASCH Batch Job Failed`.
+//! Since messages can be delivered more than once, so can announcements.
+//!
+//! Requests that fail are done, as far as the queue is concerned: their errors
+//! are saved and announced like any other result. But if the server is too
+//! busy to take a request, or its result can't be saved or announced, the
+//! message is reported as a batch item failure, so that SQS delivers it again
+//! later. The event source mapping must have `ReportBatchItemFailures`
+//! enabled for that to work; otherwise SQS retries the whole batch. Admin-only
+//! services can't be invoked this way.
+
+use aws_sdk_eventbridge::types::PutEventsRequestEntry;
+use lambda_http::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+use crate::{backend::ObjectStore, config::Config, staging::Stager};
+
+/// One request from the queue.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Job {
+    /// The service to invoke.
+    pub service: String,
+
+    /// The request payload.
+    pub request: Value,
+
+    /// The name of the result object, if not the message ID.
+    #[serde(default)]
+    pub output: Option<String>,
+}
+
+/// A message from the queue: its ID and body.
+pub struct Message {
+    pub id: String,
+    pub body: String,
+}
+
+/// Get the messages of an SQS event.
+pub fn messages(event: &Value) -> Result<Vec<Message>, Error> {
+    let records = event["Records"]
+        .as_array()
+        .ok_or_else(|| -> Error { "not an SQS event: no `Records`".into() })?;
+
+    records
+        .iter()
+        .map(|r| match (r["messageId"].as_str(), r["body"].as_str()) {
+            (Some(id), Some(body)) => Ok(Message {
+                id: id.to_owned(),
+                body: body.to_owned(),
+            }),
+            _ => Err("not an SQS event: record without a `messageId` and `body`".into()),
+        })
+        .collect()
+}
+
+/// The EventBridge source of our announcements.
+const EVENT_SOURCE: &str = "dasch.science";
+
+/// The announcement of a completed request.
+#[derive(Serialize)]
+pub struct Completion<'a> {
+    /// The SQS message ID of the request.
+    pub message_id: &'a str,
+
+    /// The name of the result object.
+    pub output: &'a str,
+
+    /// The service invoked, if the message was valid.
+    pub service: Option<&'a str>,
+
+    /// Whether the request succeeded.
+    pub succeeded: bool,
+
+    /// If the request failed, why.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+
+    /// The bucket of the result object.
+    pub bucket: &'a str,
+
+    /// The key of the result object.
+    pub key: &'a str,
+
+    /// A URL from which the result can be downloaded, without credentials,
+    /// for a limited time.
+    pub url: String,
+
+    /// Statistics of the request.
+    pub stats: Stats,
+}
+
+/// Statistics of a completed request.
+#[derive(Serialize)]
+pub struct Stats {
+    /// How long the request took to run, in milliseconds.
+    pub duration_ms: u128,
+
+    /// The DynamoDB read capacity units that the request consumed, if it
+    /// succeeded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_capacity_units: Option<f64>,
+
+    /// For tabular results, whether and why the result is incomplete, as in
+    /// the envelope.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncation: Option<Value>,
+}
+
+impl Stats {
+    /// Gather the statistics of a request from its outcome.
+    pub fn new(result: &Result<Value, Error>, duration: Duration) -> Self {
+        let envelope = result.as_ref().ok();
+
+        Stats {
+            duration_ms: duration.as_millis(),
+            read_capacity_units: envelope.and_then(|e| e["read_capacity_units"].as_f64()),
+            truncation: envelope
+                .and_then(|e| e.get("truncation"))
+                .filter(|t| !t.is_null())
+                .cloned(),
+        }
+    }
+}
+
+/// Save the result of a request, returning its key and download URL.
+pub async fn save(
+    objects: &dyn ObjectStore,
+    config: &Config,
+    output: &str,
+    result: &Value,
+) -> Result<(String, String), Error> {
+    let key = format!("{}{}.json", config.batch_results_prefix, output);
+    let mut stager = Stager::start_at(objects, config, key.clone(), "application/json").await?;
+    stager.push_bytes(&serde_json::to_vec(result)?).await?;
+    let staged = stager.finish().await?;
+    Ok((key, staged.url))
+}
+
+/// Publish the announcement of a completed request on SNS.
+pub async fn notify_sns(
+    client: &aws_sdk_sns::Client,
+    topic_arn: &str,
+    completion: &Completion<'_>,
+) -> Result<(), Error> {
+    client
+        .publish()
+        .topic_arn(topic_arn)
+        .message(serde_json::to_string(completion)?)
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Publish the announcement of a completed request on EventBridge.
+pub async fn notify_eventbridge(
+    client: &aws_sdk_eventbridge::Client,
+    bus: &str,
+    completion: &Completion<'_>,
+) -> Result<(), Error> {
+    let detail_type = if completion.succeeded {
+        "DASCH Batch Job Completed"
+    } else {
+        "DASCH Batch Job Failed"
+    };
+
+    let entry = PutEventsRequestEntry::builder()
+        .event_bus_name(bus)
+        .source(EVENT_SOURCE)
+        .detail_type(detail_type)
+        .detail(serde_json::to_string(completion)?)
+        .build();
+
+    let output = client.put_events().entries(entry).send().await?;
+
+    // PutEvents reports failures per entry, rather than as an error.
+    if output.failed_entry_count() > 0 {
+        let message = output
+            .entries()
+            .iter()
+            .find_map(|e| e.error_message())
+            .unwrap_or("unknown error");
+        return Err(format!("failed to publish the completion event: {message}").into());
+    }
+
+    Ok(())
+}
+
+/// The response to an SQS event, listing the messages to be delivered again.
+pub fn batch_response(failed_ids: Vec<String>) -> Value {
+    let failures: Vec<_> = failed_ids
+        .into_iter()
+        .map(|id| json!({ "itemIdentifier": id }))
+        .collect();
+
+    json!({ "batchItemFailures": failures })
+}
@@ -0,0 +1,190 @@
+//! Staging large query results to S3.
+//!
+//! Lambda responses are limited to 6 MB, and whole-series queries can produce
+//! far more than that. Clients can instead ask for results to be staged: we
+//! write the CSV rows to an S3 object as they are produced, using a multipart
+//! upload, and return a time-limited URL from which the object can be
+//! downloaded. Only one upload part is buffered at a time, so memory use
+//! stays flat no matter how big the result is.
+//!
+//! Staged objects go under `Config::results_prefix` in the results bucket. We
+//! don't clean them up ourselves: the bucket should have a lifecycle rule that
+//! expires them, and that aborts incomplete multipart uploads left behind by
+//! failed requests.
+
+use lambda_http::Error;
+use serde::Serialize;
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{backend::ObjectStore, config::Config};
+
+/// The size at which we upload a part of a staged object. S3 requires all
+/// parts but the last to be at least 5 MiB.
+const PART_BYTES: usize = 8 * 1024 * 1024;
+
+/// The response information about a staged result.
+#[derive(Serialize)]
+pub struct Staged {
+    /// A URL from which the result can be downloaded, without credentials,
+    /// for a limited time.
+    pub url: String,
+
+    /// The number of data rows in the result, not counting the header.
+    pub n_rows: u64,
+}
+
+/// An in-progress upload of a staged result.
+pub struct Stager<'a> {
+    objects: &'a dyn ObjectStore,
+    bucket: String,
+    key: String,
+    upload_id: String,
+    url_ttl: Duration,
+    buf: Vec<u8>,
+    parts: Vec<(i32, String)>,
+    n_rows: u64,
+}
+
+impl<'a> Stager<'a> {
+    /// Start staging a CSV result for the named service, beginning with the
+    /// given header row.
+    pub async fn start(
+        objects: &'a dyn ObjectStore,
+        config: &Config,
+        service: &str,
+        header: &str,
+    ) -> Result<Stager<'a>, Error> {
+        let mut stager = Self::start_file(objects, config, service, "csv", "text/csv").await?;
+        stager.buf.extend_from_slice(header.as_bytes());
+        stager.buf.push(b'\n');
+        Ok(stager)
+    }
+
+    /// Start staging a non-CSV result for the named service, with the given
+    /// filename extension and content type. Its data are added with
+    /// [`Self::push_bytes`].
+    pub async fn start_file(
+        objects: &'a dyn ObjectStore,
+        config: &Config,
+        service: &str,
+        extension: &str,
+        content_type: &str,
+    ) -> Result<Stager<'a>, Error> {
+        let key = format!(
+            "{}{}/{}.{}",
+            config.results_prefix,
+            service,
+            new_name(),
+            extension
+        );
+        Self::start_at(objects, config, key, content_type).await
+    }
+
+    /// Start staging a result to the given key in the results bucket, rather
+    /// than a fresh one under the results prefix. Its data are added with
+    /// [`Self::push_bytes`].
+    pub async fn start_at(
+        objects: &'a dyn ObjectStore,
+        config: &Config,
+        key: String,
+        content_type: &str,
+    ) -> Result<Stager<'a>, Error> {
+        let bucket = config.results_bucket().to_owned();
+        let upload_id = objects.create_upload(&bucket, &key, content_type).await?;
+
+        Ok(Stager {
+            objects,
+            bucket,
+            key,
+            upload_id,
+            url_ttl: config.results_url_ttl,
+            buf: Vec::with_capacity(PART_BYTES),
+            parts: Vec::new(),
+            n_rows: 0,
+        })
+    }
+
+    /// Add data rows to the result, uploading a part if we've buffered
+    /// enough.
+    pub async fn push(&mut self, rows: Vec<String>) -> Result<(), Error> {
+        for row in rows {
+            self.buf.extend_from_slice(row.as_bytes());
+            self.buf.push(b'\n');
+            self.n_rows += 1;
+        }
+
+        if self.buf.len() >= PART_BYTES {
+            self.upload_part().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Add raw data to a result started with [`Self::start_file`], uploading
+    /// a part if we've buffered enough.
+    pub async fn push_bytes(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.buf.extend_from_slice(data);
+
+        if self.buf.len() >= PART_BYTES {
+            self.upload_part().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn upload_part(&mut self) -> Result<(), Error> {
+        let n = self.parts.len() as i32 + 1;
+        let data = std::mem::replace(&mut self.buf, Vec::with_capacity(PART_BYTES));
+        let etag = self
+            .objects
+            .upload_part(&self.bucket, &self.key, &self.upload_id, n, data)
+            .await?;
+        self.parts.push((n, etag));
+        Ok(())
+    }
+
+    /// Finish the upload and get the download URL.
+    pub async fn finish(mut self) -> Result<Staged, Error> {
+        // The last part may be short. It's only empty if the result ended
+        // right at a part boundary, in which case we skip it.
+        if !self.buf.is_empty() || self.parts.is_empty() {
+            self.upload_part().await?;
+        }
+
+        self.objects
+            .complete_upload(&self.bucket, &self.key, &self.upload_id, self.parts)
+            .await?;
+
+        let url = self
+            .objects
+            .download_url(&self.bucket, &self.key, self.url_ttl)
+            .await?;
+
+        Ok(Staged {
+            url,
+            n_rows: self.n_rows,
+        })
+    }
+}
+
+/// Generate a unique name for a staged object. The timestamp prefix makes
+/// the objects easy to sort when debugging.
+pub fn new_name() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut name = format!("{}-", now.as_secs());
+
+    for i in 0..2 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(now.as_nanos());
+        hasher.write_u32(i);
+        name.push_str(&format!("{:016x}", hasher.finish()));
+    }
+
+    name
+}
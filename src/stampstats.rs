@@ -0,0 +1,126 @@
+//! Statistics of cutout stamps, and display hints.
+//!
+//! Viewers need to know the range of a stamp's pixel values before they can
+//! show it, which means a pass over the pixels after decoding it. When a
+//! cutout request sets `statistics`, we do that pass for them while the pixels
+//! are at hand, and return the results alongside the image, with a suggested
+//! asinh stretch.
+//!
+//! The statistics only cover the non-null pixels, and are in the stamp's
+//! units (ADU). Since the resampled pixels are 16-bit integers, we compute
+//! exact percentiles from a histogram, using the nearest-rank definition.
+//!
+//! The suggested stretch maps a pixel value `x` to a display level between 0
+//! and 1 as
+//!
+//! ```text
+//! asinh((x - black) / softening) / asinh((white - black) / softening)
+//! ```
+//!
+//! clipped to that range. `black` is the 1st percentile and `white` the 99.5th.
+//! The `softening` is a robust estimate of the background noise, the
+//! difference between the median and the 16th percentile, which makes the
+//! stretch about linear within the noise and logarithmic in bright objects.
+
+use serde::Serialize;
+
+/// Summary statistics of a stamp's non-null pixels.
+#[derive(Clone, Debug, Serialize)]
+pub struct ImageStatistics {
+    /// The number of non-null pixels.
+    pub n_pixels: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub median: f64,
+    pub p1: f64,
+    pub p5: f64,
+    pub p16: f64,
+    pub p25: f64,
+    pub p75: f64,
+    pub p84: f64,
+    pub p95: f64,
+    pub p99: f64,
+    pub p99_5: f64,
+
+    /// A suggested display stretch.
+    pub stretch: AsinhStretch,
+}
+
+/// The parameters of an asinh display stretch; see the module documentation.
+#[derive(Clone, Debug, Serialize)]
+pub struct AsinhStretch {
+    pub black: f64,
+    pub white: f64,
+    pub softening: f64,
+}
+
+impl AsinhStretch {
+    /// The display level of a pixel value, between 0 and 1.
+    pub fn level(&self, x: f64) -> f64 {
+        let scale = f64::asinh((self.white - self.black) / self.softening);
+        (f64::asinh((x - self.black) / self.softening) / scale).clamp(0., 1.)
+    }
+}
+
+/// Compute the statistics of some pixel values, or `None` if there aren't
+/// any.
+pub fn compute(pixels: impl Iterator<Item = i16>) -> Option<ImageStatistics> {
+    let mut histogram = vec![0usize; 1 << 16];
+    let mut n = 0;
+    let mut sum = 0.;
+
+    for p in pixels {
+        histogram[(p as i32 - i16::MIN as i32) as usize] += 1;
+        n += 1;
+        sum += p as f64;
+    }
+
+    if n == 0 {
+        return None;
+    }
+
+    // The value with the given (one-based) rank.
+    let at_rank = |rank: usize| {
+        let mut seen = 0;
+
+        for (i, count) in histogram.iter().enumerate() {
+            seen += count;
+
+            if seen >= rank {
+                return (i as i32 + i16::MIN as i32) as f64;
+            }
+        }
+
+        unreachable!()
+    };
+
+    let percentile = |p: f64| at_rank(((p / 100. * n as f64).ceil() as usize).clamp(1, n));
+
+    let median = percentile(50.);
+    let p1 = percentile(1.);
+    let p16 = percentile(16.);
+    let p99_5 = percentile(99.5);
+
+    Some(ImageStatistics {
+        n_pixels: n,
+        min: at_rank(1),
+        max: at_rank(n),
+        mean: sum / n as f64,
+        median,
+        p1,
+        p5: percentile(5.),
+        p16,
+        p25: percentile(25.),
+        p75: percentile(75.),
+        p84: percentile(84.),
+        p95: percentile(95.),
+        p99: percentile(99.),
+        p99_5,
+        stretch: AsinhStretch {
+            black: p1,
+            white: p99_5.max(p1 + 1.),
+            softening: (median - p16).max(1.),
+        },
+    })
+}
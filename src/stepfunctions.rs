@@ -0,0 +1,108 @@
+//! Orchestration by AWS Step Functions.
+//!
+//! State machines can invoke the services directly, as Lambda tasks, to
+//! orchestrate bulk product generation. The simplest task passes the request
+//! itself as the payload, and gets the response envelope back as its output.
+//! But Step Functions payloads are usually built from parts of the state, so
+//! the request can also be nested in an `Input` field:
+//!
+//! ```json
+//! { "Input.$": "$.request" }
+//! ```
+//!
+//! For tasks that use the `.waitForTaskToken` integration pattern, the payload
+//! must also carry the task token, either alongside the nested input or
+//! alongside the fields of the request itself:
+//!
+//! ```json
+//! { "Input.$": "$.request", "TaskToken.$": "$$.Task.Token" }
+//! ```
+//!
+//! In that case, once the request is done, we report the outcome with
+//! `SendTaskSuccess`, whose output is the response envelope, or with
+//! `SendTaskFailure`. Failures are named `DASCH.Busy` when the server was too
+//! busy to take the request, which is worth retrying after a pause,
+//! `DASCH.OutputTooLarge` when the envelope exceeds the Step Functions limit on
+//! task outputs (large query results can be staged to S3 instead), and
+//! `DASCH.Error` otherwise.
+//!
+//! As always, long queries may stop early and return a continuation token; see
+//! `deadline.rs`. A state machine can loop on it until the query is done.
+
+use lambda_http::Error;
+use serde_json::Value;
+
+use crate::admission::BusyError;
+
+/// The maximum size of a task output, in bytes, imposed by Step Functions.
+const MAX_OUTPUT_BYTES: usize = 262_144;
+
+/// The maximum length of a failure cause, in characters.
+const MAX_CAUSE_CHARS: usize = 32_768;
+
+/// Unpack a Step Functions task payload into the request and the task token,
+/// if any. Ordinary payloads are returned as-is, without a token.
+pub fn unpack(payload: Option<Value>) -> Result<(Option<Value>, Option<String>), Error> {
+    let Some(Value::Object(mut fields)) = payload else {
+        return Ok((payload, None));
+    };
+
+    let token = match fields.remove("TaskToken") {
+        None => None,
+        Some(Value::String(t)) => Some(t),
+        Some(_) => return Err("illegal `TaskToken` parameter: must be a string".into()),
+    };
+
+    let Some(input) = fields.remove("Input") else {
+        return Ok((Some(Value::Object(fields)), token));
+    };
+
+    if let Some(key) = fields.keys().next() {
+        return Err(format!("unexpected parameter `{key}` alongside `Input`").into());
+    }
+
+    Ok((Some(input), token))
+}
+
+/// Report the outcome of a request to the task that is waiting for it.
+pub async fn report(
+    client: &aws_sdk_sfn::Client,
+    token: &str,
+    result: &Result<Value, Error>,
+) -> Result<(), Error> {
+    let (error, cause) = match result {
+        Ok(envelope) => {
+            let output = serde_json::to_string(envelope)?;
+
+            if output.len() <= MAX_OUTPUT_BYTES {
+                client
+                    .send_task_success()
+                    .task_token(token)
+                    .output(output)
+                    .send()
+                    .await?;
+                return Ok(());
+            }
+
+            (
+                "DASCH.OutputTooLarge",
+                format!(
+                    "the response is {} bytes, more than Step Functions allows ({MAX_OUTPUT_BYTES})",
+                    output.len()
+                ),
+            )
+        }
+
+        Err(e) if e.is::<BusyError>() => ("DASCH.Busy", e.to_string()),
+        Err(e) => ("DASCH.Error", e.to_string()),
+    };
+
+    client
+        .send_task_failure()
+        .task_token(token)
+        .error(error)
+        .cause(cause.chars().take(MAX_CAUSE_CHARS).collect::<String>())
+        .send()
+        .await?;
+    Ok(())
+}
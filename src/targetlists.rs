@@ -0,0 +1,239 @@
+//! Uploaded target lists.
+//!
+//! Batch operations take lists of targets that can easily exceed the 6 MB
+//! limit on Lambda request payloads. So, rather than passing them inline,
+//! clients upload them to S3. The `targetlist` service works in two steps:
+//!
+//! 1. A request without an `upload_id` reserves a new ID and returns a
+//!    presigned URL to which the client can `PUT` its list, without AWS
+//!    credentials, for `Config::upload_url_ttl`.
+//! 2. A request with the `upload_id` registers the upload: it checks that the
+//!    list is there and valid, and returns the number of targets in it, along
+//!    with the first few.
+//!
+//! After that, batch `cutout` requests can give the `upload_id` as their
+//! `target_list`, in place of an inline list of `centers`; they read the
+//! targets with [`load`].
+//!
+//! A target list is a CSV file with a header row naming its columns. It must
+//! have `ra_deg` and `dec_deg` columns, in degrees, and may have a `name`
+//! column. Other columns are ignored. The coordinate columns may also be
+//! spelled `raDeg` and `decDeg`, so that the rows of `querycat` output, with
+//! the default formatting, can be uploaded as-is. Rows with both coordinates
+//! empty, like those of catalog sources with unknown positions, are skipped.
+//! A list may have at most [`MAX_TARGETS`] targets and [`MAX_BYTES`] bytes; we
+//! only read the first `MAX_BYTES + 1` bytes of an upload, so that an
+//! oversized one can't exhaust our memory.
+//!
+//! The lists go under `Config::target_lists_prefix` in the results bucket.
+//! As with staged results, we don't clean them up ourselves; the bucket should
+//! have a lifecycle rule that expires them.
+
+use lambda_http::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{backend::ObjectStore, config::Config, envelope, staging, validation};
+
+/// The content type of uploaded target lists. Presigned uploads must use it.
+pub const CONTENT_TYPE: &str = "text/csv";
+
+/// The maximum number of targets in a list.
+pub const MAX_TARGETS: usize = 100_000;
+
+/// The maximum size of a list, in bytes. Lists are read into memory whole, so
+/// we don't read more than this, whatever was uploaded.
+pub const MAX_BYTES: usize = 32 << 20;
+
+/// The number of targets echoed back when registering a list.
+const N_PREVIEW: usize = 5;
+
+/// Sync with `json-schemas/targetlist_request.json`.
+#[derive(Deserialize, Serialize)]
+pub struct Request {
+    /// The upload to register, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    upload_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Response {
+    /// The ID of the upload, to be passed to batch services.
+    pub upload_id: String,
+
+    /// When reserving an upload, the URL to which the list should be `PUT`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload_url: Option<String>,
+
+    /// When reserving an upload, the content type that it must have.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<&'static str>,
+
+    /// When registering an upload, the number of targets in the list.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n_targets: Option<usize>,
+
+    /// When registering an upload, the first few targets, so that clients
+    /// can check that the list was read as they intended.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub preview: Vec<Target>,
+}
+
+/// One target from a list.
+#[derive(Debug, Serialize)]
+pub struct Target {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub ra_deg: f64,
+    pub dec_deg: f64,
+}
+
+pub async fn handler(
+    req: Option<Value>,
+    config: &Config,
+    objects: &dyn ObjectStore,
+) -> Result<Value, Error> {
+    let request: Request =
+        serde_json::from_value(req.ok_or_else(|| -> Error { "no request payload".into() })?)?;
+    let echo = serde_json::to_value(&request)?;
+    let result = implementation(request, config, objects).await?;
+    envelope::wrap("targetlist", &echo, result)
+}
+
+pub async fn implementation(
+    request: Request,
+    config: &Config,
+    objects: &dyn ObjectStore,
+) -> Result<Response, Error> {
+    match request.upload_id {
+        None => {
+            let upload_id = staging::new_name();
+            let upload_url = objects
+                .upload_url(
+                    config.results_bucket(),
+                    &key(config, &upload_id)?,
+                    CONTENT_TYPE,
+                    config.upload_url_ttl,
+                )
+                .await?;
+
+            Ok(Response {
+                upload_id,
+                upload_url: Some(upload_url),
+                content_type: Some(CONTENT_TYPE),
+                n_targets: None,
+                preview: Vec::new(),
+            })
+        }
+
+        Some(upload_id) => {
+            let mut targets = load(objects, config, &upload_id).await?;
+            let n_targets = targets.len();
+            targets.truncate(N_PREVIEW);
+
+            Ok(Response {
+                upload_id,
+                upload_url: None,
+                content_type: None,
+                n_targets: Some(n_targets),
+                preview: targets,
+            })
+        }
+    }
+}
+
+/// The S3 key of an uploaded target list. Upload IDs come from clients, so we
+/// make sure that they're of the form that we issue before using them.
+fn key(config: &Config, upload_id: &str) -> Result<String, Error> {
+    if upload_id.is_empty()
+        || !upload_id
+            .bytes()
+            .all(|b| b.is_ascii_hexdigit() || b == b'-')
+    {
+        return Err(format!("invalid upload_id `{upload_id}`").into());
+    }
+
+    Ok(format!("{}{}.csv", config.target_lists_prefix, upload_id))
+}
+
+/// Read and validate an uploaded target list.
+pub async fn load(
+    objects: &dyn ObjectStore,
+    config: &Config,
+    upload_id: &str,
+) -> Result<Vec<Target>, Error> {
+    let data = objects
+        .get_object_start(
+            config.results_bucket(),
+            &key(config, upload_id)?,
+            MAX_BYTES + 1,
+        )
+        .await?
+        .ok_or_else(|| -> Error {
+            format!("nothing has been uploaded for upload_id `{upload_id}`").into()
+        })?;
+
+    if data.len() > MAX_BYTES {
+        return Err(format!("target list is bigger than {MAX_BYTES} bytes").into());
+    }
+
+    let text =
+        String::from_utf8(data).map_err(|_| -> Error { "target list is not UTF-8".into() })?;
+    parse(&text)
+}
+
+fn parse(text: &str) -> Result<Vec<Target>, Error> {
+    let mut lines = text.lines().filter(|l| !l.trim().is_empty());
+
+    let header: Vec<&str> = lines
+        .next()
+        .ok_or_else(|| -> Error { "target list is empty".into() })?
+        .split(',')
+        .map(str::trim)
+        .collect();
+
+    // The second spelling of each coordinate is that of `querycat` output.
+    let column = |names: &[&str]| header.iter().position(|h| names.contains(h));
+    let missing = |name: &str| -> Error { format!("target list has no `{name}` column").into() };
+    let i_ra = column(&["ra_deg", "raDeg"]).ok_or_else(|| missing("ra_deg"))?;
+    let i_dec = column(&["dec_deg", "decDeg"]).ok_or_else(|| missing("dec_deg"))?;
+    let i_name = column(&["name"]);
+
+    let mut targets = Vec::new();
+
+    for (n, line) in lines.enumerate() {
+        // Row numbers count from 1, after the header.
+        let row = n + 1;
+
+        if row > MAX_TARGETS {
+            return Err(format!("target list has more than {MAX_TARGETS} targets").into());
+        }
+
+        let cells: Vec<&str> = line.split(',').map(str::trim).collect();
+        let is_empty = |i: usize| cells.get(i).is_none_or(|c| c.is_empty());
+
+        if is_empty(i_ra) && is_empty(i_dec) {
+            continue;
+        }
+
+        let number = |i: usize, name: &str| -> Result<f64, Error> {
+            cells
+                .get(i)
+                .and_then(|c| c.parse().ok())
+                .ok_or_else(|| format!("target list row {row}: invalid `{name}` value").into())
+        };
+
+        let ra_deg = validation::ra("ra_deg", number(i_ra, "ra_deg")?)
+            .map_err(|e| -> Error { format!("target list row {row}: {e}").into() })?;
+        let dec_deg = validation::dec("dec_deg", number(i_dec, "dec_deg")?)
+            .map_err(|e| -> Error { format!("target list row {row}: {e}").into() })?;
+
+        targets.push(Target {
+            name: i_name.and_then(|i| cells.get(i)).map(|s| s.to_string()),
+            ra_deg,
+            dec_deg,
+        });
+    }
+
+    Ok(targets)
+}
@@ -0,0 +1,184 @@
+//! Resolution of named targets.
+//!
+//! Simple clients shouldn't need astropy just to turn "3C 273" into
+//! coordinates, so the services that take a sky position also accept a
+//! `target_name` field instead. Names are looked up in a bundled table of
+//! well-known objects, `data/bright-objects.csv`, ignoring case and spaces.
+//!
+//! Names that aren't in the table can be resolved with CDS's Sesame service,
+//! which asks SIMBAD, NED, and VizieR in turn. This makes every such request
+//! depend on an external service, so it's behind the `sesame_lookup` feature
+//! flag (see `features.rs`), and off by default. `Config::sesame_url` sets the
+//! Sesame endpoint; only plain HTTP is supported.
+//!
+//! Resolution happens before a request is parsed: the resolved position is
+//! filled into the request's position fields, so that the rest of the service
+//! doesn't need to know about it, and so that the request echoed in the
+//! response envelope shows the position that was used.
+
+use http_body_util::{BodyExt, Empty};
+use hyper::{body::Bytes, Uri};
+use hyper_util::rt::TokioIo;
+use lambda_http::Error;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use std::{collections::HashMap, time::Duration};
+
+use crate::{
+    config::Config,
+    features::{Feature, FeatureOverrides},
+};
+
+/// How long we wait for Sesame before giving up.
+const SESAME_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The bundled table of well-known objects, keyed by normalized name.
+static BRIGHT_OBJECTS: Lazy<HashMap<String, (f64, f64)>> = Lazy::new(|| {
+    let mut table = HashMap::new();
+
+    for line in include_str!("../data/bright-objects.csv")
+        .lines()
+        .filter(|l| !l.starts_with('#'))
+        .skip(1)
+    {
+        let mut fields = line.split(',');
+        let (Some(names), Some(ra), Some(dec)) = (fields.next(), fields.next(), fields.next())
+        else {
+            panic!("bad line in bundled object table: `{line}`");
+        };
+
+        // The table is checked into the repository, so it'd better be valid.
+        let pos: (f64, f64) = (
+            ra.parse().expect("bundled object RA should be a number"),
+            dec.parse().expect("bundled object dec should be a number"),
+        );
+
+        for name in names.split('|') {
+            table.insert(normalize_name(name), pos);
+        }
+    }
+
+    table
+});
+
+/// Normalize a target name for lookups in the bundled table.
+fn normalize_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| !c.is_whitespace())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// If a request payload has a `target_name` field, resolve it and fill the
+/// position into the fields named `ra_field` and `dec_field`, which the
+/// request must not set itself.
+pub async fn resolve_position(
+    req: &mut Value,
+    config: &Config,
+    ra_field: &str,
+    dec_field: &str,
+) -> Result<(), Error> {
+    let Some(fields) = req.as_object_mut() else {
+        return Ok(());
+    };
+
+    let name = match fields.get("target_name") {
+        None => return Ok(()),
+        Some(Value::String(s)) if !s.trim().is_empty() => s.trim().to_owned(),
+        Some(_) => return Err("illegal `target_name` parameter: must be a name".into()),
+    };
+
+    if fields.contains_key(ra_field) || fields.contains_key(dec_field) {
+        return Err(format!(
+            "must specify either `target_name`, or `{ra_field}` and `{dec_field}`, but not both"
+        )
+        .into());
+    }
+
+    let overrides: FeatureOverrides = match fields.get("features") {
+        Some(v) => serde_json::from_value(v.clone())?,
+        None => FeatureOverrides::new(),
+    };
+
+    let (ra, dec) = match BRIGHT_OBJECTS.get(&normalize_name(&name)) {
+        Some(pos) => *pos,
+
+        None if config
+            .features
+            .with_overrides(&overrides)
+            .enabled(Feature::SesameLookup) =>
+        {
+            sesame_lookup(config, &name).await?
+        }
+
+        None => {
+            return Err(format!(
+                "unknown `target_name` `{name}`; give its position instead, or enable the \
+                 `sesame_lookup` feature to resolve it with Sesame"
+            )
+            .into())
+        }
+    };
+
+    fields.insert(ra_field.to_owned(), ra.into());
+    fields.insert(dec_field.to_owned(), dec.into());
+    Ok(())
+}
+
+/// Resolve a name with Sesame, returning its RA and declination in degrees.
+async fn sesame_lookup(config: &Config, name: &str) -> Result<(f64, f64), Error> {
+    let uri: Uri = format!("{}?{}", config.sesame_url, percent_encode(name)).parse()?;
+
+    let text = tokio::time::timeout(SESAME_TIMEOUT, http_get(&uri))
+        .await
+        .map_err(|_| -> Error { format!("timed out resolving `{name}` with Sesame").into() })?
+        .map_err(|e| -> Error { format!("failed to resolve `{name}` with Sesame: {e}").into() })?;
+
+    // The plain-text output gives the J2000 position in degrees on a line
+    // like `%J 187.2779154 +02.0523883 = 12:29:06.69 +02:03:08.5`.
+    text.lines()
+        .find_map(|line| {
+            let mut words = line.strip_prefix("%J ")?.split_whitespace();
+            let ra = words.next()?.parse().ok()?;
+            let dec = words.next()?.parse().ok()?;
+            Some((ra, dec))
+        })
+        .ok_or_else(|| format!("Sesame could not resolve `target_name` `{name}`").into())
+}
+
+/// Make a plain HTTP GET request, returning the response body as text.
+async fn http_get(uri: &Uri) -> Result<String, Error> {
+    if uri.scheme_str() != Some("http") {
+        return Err(format!("only `http` Sesame URLs are supported, not `{uri}`").into());
+    }
+
+    let host = uri.host().ok_or("Sesame URL has no host")?;
+    let port = uri.port_u16().unwrap_or(80);
+    let stream = tokio::net::TcpStream::connect((host, port)).await?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(conn);
+
+    let req = hyper::Request::get(uri.path_and_query().map_or("/", |p| p.as_str()))
+        .header(hyper::header::HOST, host)
+        .body(Empty::<Bytes>::new())?;
+    let resp = sender.send_request(req).await?;
+
+    if !resp.status().is_success() {
+        return Err(format!("HTTP status {}", resp.status()).into());
+    }
+
+    let body = resp.into_body().collect().await?.to_bytes();
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Percent-encode a string for use in a URL query.
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
@@ -0,0 +1,151 @@
+//! Times of plate exposures.
+//!
+//! The database records exposure midpoints as UTC dates in ISO 8601 form.
+//! Clients want them as Julian Dates and Modified Julian Dates too, and used to
+//! derive those themselves, each in a slightly different way. So we do the
+//! conversions here, once, for all of the services.
+//!
+//! The dates have some quirks, accumulated over a century of plate logbooks
+//! and several generations of digitization. Some lack a time of day, some use
+//! a space instead of the `T` separator, and some carry an explicit `+00:00`
+//! offset instead of `Z`. All of these are accepted. Dates are in the
+//! proleptic Gregorian calendar, which is what the database uses throughout,
+//! even for the earliest plates.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A UTC time, as parsed from the database.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UtcTime {
+    year: i64,
+    month: i64,
+    day: i64,
+    seconds: f64,
+}
+
+/// The offset between JD and MJD.
+const MJD_OFFSET: f64 = 2400000.5;
+
+impl UtcTime {
+    /// Parse a date from the database, returning None if it can't be
+    /// understood.
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        let text = text
+            .strip_suffix('Z')
+            .or_else(|| text.strip_suffix("+00:00"))
+            .unwrap_or(text);
+        let (date, time) = text.split_once(['T', ' ']).unwrap_or((text, "00:00:00"));
+
+        let mut pieces = date.splitn(3, '-');
+        let year: i64 = pieces.next()?.parse().ok()?;
+        let month: i64 = pieces.next()?.parse().ok()?;
+        let day: i64 = pieces.next()?.parse().ok()?;
+
+        if !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day) {
+            return None;
+        }
+
+        let mut pieces = time.splitn(3, ':');
+        let hour: f64 = pieces.next()?.parse().ok()?;
+        let minute: f64 = pieces.next().unwrap_or("0").parse().ok()?;
+        let second: f64 = pieces.next().unwrap_or("0").parse().ok()?;
+
+        // Allow for leap seconds.
+        if !(0. ..24.).contains(&hour)
+            || !(0. ..60.).contains(&minute)
+            || !(0. ..61.).contains(&second)
+        {
+            return None;
+        }
+
+        Some(UtcTime {
+            year,
+            month,
+            day,
+            seconds: hour * 3600. + minute * 60. + second,
+        })
+    }
+
+    /// The current time, according to the system clock.
+    pub fn now() -> Self {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let days = (secs / 86400.).floor();
+
+        // Following Howard Hinnant's `civil_from_days`, the inverse of the
+        // algorithm in `mjd`.
+        let z = days as i64 + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+
+        UtcTime {
+            year,
+            month,
+            day,
+            seconds: secs - days * 86400.,
+        }
+    }
+
+    /// The Modified Julian Date.
+    pub fn mjd(&self) -> f64 {
+        // Days since 1970-01-01, following Howard Hinnant's
+        // `days_from_civil`.
+        let y = if self.month <= 2 {
+            self.year - 1
+        } else {
+            self.year
+        };
+        let era = y.div_euclid(400);
+        let yoe = y - era * 400;
+        let doy = (153 * ((self.month + 9) % 12) + 2) / 5 + self.day - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+
+        // MJD 40587 is 1970-01-01.
+        days as f64 + 40587. + self.seconds / 86400.
+    }
+
+    /// The Julian Date.
+    pub fn jd(&self) -> f64 {
+        self.mjd() + MJD_OFFSET
+    }
+
+    /// The time in the ISO 8601 form used by the FITS `DATE-OBS` keyword,
+    /// like `1925-03-01T04:00:00.000`.
+    pub fn iso(&self) -> String {
+        let ms = (self.seconds * 1000.).round() as i64;
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}",
+            self.year,
+            self.month,
+            self.day,
+            ms / 3_600_000,
+            (ms / 60_000) % 60,
+            (ms / 1000) % 60,
+            ms % 1000
+        )
+    }
+
+    /// The calendar date alone, like `1925-03-01`.
+    pub fn date(&self) -> String {
+        format!("{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
@@ -0,0 +1,111 @@
+//! Validation of numeric request parameters.
+//!
+//! Every service checks its numeric parameters against some allowed range,
+//! and clients deserve the same kind of answer from all of them when a check
+//! fails. The helpers here return [`DetailedError`]s whose details name the
+//! parameter, give the value provided, and state the allowed range using the
+//! same keywords as JSON Schema (`minimum`, `exclusiveMaximum`, etc.), so that
+//! tools like daschlab can report problems precisely.
+//!
+//! The checks are built on [`RangeBounds::contains`], which is false for NaN,
+//! so NaNs are always rejected. (This used to be handled by hand with
+//! carefully inverted comparisons in each service, which was easy to get
+//! wrong.)
+//!
+//! Request structs use the [`validate_fields!`] macro, which runs each
+//! named field through its check and uses the field name as the parameter
+//! name, so that the two can't drift apart.
+
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::{
+    fmt::Display,
+    ops::{Bound, RangeBounds},
+};
+
+use crate::{coords::normalize_ra, envelope::DetailedError};
+
+/// Run some fields of a request through validation functions, replacing them
+/// with the validated values, and returning early on error. Each check is
+/// called with the field name and value.
+///
+/// ```ignore
+/// validate_fields!(request {
+///     ra_deg: validation::ra,
+///     radius_arcsec: |n, v| validation::range(n, v, 0. ..=60.),
+/// });
+/// ```
+macro_rules! validate_fields {
+    ($req:ident { $($field:ident: $check:expr),* $(,)? }) => {
+        $(
+            $req.$field = ($check)(stringify!($field), $req.$field)?;
+        )*
+    };
+}
+
+pub(crate) use validate_fields;
+
+/// Check that a parameter is within a range.
+pub fn range<T, R>(name: &str, value: T, range: R) -> Result<T, DetailedError>
+where
+    T: PartialOrd + Display + Serialize,
+    R: RangeBounds<T>,
+{
+    if range.contains(&value) {
+        return Ok(value);
+    }
+
+    let mut details = Map::new();
+    details.insert("parameter".to_owned(), json!(name));
+    details.insert("value".to_owned(), json!(value));
+    let mut allowed = Vec::new();
+
+    match range.start_bound() {
+        Bound::Included(v) => {
+            details.insert("minimum".to_owned(), json!(v));
+            allowed.push(format!("at least {v}"));
+        }
+        Bound::Excluded(v) => {
+            details.insert("exclusiveMinimum".to_owned(), json!(v));
+            allowed.push(format!("greater than {v}"));
+        }
+        Bound::Unbounded => {}
+    }
+
+    match range.end_bound() {
+        Bound::Included(v) => {
+            details.insert("maximum".to_owned(), json!(v));
+            allowed.push(format!("at most {v}"));
+        }
+        Bound::Excluded(v) => {
+            details.insert("exclusiveMaximum".to_owned(), json!(v));
+            allowed.push(format!("less than {v}"));
+        }
+        Bound::Unbounded => {}
+    }
+
+    Err(DetailedError {
+        message: format!(
+            "illegal `{name}` parameter: must be {}; got {value}",
+            allowed.join(" and ")
+        ),
+        details: Value::Object(details),
+    })
+}
+
+/// Validate an RA parameter, in degrees, and normalize it into [0, 360).
+pub fn ra(name: &str, ra_deg: f64) -> Result<f64, DetailedError> {
+    range(name, ra_deg, 0. ..=360.).map(normalize_ra)
+}
+
+/// Validate a declination parameter, in degrees.
+pub fn dec(name: &str, dec_deg: f64) -> Result<f64, DetailedError> {
+    range(name, dec_deg, -90. ..=90.)
+}
+
+/// Adapt a check to an optional parameter, which passes if it is absent.
+pub fn optional<T>(
+    check: impl Fn(&str, T) -> Result<T, DetailedError>,
+) -> impl Fn(&str, Option<T>) -> Result<Option<T>, DetailedError> {
+    move |name, value| value.map(|v| check(name, v)).transpose()
+}
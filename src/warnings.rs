@@ -0,0 +1,97 @@
+//! Data-quality warnings reported alongside results.
+//!
+//! The services make a lot of quiet decisions about imperfect data: falling
+//! back to approximate WCS for plates whose astrometry can't be read, leaving
+//! out catalog sources without measured positions, patching up nonstandard
+//! WCS headers, and so on. None of these should fail a request, but users
+//! deserve to know about them, so handlers can record warnings, which are
+//! reported in the response envelope's `warnings` array. Each warning has a
+//! stable `code`, for clients to act on, a human-readable `message`, and, for
+//! warnings that apply to many items, the `count` of the items. The array is
+//! left out if there's nothing to report.
+//!
+//! Like the read capacity accounting in `capacity.rs`, the warnings live in a
+//! Tokio task-local, set up by
+//! [`Services::dispatch_as`](crate::Services::dispatch_as) around each
+//! invocation, so that they can be recorded from deep inside the services
+//! without threading a collector through every handler. Warnings recorded
+//! outside of an invocation, or on other threads, like those of the blocking
+//! pool, are dropped.
+
+use serde::Serialize;
+use std::{cell::RefCell, future::Future};
+
+tokio::task_local! {
+    static WARNINGS: RefCell<Vec<Warning>>;
+}
+
+/// A data-quality warning.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Warning {
+    /// A stable identifier of the kind of warning.
+    pub code: &'static str,
+
+    /// A description of what happened.
+    pub message: String,
+
+    /// The number of items that the warning applies to, for tallied warnings.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<usize>,
+}
+
+/// Run a future, collecting the warnings that it records.
+pub async fn collect<F: Future>(fut: F) -> F::Output {
+    WARNINGS.scope(RefCell::new(Vec::new()), fut).await
+}
+
+/// Record a warning for the current request. Repeats of a warning that has
+/// already been recorded are ignored.
+pub fn add(code: &'static str, message: impl Into<String>) {
+    let message = message.into();
+
+    let _ = WARNINGS.try_with(|w| {
+        let mut warnings = w.borrow_mut();
+
+        if !warnings
+            .iter()
+            .any(|x| x.code == code && x.message == message)
+        {
+            warnings.push(Warning {
+                code,
+                message,
+                count: None,
+            });
+        }
+    });
+}
+
+/// Add `n` items to the count of a tallied warning for the current request,
+/// recording it if this is the first time.
+pub fn tally(code: &'static str, message: &str, n: usize) {
+    if n == 0 {
+        return;
+    }
+
+    let _ = WARNINGS.try_with(|w| {
+        let mut warnings = w.borrow_mut();
+
+        match warnings
+            .iter_mut()
+            .find(|x| x.code == code && x.count.is_some())
+        {
+            Some(x) => x.count = x.count.map(|c| c + n),
+            None => warnings.push(Warning {
+                code,
+                message: message.to_owned(),
+                count: Some(n),
+            }),
+        }
+    });
+}
+
+/// The warnings recorded so far for the current request.
+pub fn current() -> Vec<Warning> {
+    WARNINGS
+        .try_with(|w| w.borrow().clone())
+        .unwrap_or_default()
+}
@@ -2,14 +2,76 @@ use anyhow::{bail, Result};
 use fitswcs_sys::wcslib;
 use libc::{c_char, c_int};
 use ndarray::{Array, Ix2, Ix3};
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::CStr,
+    sync::{Mutex, Once},
+};
 
 #[derive(Debug)]
 pub struct WcsCollection {
     all_handles: wcslib::WcsPrm,
     nwcs: c_int,
     struct_size: isize,
+    n_rejected: usize,
+    report: Vec<String>,
+    fixes: Vec<String>,
 }
 
+/// How strictly wcslib's header parser treats nonstandard WCS keywords. Many
+/// legacy DASCH headers use some, so we accept everything by default, but
+/// stricter parsing helps to diagnose headers that don't behave.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WcsRelax {
+    /// Accept all of the nonstandard keywords that wcslib knows about.
+    #[default]
+    All,
+
+    /// Only accept standard keywords, and reject the rest.
+    Standard,
+
+    /// Also reject keywords that are technically nonstandard but usually
+    /// tolerated.
+    Strict,
+}
+
+impl WcsRelax {
+    pub fn is_default(&self) -> bool {
+        *self == WcsRelax::All
+    }
+
+    fn flags(self) -> c_int {
+        match self {
+            WcsRelax::All => wcslib::WCSHDR_ALL,
+            WcsRelax::Standard => wcslib::WCSHDR_NONE | wcslib::WCSHDR_REJECT,
+            WcsRelax::Strict => wcslib::WCSHDR_REJECT | wcslib::WCSHDR_STRICT,
+        }
+    }
+}
+
+/// Options for parsing WCS headers.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeaderOptions {
+    /// How to treat nonstandard keywords.
+    pub relax: WcsRelax,
+
+    /// If true, capture wcslib's report of the keywords that it rejected.
+    pub report: bool,
+
+    /// If true, don't repair plate headers with `wcsfix` after parsing them.
+    /// Only used by `crate::mosaics::load_b01_header`.
+    pub skip_fix: bool,
+}
+
+/// wcslib's captured diagnostic output is global state, so we only let one
+/// thread at a time parse headers with reporting.
+static REPORT_LOCK: Mutex<()> = Mutex::new(());
+
+/// wcslib only describes the fixes that `wcsfixi` applies if its messages are
+/// enabled, which is a global setting.
+static ENABLE_MESSAGES: Once = Once::new();
+
 #[derive(Debug)]
 pub struct Wcs<'a> {
     // We don't actually need to hold a pointer/reference here, but we need to
@@ -33,24 +95,77 @@ macro_rules! try_wcslib {
 
 impl WcsCollection {
     /// Initialize WCS from FITS headers, based on a raw pointer.
-    pub unsafe fn new_raw(header: *const c_char, nkeys: c_int) -> Result<Self> {
+    ///
+    /// # Safety
+    ///
+    /// `header` must point to `nkeys` 80-character header records.
+    pub unsafe fn new_raw(
+        header: *const c_char,
+        nkeys: c_int,
+        options: HeaderOptions,
+    ) -> Result<Self> {
         let mut all_handles: wcslib::WcsPrm = std::ptr::null_mut();
         let mut nreject: c_int = 0;
         let mut nwcs: c_int = 0;
+        let mut report = Vec::new();
 
         // If we have a header with multiple WCS solutions, this will load
         // up *all* of them into a list of WcsPrms.
-        try_wcslib!(wcslib::wcspih(
-            header,
-            nkeys,
-            wcslib::WCSHDR_ALL,
-            0,
-            &mut nreject,
-            &mut nwcs,
-            &mut all_handles,
-        ));
-
-        assert!(nwcs > 0);
+        let status = if options.report {
+            let _guard = REPORT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            wcslib::wcsprintf_set(std::ptr::null_mut());
+
+            let status = wcslib::wcspih(
+                header,
+                nkeys,
+                options.relax.flags(),
+                wcslib::WCSHDR_CTRL_REPORT,
+                &mut nreject,
+                &mut nwcs,
+                &mut all_handles,
+            );
+
+            let buf = wcslib::wcsprintf_buf();
+
+            if !buf.is_null() {
+                report = CStr::from_ptr(buf)
+                    .to_string_lossy()
+                    .lines()
+                    .map(|l| l.trim_end().to_owned())
+                    .filter(|l| !l.is_empty())
+                    .collect();
+            }
+
+            status
+        } else {
+            wcslib::wcspih(
+                header,
+                nkeys,
+                options.relax.flags(),
+                0,
+                &mut nreject,
+                &mut nwcs,
+                &mut all_handles,
+            )
+        };
+
+        if status != 0 || nwcs < 1 {
+            if !all_handles.is_null() {
+                wcslib::wcsvfree(&mut nwcs, &mut all_handles);
+            }
+
+            let mut msg = if status != 0 {
+                format!("wcslib error code {}", status)
+            } else {
+                "no WCS solutions found in header".to_owned()
+            };
+
+            if !report.is_empty() {
+                msg = format!("{msg}; wcslib reported: {}", report.join(" / "));
+            }
+
+            bail!(msg);
+        }
 
         // In order to handle multiple-solution setups, we need to index by the
         // size of the wcsprm struct. Fortunately there is an API that tells us
@@ -62,9 +177,85 @@ impl WcsCollection {
             all_handles,
             nwcs,
             struct_size: sizes[0] as isize,
+            n_rejected: nreject as usize,
+            report,
+            fixes: Vec::new(),
         })
     }
 
+    /// Repair nonstandard values in all of the WCS solutions with wcslib's
+    /// `wcsfixi`, like old-style dates and unit strings, and record
+    /// descriptions of the fixes that it applied. Fixes that fail are recorded
+    /// too, but aren't errors, since the solution may still be usable.
+    pub fn fix(&mut self) {
+        ENABLE_MESSAGES.call_once(|| unsafe {
+            wcslib::wcserr_enable(1);
+        });
+
+        for i in 0..self.nwcs as isize {
+            let handle = unsafe { self.all_handles.byte_offset(i * self.struct_size) };
+            let mut stat: [c_int; wcslib::NWCSFIX] = [0; wcslib::NWCSFIX];
+            let mut info: [wcslib::WcsErr; wcslib::NWCSFIX] =
+                std::array::from_fn(|_| wcslib::WcsErr {
+                    status: 0,
+                    line_no: 0,
+                    function: std::ptr::null(),
+                    file: std::ptr::null(),
+                    msg: std::ptr::null_mut(),
+                });
+
+            unsafe {
+                wcslib::wcsfixi(
+                    0,
+                    std::ptr::null(),
+                    handle,
+                    stat.as_mut_ptr(),
+                    info.as_mut_ptr(),
+                );
+            }
+
+            for (j, (status, err)) in stat.iter().zip(info.iter_mut()).enumerate() {
+                let msg = if err.msg.is_null() {
+                    None
+                } else {
+                    let msg = unsafe { CStr::from_ptr(err.msg) }
+                        .to_string_lossy()
+                        .into_owned();
+                    unsafe { wcslib::wcsdealloc(err.msg as *mut _) };
+                    err.msg = std::ptr::null_mut();
+                    Some(msg)
+                };
+
+                if *status == wcslib::FIXERR_NO_CHANGE || *status == wcslib::FIXERR_NOT_INVOKED {
+                    continue;
+                }
+
+                let name = wcslib::WCSFIX_NAMES[j];
+                let what = if *status > 0 { "failed" } else { "applied" };
+                self.fixes.push(match msg {
+                    Some(msg) => format!("WCS #{i}: {name} {what}: {msg}"),
+                    None => format!("WCS #{i}: {name} {what}"),
+                });
+            }
+        }
+    }
+
+    /// Descriptions of the fixes applied by [`Self::fix`].
+    pub fn fixes(&self) -> &[String] {
+        &self.fixes
+    }
+
+    /// The number of WCS keywords that wcslib rejected when parsing the
+    /// header.
+    pub fn n_rejected(&self) -> usize {
+        self.n_rejected
+    }
+
+    /// wcslib's report of the rejected keywords, if it was requested.
+    pub fn report(&self) -> &[String] {
+        &self.report
+    }
+
     pub fn new_tan(crval1: f64, crval2: f64, crpix1: f64, crpix2: f64, cd22: f64) -> Self {
         let header = format!(
             "\
@@ -79,11 +270,11 @@ CD1_1   = {:24}                                              \
 CD2_2   = {:24}                                              ",
             crval1, crval2, crpix1, crpix2, -cd22, cd22
         );
-        unsafe { Self::new_raw(header.as_ptr() as *const _, 9) }
+        unsafe { Self::new_raw(header.as_ptr() as *const _, 9, HeaderOptions::default()) }
             .expect("out of memory? TAN construction should be infallible")
     }
 
-    pub fn get(&mut self, solnum: usize) -> Result<Wcs> {
+    pub fn get(&mut self, solnum: usize) -> Result<Wcs<'_>> {
         if solnum >= self.nwcs as usize {
             bail!(
                 "requested WCS solution #{} (0-based), but there are only {} in this header",
@@ -114,44 +305,88 @@ impl Drop for WcsCollection {
     }
 }
 
+/// Whether a keyword written by `wcshdo` belongs to one of the families of
+/// WCS keywords that are tagged with an alternate letter. The others, like
+/// `DATE-OBS` and `MJDREF`, apply to all of the coordinate systems.
+fn is_alternate_keyword(keyword: &str) -> bool {
+    const PREFIXES: &[&str] = &[
+        "WCSAXES", "CRPIX", "PC", "CD", "CROTA", "CUNIT", "CTYPE", "CRVAL", "PV", "PS", "LONPOLE",
+        "LATPOLE", "RESTFRQ", "RESTWAV", "CNAME", "CRDER", "CSYER", "CZPHS", "CPERI", "WCSNAME",
+        "RADESYS", "EQUINOX", "SPECSYS", "SSYSOBS", "VELOSYS", "ZSOURCE", "SSYSSRC", "VELANGL",
+    ];
+
+    PREFIXES.iter().any(|p| keyword.starts_with(p))
+}
+
+/// The number of coordinates that we hand to wcslib at once in the bulk
+/// transforms. wcslib needs scratch arrays for various intermediate values that
+/// we don't care about; by processing coordinates in chunks, we keep that
+/// scratch space small no matter how big the output grid is.
+const TRANSFORM_CHUNK_SIZE: usize = 16384;
+
+/// Scratch space for the chunked transforms. wcslib fills these in, but we
+/// never look at them.
+struct Scratch {
+    intermediate: Vec<f64>,
+    phi: Vec<f64>,
+    theta: Vec<f64>,
+}
+
+impl Scratch {
+    fn new(ncoord: usize) -> Self {
+        let n = usize::min(ncoord, TRANSFORM_CHUNK_SIZE);
+
+        Scratch {
+            intermediate: vec![0.; 2 * n],
+            phi: vec![0.; n],
+            theta: vec![0.; n],
+        }
+    }
+}
+
 impl<'a> Wcs<'a> {
-    /// Sample world coordinates on a grid of pixel indices.
-    pub fn sample_world_square(&mut self, size: usize) -> Result<Array<f64, Ix3>> {
+    /// Sample world coordinates on a grid of pixel indices. The result has
+    /// shape `(height, width, 2)`.
+    pub fn sample_world(&mut self, width: usize, height: usize) -> Result<Array<f64, Ix3>> {
         const NELEM: c_int = 2;
 
-        // Pixel coordinate array to be fed into wcslib: we can treat it as a
-        // NxNx2 array of 1-based X and Y coordinates.
-        let pixel = Array::from_shape_fn((size, size, 2), |(i, j, k)| {
-            if k == 0 {
-                j as f64 + 1.
-            } else {
-                i as f64 + 1.
-            }
-        });
+        let ncoord = width * height;
+        let mut world = Array::<f64, _>::zeros((height, width, 2));
+        let world_flat = world.as_slice_mut().unwrap();
+        let mut scratch = Scratch::new(ncoord);
+        let mut status = vec![0 as c_int; usize::min(ncoord, TRANSFORM_CHUNK_SIZE)];
 
-        let mut image = Array::<f64, _>::uninit(pixel.dim());
-        let mut phi = Array::<f64, _>::uninit(pixel.dim());
-        let mut theta = Array::<f64, _>::uninit(pixel.dim());
-        let mut world = Array::<f64, _>::uninit(pixel.dim());
-        let mut status = Array::<c_int, _>::uninit((size, size));
+        // Pixel coordinates to be fed into wcslib, for one chunk at a time: we
+        // can treat it as an Nx2 array of 1-based X and Y coordinates.
+        let mut pixel = vec![0.; 2 * status.len()];
 
-        try_wcslib!(unsafe {
-            wcslib::wcsp2s(
-                self.handle,
-                (size * size) as c_int,
-                NELEM,
-                pixel.as_ptr(),
-                image.as_mut_ptr() as *mut _,
-                phi.as_mut_ptr() as *mut _,
-                theta.as_mut_ptr() as *mut _,
-                world.as_mut_ptr() as *mut _,
-                status.as_mut_ptr() as *mut _,
-            )
-        });
+        for start in (0..ncoord).step_by(TRANSFORM_CHUNK_SIZE) {
+            let n = usize::min(TRANSFORM_CHUNK_SIZE, ncoord - start);
+
+            for k in 0..n {
+                let index = start + k;
+                pixel[2 * k] = (index % width) as f64 + 1.;
+                pixel[2 * k + 1] = (index / width) as f64 + 1.;
+            }
+
+            try_wcslib!(unsafe {
+                wcslib::wcsp2s(
+                    self.handle,
+                    n as c_int,
+                    NELEM,
+                    pixel.as_ptr(),
+                    scratch.intermediate.as_mut_ptr(),
+                    scratch.phi.as_mut_ptr(),
+                    scratch.theta.as_mut_ptr(),
+                    world_flat[2 * start..].as_mut_ptr(),
+                    status.as_mut_ptr(),
+                )
+            });
+        }
 
         // Let's just ignore any problems.
 
-        Ok(unsafe { world.assume_init() })
+        Ok(world)
     }
 
     /// Convert world coordinates to pixel coordinates. The returned coordinates
@@ -162,31 +397,34 @@ impl<'a> Wcs<'a> {
         &mut self,
         world: Array<f64, Ix3>,
     ) -> Result<(Array<f64, Ix3>, Array<c_int, Ix2>)> {
-        let ncoord = world.shape()[0] * world.shape()[1];
         const NELEM: c_int = 2;
 
-        let mut phi = Array::<f64, _>::uninit(world.dim());
-        let mut theta = Array::<f64, _>::uninit(world.dim());
-        let mut image = Array::<f64, _>::uninit(world.dim());
-        let mut pixel = Array::<f64, _>::uninit(world.dim());
-        let mut status = Array::<c_int, _>::uninit((world.shape()[0], world.shape()[1]));
-
-        try_wcslib!(unsafe {
-            wcslib::wcss2p(
-                self.handle,
-                ncoord as c_int,
-                NELEM,
-                world.as_ptr(),
-                phi.as_mut_ptr() as *mut _,
-                theta.as_mut_ptr() as *mut _,
-                image.as_mut_ptr() as *mut _,
-                pixel.as_mut_ptr() as *mut _,
-                status.as_mut_ptr() as *mut _,
-            )
-        });
-
-        let mut pixel = unsafe { pixel.assume_init() };
-        let status = unsafe { status.assume_init() };
+        let ncoord = world.shape()[0] * world.shape()[1];
+        let world = world.as_standard_layout();
+        let world_flat = world.as_slice().unwrap();
+        let mut pixel = Array::<f64, _>::zeros(world.dim());
+        let pixel_flat = pixel.as_slice_mut().unwrap();
+        let mut status = Array::<c_int, _>::zeros((world.shape()[0], world.shape()[1]));
+        let status_flat = status.as_slice_mut().unwrap();
+        let mut scratch = Scratch::new(ncoord);
+
+        for start in (0..ncoord).step_by(TRANSFORM_CHUNK_SIZE) {
+            let n = usize::min(TRANSFORM_CHUNK_SIZE, ncoord - start);
+
+            try_wcslib!(unsafe {
+                wcslib::wcss2p(
+                    self.handle,
+                    n as c_int,
+                    NELEM,
+                    world_flat[2 * start..].as_ptr(),
+                    scratch.phi.as_mut_ptr(),
+                    scratch.theta.as_mut_ptr(),
+                    scratch.intermediate.as_mut_ptr(),
+                    pixel_flat[2 * start..].as_mut_ptr(),
+                    status_flat[start..].as_mut_ptr(),
+                )
+            });
+        }
 
         // Convert to 0-based pixel indices.
         pixel -= 1.;
@@ -212,6 +450,79 @@ impl<'a> Wcs<'a> {
         Ok(maybe)
     }
 
+    /// Write out the WCS as FITS header records describing the primary
+    /// coordinate system of an image whose pixel grid is offset from the
+    /// original one by `offset` pixels, so that the original pixel `offset` is
+    /// the first one of the new image.
+    ///
+    /// `wcshdo` tags the keywords of an alternate coordinate system with its
+    /// letter, which we strip, so that tools that only look at the primary
+    /// system find it. The image's `CRPIXn` are shifted by the offset.
+    pub fn primary_header(&mut self, offset: (f64, f64)) -> Result<Vec<String>> {
+        let mut nkeyrec: c_int = 0;
+        let mut header: *mut c_char = std::ptr::null_mut();
+
+        try_wcslib!(unsafe {
+            wcslib::wcshdo(
+                wcslib::WCSHDO_SAFE | wcslib::WCSHDO_P14,
+                self.handle,
+                &mut nkeyrec,
+                &mut header,
+            )
+        });
+
+        let text = unsafe {
+            let bytes = std::slice::from_raw_parts(header as *const u8, nkeyrec as usize * 80);
+            let text = String::from_utf8_lossy(bytes).into_owned();
+            wcslib::wcsdealloc(header as *mut _);
+            text
+        };
+
+        let cards: Vec<&str> = (0..text.len() / 80)
+            .map(|i| &text[80 * i..80 * (i + 1)])
+            .collect();
+
+        // The alternate letter is the one after `CTYPE1`, if there is one.
+        let alt = cards
+            .iter()
+            .find(|c| c.starts_with("CTYPE1"))
+            .map(|c| c.as_bytes()[6] as char)
+            .filter(|c| c.is_ascii_uppercase());
+
+        let mut result = Vec::with_capacity(cards.len());
+
+        // `wcshdo` separates groups of keywords with blank records, which we
+        // drop.
+        for card in cards.into_iter().filter(|c| !c.trim().is_empty()) {
+            let keyword = card[..8].trim_end();
+            let keyword = match alt {
+                Some(a) if is_alternate_keyword(keyword) && keyword.ends_with(a) => {
+                    &keyword[..keyword.len() - 1]
+                }
+                _ => keyword,
+            };
+
+            let delta = match keyword {
+                "CRPIX1" => Some(offset.0),
+                "CRPIX2" => Some(offset.1),
+                _ => None,
+            };
+
+            let card = match delta {
+                Some(d) => {
+                    let value: f64 = card[10..30].trim().parse()?;
+                    format!("{keyword:8}= {:>20}", format!("{:.6}", value - d))
+                }
+
+                None => format!("{keyword:8}{}", &card[8..]),
+            };
+
+            result.push(format!("{card:80}"));
+        }
+
+        Ok(result)
+    }
+
     /// Dumb utility. We should use generics better.
     ///
     /// We use 0-based pixel indexes.
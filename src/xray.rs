@@ -0,0 +1,235 @@
+//! Optional export of tracing spans to AWS X-Ray.
+//!
+//! When a cutout is slow, we want to know whether the time went into DynamoDB,
+//! S3, or our own computation. The handlers, the DynamoDB calls, and the S3
+//! reads are all wrapped in `tracing` spans. If the `DASCH_TRACING`
+//! environment variable is set to `xray`, the [`XRayLayer`] sends each span,
+//! when it closes, to the X-Ray daemon as a subsegment of the Lambda
+//! invocation's trace.
+//!
+//! The Lambda runtime provides the trace context of each invocation in the
+//! `_X_AMZN_TRACE_ID` environment variable, and the daemon address in
+//! `AWS_XRAY_DAEMON_ADDRESS`. We only send data for sampled invocations. The
+//! daemon protocol is just JSON over UDP, so sending is cheap and failures are
+//! ignored.
+
+use lambda_runtime::tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    subscriber::{layer::Context, registry::LookupSpan, Layer},
+    Subscriber,
+};
+use serde_json::{json, Map, Value};
+use std::{
+    collections::hash_map::RandomState,
+    env,
+    hash::{BuildHasher, Hasher},
+    net::{SocketAddr, UdpSocket},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The default address of the X-Ray daemon.
+const DEFAULT_DAEMON_ADDRESS: &str = "127.0.0.1:2000";
+
+/// A `tracing` layer that reports spans to the X-Ray daemon.
+#[derive(Debug)]
+pub struct XRayLayer {
+    socket: UdpSocket,
+    daemon: SocketAddr,
+    seed: RandomState,
+    counter: AtomicU64,
+}
+
+/// The per-span state that we store in the span's extensions.
+struct SpanState {
+    id: String,
+    start_time: f64,
+    fields: Map<String, Value>,
+}
+
+impl XRayLayer {
+    /// Create the layer if X-Ray export is enabled in the environment.
+    pub fn from_env() -> Option<Self> {
+        if env::var("DASCH_TRACING").ok()?.to_lowercase() != "xray" {
+            return None;
+        }
+
+        // The address may be given as `host:port`, or as
+        // `tcp:host:port udp:host:port`.
+        let addr_text = env::var("AWS_XRAY_DAEMON_ADDRESS")
+            .ok()
+            .and_then(|v| {
+                v.split_whitespace()
+                    .find_map(|p| p.strip_prefix("udp:").map(|s| s.to_owned()))
+                    .or(Some(v))
+            })
+            .unwrap_or_else(|| DEFAULT_DAEMON_ADDRESS.to_owned());
+
+        let daemon = match addr_text.parse() {
+            Ok(a) => a,
+            Err(e) => {
+                eprintln!("X-Ray tracing disabled: bad daemon address `{addr_text}`: {e}");
+                return None;
+            }
+        };
+
+        let socket = match UdpSocket::bind("0.0.0.0:0") {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("X-Ray tracing disabled: cannot create socket: {e}");
+                return None;
+            }
+        };
+
+        Some(XRayLayer {
+            socket,
+            daemon,
+            seed: RandomState::new(),
+            counter: AtomicU64::new(0),
+        })
+    }
+
+    /// Generate a new 64-bit subsegment ID, as 16 hex digits.
+    fn new_id(&self) -> String {
+        let mut hasher = self.seed.build_hasher();
+        hasher.write_u64(self.counter.fetch_add(1, Ordering::Relaxed));
+        format!("{:016x}", hasher.finish())
+    }
+
+    fn send(&self, document: &Value) {
+        let packet = format!("{{\"format\": \"json\", \"version\": 1}}\n{document}");
+        let _ = self.socket.send_to(packet.as_bytes(), self.daemon);
+    }
+}
+
+/// The trace context of the current invocation, from the Lambda environment.
+struct TraceContext {
+    root: String,
+    parent: String,
+}
+
+impl TraceContext {
+    /// Get the context, if the current invocation is being sampled.
+    fn current() -> Option<Self> {
+        let header = env::var("_X_AMZN_TRACE_ID").ok()?;
+        let mut root = None;
+        let mut parent = None;
+        let mut sampled = false;
+
+        for part in header.split(';') {
+            match part.split_once('=') {
+                Some(("Root", v)) => root = Some(v.to_owned()),
+                Some(("Parent", v)) => parent = Some(v.to_owned()),
+                Some(("Sampled", v)) => sampled = v == "1",
+                _ => {}
+            }
+        }
+
+        if !sampled {
+            return None;
+        }
+
+        Some(TraceContext {
+            root: root?,
+            parent: parent?,
+        })
+    }
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}
+
+struct FieldVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_owned(), format!("{value:?}").into());
+    }
+}
+
+impl<S> Layer<S> for XRayLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+
+        let mut fields = Map::new();
+        attrs.record(&mut FieldVisitor(&mut fields));
+
+        span.extensions_mut().insert(SpanState {
+            id: self.new_id(),
+            start_time: now(),
+            fields,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut ext = span.extensions_mut();
+
+        if let Some(state) = ext.get_mut::<SpanState>() {
+            values.record(&mut FieldVisitor(&mut state.fields));
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(trace) = TraceContext::current() else {
+            return;
+        };
+
+        let Some(span) = ctx.span(&id) else { return };
+        let ext = span.extensions();
+        let Some(state) = ext.get::<SpanState>() else {
+            return;
+        };
+
+        let parent_id = span
+            .parent()
+            .and_then(|p| p.extensions().get::<SpanState>().map(|s| s.id.clone()))
+            .unwrap_or(trace.parent);
+
+        // Spans for AWS calls are marked with an `aws.service` field, which
+        // X-Ray uses to draw them as remote services.
+        let aws_service = state.fields.get("aws.service").and_then(|v| v.as_str());
+
+        let mut doc = json!({
+            "type": "subsegment",
+            "name": aws_service.unwrap_or(span.name()),
+            "id": state.id,
+            "trace_id": trace.root,
+            "parent_id": parent_id,
+            "start_time": state.start_time,
+            "end_time": now(),
+            "metadata": { "default": state.fields },
+        });
+
+        if aws_service.is_some() {
+            doc["namespace"] = "aws".into();
+        }
+
+        self.send(&doc);
+    }
+}
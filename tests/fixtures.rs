@@ -0,0 +1,2873 @@
+//! End-to-end tests of the services, run against the on-disk fixtures in
+//! `tests/data`. See `src/fixtures.rs` for the layout of that directory. The
+//! data are synthetic: a handful of plates around RA = 10.5, Dec = 20.3, with a
+//! tiny 64×64 mosaic for plate b12345.
+
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use flate2::read::GzDecoder;
+use serde_json::{json, Value};
+use std::{io::Read, time::Instant};
+
+use dasch_science_lambda::{
+    error_body, query_request, ForbiddenError, PlatesSource, Services, OPERATOR,
+};
+
+fn services() -> Services {
+    Services::from_fixtures(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+}
+
+/// Call a service, returning the full response envelope.
+async fn call_raw(function: &str, payload: Value) -> Value {
+    let arn = format!("arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-{function}");
+    services().dispatch(arn, Some(payload)).await.unwrap()
+}
+
+/// Call a service, returning its result.
+async fn call(function: &str, payload: Value) -> Value {
+    let mut envelope = call_raw(function, payload).await;
+    assert_eq!(envelope["service"], function);
+    envelope["result"].take()
+}
+
+/// Decode the gzipped, Base64-encoded FITS file returned by `cutout`.
+fn cutout_fits(result: &Value) -> Vec<u8> {
+    let gz = STANDARD.decode(result.as_str().unwrap()).unwrap();
+    let mut fits = Vec::new();
+    GzDecoder::new(&gz[..]).read_to_end(&mut fits).unwrap();
+    fits
+}
+
+/// Get the value of a numeric header keyword from a FITS file.
+fn fits_header_f64(fits: &[u8], key: &str) -> f64 {
+    let card = fits
+        .chunks(80)
+        .find(|card| card.starts_with(format!("{key:8}=").as_bytes()))
+        .unwrap();
+    std::str::from_utf8(&card[10..30])
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap()
+}
+
+fn rows(v: &Value) -> Vec<&str> {
+    v.as_array()
+        .unwrap()
+        .iter()
+        .map(|r| r.as_str().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn querycat_box() {
+    let result = call(
+        "querycat",
+        json!({"refcat": "apass", "ra_deg": 10.5, "dec_deg": 20.3, "radius_arcsec": 10.}),
+    )
+    .await;
+    assert_eq!(result["truncated"], false);
+    let rows = rows(&result["rows"]);
+
+    assert!(rows[0].starts_with("ref_text,ref_number,"));
+    assert_eq!(rows.len(), 3);
+
+    // The nearby source is reported with its offsets ...
+    let cells: Vec<_> = rows[1].split(',').collect();
+    assert_eq!(cells[1], "100001");
+    assert_eq!(cells[3], "10.5005");
+    assert!(!cells[5].is_empty());
+    assert_eq!(cells[7], "2000.000");
+
+    // ... and the one with a placeholder position without them. That source
+    // has its own position epoch, which overrides the catalog's.
+    let cells: Vec<_> = rows[2].split(',').collect();
+    assert_eq!(cells[1], "100003");
+    assert_eq!(&cells[3..7], &["", "", "", ""]);
+    assert_eq!(cells[7], "2015.500");
+}
+
+#[tokio::test]
+async fn querycat_2mass() {
+    let result = call(
+        "querycat",
+        json!({"refcat": "2mass", "ra_deg": 10.5, "dec_deg": 20.3, "radius_arcsec": 10.}),
+    )
+    .await;
+    let rows = rows(&result["rows"]);
+
+    assert!(rows[0].starts_with("designation,gscBinIndex,raDeg,"));
+    assert_eq!(rows.len(), 2);
+
+    // The source's observation epoch overrides the catalog's.
+    let cells: Vec<_> = rows[1].split(',').collect();
+    assert_eq!(cells[0], "00420012+2018018");
+    assert_eq!(cells[6], "1998.842");
+    assert_eq!(&cells[7..9], &["9.812", "0.023"]);
+    assert_eq!(cells[13], "AAA");
+
+    // Version 1 of the API only knew the catalogs with the legacy columns.
+    let err = services()
+        .dispatch(
+            "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-querycat".to_owned(),
+            Some(json!({
+                "refcat": "2mass",
+                "ra_deg": 10.5,
+                "dec_deg": 20.3,
+                "radius_arcsec": 10.,
+                "api_version": 1,
+            })),
+        )
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("api_version 2"), "{err}");
+}
+
+#[tokio::test]
+async fn query_parameters() {
+    let arn = |f: &str| format!("arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-{f}");
+
+    let request = query_request(
+        &arn("querycat"),
+        [
+            ("refcat", "apass"),
+            ("ra_deg", "10.5"),
+            ("dec_deg", "20.3"),
+            ("radius_arcsec", "10"),
+            ("formatting.sexagesimal", "true"),
+        ],
+    )
+    .unwrap();
+    assert_eq!(
+        request,
+        json!({
+            "refcat": "apass",
+            "ra_deg": 10.5,
+            "dec_deg": 20.3,
+            "radius_arcsec": 10,
+            "formatting": {"sexagesimal": true},
+        })
+    );
+    let result = services()
+        .dispatch(arn("querycat"), Some(request))
+        .await
+        .unwrap();
+    assert_eq!(
+        rows(&result["result"]["rows"])[1].split(',').nth(3),
+        Some("00:42:00.12")
+    );
+
+    // String fields are taken literally, and array elements can be repeated.
+    let request = query_request(
+        &arn("cutout"),
+        [
+            ("plate_id", "b12345"),
+            ("target_name", "1234"),
+            ("solution_numbers", "1"),
+            ("solution_numbers", "0"),
+        ],
+    )
+    .unwrap();
+    assert_eq!(request["target_name"], "1234");
+    assert_eq!(request["solution_numbers"], json!([1, 0]));
+
+    let err = query_request(&arn("querycat"), [("ra_deg", "1"), ("ra_deg", "2")]).unwrap_err();
+    assert!(err.to_string().contains("more than once"), "{err}");
+}
+
+#[tokio::test]
+async fn formatting() {
+    let payload = json!({"refcat": "apass", "ra_deg": 10.5, "dec_deg": 20.3, "radius_arcsec": 10.});
+    let plain = call("querycat", payload.clone()).await;
+    let plain: Vec<_> = rows(&plain["rows"])[1]
+        .split(',')
+        .map(String::from)
+        .collect();
+
+    let mut formatted = payload.clone();
+    formatted["formatting"] = json!({"coord_decimals": 2, "mag_decimals": 1});
+    let result = call("querycat", formatted).await;
+    let cells: Vec<_> = rows(&result["rows"])[1].split(',').collect();
+    assert_eq!(cells[3], "10.50");
+    assert_eq!(
+        cells[12],
+        format!("{:.1}", plain[12].parse::<f64>().unwrap())
+    );
+
+    // Only the formatted columns change.
+    assert_eq!(cells[5..12], plain[5..12]);
+
+    let mut formatted = payload.clone();
+    formatted["formatting"] = json!({"sexagesimal": true});
+    let result = call("querycat", formatted).await;
+    let cells: Vec<_> = rows(&result["rows"])[1].split(',').collect();
+    assert_eq!(cells[3], "00:42:00.12");
+    assert!(cells[4].starts_with('+'));
+    assert_eq!(result["coordinates"]["frame"], "icrs");
+    assert_eq!(result["coordinates"]["notation"], "sexagesimal");
+    assert_eq!(result["coordinates"]["epoch_column"], "posEpoch");
+
+    // Precessing to 1900 moves the source back about 1.3° in RA, but leaves
+    // its offsets from the search position alone.
+    let mut formatted = payload;
+    formatted["formatting"] = json!({"equinox": 1900});
+    let result = call("querycat", formatted).await;
+    let cells: Vec<_> = rows(&result["rows"])[1].split(',').collect();
+    let ra: f64 = cells[3].parse().unwrap();
+    let dec: f64 = cells[4].parse().unwrap();
+    assert!((9.1..9.3).contains(&ra), "{ra}");
+    assert!((19.7..20.0).contains(&dec), "{dec}");
+    assert_eq!(cells[5..7], plain[5..7]);
+    assert_eq!(result["coordinates"]["frame"], "fk5");
+    assert_eq!(result["coordinates"]["equinox"], "J1900.0");
+
+    // Positions computed by the service are formatted too.
+    let result = call(
+        "queryepoch",
+        json!({
+            "jd_start": 2424210.5,
+            "jd_end": 2424211.5,
+            "formatting": {"sexagesimal": true, "coord_decimals": 3},
+        }),
+    )
+    .await;
+    assert!(rows(&result["rows"])[1].starts_with("b,12345,1,00:42:00.000,+20:18:00.00,"));
+    assert_eq!(result["coordinates"]["columns"], json!(["ra", "dec"]));
+
+    let err = services()
+        .dispatch(
+            "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-queryexps".to_owned(),
+            Some(json!({"ra_deg": 10.5, "dec_deg": 20.3, "formatting": {"coord_decimals": 20}})),
+        )
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("formatting.coord_decimals"));
+
+    let err = services()
+        .dispatch(
+            "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-queryepoch".to_owned(),
+            Some(json!({
+                "jd_start": 2424210.5,
+                "jd_end": 2424211.5,
+                "formatting": {"equinox": 1066},
+            })),
+        )
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("formatting.equinox"));
+}
+
+#[tokio::test]
+async fn api_version_1() {
+    // Version 1 responses are bare arrays of rows with the original columns.
+    let current = call("queryexps", json!({"ra_deg": 10.5, "dec_deg": 20.3})).await;
+    let v1 = call_raw(
+        "queryexps",
+        json!({"ra_deg": 10.5, "dec_deg": 20.3, "api_version": 1}),
+    )
+    .await;
+    let v1 = rows(&v1);
+    assert_eq!(
+        v1[0],
+        "series,platenum,scannum,mosnum,expnum,solnum,class,ra,dec,exptime,\
+         expdate,epoch,wcssource,scandate,mosdate,centerdist,edgedist"
+    );
+    assert_eq!(v1.len(), current["rows"].as_array().unwrap().len());
+
+    for (old, new) in v1.iter().zip(rows(&current["rows"])) {
+        let old: Vec<_> = old.split(',').collect();
+        let new: Vec<_> = new.split(',').collect();
+        assert_eq!(old[..], new[..17]);
+    }
+
+    let v1 = call_raw(
+        "querycat",
+        json!({
+            "refcat": "apass",
+            "ra_deg": 10.5,
+            "dec_deg": 20.3,
+            "radius_arcsec": 10.,
+            "api_version": 1,
+        }),
+    )
+    .await;
+    assert_eq!(rows(&v1).len(), 3);
+
+    // Cutouts are just the encoded FITS file.
+    let v1 = call_raw(
+        "cutout",
+        json!({
+            "plate_id": "b12345",
+            "solution_number": 0,
+            "center_ra_deg": 10.5,
+            "center_dec_deg": 20.3,
+            "api_version": 1,
+        }),
+    )
+    .await;
+    assert!(cutout_fits(&v1).starts_with(b"SIMPLE  ="));
+
+    // Newer features aren't available, and unknown versions are rejected.
+    for payload in [
+        json!({"ra_deg": 10.5, "dec_deg": 20.3, "api_version": 1, "estimate": true}),
+        json!({"ra_deg": 10.5, "dec_deg": 20.3, "api_version": 3}),
+    ] {
+        let err = services()
+            .dispatch(
+                "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-queryexps".to_owned(),
+                Some(payload),
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("api_version"), "{err}");
+    }
+
+    // Giving the current version explicitly doesn't change the request hash.
+    let a = call_raw("queryexps", json!({"ra_deg": 10.5, "dec_deg": 20.3})).await;
+    let b = call_raw(
+        "queryexps",
+        json!({"ra_deg": 10.5, "dec_deg": 20.3, "api_version": 2}),
+    )
+    .await;
+    assert_eq!(a["request_hash"], b["request_hash"]);
+}
+
+#[tokio::test]
+async fn queryexps_overlaps() {
+    let result = call("queryexps", json!({"ra_deg": 10.5, "dec_deg": 20.3})).await;
+    assert_eq!(result["truncated"], false);
+
+    let mut rows = rows(&result["rows"]);
+    assert!(rows[0].starts_with("series,platenum,"));
+    rows[1..].sort();
+    assert_eq!(rows.len(), 4);
+
+    // Solved plate, with the search point at its center:
+    let cells: Vec<_> = rows[1].split(',').collect();
+    assert_eq!(&cells[..6], &["b", "12345", "1", "1", "1", "0"]);
+    assert_eq!(cells[15], "0.0");
+    assert_eq!(cells[17], "0.000");
+    assert_eq!(&cells[19..23], &["", "", "", ""]);
+    assert_eq!(&cells[23..25], &["1", "0"]);
+    assert_eq!(&cells[25..27], &["24210.16667", "2424210.66667"]);
+    assert_eq!(&cells[27..29], &["0.0000", "0.00"]);
+    assert_eq!(&cells[29..31], &["4123.5", "0.00125"]);
+    assert_eq!(cells[34], "1");
+    assert_eq!(cells[35], "deny_listed");
+
+    // The uncertainty comes from the residuals of the solution's reference
+    // stars, which show no growth toward the edges:
+    assert_eq!(cells[31], "0.88");
+
+    // The fixture series metadata puts the series at a site where the search
+    // point was just east of the meridian at the exposure midpoint:
+    assert_eq!(&cells[32..34], &["1.051", "-0.738"]);
+
+    // Plate with approximate WCS only:
+    let cells: Vec<_> = rows[2].split(',').collect();
+    assert_eq!(&cells[..6], &["b", "23456", "-1", "-1", "1", "-1"]);
+    assert!(!cells[7].is_empty());
+    assert_eq!(&cells[17..19], &["0.137", "6.229"]);
+    assert_eq!(&cells[19..23], &["12.658", "12.658", "25.4", "25.4"]);
+    assert_eq!(&cells[23..25], &["0", "0"]);
+
+    // The search point is north-east of its logged center:
+    assert_eq!(&cells[27..29], &["0.1371", "43.16"]);
+
+    // There's no mosaic, so no scan statistics, and approximate WCS gives no
+    // uncertainty estimate:
+    assert_eq!(&cells[29..32], &["", "", ""]);
+
+    // Plate with a placeholder position, one listed in the fixture series
+    // metadata:
+    let cells: Vec<_> = rows[3].split(',').collect();
+    assert_eq!(&cells[..2], &["b", "34567"]);
+    assert_eq!(&cells[7..9], &["", ""]);
+    assert_eq!(cells[15], "");
+
+    // The observing conditions only depend on the time and the search point:
+    assert_eq!(&cells[32..34], &["1.051", "-0.738"]);
+
+    // Each plate has a single overlapping exposure, which is recommended:
+    assert!(rows[1..].iter().all(|r| r.split(',').nth(34) == Some("1")));
+    assert_eq!(result["recommendation_version"], 1);
+
+    // Only the solved plate has solutions to map:
+    assert_eq!(
+        result["solutions"],
+        json!({
+            "b12345": [{"solution_number": 0, "exposure_number": 1}],
+            "b23456": [],
+            "b34567": [],
+        })
+    );
+}
+
+#[tokio::test]
+async fn queryexps_overlap_cache() {
+    // The fixture store keeps written items across test runs, so use a fresh
+    // table each time.
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let table = format!("dasch-test-overlap-{nanos}");
+    let arn = "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-queryexps";
+    let search = json!({"ra_deg": 10.5, "dec_deg": 20.3});
+
+    // The first search finds that plate b45678, far away, misses the search
+    // point, and the second one doesn't even fetch its record.
+    let mut envelopes = Vec::new();
+
+    for _ in 0..2 {
+        let svcs = services().with_overlap_cache_table(&table);
+        envelopes.push(
+            svcs.dispatch(arn.to_owned(), Some(search.clone()))
+                .await
+                .unwrap(),
+        );
+    }
+
+    assert_eq!(envelopes[0]["result"], envelopes[1]["result"]);
+    let units = |e: &Value| e["read_capacity_units"].as_f64().unwrap();
+    assert!(units(&envelopes[1]) < units(&envelopes[0]));
+}
+
+#[tokio::test]
+async fn queryexps_deny_list_omit() {
+    let result = call(
+        "queryexps",
+        json!({"ra_deg": 10.5, "dec_deg": 20.3, "deny_list": "omit"}),
+    )
+    .await;
+    let rows = rows(&result["rows"]);
+    assert_eq!(rows.len(), 3);
+    assert!(!rows.iter().any(|r| r.starts_with("b,12345,")));
+}
+
+#[tokio::test]
+async fn dry_run() {
+    let svcs = services().with_provenance_table("dasch-test-provenance");
+    let arn = |f: &str| format!("arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-{f}");
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let identity = format!("key:test-{nanos}");
+
+    let request = json!({
+        "plate_id": "b12345",
+        "solution_number": 0,
+        "center_ra_deg": 10.5,
+        "center_dec_deg": 20.3,
+        "dry_run": true,
+    });
+    let envelope = svcs
+        .dispatch_as(arn("cutout"), Some(request), None, &identity)
+        .await
+        .unwrap();
+    assert!(envelope["request"].get("dry_run").is_none());
+
+    let ops = envelope["result"]["operations"].as_array().unwrap();
+    let find = |operation: &str| {
+        ops.iter()
+            .find(|op| op["operation"] == operation)
+            .unwrap_or_else(|| panic!("no {operation} in {ops:?}"))
+    };
+
+    let get = find("GetItem");
+    assert_eq!(get["service"], "dynamodb");
+    assert_eq!(get["resource"], "dasch-dev-dr7-plates");
+    assert_eq!(get["details"]["key"]["plateId"], "b12345");
+    assert_eq!(get["performed"], true);
+    assert_eq!(find("OpenFits")["service"], "s3");
+
+    // The provenance record is reported, but not written.
+    let put = find("PutItem");
+    assert_eq!(put["resource"], "dasch-test-provenance");
+    assert_eq!(put["details"]["key"]["requester"], identity.as_str());
+    assert_eq!(put["performed"], false);
+
+    let history = svcs
+        .dispatch_as(arn("history"), Some(json!({})), None, &identity)
+        .await
+        .unwrap();
+    assert_eq!(history["result"]["records"].as_array().unwrap().len(), 0);
+
+    let result = call("describe", json!({"dry_run": true})).await;
+    assert_eq!(result["operations"].as_array().unwrap().len(), 0);
+
+    let err = services()
+        .dispatch(arn("describe"), Some(json!({"dry_run": "yes"})))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("`dry_run`"));
+}
+
+#[tokio::test]
+async fn step_functions_input() {
+    let arn = "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-querycat";
+    let request = json!({"refcat": "apass", "ra_deg": 10.5, "dec_deg": 20.3, "radius_arcsec": 10.});
+
+    let direct = call_raw("querycat", request.clone()).await;
+    let nested = services()
+        .dispatch_task(arn.to_owned(), Some(json!({ "Input": request })), None)
+        .await
+        .unwrap();
+    assert_eq!(nested["request"], direct["request"]);
+    assert_eq!(nested["result"], direct["result"]);
+
+    let err = services()
+        .dispatch_task(
+            arn.to_owned(),
+            Some(json!({ "Input": {}, "refcat": "apass" })),
+            None,
+        )
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("`refcat` alongside `Input`"));
+}
+
+#[tokio::test]
+async fn negative_cache() {
+    let svcs = services();
+    let arn = |f: &str| format!("arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-{f}");
+
+    // Nothing covers this part of the sky, so the first search looks for
+    // coverage bins that don't exist, and the second doesn't bother.
+    let search = json!({"ra_deg": 200., "dec_deg": -40., "dry_run": true});
+    let first = svcs
+        .dispatch(arn("queryexps"), Some(search.clone()))
+        .await
+        .unwrap();
+    let ops = first["result"]["operations"].as_array().unwrap();
+    assert!(ops.iter().any(|op| op["operation"] == "GetObject"));
+
+    let second = svcs.dispatch(arn("queryexps"), Some(search)).await.unwrap();
+    let ops = second["result"]["operations"].as_array().unwrap();
+    assert!(
+        !ops.iter().any(|op| op["operation"] == "GetObject"),
+        "{ops:?}"
+    );
+
+    // Missing plates are still reported as missing.
+    for _ in 0..2 {
+        let err = svcs
+            .dispatch(
+                arn("cutout"),
+                Some(json!({
+                    "plate_id": "b99999",
+                    "solution_number": 0,
+                    "center_ra_deg": 10.5,
+                    "center_dec_deg": 20.3,
+                })),
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("no such plate_id `b99999`"));
+    }
+}
+
+#[tokio::test]
+async fn plates_snapshot() {
+    let arn = |f: &str| format!("arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-{f}");
+    let svcs = services().with_plates_source(PlatesSource::Snapshot);
+
+    // Only two of the plates are in the snapshot.
+    let resp = svcs
+        .dispatch(
+            arn("queryexps"),
+            Some(json!({"ra_deg": 10.5, "dec_deg": 20.3})),
+        )
+        .await
+        .unwrap();
+    let mut lines = rows(&resp["result"]["rows"]);
+    lines[1..].sort();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[1].starts_with("b,12345,"));
+    assert!(lines[2].starts_with("b,23456,"));
+
+    let resp = svcs
+        .dispatch(
+            arn("cutout"),
+            Some(json!({
+                "plate_id": "b12345",
+                "solution_number": 0,
+                "center_ra_deg": 10.5,
+                "center_dec_deg": 20.3,
+            })),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        fits_header_f64(&cutout_fits(&resp["result"]), "NAXIS1"),
+        835.
+    );
+
+    // Queries of the plates table can't be served from the snapshot.
+    let err = svcs
+        .dispatch(
+            arn("queryepoch"),
+            Some(json!({"jd_start": 2424210.5, "jd_end": 2424211.5})),
+        )
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("snapshot"), "{err}");
+
+    // If DynamoDB works, the fallback mode doesn't need the snapshot.
+    let svcs = services().with_plates_source(PlatesSource::Fallback);
+    let resp = svcs
+        .dispatch(
+            arn("queryexps"),
+            Some(json!({"ra_deg": 10.5, "dec_deg": 20.3})),
+        )
+        .await
+        .unwrap();
+    assert_eq!(rows(&resp["result"]["rows"]).len(), 4);
+}
+
+#[tokio::test]
+async fn row_filters() {
+    let filtered = |filter: &str| json!({"ra_deg": 10.5, "dec_deg": 20.3, "filter": filter});
+
+    let result = call("queryexps", filtered("platenum in (12345, 23456)")).await;
+    let mut lines = rows(&result["rows"]);
+    lines[1..].sort();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[1].starts_with("b,12345,"));
+    assert!(lines[2].starts_with("b,23456,"));
+
+    // Comparisons with empty cells are false, whatever the operator.
+    let result = call("queryexps", filtered("!(flags == 'deny_listed')")).await;
+    assert_eq!(rows(&result["rows"]).len(), 3);
+    let result = call("queryexps", filtered("flags != 'deny_listed'")).await;
+    assert_eq!(rows(&result["rows"]).len(), 1);
+
+    let result = call(
+        "querycat",
+        json!({
+            "refcat": "apass",
+            "ra_deg": 10.5,
+            "dec_deg": 20.3,
+            "radius_arcsec": 10.,
+            "filter": "ref_number == 100001 || (raDeg > 11 && stdmag < 1)",
+        }),
+    )
+    .await;
+    let lines = rows(&result["rows"]);
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[1].split(',').nth(1), Some("100001"));
+
+    let arn = "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-queryexps";
+
+    for (filter, message) in [
+        ("exptime >=", "expected a number or a string"),
+        ("series == 'b", "unterminated string"),
+        ("seires == 'b'", "unknown column `seires`"),
+        ("exptime > 10 exptime", "unexpected text"),
+    ] {
+        let err = services()
+            .dispatch(arn.to_owned(), Some(filtered(filter)))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains(message), "{err}");
+    }
+}
+
+#[tokio::test]
+async fn queryexps_estimate() {
+    let result = call(
+        "queryexps",
+        json!({"ra_deg": 10.5, "dec_deg": 20.3, "estimate": true}),
+    )
+    .await;
+    // One candidate plate turns out not to overlap the search point, so the
+    // actual query returns only three rows.
+    assert_eq!(result["n_plates"], 4);
+    assert_eq!(result["max_rows"], 4);
+    assert!(result["approx_max_bytes"].as_u64().unwrap() > 0);
+
+    let result = call(
+        "queryexps",
+        json!({"ra_deg": 10.5, "dec_deg": 20.3, "estimate": true, "deny_list": "omit"}),
+    )
+    .await;
+    assert_eq!(result["n_plates"], 3);
+}
+
+#[tokio::test]
+async fn queryexps_coverage_v2() {
+    // This bin has both v2 and CSV files, and the v2 one wins. It has an
+    // unsolved exposure centered far from the search point, which we skip,
+    // a solved one centered in the same place, which we keep, and unsolved
+    // ones near the search point or of unknown position, which we keep.
+    let result = call(
+        "queryexps",
+        json!({"ra_deg": 199.5527, "dec_deg": -29.5, "estimate": true}),
+    )
+    .await;
+    assert_eq!(result["n_plates"], 3);
+    assert_eq!(result["max_rows"], 3);
+}
+
+#[tokio::test]
+async fn queryexps_missing_v2_bins() {
+    // This bin only has a CSV file. Once an instance has found that out, it
+    // doesn't look for the v2 file again.
+    let request = json!({"ra_deg": 10.5, "dec_deg": 20.3, "estimate": true, "dry_run": true});
+    call("queryexps", request.clone()).await;
+    let report = call("queryexps", request).await;
+    let keys: Vec<_> = report["operations"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|op| op["operation"] == "GetObject")
+        .map(|op| op["details"]["key"].as_str().unwrap())
+        .collect();
+    assert!(
+        keys.contains(&"dasch-dr7-coverage-bins/27635.csv"),
+        "{keys:?}"
+    );
+    assert!(
+        !keys.contains(&"dasch-dr7-coverage-bins/27635.bin"),
+        "{keys:?}"
+    );
+}
+
+#[tokio::test]
+async fn queryexps_staged() {
+    let inline = call("queryexps", json!({"ra_deg": 10.5, "dec_deg": 20.3})).await;
+    let staged = call(
+        "queryexps",
+        json!({"ra_deg": 10.5, "dec_deg": 20.3, "stage_results": true}),
+    )
+    .await;
+
+    let rows = inline["rows"].as_array().unwrap();
+    assert_eq!(staged["rows"], json!([]));
+    assert_eq!(staged["staged"]["n_rows"], rows.len() - 1);
+
+    // With fixtures, the "URL" is the path of the uploaded file.
+    let csv = std::fs::read_to_string(staged["staged"]["url"].as_str().unwrap()).unwrap();
+    let expected: String = rows
+        .iter()
+        .map(|r| format!("{}\n", r.as_str().unwrap()))
+        .collect();
+    assert_eq!(csv, expected);
+}
+
+#[tokio::test]
+async fn sqs_batch() {
+    let querycat =
+        json!({"refcat": "apass", "ra_deg": 10.5, "dec_deg": 20.3, "radius_arcsec": 10.});
+    let event = json!({
+        "Records": [
+            {
+                "messageId": "m1",
+                "body": json!({"service": "querycat", "output": "sqs-test/q", "request": querycat})
+                    .to_string(),
+            },
+            {
+                "messageId": "m2",
+                "body": json!({"service": "checkbin", "request": {}}).to_string(),
+            },
+        ],
+    });
+
+    let response = services().dispatch_sqs(event, None).await.unwrap();
+    assert_eq!(response["batchItemFailures"], json!([]));
+
+    // With fixtures, the results land in the scratch directory.
+    let read = |key: &str| -> Value {
+        let path = std::env::temp_dir().join(format!(
+            "dasch-science-lambda-uploads/s3/dasch-prod-user/dasch-batch-results/{key}.json"
+        ));
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    };
+
+    let direct = call("querycat", querycat).await;
+    assert_eq!(read("sqs-test/q")["result"], direct);
+
+    // Admin-only services can't be queued.
+    assert!(read("m2")["errorMessage"]
+        .as_str()
+        .unwrap()
+        .contains("only available to administrators"));
+}
+
+#[tokio::test]
+async fn request_echo() {
+    let a = call_raw("queryexps", json!({"ra_deg": 360., "dec_deg": 20.3})).await;
+    let b = call_raw(
+        "queryexps",
+        json!({"dec_deg": 20.3, "ra_deg": 0., "data_release": "dr7"}),
+    )
+    .await;
+
+    // Normalization and defaulting make these the same request.
+    assert_eq!(a["request"]["ra_deg"], 0.);
+    assert_eq!(a["request"]["data_release"], "dr7");
+    assert_eq!(a["request_hash"], b["request_hash"]);
+    assert_eq!(a["request_hash"].as_str().unwrap().len(), 64);
+}
+
+#[tokio::test]
+async fn read_capacity() {
+    let resp = call_raw(
+        "querycat",
+        json!({"refcat": "apass", "ra_deg": 10.5, "dec_deg": 20.3, "radius_arcsec": 10.}),
+    )
+    .await;
+    let units = resp["read_capacity_units"].as_f64().unwrap();
+
+    // The fixture store charges half a unit per 4 KiB read, and each bin
+    // searched costs at least one read.
+    assert!(units >= 0.5);
+    assert_eq!((units * 2.).fract(), 0.);
+
+    let resp = call_raw("describe", json!({})).await;
+    assert_eq!(resp["read_capacity_units"], 0.);
+}
+
+#[tokio::test]
+async fn querycat_neighbors() {
+    let result = call(
+        "querycat",
+        json!({
+            "refcat": "apass",
+            "ra_deg": 10.5,
+            "dec_deg": 20.3,
+            "radius_arcsec": 10.,
+            "neighbor_radius_arcsec": 20.,
+        }),
+    )
+    .await;
+    let rows = rows(&result["rows"]);
+    assert!(rows[0].ends_with(",n_neighbors"));
+
+    // One other source lies just outside the search box ...
+    let cells: Vec<_> = rows[1].split(',').collect();
+    assert_eq!(cells[1], "100001");
+    assert_eq!(cells[17], "1");
+
+    // ... and placeholder positions can't be counted.
+    let cells: Vec<_> = rows[2].split(',').collect();
+    assert_eq!(cells[17], "");
+}
+
+#[tokio::test]
+async fn querycat_wraparound() {
+    // Searches near RA = 0 cover bins at both ends of the declination row, and
+    // source 200001 is stored in both of them, at RA 0 and RA 360.
+    for ra_deg in [0., 359.9999, 0.0001] {
+        let result = call(
+            "querycat",
+            json!({"refcat": "apass", "ra_deg": ra_deg, "dec_deg": 20.3, "radius_arcsec": 10.}),
+        )
+        .await;
+        let mut ids: Vec<_> = rows(&result["rows"])[1..]
+            .iter()
+            .map(|r| r.split(',').nth(1).unwrap())
+            .collect();
+        ids.sort();
+        assert_eq!(ids, ["200001", "200002"], "ra_deg = {ra_deg}");
+    }
+}
+
+#[tokio::test]
+async fn querycat_nearest() {
+    let request = |extra: Value| {
+        let mut req = json!({"refcat": "apass", "ra_deg": 10.5, "dec_deg": 20.3});
+        req.as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        req
+    };
+    let ids = |result: &Value| -> Vec<String> {
+        rows(&result["rows"])[1..]
+            .iter()
+            .map(|r| r.split(',').nth(1).unwrap().to_owned())
+            .collect()
+    };
+
+    // The results are sorted by distance, and the search expands well beyond
+    // its starting radius to find enough of them. The source with a
+    // placeholder position is left out.
+    let result = call("querycat", request(json!({"nearest": 2}))).await;
+    assert_eq!(ids(&result), ["100001", "100004"]);
+
+    let result = call("querycat", request(json!({"nearest": 10}))).await;
+    assert_eq!(result["truncated"], false);
+    assert_eq!(ids(&result), ["100001", "100004", "100002"]);
+
+    // The radius limits the search.
+    let result = call(
+        "querycat",
+        request(json!({"nearest": 10, "radius_arcsec": 60.})),
+    )
+    .await;
+    assert_eq!(ids(&result), ["100001", "100004"]);
+
+    // Only the rows that pass the filter count.
+    let result = call(
+        "querycat",
+        request(json!({"nearest": 1, "filter": "ref_number != 100001"})),
+    )
+    .await;
+    assert_eq!(ids(&result), ["100004"]);
+
+    let arn = "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-querycat";
+
+    for (extra, message) in [
+        (json!({}), "`radius_arcsec` or `nearest`"),
+        (json!({"nearest": 0}), "nearest"),
+        (json!({"nearest": 2, "max_rows": 1}), "can't be combined"),
+        (json!({"nearest": 2, "api_version": 1}), "api_version 2"),
+    ] {
+        let err = services()
+            .dispatch(arn.to_owned(), Some(request(extra)))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains(message), "{err}");
+    }
+}
+
+#[tokio::test]
+async fn response_warnings() {
+    // The nearest-source search leaves out the source with a placeholder
+    // position, and says so.
+    let envelope = call_raw(
+        "querycat",
+        json!({"refcat": "apass", "ra_deg": 10.5, "dec_deg": 20.3, "nearest": 10}),
+    )
+    .await;
+    assert_eq!(
+        envelope["warnings"],
+        json!([{
+            "code": "positions_unknown",
+            "message": "catalog sources without measured positions were left out of the nearest-source search",
+            "count": 1,
+        }])
+    );
+
+    // Box searches report it with empty positions instead.
+    let envelope = call_raw(
+        "querycat",
+        json!({"refcat": "apass", "ra_deg": 10.5, "dec_deg": 20.3, "radius_arcsec": 300.}),
+    )
+    .await;
+    assert!(envelope.get("warnings").is_none());
+
+    // The header of b12345 has an old-style DATE-OBS, which wcsfix repairs.
+    let cutout = |features: Value| {
+        json!({
+            "plate_id": "b12345",
+            "solution_number": 0,
+            "center_ra_deg": 10.5,
+            "center_dec_deg": 20.3,
+            "width_pixels": 16,
+            "height_pixels": 16,
+            "features": features,
+        })
+    };
+    let envelope = call_raw("cutout", cutout(json!({}))).await;
+    let warnings = envelope["warnings"].as_array().unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0]["code"], "wcs_fixed");
+    assert!(warnings[0]["message"].as_str().unwrap().contains("b12345"));
+
+    let envelope = call_raw("cutout", cutout(json!({"wcs_fix": false}))).await;
+    assert!(envelope.get("warnings").is_none());
+}
+
+#[tokio::test]
+async fn querycat_continuation() {
+    let svcs = services();
+    let arn = "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-querycat";
+    let mut payload =
+        json!({"refcat": "apass", "ra_deg": 10.5, "dec_deg": 20.3, "radius_arcsec": 300.});
+
+    let full = call("querycat", payload.clone()).await;
+    assert_eq!(full["truncated"], false);
+
+    // With a deadline that has already passed, each call searches one bin and
+    // hands back a token for the rest.
+    let mut rows_seen = Vec::new();
+    let mut n_calls = 0;
+
+    loop {
+        let resp = svcs
+            .dispatch_until(arn.to_owned(), Some(payload.clone()), Some(Instant::now()))
+            .await
+            .unwrap();
+        let result = &resp["result"];
+        n_calls += 1;
+
+        let rows = rows(&result["rows"]);
+        rows_seen.extend(rows[1..].iter().map(|s| s.to_string()));
+
+        if result["truncated"] == false {
+            break;
+        }
+
+        payload["continuation"] = result["continuation"].clone();
+    }
+
+    assert!(n_calls > 1);
+    let expected: Vec<_> = rows(&full["rows"])[1..]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    assert_eq!(rows_seen, expected);
+
+    // Tokens are signed, and only good for the service and request that they
+    // were issued for.
+    payload.as_object_mut().unwrap().remove("continuation");
+    let resp = svcs
+        .dispatch_until(arn.to_owned(), Some(payload.clone()), Some(Instant::now()))
+        .await
+        .unwrap();
+    let token = resp["result"]["continuation"].as_str().unwrap().to_owned();
+
+    // Flip a bit of the work offset.
+    let mut tampered = URL_SAFE_NO_PAD.decode(&token).unwrap();
+    tampered[8] ^= 1;
+    payload["continuation"] = URL_SAFE_NO_PAD.encode(tampered).into();
+    assert!(svcs
+        .dispatch(arn.to_owned(), Some(payload.clone()))
+        .await
+        .is_err());
+
+    let err = svcs
+        .dispatch(
+            arn.replace("querycat", "queryepoch"),
+            Some(json!({"jd_start": 2415020.5, "jd_end": 2415021.5, "continuation": token})),
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "invalid continuation token");
+
+    let mut other = payload.clone();
+    other["continuation"] = token.clone().into();
+    other["radius_arcsec"] = 200.into();
+    let err = svcs
+        .dispatch(arn.to_owned(), Some(other))
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "invalid continuation token");
+
+    payload["continuation"] = token.clone().into();
+    assert!(svcs
+        .dispatch(arn.to_owned(), Some(payload.clone()))
+        .await
+        .is_ok());
+
+    // Tokens of the old, unbound format can't be used.
+    let mut old = URL_SAFE_NO_PAD.decode(&token).unwrap();
+    old[0] = 1;
+    payload["continuation"] = URL_SAFE_NO_PAD.encode(old).into();
+    let err = svcs
+        .dispatch(arn.to_owned(), Some(payload.clone()))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("start the query again"), "{err}");
+
+    payload["continuation"] = "bogus".into();
+    assert!(svcs.dispatch(arn.to_owned(), Some(payload)).await.is_err());
+}
+
+/// Run a query with `max_rows: 1`, following the continuation tokens, and
+/// check that the rows add up to those of the uncapped query.
+async fn check_max_rows(function: &str, mut payload: Value) {
+    let full = call(function, payload.clone()).await;
+    let expected: Vec<_> = rows(&full["rows"])[1..]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    assert!(expected.len() > 1);
+
+    payload["max_rows"] = 1.into();
+    let mut rows_seen = Vec::new();
+
+    loop {
+        let envelope = call_raw(function, payload.clone()).await;
+        let result = &envelope["result"];
+        let truncation = &envelope["truncation"];
+        assert_eq!(truncation["truncated"], result["truncated"]);
+
+        let rows = rows(&result["rows"]);
+        assert!(rows.len() <= 2);
+        assert_eq!(truncation["n_rows"], rows.len() - 1);
+        rows_seen.extend(rows[1..].iter().map(|s| s.to_string()));
+
+        if result["truncated"] == false {
+            assert!(truncation.get("reason").is_none());
+            assert_eq!(truncation["estimated_total_rows"], rows.len() - 1);
+            break;
+        }
+
+        assert_eq!(truncation["reason"], "max_rows");
+        assert!(truncation["estimated_total_rows"].as_u64().unwrap() > 1);
+        payload["continuation"] = result["continuation"].clone();
+    }
+
+    assert_eq!(rows_seen, expected);
+}
+
+#[tokio::test]
+async fn querycat_max_rows() {
+    check_max_rows(
+        "querycat",
+        json!({"refcat": "apass", "ra_deg": 10.5, "dec_deg": 20.3, "radius_arcsec": 300.}),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn queryexps_max_rows() {
+    check_max_rows("queryexps", json!({"ra_deg": 10.5, "dec_deg": 20.3})).await;
+}
+
+#[tokio::test]
+async fn queryepoch() {
+    // All of the fixture exposures on this day are at 1925-03-01T04:00Z.
+    let window = json!({"jd_start": 2424210.5, "jd_end": 2424211.5});
+    let result = call("queryepoch", window.clone()).await;
+    let rows = rows(&result["rows"]);
+    assert_eq!(
+        rows[0],
+        "series,platenum,expnum,ra,dec,exptime,expdate,expmjd,expjd,flags"
+    );
+    assert_eq!(rows.len(), 5);
+    assert_eq!(
+        rows[1],
+        "b,12345,1,10.500000,20.300000,30.00,1925-03-01T04:00:00Z,24210.16667,2424210.66667,deny_listed"
+    );
+
+    // Placeholder positions are left out.
+    assert!(rows[3].starts_with("b,34567,1,,,"));
+
+    let mut omit = window.clone();
+    omit["deny_list"] = "omit".into();
+    let result = call("queryepoch", omit).await;
+    assert_eq!(result["rows"].as_array().unwrap().len(), 4);
+
+    // The window is exclusive at its end.
+    let result = call(
+        "queryepoch",
+        json!({"jd_start": 2424210., "jd_end": 2424210.6}),
+    )
+    .await;
+    assert_eq!(result["rows"].as_array().unwrap().len(), 1);
+
+    check_max_rows("queryepoch", window).await;
+
+    let err = services()
+        .dispatch(
+            "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-queryepoch".to_owned(),
+            Some(json!({"jd_start": 2424210.5, "jd_end": 2424250.5})),
+        )
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("jd_end"));
+}
+
+#[tokio::test]
+async fn series_placeholders() {
+    // The series defaults list an RA of 888 as a placeholder of series b only.
+    let result = call(
+        "queryepoch",
+        json!({"jd_start": 2424300.5, "jd_end": 2424301.5}),
+    )
+    .await;
+    let rows = rows(&result["rows"]);
+    assert_eq!(rows.len(), 3);
+    assert!(
+        rows[1].starts_with("a,1234,1,888.000000,45.000000,"),
+        "{}",
+        rows[1]
+    );
+    assert!(rows[2].starts_with("b,56790,1,,,"), "{}", rows[2]);
+}
+
+#[tokio::test]
+async fn max_rows_v1() {
+    let err = services()
+        .dispatch(
+            "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-queryexps".to_owned(),
+            Some(json!({"ra_deg": 10.5, "dec_deg": 20.3, "max_rows": 1, "api_version": 1})),
+        )
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("max_rows"));
+}
+
+#[tokio::test]
+async fn lightcurve() {
+    let request = json!({
+        "refcat": "apass",
+        "ref_number": 100001,
+        "ra_deg": 10.5005,
+        "dec_deg": 20.3005,
+    });
+    let result = call("lightcurve", request.clone()).await;
+    let all = rows(&result["rows"]);
+    assert_eq!(all.len(), 4);
+    assert!(all[0].starts_with("ref_number,plate_id,"));
+    assert!(all[1..].iter().all(|r| r.starts_with("100001,")));
+
+    // Capped results continue where they left off.
+    let mut capped = request.clone();
+    capped["max_rows"] = json!(2);
+    let result = call("lightcurve", capped.clone()).await;
+    assert_eq!(rows(&result["rows"]).len(), 3);
+    assert_eq!(result["truncated"], true);
+
+    capped["continuation"] = result["continuation"].clone();
+    let result = call("lightcurve", capped).await;
+    let rest = rows(&result["rows"]);
+    assert_eq!(rest.len(), 2);
+    assert!(rest[1].contains(",b56789,"));
+
+    // A position in the wrong bin doesn't find the source.
+    let mut wrong = request;
+    wrong["dec_deg"] = json!(-20.3);
+    let err = services()
+        .dispatch(
+            "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-lightcurve".to_owned(),
+            Some(wrong),
+        )
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("no apass photometry"), "{err}");
+}
+
+#[tokio::test]
+async fn target_name() {
+    let envelope = call_raw("queryexps", json!({"target_name": "3c273"})).await;
+    assert_eq!(envelope["request"]["target_name"], "3c273");
+    assert_eq!(envelope["request"]["ra_deg"], 187.277915);
+    assert_eq!(envelope["request"]["dec_deg"], 2.052388);
+
+    let err = services()
+        .dispatch(
+            "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-cutout".to_owned(),
+            Some(json!({
+                "plate_id": "b12345",
+                "solution_number": 0,
+                "target_name": "3C 273",
+                "center_ra_deg": 10.5,
+            })),
+        )
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("`center_ra_deg`"));
+
+    let arn = "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-queryexps";
+
+    for (payload, message) in [
+        (
+            json!({"target_name": "M31", "ra_deg": 10.5, "dec_deg": 20.3}),
+            "not both",
+        ),
+        (json!({"target_name": "Nova Nowhere 1901"}), "sesame_lookup"),
+        (
+            json!({"target_name": "M31", "api_version": 1}),
+            "target_name",
+        ),
+    ] {
+        let err = services()
+            .dispatch(arn.to_owned(), Some(payload))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains(message), "{err}");
+    }
+}
+
+#[tokio::test]
+async fn unknown_release() {
+    let err = services()
+        .dispatch(
+            "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-queryexps".to_owned(),
+            Some(json!({"ra_deg": 10.5, "dec_deg": 20.3, "data_release": "dr99"})),
+        )
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("data_release"));
+}
+
+#[tokio::test]
+async fn queryexps_empty_bin() {
+    let result = call("queryexps", json!({"ra_deg": 200., "dec_deg": -40.})).await;
+    assert_eq!(rows(&result["rows"]).len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cutout_concurrent() {
+    // Cutouts of the same mosaic share pooled handles, one at a time, and
+    // should all come out the same as a lone one.
+    let svcs = services();
+    let arn = "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-cutout";
+    let payload = json!({
+        "plate_id": "b12345",
+        "solution_number": 0,
+        "center_ra_deg": 10.5,
+        "center_dec_deg": 20.3,
+    });
+
+    let lone = svcs
+        .dispatch(arn.to_owned(), Some(payload.clone()))
+        .await
+        .unwrap();
+
+    let results = futures::future::join_all(
+        (0..4).map(|_| svcs.dispatch(arn.to_owned(), Some(payload.clone()))),
+    )
+    .await;
+
+    for result in results {
+        assert_eq!(result.unwrap()["result"], lone["result"]);
+    }
+}
+
+#[tokio::test]
+async fn cutout_wcs_report() {
+    let result = call(
+        "cutout",
+        json!({
+            "plate_id": "b12345",
+            "solution_number": 0,
+            "center_ra_deg": 10.5,
+            "center_dec_deg": 20.3,
+            "wcs_relax": "strict",
+            "wcs_report": true,
+        }),
+    )
+    .await;
+
+    let fits = cutout_fits(&result);
+    let needle = b"HISTORY wcslib rejected";
+    assert!(fits.windows(needle.len()).any(|w| w == needle));
+
+    // The plate's header has an old-style DATE-OBS, which wcsfix repairs.
+    let needle = b"HISTORY wcsfix: WCS #0: datfix applied";
+    assert!(fits.windows(needle.len()).any(|w| w == needle));
+
+    // Unless the request turns that feature off.
+    let result = call(
+        "cutout",
+        json!({
+            "plate_id": "b12345",
+            "solution_number": 0,
+            "center_ra_deg": 10.5,
+            "center_dec_deg": 20.3,
+            "wcs_report": true,
+            "features": {"wcs_fix": false},
+        }),
+    )
+    .await;
+
+    let fits = cutout_fits(&result);
+    let needle = b"HISTORY wcsfix";
+    assert!(!fits.windows(needle.len()).any(|w| w == needle));
+
+    let err = services()
+        .dispatch(
+            "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-cutout".to_owned(),
+            Some(json!({
+                "plate_id": "b12345",
+                "solution_number": 0,
+                "center_ra_deg": 10.5,
+                "center_dec_deg": 20.3,
+                "wcs_relax": "lenient",
+            })),
+        )
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string().contains("unknown variant `lenient`"),
+        "{err}"
+    );
+}
+
+#[tokio::test]
+async fn cutout_overlaps() {
+    let result = call(
+        "cutout",
+        json!({
+            "plate_id": "b12345",
+            "solution_number": 0,
+            "center_ra_deg": 10.5,
+            "center_dec_deg": 20.3,
+        }),
+    )
+    .await;
+
+    let fits = cutout_fits(&result);
+    assert!(fits.starts_with(b"SIMPLE  ="));
+    assert_eq!(fits.len() % 2880, 0);
+
+    // The mosaic is uniformly 1000, which should show up in the middle of the
+    // cutout.
+    assert!(fits.windows(2).any(|w| w == 1000i16.to_be_bytes()));
+
+    // The plate is deny-listed, which is noted in the header ...
+    assert!(fits.windows(8).any(|w| w == b"DASCHBAD"));
+
+    // ... or makes the request fail, if desired.
+    let err = services()
+        .dispatch(
+            "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-cutout".to_owned(),
+            Some(json!({
+                "plate_id": "b12345",
+                "solution_number": 0,
+                "center_ra_deg": 10.5,
+                "center_dec_deg": 20.3,
+                "deny_list": "omit",
+            })),
+        )
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("deny-listed"));
+}
+
+#[tokio::test]
+async fn cutout_null_pixels() {
+    let request = |mode: &str| {
+        json!({
+            "plate_id": "b12345",
+            "solution_number": 0,
+            "center_ra_deg": 10.5,
+            "center_dec_deg": 20.3,
+            "null_pixels": mode,
+        })
+    };
+
+    let has = |fits: &[u8], text: &[u8]| fits.windows(text.len()).any(|w| w == text);
+
+    // The tiny mosaic covers only part of the cutout, so there are null pixels.
+    let fits = cutout_fits(&call("cutout", request("nan")).await);
+    assert!(has(&fits, b"BITPIX  =                  -32"));
+    assert!(!has(&fits, b"BLANK   ="));
+    assert!(fits
+        .chunks(4)
+        .any(|c| f32::from_be_bytes(c.try_into().unwrap()).is_nan()));
+
+    let fits = cutout_fits(&call("cutout", request("mask")).await);
+    assert!(has(&fits, b"XTENSION= 'IMAGE   '"));
+    assert!(has(&fits, b"EXTNAME = 'MASK    '"));
+    assert!(!has(&fits, b"BLANK   ="));
+    assert_eq!(fits.len() % 2880, 0);
+}
+
+#[tokio::test]
+async fn cutout_statistics() {
+    let request = json!({
+        "plate_id": "b12345",
+        "solution_number": 0,
+        "center_ra_deg": 10.5,
+        "center_dec_deg": 20.3,
+        "statistics": true,
+    });
+
+    let result = call("cutout", request).await;
+    assert_eq!(result["encoding"], "fits+gzip+base64");
+    let stats = &result["statistics"];
+    assert!(stats["n_pixels"].as_u64().unwrap() > 0);
+
+    let value = |key: &str| stats[key].as_f64().unwrap();
+    assert!(value("min") <= value("p1"));
+    assert!(value("p1") <= value("median"));
+    assert!(value("median") <= value("p99_5"));
+    assert!(value("p99_5") <= value("max"));
+
+    let stretch = &stats["stretch"];
+    assert_eq!(stretch["black"], stats["p1"]);
+    assert!(stretch["white"].as_f64().unwrap() > stretch["black"].as_f64().unwrap());
+    assert!(stretch["softening"].as_f64().unwrap() >= 1.);
+}
+
+#[tokio::test]
+async fn cutout_native() {
+    let request = |width: usize| {
+        json!({
+            "plate_id": "b12345",
+            "solution_number": 0,
+            "center_ra_deg": 10.5,
+            "center_dec_deg": 20.3,
+            "grid": "native",
+            "width_pixels": width,
+            "height_pixels": 8,
+            "statistics": true,
+        })
+    };
+
+    // The stamp is a rectangle of the mosaic, which is uniformly 1000 ...
+    let result = call("cutout", request(16)).await;
+    let fits = cutout_fits(&result["image"]);
+    assert_eq!(fits_header_f64(&fits, "NAXIS1"), 16.);
+    assert_eq!(fits_header_f64(&fits, "NAXIS2"), 8.);
+    assert_eq!(result["statistics"]["n_pixels"], 128);
+    assert_eq!(result["statistics"]["min"], 1000.);
+    assert_eq!(result["statistics"]["max"], 1000.);
+
+    // ... with the plate's own WCS, rather than a resampled TAN grid.
+    let has = |fits: &[u8], text: &[u8]| fits.windows(text.len()).any(|w| w == text);
+    assert!(has(&fits, b"CTYPE1  = 'RA---TPV'"));
+
+    // The center is at the reference pixel of the plate's WCS, which lands
+    // in the middle of the stamp.
+    assert_eq!(fits_header_f64(&fits, "CRPIX1"), 8.5);
+    assert_eq!(fits_header_f64(&fits, "CRPIX2"), 4.5);
+
+    // Pixels beyond the edge of the mosaic are null.
+    let result = call("cutout", request(100)).await;
+    assert_eq!(result["statistics"]["n_pixels"], 64 * 8);
+
+    // The pixel scale is that of the mosaic.
+    let mut bad = request(16);
+    bad["pixel_scale_arcsec"] = json!(2.);
+    let err = services()
+        .dispatch(
+            "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-cutout".to_owned(),
+            Some(bad),
+        )
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("`grid: native`"));
+}
+
+#[tokio::test]
+async fn cutout_compression() {
+    let request = |compression: &str, null_pixels: &str| {
+        json!({
+            "plate_id": "b12345",
+            "solution_number": 0,
+            "center_ra_deg": 10.5,
+            "center_dec_deg": 20.3,
+            "null_pixels": null_pixels,
+            "compression": compression,
+        })
+    };
+
+    let has = |fits: &[u8], text: &[u8]| fits.windows(text.len()).any(|w| w == text);
+
+    let result = call("cutout", request("rice", "mask")).await;
+    assert_eq!(result["encoding"], "fits+rice+base64");
+    let fits = STANDARD.decode(result["image"].as_str().unwrap()).unwrap();
+    assert!(has(&fits, b"NAXIS   =                    0"));
+    assert!(has(&fits, b"ZIMAGE  =                    T"));
+    assert!(has(&fits, b"ZCMPTYPE= 'RICE_1  '"));
+    assert!(has(&fits, b"EXTNAME = 'MASK    '"));
+    assert_eq!(fits.len() % 2880, 0);
+
+    // The image is mostly nulls, so either encoding compresses well; `auto`
+    // returns the smaller one.
+    let gzip_len = call("cutout", request("gzip", "blank"))
+        .await
+        .as_str()
+        .unwrap()
+        .len();
+    let rice_len = call("cutout", request("rice", "blank")).await["image"]
+        .as_str()
+        .unwrap()
+        .len();
+    let result = call("cutout", request("auto", "blank")).await;
+    assert_eq!(
+        result["image"].as_str().unwrap().len(),
+        gzip_len.min(rice_len)
+    );
+
+    // Floating-point images are always gzipped.
+    let result = call("cutout", request("auto", "nan")).await;
+    assert_eq!(result["encoding"], "fits+gzip+base64");
+
+    let err = services()
+        .dispatch(
+            "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-cutout".to_owned(),
+            Some(request("rice", "nan")),
+        )
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("integer pixels"));
+}
+
+#[tokio::test]
+async fn cutout_strip() {
+    let request = |width: usize, height: usize| {
+        json!({
+            "plate_id": "b12345",
+            "solution_number": 0,
+            "center_ra_deg": 10.5,
+            "center_dec_deg": 20.3,
+            "width_pixels": width,
+            "height_pixels": height,
+        })
+    };
+
+    let fits = cutout_fits(&call("cutout", request(1600, 101)).await);
+    let has = |text: &[u8]| fits.windows(text.len()).any(|w| w == text);
+    assert!(has(b"NAXIS1  =                 1600"));
+    assert!(has(b"NAXIS2  =                  101"));
+    assert_eq!(fits_header_f64(&fits, "CRPIX2"), 51.);
+    assert!(fits.windows(2).any(|w| w == 1000i16.to_be_bytes()));
+
+    let err = services()
+        .dispatch(
+            "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-cutout".to_owned(),
+            Some(request(3000, 3000)),
+        )
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("at most"));
+
+    // Larger fields are allowed, as long as they're sure to fit in the
+    // response.
+    let fits = cutout_fits(&call("cutout", request(1200, 1000)).await);
+    assert_eq!(fits_header_f64(&fits, "NAXIS1"), 1200.);
+
+    let mut nan = request(1200, 1000);
+    nan["null_pixels"] = "nan".into();
+    let err = services()
+        .dispatch(
+            "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-cutout".to_owned(),
+            Some(nan),
+        )
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("responses are limited"), "{err}");
+}
+
+#[tokio::test]
+async fn cutout_pixel_scale() {
+    let request = |scale: f64, version: u32| {
+        json!({
+            "plate_id": "b12345",
+            "solution_number": 0,
+            "center_ra_deg": 10.5,
+            "center_dec_deg": 20.3,
+            "width_pixels": 101,
+            "height_pixels": 101,
+            "pixel_scale_arcsec": scale,
+            "api_version": version,
+        })
+    };
+
+    let envelope = call_raw("cutout", request(3.6, 2)).await;
+    assert_eq!(envelope["request"]["pixel_scale_arcsec"], 3.6);
+    let fits = cutout_fits(&envelope["result"]);
+    assert!((fits_header_f64(&fits, "CD2_2") - 0.001).abs() < 1e-12);
+    assert!((fits_header_f64(&fits, "CD1_1") + 0.001).abs() < 1e-12);
+
+    for (scale, version, message) in [(3.6, 1, "api_version 2"), (50., 2, "pixel_scale_arcsec")] {
+        let err = services()
+            .dispatch(
+                "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-cutout".to_owned(),
+                Some(request(scale, version)),
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains(message), "{err}");
+    }
+}
+
+#[tokio::test]
+async fn cutout_ephemeris() {
+    // The exposure of plate b12345 is at MJD 24210.16667, half-way between
+    // these two points.
+    let request = |offset: f64| {
+        json!({
+            "plate_id": "b12345",
+            "solution_number": 0,
+            "ephemeris": [
+                {"mjd": 24211.16667 + offset, "ra_deg": 10.6, "dec_deg": 20.4},
+                {"mjd": 24209.16667 + offset, "ra_deg": 10.4, "dec_deg": 20.2},
+            ],
+        })
+    };
+
+    let fits = cutout_fits(&call("cutout", request(0.)).await);
+    assert!((fits_header_f64(&fits, "CRVAL1") - 10.5).abs() < 1e-6);
+    assert!((fits_header_f64(&fits, "CRVAL2") - 20.3).abs() < 1e-6);
+    assert!((fits_header_f64(&fits, "MJD-OBS") - 24210.16667).abs() < 1e-4);
+    let date_obs = b"DATE-OBS= '1925-03-01T04:00:00.000'";
+    assert!(fits.windows(date_obs.len()).any(|w| w == date_obs));
+
+    let err = services()
+        .dispatch(
+            "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-cutout".to_owned(),
+            Some(request(10.)),
+        )
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("does not cover"));
+}
+
+#[tokio::test]
+async fn cutout_header_keywords() {
+    let result = call(
+        "cutout",
+        json!({
+            "plate_id": "b12345",
+            "solution_number": 0,
+            "center_ra_deg": 10.5,
+            "center_dec_deg": 20.3,
+        }),
+    )
+    .await;
+    let fits = cutout_fits(&result);
+    let has = |card: &[u8]| fits.windows(card.len()).any(|w| w == card);
+
+    assert!(has(b"BUNIT   = 'adu     '"));
+    assert!(has(b"PLATEID = 'b12345  '"));
+    assert!(has(b"MOSDATE = '2020-01-01T00:00:00Z'"));
+    assert!(has(b"COMMENT Pixel values are scanner ADU"));
+    assert!(has(b"SERIES  = 'b       '"));
+    assert!(has(b"CREATOR = 'dasch-science-lambda "));
+    assert!(has(b"DATE    = '20"));
+    assert_eq!(fits_header_f64(&fits, "PLATENUM"), 12345.);
+    assert_eq!(fits_header_f64(&fits, "SOLNUM"), 0.);
+    assert_eq!(fits_header_f64(&fits, "REQRA"), 10.5);
+    assert_eq!(fits_header_f64(&fits, "REQDEC"), 20.3);
+    assert_eq!(fits_header_f64(&fits, "MOSNUM"), 1.);
+    assert_eq!(fits_header_f64(&fits, "SCANNUM"), 1.);
+    assert_eq!(fits_header_f64(&fits, "BKGLEVEL"), 4123.5);
+    assert_eq!(fits_header_f64(&fits, "SATFRAC"), 0.00125);
+}
+
+#[tokio::test]
+async fn cutout_series() {
+    // Plate b56789 has two exposures, an hour apart.
+    let request = |extra: Value| {
+        let mut req = json!({
+            "plate_id": "b56789",
+            "center_ra_deg": 10.5,
+            "center_dec_deg": 20.3,
+            "width_pixels": 200,
+            "height_pixels": 100,
+        });
+        req.as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        req
+    };
+
+    let result = call("cutout", request(json!({"solution_numbers": [1, 0]}))).await;
+    let stamps = result.as_array().unwrap();
+    assert_eq!(stamps.len(), 2);
+
+    for (stamp, solnum) in stamps.iter().zip([1, 0]) {
+        assert_eq!(stamp["solution_number"], solnum);
+        assert_eq!(stamp["encoding"], "fits+gzip+base64");
+        let fits = cutout_fits(&stamp["image"]);
+        assert_eq!(fits_header_f64(&fits, "SOLNUM"), solnum as f64);
+        assert_eq!(fits_header_f64(&fits, "NAXIS1"), 200.);
+    }
+
+    let date_obs = b"DATE-OBS= '1925-03-01T05:00:00.000'";
+    let fits = cutout_fits(&stamps[0]["image"]);
+    assert!(fits.windows(date_obs.len()).any(|w| w == date_obs));
+
+    // The stamps are the same as the individual cutouts.
+    let single = call("cutout", request(json!({"solution_number": 0}))).await;
+    assert_eq!(stamps[1]["image"], single);
+
+    let result = call(
+        "cutout",
+        request(json!({"solution_numbers": [0, 1], "estimate": true})),
+    )
+    .await;
+    let estimates = result.as_array().unwrap();
+    assert_eq!(estimates.len(), 2);
+    assert_eq!(estimates[1]["solution_number"], 1);
+    assert_eq!(estimates[1]["output_width"], 200);
+
+    let arn = "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-cutout";
+
+    for (extra, message) in [
+        (json!({"solution_numbers": [0, 2]}), "only has 2 solutions"),
+        (json!({"solution_numbers": [0, 0]}), "more than once"),
+        (json!({"solution_numbers": []}), "between 1 and 16"),
+        (
+            json!({"solution_numbers": [0], "solution_number": 0}),
+            "exactly one of",
+        ),
+        (json!({}), "exactly one of"),
+        (
+            json!({"solution_numbers": [0], "api_version": 1}),
+            "api_version 2",
+        ),
+        (
+            json!({"solution_numbers": [0, 1, 2], "width_pixels": 835, "height_pixels": 835}),
+            "at most",
+        ),
+    ] {
+        let err = services()
+            .dispatch(arn.to_owned(), Some(request(extra)))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains(message), "{err}");
+    }
+}
+
+#[tokio::test]
+async fn cutout_all_solutions() {
+    let request = |extra: Value| {
+        let mut req = json!({
+            "plate_id": "b56789",
+            "all_solutions": true,
+            "center_ra_deg": 10.5,
+            "center_dec_deg": 20.3,
+            "width_pixels": 200,
+            "height_pixels": 100,
+        });
+        req.as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        req
+    };
+
+    let has = |fits: &[u8], text: &[u8]| fits.windows(text.len()).any(|w| w == text);
+
+    // Both exposures of b56789 cover the target, so each gets an extension
+    // after the null primary HDU.
+    let result = call("cutout", request(json!({}))).await;
+    let fits = cutout_fits(&result);
+    assert!(fits.starts_with(b"SIMPLE  ="));
+    assert!(has(&fits, b"NAXIS   =                    0"));
+    assert!(has(&fits, b"EXTNAME = 'SOL0    '"));
+    assert!(has(&fits, b"EXTNAME = 'SOL1    '"));
+    assert!(has(&fits, b"DATE-OBS= '1925-03-01T05:00:00.000'"));
+    assert_eq!(fits.len() % 2880, 0);
+
+    let result = call(
+        "cutout",
+        request(json!({"null_pixels": "mask", "compression": "rice"})),
+    )
+    .await;
+    assert_eq!(result["encoding"], "fits+rice+base64");
+    let fits = STANDARD.decode(result["image"].as_str().unwrap()).unwrap();
+    assert!(has(&fits, b"ZCMPTYPE= 'RICE_1  '"));
+    assert!(has(&fits, b"EXTNAME = 'SOL1    '"));
+    assert!(has(&fits, b"EXTNAME = 'MASK1   '"));
+
+    // Estimates are a series, listing the solutions that would be included.
+    let result = call("cutout", request(json!({"estimate": true}))).await;
+    let estimates = result.as_array().unwrap();
+    assert_eq!(estimates.len(), 2);
+    assert_eq!(estimates[1]["solution_number"], 1);
+
+    let arn = "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-cutout";
+
+    for (extra, message) in [
+        (json!({"solution_number": 0}), "can't be combined"),
+        (
+            json!({"statistics": true}),
+            "can't be used with `all_solutions`",
+        ),
+        (json!({"api_version": 1}), "api_version 2"),
+        (
+            json!({"center_ra_deg": 10.5, "center_dec_deg": 25.}),
+            "none of the solutions",
+        ),
+    ] {
+        let err = services()
+            .dispatch(arn.to_owned(), Some(request(extra)))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains(message), "{err}");
+    }
+}
+
+#[tokio::test]
+async fn cutout_preview() {
+    let request = |extra: Value| {
+        let mut req = json!({
+            "plate_id": "b56789",
+            "solution_number": 0,
+            "center_ra_deg": 10.5,
+            "center_dec_deg": 20.3,
+            "width_pixels": 64,
+            "height_pixels": 32,
+        });
+        req.as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        req
+    };
+
+    let result = call("cutout", request(json!({"output_format": "png"}))).await;
+    assert_eq!(result["encoding"], "png+base64");
+    assert!(result.get("statistics").is_none());
+    let png = STANDARD.decode(result["image"].as_str().unwrap()).unwrap();
+    assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+    assert_eq!(&png[16..24], &[0, 0, 0, 64, 0, 0, 0, 32]);
+
+    let result = call(
+        "cutout",
+        request(json!({"output_format": "jpeg", "statistics": true})),
+    )
+    .await;
+    assert_eq!(result["encoding"], "jpeg+base64");
+    assert!(result["statistics"].is_object());
+    let jpeg = STANDARD.decode(result["image"].as_str().unwrap()).unwrap();
+    assert!(jpeg.starts_with(&[0xff, 0xd8]));
+    assert!(jpeg.ends_with(&[0xff, 0xd9]));
+
+    let arn = "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-cutout";
+
+    for (extra, message) in [
+        (
+            json!({"output_format": "png", "compression": "rice"}),
+            "only apply to `output_format: fits`",
+        ),
+        (
+            json!({"output_format": "jpeg", "api_version": 1}),
+            "api_version 2",
+        ),
+    ] {
+        let err = services()
+            .dispatch(arn.to_owned(), Some(request(extra)))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains(message), "{err}");
+    }
+}
+
+#[tokio::test]
+async fn cutout_soda_shapes() {
+    let arn = "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-cutout";
+
+    let request = |extra: Value| {
+        let mut req = json!({
+            "plate_id": "b56789",
+            "solution_number": 0,
+        });
+        req.as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        req
+    };
+
+    // SODA clients send upper-case parameter names.
+    let query = query_request(
+        arn,
+        [
+            ("plate_id", "b56789"),
+            ("solution_number", "0"),
+            ("CIRCLE", "10.5 20.3 0.0199"),
+        ],
+    )
+    .unwrap();
+    assert_eq!(query, request(json!({"circle": "10.5 20.3 0.0199"})));
+
+    // The circle becomes the square stamp that contains it, at the default
+    // scale of 0.0004 degrees per pixel, and the echoed request shows it.
+    let envelope = call_raw("cutout", query).await;
+    assert!(envelope["request"].get("circle").is_none());
+    assert_eq!(envelope["request"]["center_ra_deg"], 10.5);
+    assert_eq!(envelope["request"]["width_pixels"], 100);
+    let fits = cutout_fits(&envelope["result"]);
+    assert_eq!(fits_header_f64(&fits, "NAXIS1"), 100.);
+    assert_eq!(fits_header_f64(&fits, "NAXIS2"), 100.);
+
+    // A polygon becomes its bounding box, which is narrower in RA.
+    let result = call(
+        "cutout",
+        request(json!({"polygon": "10.48 20.28 10.52 20.28 10.52 20.32 10.48 20.32"})),
+    )
+    .await;
+    let fits = cutout_fits(&result);
+    let width = fits_header_f64(&fits, "NAXIS1");
+    let height = fits_header_f64(&fits, "NAXIS2");
+    assert!((93. ..=95.).contains(&width), "{width}");
+    assert!((100. ..=101.).contains(&height), "{height}");
+
+    for (extra, message) in [
+        (
+            json!({"circle": "10.5 20.3 0.02", "width_pixels": 50}),
+            "can't be combined",
+        ),
+        (
+            json!({"circle": "10.5 20.3 0.02", "polygon": "10 20 11 20 11 21"}),
+            "at most one of",
+        ),
+        (json!({"circle": "10.5 20.3"}), "must be three numbers"),
+        (
+            json!({"circle": "10.5 20.3 0"}),
+            "illegal `circle` parameter",
+        ),
+        (json!({"polygon": "10 20 11 20"}), "between 3 and"),
+        (
+            json!({"circle": "10.5 20.3 0.02", "grid": "native"}),
+            "can't be used with `grid: native`",
+        ),
+        (
+            json!({"circle": "10.5 20.3 0.02", "api_version": 1}),
+            "api_version 2",
+        ),
+        (json!({"circle": "10.5 20.3 5"}), "at most 3340 are allowed"),
+    ] {
+        let err = services()
+            .dispatch(arn.to_owned(), Some(request(extra)))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains(message), "{err}");
+    }
+}
+
+#[tokio::test]
+async fn cutout_batch() {
+    let single = call(
+        "cutout",
+        json!({
+            "plate_id": "b56789",
+            "solution_number": 0,
+            "center_ra_deg": 10.5,
+            "center_dec_deg": 20.3,
+            "width_pixels": 64,
+            "height_pixels": 32,
+        }),
+    )
+    .await;
+
+    let result = call(
+        "cutout",
+        json!({
+            "plate_id": "b56789",
+            "solution_number": 0,
+            "centers": [
+                {"ra_deg": 10.5, "dec_deg": 20.3},
+                {"ra_deg": 10.51, "dec_deg": 20.29},
+            ],
+            "width_pixels": 64,
+            "height_pixels": 32,
+        }),
+    )
+    .await;
+    let stamps = result.as_array().unwrap();
+    assert_eq!(stamps.len(), 2);
+    assert_eq!(stamps[0]["plate_id"], "b56789");
+    assert_eq!(stamps[0]["solution_number"], 0);
+    assert_eq!(stamps[1]["center_ra_deg"], 10.51);
+    assert_eq!(stamps[0]["image"], single);
+    assert_ne!(stamps[1]["image"], single);
+
+    // A stamp that fails doesn't fail the rest of the batch.
+    let result = call(
+        "cutout",
+        json!({
+            "plates": [
+                {"plate_id": "b56789", "solution_number": 1},
+                {"plate_id": "b12345", "solution_number": 0},
+            ],
+            "center_ra_deg": 10.5,
+            "center_dec_deg": 20.3,
+            "width_pixels": 64,
+            "height_pixels": 32,
+            "deny_list": "omit",
+        }),
+    )
+    .await;
+    let stamps = result.as_array().unwrap();
+    assert_eq!(stamps.len(), 2);
+    let fits = cutout_fits(&stamps[0]["image"]);
+    assert_eq!(fits_header_f64(&fits, "SOLNUM"), 1.);
+    assert_eq!(stamps[1]["plate_id"], "b12345");
+    assert!(stamps[1].get("image").is_none());
+    assert!(stamps[1]["error"].as_str().unwrap().contains("deny-listed"));
+
+    let arn = "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-cutout";
+    let centers: Vec<Value> = (0..300)
+        .map(|_| json!({"ra_deg": 10.5, "dec_deg": 20.3}))
+        .collect();
+
+    for (req, message) in [
+        (
+            json!({"plate_id": "b56789", "solution_number": 0, "centers": [], "api_version": 1}),
+            "api_version 2",
+        ),
+        (
+            json!({"plate_id": "b56789", "solution_number": 0, "centers": centers.clone()}),
+            "between 1 and 256",
+        ),
+        (
+            json!({
+                "plate_id": "b56789",
+                "solution_number": 0,
+                "centers": [{"ra_deg": 10.5, "dec_deg": 20.3}],
+                "center_ra_deg": 10.5,
+                "center_dec_deg": 20.3,
+            }),
+            "can't be combined",
+        ),
+        (
+            json!({
+                "plate_id": "b56789",
+                "plates": [{"plate_id": "b56789", "solution_number": 0}],
+                "center_ra_deg": 10.5,
+                "center_dec_deg": 20.3,
+            }),
+            "can't be combined",
+        ),
+        (
+            json!({
+                "plate_id": "b56789",
+                "solution_number": 0,
+                "centers": [{"ra_deg": 10.5, "dec_deg": 120.}],
+            }),
+            "`centers` item 0",
+        ),
+        (
+            json!({
+                "plate_id": "b56789",
+                "solution_number": 0,
+                "centers": centers[..3].to_vec(),
+                "width_pixels": 1000,
+                "height_pixels": 1000,
+            }),
+            "requested batch could take",
+        ),
+    ] {
+        let err = services()
+            .dispatch(arn.to_owned(), Some(req))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains(message), "{err}");
+    }
+}
+
+#[tokio::test]
+async fn cutout_batch_staged() {
+    let request = json!({
+        "plate_id": "b56789",
+        "solution_number": 0,
+        "centers": [
+            {"ra_deg": 10.5, "dec_deg": 20.3},
+            {"ra_deg": 10.51, "dec_deg": 20.29},
+        ],
+        "width_pixels": 64,
+        "height_pixels": 32,
+    });
+    let inline = call("cutout", request.clone()).await;
+
+    let mut staged = request;
+    staged["stage_results"] = json!(true);
+    let result = call("cutout", staged).await;
+    let stamps = result["stamps"].as_array().unwrap();
+    assert_eq!(stamps.len(), 2);
+
+    // With the fixture store, the download URLs are local paths.
+    for (stamp, inline) in stamps.iter().zip(inline.as_array().unwrap()) {
+        assert!(stamp.get("image").is_none());
+        assert_eq!(stamp["encoding"], "fits+gzip");
+        let url = stamp["url"].as_str().unwrap();
+        assert!(url.ends_with(".fits.gz"), "{url}");
+        assert_eq!(
+            std::fs::read(url).unwrap(),
+            STANDARD.decode(inline["image"].as_str().unwrap()).unwrap()
+        );
+    }
+
+    let manifest: Value =
+        serde_json::from_slice(&std::fs::read(result["manifest_url"].as_str().unwrap()).unwrap())
+            .unwrap();
+    assert_eq!(&manifest, &result["stamps"]);
+}
+
+#[tokio::test]
+async fn cutout_estimate() {
+    let result = call(
+        "cutout",
+        json!({
+            "plate_id": "b12345",
+            "solution_number": 0,
+            "center_ra_deg": 10.5,
+            "center_dec_deg": 20.3,
+            "estimate": true,
+        }),
+    )
+    .await;
+
+    // The whole tiny mosaic is within the cutout region.
+    let width = result["source_width"].as_u64().unwrap();
+    let height = result["source_height"].as_u64().unwrap();
+    assert!(width > 0 && width <= 64);
+    assert!(height > 0 && height <= 64);
+    assert_eq!(result["source_bytes"], 2 * width * height);
+
+    let coverage = result["coverage"].as_f64().unwrap();
+    assert!(coverage > 0. && coverage <= 1.);
+    assert_eq!(result["output_width"], 835);
+    assert_eq!(result["output_height"], 835);
+}
+
+#[tokio::test]
+async fn cutout_staged() {
+    let request = json!({
+        "plate_id": "b12345",
+        "solution_number": 0,
+        "center_ra_deg": 10.5,
+        "center_dec_deg": 20.3,
+        "width_pixels": 64,
+        "height_pixels": 32,
+    });
+    let inline = call("cutout", request.clone()).await;
+
+    let mut staged = request;
+    staged["stage_results"] = json!(true);
+    let result = call("cutout", staged.clone()).await;
+    let stamps = result["stamps"].as_array().unwrap();
+    assert_eq!(stamps.len(), 1);
+    assert_eq!(stamps[0]["encoding"], "fits+gzip");
+
+    // With the fixture store, the download URLs are local paths.
+    let url = stamps[0]["url"].as_str().unwrap();
+    assert!(url.ends_with("/00000.fits.gz"), "{url}");
+    assert_eq!(
+        std::fs::read(url).unwrap(),
+        STANDARD.decode(inline.as_str().unwrap()).unwrap()
+    );
+
+    let manifest: Value =
+        serde_json::from_slice(&std::fs::read(result["manifest_url"].as_str().unwrap()).unwrap())
+            .unwrap();
+    assert_eq!(&manifest, &result["stamps"]);
+
+    for (field, value, message) in [
+        ("estimate", json!(true), "`estimate`"),
+        ("api_version", json!(1), "api_version"),
+    ] {
+        let mut bad = staged.clone();
+        bad[field] = value;
+        let err = services()
+            .dispatch(
+                "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-cutout".to_owned(),
+                Some(bad),
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains(message), "{err}");
+    }
+}
+
+#[tokio::test]
+async fn cutout_provenance() {
+    let svcs = services().with_provenance_table("dasch-test-provenance");
+    let arn = |f: &str| format!("arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-{f}");
+
+    // The fixture store keeps written items across test runs, so use a fresh
+    // identity every time.
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let identity = format!("key:test-{nanos}");
+    let request = json!({
+        "plate_id": "b12345",
+        "solution_number": 0,
+        "center_ra_deg": 10.5,
+        "center_dec_deg": 20.3,
+    });
+
+    let mut estimate = request.clone();
+    estimate["estimate"] = true.into();
+    svcs.dispatch_as(arn("cutout"), Some(estimate), None, &identity)
+        .await
+        .unwrap();
+    let cutout = svcs
+        .dispatch_as(arn("cutout"), Some(request), None, &identity)
+        .await
+        .unwrap();
+
+    // Only the real cutout is recorded.
+    let history = svcs
+        .dispatch_as(arn("history"), Some(json!({})), None, &identity)
+        .await
+        .unwrap();
+    let records = history["result"]["records"].as_array().unwrap();
+    assert_eq!(records.len(), 1);
+    let record = &records[0];
+    assert_eq!(record["plate_id"], "b12345");
+    assert_eq!(record["solution_number"], 0);
+    assert_eq!(record["center_ra_deg"], 10.5);
+    assert_eq!(record["request_hash"], cutout["request_hash"]);
+
+    // The recorded request re-creates the same product.
+    let again = svcs
+        .dispatch_as(
+            arn("cutout"),
+            Some(record["request"].clone()),
+            None,
+            &identity,
+        )
+        .await
+        .unwrap();
+    assert_eq!(again["result"], cutout["result"]);
+
+    let history = svcs
+        .dispatch_as(
+            arn("history"),
+            Some(json!({"limit": 1, "request_hash": cutout["request_hash"]})),
+            None,
+            &identity,
+        )
+        .await
+        .unwrap();
+    let records = history["result"]["records"].as_array().unwrap();
+    assert_eq!(records.len(), 1);
+    assert!(records[0]["request_id"] != record["request_id"]);
+
+    // Anonymous clients can't see the history, and neither can anyone if it
+    // isn't recorded.
+    let err = svcs
+        .dispatch(arn("history"), Some(json!({})))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("identified clients"), "{err}");
+
+    let err = services()
+        .dispatch_as(arn("history"), Some(json!({})), None, &identity)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("does not record"), "{err}");
+}
+
+#[tokio::test]
+async fn plate_id_parsing() {
+    let svcs = services();
+    let arn = "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-selftest";
+
+    let resp = svcs
+        .dispatch(arn.to_owned(), Some(json!({"plate_id": "B 12345"})))
+        .await
+        .unwrap();
+    assert_eq!(resp["request"]["plate_id"], "b12345");
+
+    for (bad, expected) in [
+        ("b12x45", "malformed plate_id"),
+        ("zz12345", "unknown plate series"),
+    ] {
+        let err = svcs
+            .dispatch(arn.to_owned(), Some(json!({ "plate_id": bad })))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains(expected), "{err}");
+    }
+}
+
+#[tokio::test]
+async fn cutout_bad_solution() {
+    let err = services()
+        .dispatch(
+            "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-cutout".to_owned(),
+            Some(json!({
+                "plate_id": "b12345",
+                "solution_number": 3,
+                "center_ra_deg": 10.5,
+                "center_dec_deg": 20.3,
+            })),
+        )
+        .await
+        .unwrap_err();
+
+    let body = error_body(&err);
+    assert!(body["errorMessage"]
+        .as_str()
+        .unwrap()
+        .contains("only has 1 solutions"));
+    assert_eq!(
+        body["errorDetails"]["valid_solutions"],
+        json!([{"solution_number": 0, "exposure_number": 1, "ra_deg": 10.5, "dec_deg": 20.3}])
+    );
+}
+
+#[tokio::test]
+async fn validation_details() {
+    let err = services()
+        .dispatch(
+            "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-querycat".to_owned(),
+            Some(json!({
+                "refcat": "apass",
+                "ra_deg": 10.5,
+                "dec_deg": 20.3,
+                "radius_arcsec": 5000.,
+            })),
+        )
+        .await
+        .unwrap_err();
+
+    let body = error_body(&err);
+    assert_eq!(
+        body["errorDetails"],
+        json!({
+            "parameter": "radius_arcsec",
+            "value": 5000.,
+            "exclusiveMinimum": 0.,
+            "exclusiveMaximum": 3600.,
+        })
+    );
+
+    let err = services()
+        .dispatch(
+            "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-queryexps".to_owned(),
+            Some(json!({"ra_deg": 10.5, "dec_deg": -91.})),
+        )
+        .await
+        .unwrap_err();
+
+    let body = error_body(&err);
+    assert_eq!(body["errorDetails"]["parameter"], "dec_deg");
+    assert_eq!(body["errorDetails"]["minimum"], -90.);
+    assert_eq!(body["errorDetails"]["maximum"], 90.);
+}
+
+#[tokio::test]
+async fn selftest_passes() {
+    let result = call("selftest", json!({"plate_id": "b12345"})).await;
+    assert_eq!(result["passed"], true, "{result}");
+    assert_eq!(result["checks"].as_array().unwrap().len(), 3);
+
+    let result = call("selftest", json!({"plate_id": "b23456"})).await;
+    assert_eq!(result["passed"], false);
+}
+
+#[tokio::test]
+async fn checkbin() {
+    let arn = |f: &str| format!("arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-{f}");
+    let svcs = services();
+    let result = svcs
+        .dispatch_as(
+            arn("checkbin"),
+            Some(json!({"refcat": "apass", "total_bin": 113790061})),
+            None,
+            OPERATOR,
+        )
+        .await
+        .unwrap();
+    let result = &result["result"];
+    assert_eq!(result["n_sources"], 4);
+    assert_eq!(result["n_checked"], 3);
+    assert_eq!(result["n_unpositioned"], 1);
+
+    // The source at RA = 10.6 is several bins east of the others.
+    let mismatches = result["mismatches"].as_array().unwrap();
+    assert_eq!(mismatches.len(), 1);
+    assert_eq!(mismatches[0]["ra_deg"], 10.6);
+    assert_eq!(mismatches[0]["stored_bin"], 113790061);
+    assert!(mismatches[0]["computed_bin"].as_u64().unwrap() > 113790061);
+
+    let err = svcs
+        .dispatch_as(
+            arn("checkbin"),
+            Some(json!({"refcat": "apass", "total_bin": 999999999})),
+            None,
+            OPERATOR,
+        )
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("total_bin"));
+}
+
+#[tokio::test]
+async fn checkbin_is_admin_only() {
+    let svcs = services();
+    let arn = "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-";
+    assert!(!svcs.is_authorized(&format!("{arn}checkbin"), "key:abc123"));
+    assert!(!svcs.is_authorized(&format!("{arn}checkbin"), "ip:10.0.0.1"));
+    assert!(svcs.is_authorized(&format!("{arn}checkbin"), OPERATOR));
+    assert!(svcs.is_authorized(&format!("{arn}querycat"), "ip:10.0.0.1"));
+
+    // The check is made when dispatching, whichever server the request came
+    // through.
+    let request = json!({"refcat": "apass", "total_bin": 113790061});
+    let err = svcs
+        .dispatch(format!("{arn}checkbin"), Some(request.clone()))
+        .await
+        .unwrap_err();
+    assert!(err.is::<ForbiddenError>(), "{err}");
+
+    let err = svcs
+        .dispatch_as(format!("{arn}checkbin"), Some(request), None, "key:abc123")
+        .await
+        .unwrap_err();
+    assert!(err.is::<ForbiddenError>(), "{err}");
+}
+
+#[tokio::test]
+async fn targetlist() {
+    let reserved = call("targetlist", json!({})).await;
+    let upload_id = reserved["upload_id"].as_str().unwrap();
+    assert_eq!(reserved["content_type"], "text/csv");
+
+    // With the fixture store, the "upload URL" is a local path.
+    std::fs::write(
+        reserved["upload_url"].as_str().unwrap(),
+        "name,ra_deg,dec_deg\nfirst,10.5,20.3\nsecond,360,-45\n",
+    )
+    .unwrap();
+
+    let registered = call("targetlist", json!({ "upload_id": upload_id })).await;
+    assert_eq!(registered["n_targets"], 2);
+    assert_eq!(
+        registered["preview"][1],
+        json!({"name": "second", "ra_deg": 0., "dec_deg": -45.})
+    );
+
+    // Batch cutouts can take their centers from the list.
+    let result = call(
+        "cutout",
+        json!({
+            "plate_id": "b56789",
+            "solution_number": 0,
+            "target_list": upload_id,
+            "width_pixels": 64,
+            "height_pixels": 32,
+        }),
+    )
+    .await;
+    let stamps = result.as_array().unwrap();
+    assert_eq!(stamps.len(), 2);
+    assert_eq!(stamps[0]["center_ra_deg"], 10.5);
+    assert!(stamps[0]["image"].is_string());
+
+    // The output of querycat can be uploaded as it is.
+    let querycat = call(
+        "querycat",
+        json!({"refcat": "apass", "ra_deg": 10.5, "dec_deg": 20.3, "radius_arcsec": 10.}),
+    )
+    .await;
+    let catalog = rows(&querycat["rows"]);
+    let reserved = call("targetlist", json!({})).await;
+    std::fs::write(reserved["upload_url"].as_str().unwrap(), catalog.join("\n")).unwrap();
+    let registered = call("targetlist", json!({ "upload_id": reserved["upload_id"] })).await;
+    // One of the sources has no known position, and is skipped.
+    assert_eq!(registered["n_targets"], catalog.len() - 2);
+    let header: Vec<_> = catalog[0].split(',').collect();
+    let first: Vec<_> = catalog[1].split(',').collect();
+    let i_ra = header.iter().position(|c| *c == "raDeg").unwrap();
+    assert_eq!(
+        registered["preview"][0]["ra_deg"].as_f64().unwrap(),
+        first[i_ra].parse::<f64>().unwrap()
+    );
+
+    let svcs = services();
+    let arn = "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-targetlist";
+
+    for (upload_id, message) in [
+        ("0-0", "nothing has been uploaded"),
+        ("../plates", "invalid upload_id"),
+    ] {
+        let err = svcs
+            .dispatch(arn.to_owned(), Some(json!({ "upload_id": upload_id })))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains(message), "{err}");
+    }
+
+    // Oversized lists are refused. Blank lines would otherwise be skipped.
+    let reserved = call("targetlist", json!({})).await;
+    let path = reserved["upload_url"].as_str().unwrap();
+    let mut big = b"ra_deg,dec_deg\n".to_vec();
+    big.resize((32 << 20) + 1, b'\n');
+    std::fs::write(path, big).unwrap();
+    let err = svcs
+        .dispatch(
+            arn.to_owned(),
+            Some(json!({ "upload_id": reserved["upload_id"] })),
+        )
+        .await
+        .unwrap_err();
+    std::fs::remove_file(path).unwrap();
+    assert!(err.to_string().contains("bigger than"), "{err}");
+}
+
+#[tokio::test]
+async fn residuals() {
+    let table = call(
+        "residuals",
+        json!({"plate_id": "b12345", "solution_number": 0}),
+    )
+    .await;
+    assert_eq!(table["n_stars"], 3);
+    assert_eq!(table["rows"][0], "x_pix,y_pix,dra_arcsec,ddec_arcsec");
+    assert_eq!(table["rows"][2], "40.25,30.5,-1.2,0.5");
+    let rms = table["rms_arcsec"].as_f64().unwrap();
+    assert!((rms - (2.3f64 / 3.).sqrt()).abs() < 1e-9, "{rms}");
+
+    let plot = call(
+        "residuals",
+        json!({"plate_id": "b12345", "solution_number": 0, "format": "png"}),
+    )
+    .await;
+    let png = STANDARD.decode(plot["png"].as_str().unwrap()).unwrap();
+    assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+    assert!(png.ends_with(b"IEND\xae\x42\x60\x82"));
+    assert!(plot.get("rows").is_none());
+
+    for (payload, message) in [
+        (
+            json!({"plate_id": "b23456", "solution_number": 0}),
+            "no registered astrometric solutions",
+        ),
+        (
+            json!({"plate_id": "b12345", "solution_number": 1}),
+            "only has 1 solutions",
+        ),
+    ] {
+        let err = services()
+            .dispatch(
+                "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-residuals".to_owned(),
+                Some(payload),
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains(message), "{err}");
+    }
+}
+
+#[tokio::test]
+async fn photcal() {
+    let result = call("photcal", json!({"plate_id": "b12345"})).await;
+    let cals = result["calibrations"].as_array().unwrap();
+    assert_eq!(cals.len(), 2);
+    assert_eq!(cals[0]["refcat"], "apass");
+    assert_eq!(cals[0]["zero_point_mag"], 21.37);
+    assert_eq!(cals[0]["n_calibrators"], 412);
+
+    // The ATLAS calibration failed, so it has no fit values.
+    assert_eq!(cals[1]["refcat"], "atlas");
+    assert_eq!(cals[1]["rms_mag"], Value::Null);
+
+    let err = services()
+        .dispatch(
+            "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-photcal".to_owned(),
+            Some(json!({"plate_id": "b12345", "solution_number": 2})),
+        )
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string().contains("calibrated solutions: [0]"),
+        "{err}"
+    );
+}
+
+#[tokio::test]
+async fn exportheaders() {
+    let result = call(
+        "exportheaders",
+        json!({"plate_ids": ["b12345", "B 23456", "b12345"]}),
+    )
+    .await;
+    assert_eq!(result["n_plates"], 1);
+    assert_eq!(result["missing"], json!(["b23456"]));
+    assert_eq!(result["truncated"], false);
+
+    // With fixtures, the "URL" is the path of the uploaded archive.
+    let tar = std::fs::read(result["url"].as_str().unwrap()).unwrap();
+    assert_eq!(tar.len() % 512, 0);
+    assert_eq!(&tar[..15], b"b12345_b01.hdr\0");
+    assert_eq!(&tar[257..263], b"ustar\0");
+
+    let size_text = std::str::from_utf8(&tar[124..135]).unwrap();
+    let size = usize::from_str_radix(size_text, 8).unwrap();
+    assert!(tar[512..512 + size].starts_with(b"WCSAXES ="));
+    assert!(tar[tar.len() - 1024..].iter().all(|&b| b == 0));
+
+    let by_position = call("exportheaders", json!({"ra_deg": 10.5, "dec_deg": 20.3})).await;
+    assert!(by_position["n_plates"].as_u64().unwrap() >= 1);
+
+    let err = services()
+        .dispatch(
+            "arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-exportheaders".to_owned(),
+            Some(json!({"plate_ids": ["b12345"], "ra_deg": 10.5, "dec_deg": 20.3})),
+        )
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("must specify either"), "{err}");
+}
+
+#[tokio::test]
+async fn describe() {
+    let result = call("describe", json!({})).await;
+    let handlers = result["handlers"].as_array().unwrap();
+    let names: Vec<_> = handlers
+        .iter()
+        .map(|h| h["name"].as_str().unwrap())
+        .collect();
+
+    for name in [
+        "cutout",
+        "querycat",
+        "queryexps",
+        "queryepoch",
+        "selftest",
+        "checkbin",
+        "targetlist",
+        "residuals",
+        "photcal",
+        "history",
+        "exportheaders",
+        "describe",
+    ] {
+        assert!(names.contains(&name), "missing {name}");
+    }
+
+    assert_eq!(
+        result["features"],
+        json!({"wcs_fix": true, "sesame_lookup": false})
+    );
+
+    let querycat = &handlers[names.iter().position(|n| *n == "querycat").unwrap()];
+    assert_eq!(querycat["request_schema"]["type"], "object");
+    assert_eq!(querycat["limits"]["max_radius_arcsec"], 3600.);
+    assert_eq!(querycat["admin_only"], false);
+    assert_eq!(querycat["memory_cost_mib"], 0);
+    assert_eq!(querycat["max_concurrency"], Value::Null);
+
+    let cutout = &handlers[names.iter().position(|n| *n == "cutout").unwrap()];
+    assert_eq!(cutout["memory_cost_mib"], 96);
+    let selftest = &handlers[names.iter().position(|n| *n == "selftest").unwrap()];
+    assert_eq!(selftest["max_concurrency"], 1);
+
+    assert_eq!(
+        querycat["limits"]["refcat_epochs"],
+        json!({"apass": 2000., "atlas": 2015.5, "2mass": 2000., "tycho2": 2000.})
+    );
+
+    let columns = &querycat["limits"]["refcat_columns"]["apass"];
+    assert_eq!(columns[0], json!({"name": "ref_text", "type": "string"}));
+    assert_eq!(
+        columns[3],
+        json!({"name": "raDeg", "type": "float", "unit": "deg"})
+    );
+
+    let checkbin = &handlers[names.iter().position(|n| *n == "checkbin").unwrap()];
+    assert_eq!(checkbin["admin_only"], true);
+}
+
+/// The values held by [`fake_redis`].
+#[cfg(feature = "elasticache")]
+type RedisValues = std::sync::Arc<std::sync::Mutex<std::collections::HashMap<Vec<u8>, Vec<u8>>>>;
+
+/// A stand-in for a Redis server that keeps `SETEX` values in memory, answers
+/// `GET` from them, and answers anything else with `OK`. Returns its URL and
+/// the values that have been set.
+#[cfg(feature = "elasticache")]
+fn fake_redis() -> (String, RedisValues) {
+    use std::io::{BufRead, BufReader, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("redis://{}/", listener.local_addr().unwrap());
+    let values = RedisValues::default();
+    let shared = values.clone();
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let values = shared.clone();
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+            std::thread::spawn(move || loop {
+                // Every command is an array of bulk strings.
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                    return;
+                }
+                let n: usize = line.trim()[1..].parse().unwrap();
+                let mut args = Vec::new();
+
+                for _ in 0..n {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                    let len: usize = line.trim()[1..].parse().unwrap();
+                    let mut arg = vec![0; len + 2];
+                    reader.read_exact(&mut arg).unwrap();
+                    arg.truncate(len);
+                    args.push(arg);
+                }
+
+                let reply = match &args[0].to_ascii_uppercase()[..] {
+                    b"GET" => match values.lock().unwrap().get(&args[1]) {
+                        Some(v) => [format!("${}\r\n", v.len()).as_bytes(), v, b"\r\n"].concat(),
+                        None => b"$-1\r\n".to_vec(),
+                    },
+                    b"SETEX" => {
+                        values
+                            .lock()
+                            .unwrap()
+                            .insert(args[1].clone(), args[3].clone());
+                        b"+OK\r\n".to_vec()
+                    }
+                    _ => b"+OK\r\n".to_vec(),
+                };
+
+                if stream.write_all(&reply).is_err() {
+                    return;
+                }
+            });
+        }
+    });
+
+    (url, values)
+}
+
+#[cfg(feature = "elasticache")]
+#[tokio::test]
+async fn query_cache_skips_non_refcat_tables() {
+    let (url, cache) = fake_redis();
+    let svcs = services()
+        .with_query_cache(&url)
+        .with_provenance_table("dasch-test-provenance");
+    let arn = |f: &str| format!("arn:aws:lambda:us-east-1:000000000000:function:dasch-dev-dr7-{f}");
+
+    // Refcat queries are cached, and answered the same from the cache.
+    let request = json!({"refcat": "apass", "ra_deg": 10.5, "dec_deg": 20.3, "radius_arcsec": 10.});
+    let first = svcs
+        .dispatch(arn("querycat"), Some(request.clone()))
+        .await
+        .unwrap();
+    assert!(!cache.lock().unwrap().is_empty());
+    let second = svcs.dispatch(arn("querycat"), Some(request)).await.unwrap();
+    assert_eq!(first["result"], second["result"]);
+
+    // The provenance table isn't, so `history` sees the cutouts recorded since
+    // its last call.
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let identity = format!("key:test-{nanos}");
+    let history = svcs
+        .dispatch_as(arn("history"), Some(json!({})), None, &identity)
+        .await
+        .unwrap();
+    assert_eq!(history["result"]["records"].as_array().unwrap().len(), 0);
+
+    svcs.dispatch_as(
+        arn("cutout"),
+        Some(json!({
+            "plate_id": "b12345",
+            "solution_number": 0,
+            "center_ra_deg": 10.5,
+            "center_dec_deg": 20.3,
+        })),
+        None,
+        &identity,
+    )
+    .await
+    .unwrap();
+
+    let history = svcs
+        .dispatch_as(arn("history"), Some(json!({})), None, &identity)
+        .await
+        .unwrap();
+    assert_eq!(history["result"]["records"].as_array().unwrap().len(), 1);
+
+    for key in cache.lock().unwrap().keys() {
+        let key = String::from_utf8_lossy(key);
+        assert!(key.contains("refcat"), "{key}");
+    }
+}